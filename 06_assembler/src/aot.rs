@@ -0,0 +1,645 @@
+//! Ahead-of-time translation of Hack machine code into Rust, for programs
+//! too big to interpret at a useful speed. The translation keeps the ROM and
+//! a step interpreter like `Cpu`'s, but runs most of the program as basic
+//! blocks: straight-line Rust in the arms of one `match pc`, with jumps to
+//! computed addresses going back through the match. Blocks count their
+//! instructions as the interpreter does, so a run stopped after some number
+//! of cycles leaves RAM just as `Cpu::run` would.
+
+use crate::emulator::{Cpu, RAM_SIZE};
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// `0;JMP`, which after an `@n` at n is the halt loop `Cpu::is_halted` sees
+const HALT: u16 = 0b1110_1010_1000_0111;
+
+/// What every translation starts with, up to its ROM
+const HEADER: &str = "\
+//! A Hack program translated into Rust by hack_aot. Build it with
+//! `rustc -O`, and run it as `PROGRAM [-r RAM] [-w RAM] [-n CYCLES]`:
+//! `-r` loads RAM from an image of 32K little-endian words before running,
+//! `-w` saves it to one afterwards, and `-n` stops the program after that
+//! many instructions if it hasn't halted by then.
+
+#![allow(unused_assignments, unused_variables, unreachable_code)]
+
+use std::{env, fs, process};
+
+const RAM_SIZE: usize = 32768;
+
+";
+
+/// What every translation has after its ROM and before its blocks
+const RUNTIME: &str = "
+struct Cpu {
+    ram: Box<[i16; RAM_SIZE]>,
+    a: i16,
+    d: i16,
+    pc: u16,
+    cycles: u64,
+}
+
+impl Cpu {
+    /// Executes the instruction at `pc`, for where no block starts
+    fn step(&mut self) {
+        let word = ROM.get(self.pc as usize).copied().unwrap_or(0);
+        self.cycles += 1;
+
+        if word & 0x8000 == 0 {
+            self.a = word as i16;
+            self.pc = self.pc.wrapping_add(1);
+            return;
+        }
+
+        let address = self.a as u16 as usize % RAM_SIZE;
+        let y = if word & 0x1000 != 0 {
+            self.ram[address]
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, word >> 6);
+
+        if word & 0b001000 != 0 {
+            self.ram[address] = out;
+        }
+        if word & 0b100000 != 0 {
+            self.a = out;
+        }
+        if word & 0b010000 != 0 {
+            self.d = out;
+        }
+
+        let jump = (word & 0b100 != 0 && out < 0)
+            || (word & 0b010 != 0 && out == 0)
+            || (word & 0b001 != 0 && out > 0);
+        self.pc = if jump {
+            address as u16
+        } else {
+            self.pc.wrapping_add(1)
+        };
+    }
+
+    /// Whether the program has run past the end of ROM or is spinning in an
+    /// `@n 0;JMP` loop at n
+    fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        pc >= ROM.len() || pc > 0 && ROM[pc] == 0xEA87 && ROM[pc - 1] == (pc - 1) as u16
+    }
+
+    fn run(&mut self, limit: u64) {
+        while self.cycles < limit && !self.is_halted() {
+            if !self.run_blocks(limit) {
+                self.step();
+            }
+        }
+    }
+}
+
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+    let bit = |n: u16| control & (1 << n) != 0;
+    let x = if bit(5) { 0 } else { x };
+    let x = if bit(4) { !x } else { x };
+    let y = if bit(3) { 0 } else { y };
+    let y = if bit(2) { !y } else { y };
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+fn main() {
+    let mut cpu = Cpu {
+        ram: Box::new([0; RAM_SIZE]),
+        a: 0,
+        d: 0,
+        pc: 0,
+        cycles: 0,
+    };
+    let mut limit = u64::MAX;
+    let mut output = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            \"-r\" => {
+                let image = fs::read(&value).unwrap_or_else(|err| fail(&value, err));
+                for (word, bytes) in cpu.ram.iter_mut().zip(image.chunks_exact(2)) {
+                    *word = i16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            \"-w\" => output = Some(value),
+            \"-n\" => limit = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    cpu.run(limit);
+
+    if let Some(path) = output {
+        let image: Vec<u8> = cpu.ram.iter().flat_map(|word| word.to_le_bytes()).collect();
+        fs::write(&path, image).unwrap_or_else(|err| fail(&path, err));
+    }
+    println!(
+        \"{}: cycles {}, pc {}, a {}, d {}\",
+        if cpu.is_halted() { \"halted\" } else { \"stopped\" },
+        cpu.cycles,
+        cpu.pc,
+        cpu.a,
+        cpu.d
+    );
+}
+
+fn fail(path: &str, err: std::io::Error) -> ! {
+    eprintln!(\"{}: {}\", path, err);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!(\"usage: PROGRAM [-r RAM] [-w RAM] [-n CYCLES]\");
+    process::exit(2);
+}
+";
+
+/// Translates a program into the source of a Rust program that runs it
+pub fn translate(rom: &[u16]) -> String {
+  let halts: BTreeSet<usize> = (1..rom.len())
+    .filter(|&pc| rom[pc] == HALT && rom[pc - 1] == (pc - 1) as u16)
+    .collect();
+  let leaders = leaders(rom, &halts);
+  let mut out = String::from(HEADER);
+
+  writeln!(out, "const ROM: [u16; {}] = [", rom.len()).unwrap();
+  for words in rom.chunks(8) {
+    let words: Vec<String> = words
+      .iter()
+      .map(|word| format!("0x{:04X},", word))
+      .collect();
+    writeln!(out, "    {}", words.join(" ")).unwrap();
+  }
+  out.push_str("];\n");
+  out.push_str(RUNTIME);
+
+  out.push_str(
+    "
+impl Cpu {
+    /// Runs blocks while one starts at `pc` and fits in the cycles left,
+    /// returning whether any ran
+    fn run_blocks(&mut self, limit: u64) -> bool {
+        let ram = &mut *self.ram;
+        let (mut a, mut d, mut pc, mut cycles) = (self.a, self.d, self.pc, self.cycles);
+        let start = cycles;
+
+        loop {
+            match pc {
+",
+  );
+  // A halt loop gets no block, so that it stops the program as in `Cpu`
+  for &leader in leaders.difference(&halts) {
+    block(rom, leader, &leaders, &mut out);
+  }
+  out.push_str(
+    "                _ => break,
+            }
+        }
+
+        self.a = a;
+        self.d = d;
+        self.pc = pc;
+        self.cycles = cycles;
+        cycles > start
+    }
+}
+",
+  );
+
+  out
+}
+
+/// Where blocks start: at 0, at whatever an A-instruction could jump to,
+/// after each jump and at each halt loop
+fn leaders(rom: &[u16], halts: &BTreeSet<usize>) -> BTreeSet<usize> {
+  let mut leaders: BTreeSet<usize> = halts.clone();
+  if !rom.is_empty() {
+    leaders.insert(0);
+  }
+
+  for (pc, &word) in rom.iter().enumerate() {
+    if word & 0x8000 == 0 && (word as usize) < rom.len() {
+      leaders.insert(word as usize);
+    } else if word & 0x8000 != 0 && word & 0b111 != 0 && pc + 1 < rom.len() {
+      leaders.insert(pc + 1);
+    }
+  }
+  leaders
+}
+
+/// Writes the arm of the block starting at `start`
+fn block(rom: &[u16], start: usize, leaders: &BTreeSet<usize>, out: &mut String) {
+  let mut end = start + 1;
+  while end < rom.len() && !leaders.contains(&end) && !is_jump(rom[end - 1]) {
+    end += 1;
+  }
+
+  writeln!(out, "                {} => {{", start).unwrap();
+  writeln!(
+    out,
+    "                    if limit - cycles < {} {{",
+    end - start
+  )
+  .unwrap();
+  out.push_str("                        break;\n                    }\n");
+  writeln!(out, "                    cycles += {};", end - start).unwrap();
+
+  // The value of A where it's an A-instruction's, for constant addresses
+  let mut known = None;
+  for (pc, &word) in rom.iter().enumerate().take(end).skip(start) {
+    if word & 0x8000 == 0 {
+      writeln!(out, "                    a = {};", word).unwrap();
+      known = Some(word);
+      continue;
+    }
+
+    for line in instruction(pc, word, known) {
+      writeln!(out, "                    {}", line).unwrap();
+    }
+    if word & 0b100000 != 0 {
+      known = None;
+    }
+  }
+
+  if !is_jump(rom[end - 1]) {
+    writeln!(out, "                    pc = {};", end).unwrap();
+  }
+  out.push_str("                }\n");
+}
+
+fn is_jump(word: u16) -> bool {
+  word & 0x8000 != 0 && word & 0b111 != 0
+}
+
+/// The lines of Rust running the C-instruction `word` at `pc`, with `known`
+/// the value of A if it's constant there
+fn instruction(pc: usize, word: u16, known: Option<u16>) -> Vec<String> {
+  let mut lines = Vec::new();
+  let reads = word & 0x1000 != 0;
+  let writes = word & 0b001000 != 0;
+  let jump = word & 0b111;
+
+  let address = match known {
+    Some(address) => address.to_string(),
+    None => {
+      if reads || writes || jump != 0 {
+        lines.push(String::from("let address = a as u16 as usize % RAM_SIZE;"));
+      }
+      String::from("address")
+    }
+  };
+  let y = if reads {
+    format!("ram[{}]", address)
+  } else {
+    String::from("a")
+  };
+
+  lines.push(format!("let out = {};", comp(word >> 6 & 0b11_1111, &y)));
+  if writes {
+    lines.push(format!("ram[{}] = out;", address));
+  }
+  if word & 0b100000 != 0 {
+    lines.push(String::from("a = out;"));
+  }
+  if word & 0b010000 != 0 {
+    lines.push(String::from("d = out;"));
+  }
+
+  let target = match known {
+    Some(address) => address.to_string(),
+    None => String::from("address as u16"),
+  };
+  let condition = match jump {
+    0 => return lines,
+    0b001 => "out > 0",
+    0b010 => "out == 0",
+    0b011 => "out >= 0",
+    0b100 => "out < 0",
+    0b101 => "out != 0",
+    0b110 => "out <= 0",
+    _ => {
+      lines.push(format!("pc = {};", target));
+      return lines;
+    }
+  };
+  lines.push(format!(
+    "pc = if {} {{ {} }} else {{ {} }};",
+    condition,
+    target,
+    pc + 1
+  ));
+  lines
+}
+
+/// The expression for the ALU's output with control bits `control`, D as x
+/// and `y` as y: the computations the assembler has mnemonics for directly,
+/// and the rest through `alu`
+fn comp(control: u16, y: &str) -> String {
+  match control {
+    0b101010 => String::from("0"),
+    0b111111 => String::from("1"),
+    0b111010 => String::from("-1"),
+    0b001100 => String::from("d"),
+    0b110000 => y.to_string(),
+    0b001101 => String::from("!d"),
+    0b110001 => format!("!{}", y),
+    0b001111 => String::from("d.wrapping_neg()"),
+    0b110011 => format!("{}.wrapping_neg()", y),
+    0b011111 => String::from("d.wrapping_add(1)"),
+    0b110111 => format!("{}.wrapping_add(1)", y),
+    0b001110 => String::from("d.wrapping_sub(1)"),
+    0b110010 => format!("{}.wrapping_sub(1)", y),
+    0b000010 => format!("d.wrapping_add({})", y),
+    0b010011 => format!("d.wrapping_sub({})", y),
+    0b000111 => format!("{}.wrapping_sub(d)", y),
+    0b000000 => format!("d & {}", y),
+    0b010101 => format!("d | {}", y),
+    _ => format!("alu(d, {}, 0b{:06b})", y, control),
+  }
+}
+
+/// Builds a translation into the executable `binary` with rustc, leaving
+/// its source beside it
+pub fn build(source: &str, binary: &Path) -> Result<(), String> {
+  let path = binary.with_extension("rs");
+  fs::write(&path, source).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+  let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+  let output = Command::new(&rustc)
+    .args(["-O", "--edition", "2018", "-o"])
+    .arg(binary)
+    .arg(&path)
+    .output()
+    .map_err(|err| format!("{}: {}", rustc, err))?;
+
+  if !output.status.success() {
+    return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+  }
+  Ok(())
+}
+
+/// Runs a built translation of `rom` from `ram` for at most `limit`
+/// instructions, returning the CPU as it stopped
+pub fn run(binary: &Path, rom: &[u16], ram: &[i16], limit: u64) -> Result<Cpu, String> {
+  let image_path = binary.with_extension("ram");
+  let image: Vec<u8> = ram.iter().flat_map(|word| word.to_le_bytes()).collect();
+  fs::write(&image_path, image).map_err(|err| format!("{}: {}", image_path.display(), err))?;
+
+  let output = Command::new(binary)
+    .arg("-r")
+    .arg(&image_path)
+    .arg("-w")
+    .arg(&image_path)
+    .args(["-n", &limit.to_string()])
+    .output()
+    .map_err(|err| format!("{}: {}", binary.display(), err))?;
+  if !output.status.success() {
+    return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+  }
+
+  let mut cpu = Cpu::new(rom.to_vec());
+  let image = fs::read(&image_path).map_err(|err| format!("{}: {}", image_path.display(), err))?;
+  cpu.ram = image
+    .chunks_exact(2)
+    .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+    .collect();
+  if cpu.ram.len() != RAM_SIZE {
+    return Err(format!("{} is not a RAM image", image_path.display()));
+  }
+
+  // halted: cycles 120, pc 7, a 6, d 0
+  let status = String::from_utf8_lossy(&output.stdout);
+  let registers = status
+    .trim()
+    .split_once(": ")
+    .map(|(_, registers)| registers)
+    .ok_or_else(|| format!("unexpected output '{}'", status.trim()))?;
+  for register in registers.split(", ") {
+    let bad = || format!("unexpected output '{}'", status.trim());
+    let (name, value) = register.split_once(' ').ok_or_else(bad)?;
+    match name {
+      "cycles" => cpu.cycles = value.parse().map_err(|_| bad())?,
+      "pc" => cpu.pc = value.parse().map_err(|_| bad())?,
+      "a" => cpu.a = value.parse().map_err(|_| bad())?,
+      "d" => cpu.d = value.parse().map_err(|_| bad())?,
+      _ => return Err(bad()),
+    }
+  }
+
+  Ok(cpu)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Parser;
+  use crate::script::Script;
+  use std::path::PathBuf;
+
+  /// Builds the translation of `rom`, runs it and the interpreter from
+  /// `ram` for at most each of `limits` instructions and compares them,
+  /// skipping without rustc
+  fn check(name: &str, rom: &[u16], ram: &[i16], limits: &[u64]) {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    if Command::new(rustc).arg("--version").output().is_err() {
+      eprintln!("skipping {}: no rustc", name);
+      return;
+    }
+
+    let directory = env::temp_dir().join(format!("hack_aot_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let binary = directory.join(name);
+    build(&translate(rom), &binary).unwrap();
+
+    for &limit in limits {
+      let native = run(&binary, rom, ram, limit).unwrap();
+      let mut cpu = Cpu::new(rom.to_vec());
+      cpu.ram.copy_from_slice(ram);
+      cpu.run(limit);
+
+      let registers = |cpu: &Cpu| (cpu.cycles, cpu.pc, cpu.a, cpu.d);
+      assert_eq!(registers(&native), registers(&cpu), "{}", name);
+      if let Some(address) = (0..RAM_SIZE).find(|&address| native.ram[address] != cpu.ram[address])
+      {
+        panic!(
+          "{} after {}: RAM[{}] is {}, not {}",
+          name, limit, address, native.ram[address], cpu.ram[address]
+        );
+      }
+    }
+    fs::remove_dir_all(&directory).unwrap();
+  }
+
+  fn book(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(path)
+  }
+
+  /// Assembles a program of the book's projects, some of which write
+  /// mnemonics like `M+D` that only the lenient assembler takes
+  fn assemble(path: &str) -> Vec<u16> {
+    let mut parser = Parser::new();
+    parser.set_lenient_mnemonics(true);
+    parser
+      .assemble(&fs::read_to_string(book(path)).unwrap())
+      .unwrap()
+  }
+
+  /// RAM as the `set RAM[n]` commands of a test script leave it
+  fn script_ram(path: &str) -> Vec<i16> {
+    Script::parse(&fs::read_to_string(book(path)).unwrap())
+      .unwrap()
+      .ram(RAM_SIZE)
+  }
+
+  #[test]
+  fn computes_every_comp_as_the_emulator_does() {
+    // D, A and M are -7, 1234 and 99 for each of the 128 comps, whether the
+    // assembler has a mnemonic for it or not, and each result is stored
+    let mut rom = Vec::new();
+    for control in 0..128 {
+      rom.extend(Parser::new().assemble("@7\nD=-A\n@1234").unwrap());
+      rom.push(0b1110_0000_0001_0000 | control << 6);
+      rom.extend(
+        Parser::new()
+          .assemble(&format!("@{}\nM=D", 2000 + control))
+          .unwrap(),
+      );
+    }
+
+    let mut ram = vec![0; RAM_SIZE];
+    ram[1234] = 99;
+    check("comps", &rom, &ram, &[10_000]);
+  }
+
+  #[test]
+  fn takes_every_jump_as_the_emulator_does() {
+    // Counts in RAM[3000 + jump] the values of D from -2 to 2 that jump
+    let mut asm = String::new();
+    for (index, jump) in ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"]
+      .iter()
+      .enumerate()
+    {
+      for value in 0..5 {
+        let label = format!("{}_{}", index, value);
+        asm.push_str(&format!("@{}\nD=A\n@2\nD=D-A\n", value));
+        asm.push_str(&format!(
+          "@TAKEN_{0}\nD;{1}\n@SKIP_{0}\n0;JMP\n",
+          label, jump
+        ));
+        asm.push_str(&format!(
+          "(TAKEN_{})\n@{}\nM=M+1\n(SKIP_{})\n",
+          label,
+          3000 + index,
+          label
+        ));
+      }
+    }
+
+    let rom = Parser::new().assemble(&asm).unwrap();
+    check("jumps", &rom, &vec![0; RAM_SIZE], &[10_000]);
+  }
+
+  #[test]
+  fn stops_mid_block_where_the_emulator_does() {
+    let rom = assemble("06_assembler/rect/Rect.asm");
+    let mut ram = vec![0; RAM_SIZE];
+    ram[0] = 20;
+
+    let limits: Vec<u64> = (0..40).collect();
+    check("rect", &rom, &ram, &limits);
+  }
+
+  #[test]
+  fn runs_the_machine_language_programs() {
+    check(
+      "mult",
+      &assemble("04_machine_language/mult/mult.asm"),
+      &script_ram("04_machine_language/mult/Mult.tst"),
+      &[100_000],
+    );
+
+    // Fill never halts, so runs with a key held until it has blackened
+    // the screen and some way into doing it again
+    let mut ram = vec![0; RAM_SIZE];
+    ram[24576] = 1;
+    check(
+      "fill",
+      &assemble("04_machine_language/fill/Fill.asm"),
+      &ram,
+      &[300_000],
+    );
+  }
+
+  #[test]
+  fn runs_the_computer_programs() {
+    for (name, ram) in [("Add", [0, 0]), ("Max", [3, 8]), ("Rect", [16, 0])].iter() {
+      let text = fs::read_to_string(book(&format!("05_computer_arch/{}.hack", name))).unwrap();
+      let mut initial = vec![0; RAM_SIZE];
+      initial[..2].copy_from_slice(ram);
+
+      check(
+        name,
+        &Cpu::from_hack(&text).unwrap().rom,
+        &initial,
+        &[100_000],
+      );
+    }
+  }
+
+  #[test]
+  fn runs_the_assembler_programs() {
+    let mut ram = vec![0; RAM_SIZE];
+    ram[0] = 12;
+    ram[1] = -31;
+
+    for path in &["add/Add", "max/Max", "max/MaxL", "rect/Rect", "rect/RectL"] {
+      let name = path.split('/').nth(1).unwrap();
+      check(
+        name,
+        &assemble(&format!("06_assembler/{}.asm", path)),
+        &ram,
+        &[100_000],
+      );
+    }
+  }
+
+  #[test]
+  #[ignore = "takes a while to build with rustc"]
+  fn runs_pong() {
+    // Pong waits on the keyboard, so runs for a while after drawing
+    let rom = assemble("06_assembler/pong/Pong.asm");
+    check("pong", &rom, &vec![0; RAM_SIZE], &[5_000_000]);
+  }
+
+  #[test]
+  fn runs_the_vm_test_programs() {
+    for (group, program) in &[
+      ("StackArithmetic", "SimpleAdd"),
+      ("StackArithmetic", "StackTest"),
+      ("MemoryAccess", "BasicTest"),
+      ("MemoryAccess", "PointerTest"),
+      ("MemoryAccess", "StaticTest"),
+    ] {
+      let directory = format!("07_vm_one/{}/{}/{}", group, program, program);
+      check(
+        program,
+        &assemble(&format!("{}.asm", directory)),
+        &script_ram(&format!("{}.tst", directory)),
+        &[100_000],
+      );
+    }
+  }
+}
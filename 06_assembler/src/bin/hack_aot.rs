@@ -0,0 +1,45 @@
+use hack_assembler::aot;
+use hack_assembler::emulator::Cpu;
+use hack_assembler::parser::Parser;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// Usage: `hack_aot INPUT.asm|INPUT.hack OUTPUT.rs|OUTPUT`
+///
+/// Translates the program into Rust. An output ending in .rs gets the
+/// source; any other is built into an executable with rustc, leaving the
+/// source beside it.
+fn main() {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let (input, output) = match args.as_slice() {
+    [input, output] => (input, output),
+    _ => usage("expected an input and an output"),
+  };
+
+  let text = fs::read_to_string(input).unwrap_or_else(|err| fail(input, &err.to_string()));
+  let rom = if input.ends_with(".hack") {
+    Cpu::from_hack(&text).map(|cpu| cpu.rom)
+  } else {
+    Parser::new().assemble(&text).map_err(|err| err.to_string())
+  }
+  .unwrap_or_else(|err| fail(input, &err));
+
+  let source = aot::translate(&rom);
+  if output.ends_with(".rs") {
+    fs::write(output, source).unwrap_or_else(|err| fail(output, &err.to_string()));
+  } else {
+    aot::build(&source, Path::new(output)).unwrap_or_else(|err| fail(output, &err));
+  }
+}
+
+fn fail(path: &str, message: &str) -> ! {
+  eprintln!("{}: {}", path, message);
+  process::exit(1);
+}
+
+fn usage(message: &str) -> ! {
+  eprintln!("hack_aot: {}", message);
+  eprintln!("usage: hack_aot INPUT.asm|INPUT.hack OUTPUT.rs|OUTPUT");
+  process::exit(2);
+}
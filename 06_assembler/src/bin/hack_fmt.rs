@@ -0,0 +1,94 @@
+use hack_assembler::format::{format, Case, Options};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+/// Usage: `hack_fmt [--check] [--indent N] [--comment-column N]
+/// [--case preserve|upper|lower] [FILE.asm ...]`
+///
+/// Formats each file in place. With `--check` nothing is written, and the
+/// exit status is 1 if any file is not already formatted. Without files,
+/// reads from stdin and writes to stdout.
+fn main() {
+  let mut args = std::env::args().skip(1);
+  let mut options = Options::default();
+  let mut check = false;
+  let mut paths = Vec::new();
+
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--check" => check = true,
+      "--indent" => options.indent = number_arg(&arg, args.next()),
+      "--comment-column" => options.comment_column = number_arg(&arg, args.next()),
+      "--case" => {
+        options.case = match args.next().as_deref() {
+          Some("preserve") => Case::Preserve,
+          Some("upper") => Case::Upper,
+          Some("lower") => Case::Lower,
+          _ => usage("--case must be one of preserve, upper or lower"),
+        }
+      }
+      flag if flag.starts_with("--") => usage(&format!("unknown option '{}'", flag)),
+      _ => paths.push(arg),
+    }
+  }
+
+  if paths.is_empty() {
+    let mut input = String::new();
+    io::stdin()
+      .read_to_string(&mut input)
+      .expect("problem reading stdin");
+
+    let formatted = format_or_exit("<stdin>", &input, &options);
+    if check {
+      process::exit(if formatted == input { 0 } else { 1 });
+    }
+
+    io::stdout()
+      .write_all(formatted.as_bytes())
+      .expect("problem writing to stdout");
+    return;
+  }
+
+  let mut unformatted = false;
+
+  for path in &paths {
+    let input = fs::read_to_string(path).expect("problem reading path to string");
+    let formatted = format_or_exit(path, &input, &options);
+
+    if formatted == input {
+      continue;
+    }
+
+    if check {
+      println!("{} is not formatted", path);
+      unformatted = true;
+    } else {
+      fs::write(path, formatted).expect("problem writing formatted file");
+    }
+  }
+
+  if unformatted {
+    process::exit(1);
+  }
+}
+
+fn format_or_exit(path: &str, input: &str, options: &Options) -> String {
+  format(input, options).unwrap_or_else(|err| {
+    eprintln!("{}: {}", path, err);
+    process::exit(2);
+  })
+}
+
+fn number_arg(flag: &str, value: Option<String>) -> usize {
+  match value.and_then(|value| value.parse().ok()) {
+    Some(number) => number,
+    None => usage(&format!("{} requires a number", flag)),
+  }
+}
+
+fn usage(message: &str) -> ! {
+  eprintln!("hack_fmt: {}", message);
+  eprintln!("usage: hack_fmt [--check] [--indent N] [--comment-column N] [--case preserve|upper|lower] [FILE.asm ...]");
+  process::exit(2);
+}
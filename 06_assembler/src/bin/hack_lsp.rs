@@ -0,0 +1,20 @@
+use hack_assembler::lsp::Server;
+use std::io;
+use std::process;
+
+/// Language server for Hack assembly. Editors start it with no arguments
+/// and talk to it over stdin and stdout.
+fn main() {
+  let stdin = io::stdin();
+  let stdout = io::stdout();
+
+  match Server::new().run(stdin.lock(), stdout.lock()) {
+    Ok(true) => {}
+    // exiting without a shutdown request is an error by the protocol
+    Ok(false) => process::exit(1),
+    Err(err) => {
+      eprintln!("hack_lsp: {}", err);
+      process::exit(1);
+    }
+  }
+}
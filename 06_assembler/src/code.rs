@@ -51,7 +51,7 @@ const COMP_TABLE: [(&str, &str); 28] = [
   ("D|M", "1010101"),
 ];
 
-pub fn dest(key: &str) -> Option<&str> {
+pub fn dest(key: &str) -> Option<&'static str> {
   for pair in DEST_TABLE.iter() {
     if pair.0 == key {
       return Some(pair.1);
@@ -60,7 +60,7 @@ pub fn dest(key: &str) -> Option<&str> {
   None
 }
 
-pub fn jump(key: &str) -> Option<&str> {
+pub fn jump(key: &str) -> Option<&'static str> {
   for pair in JUMP_TABLE.iter() {
     if pair.0 == key {
       return Some(pair.1);
@@ -69,7 +69,7 @@ pub fn jump(key: &str) -> Option<&str> {
   None
 }
 
-pub fn comp(key: &str) -> Option<&str> {
+pub fn comp(key: &str) -> Option<&'static str> {
   for pair in COMP_TABLE.iter() {
     if pair.0 == key {
       return Some(pair.1);
@@ -87,3 +87,124 @@ pub fn contains_comp(line: &str) -> bool {
 
   false
 }
+
+/// Mnemonics as they can be offered to someone writing a C-instruction
+pub fn dest_mnemonics() -> impl Iterator<Item = &'static str> {
+  DEST_TABLE
+    .iter()
+    .map(|pair| pair.0)
+    .filter(|key| *key != "null")
+}
+
+pub fn comp_mnemonics() -> impl Iterator<Item = &'static str> {
+  COMP_TABLE.iter().map(|pair| pair.0)
+}
+
+pub fn jump_mnemonics() -> impl Iterator<Item = &'static str> {
+  JUMP_TABLE
+    .iter()
+    .map(|pair| pair.0)
+    .filter(|key| *key != "null")
+}
+
+/// Returns the table spelling of a dest mnemonic, accepting the registers in
+/// any order and any case, so `dm` and `DM` both become `MD`
+pub fn canonical_dest(token: &str) -> Option<&'static str> {
+  let token = token.to_ascii_uppercase();
+  let ordered: String = "AMD".chars().filter(|c| token.contains(*c)).collect();
+  if ordered.len() != token.len() {
+    return None;
+  }
+
+  DEST_TABLE
+    .iter()
+    .find(|pair| pair.0 == ordered)
+    .map(|pair| pair.0)
+}
+
+/// Returns the table spelling of a comp mnemonic, ignoring case and spacing
+/// and accepting commuted operands, so `m + d` becomes `D+M`
+pub fn canonical_comp(token: &str) -> Option<&'static str> {
+  let token: String = token
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .collect::<String>()
+    .to_ascii_uppercase();
+
+  let lookup = |key: &str| {
+    COMP_TABLE
+      .iter()
+      .find(|pair| pair.0 == key)
+      .map(|pair| pair.0)
+  };
+
+  if let Some(found) = lookup(&token) {
+    return Some(found);
+  }
+
+  let chars: Vec<char> = token.chars().collect();
+  match chars.as_slice() {
+    [left, op, right] if "+&|".contains(*op) => lookup(&format!("{}{}{}", right, op, left)),
+    _ => None,
+  }
+}
+
+pub fn canonical_jump(token: &str) -> Option<&'static str> {
+  let token = token.trim().to_ascii_uppercase();
+
+  JUMP_TABLE
+    .iter()
+    .find(|pair| pair.0 == token && pair.0 != "null")
+    .map(|pair| pair.0)
+}
+
+/// Encodes a C-instruction, taking mnemonics in any form accepted by the
+/// `canonical_*` functions
+pub fn encode(dest: Option<&str>, comp: &str, jump: Option<&str>) -> Option<u16> {
+  let bits = |field: &str| u16::from_str_radix(field, 2).ok();
+
+  let dest = match dest {
+    Some(token) => bits(self::dest(canonical_dest(token)?)?)?,
+    None => 0,
+  };
+  let comp = bits(self::comp(canonical_comp(comp)?)?)?;
+  let jump = match jump {
+    Some(token) => bits(self::jump(canonical_jump(token)?)?)?,
+    None => 0,
+  };
+
+  Some(0b111 << 13 | comp << 6 | dest << 3 | jump)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn canonicalizes_dest() {
+    assert_eq!(canonical_dest("DM"), Some("MD"));
+    assert_eq!(canonical_dest("dam"), Some("AMD"));
+    assert_eq!(canonical_dest("MM"), None);
+    assert_eq!(canonical_dest("X"), None);
+  }
+
+  #[test]
+  fn canonicalizes_comp() {
+    assert_eq!(canonical_comp("D + M"), Some("D+M"));
+    assert_eq!(canonical_comp("M+D"), Some("D+M"));
+    assert_eq!(canonical_comp("1+a"), Some("A+1"));
+    assert_eq!(canonical_comp("a|d"), Some("D|A"));
+    assert_eq!(canonical_comp("M-D"), Some("M-D"));
+    assert_eq!(canonical_comp("D-M-1"), None);
+  }
+
+  #[test]
+  fn encodes_c_instructions() {
+    assert_eq!(
+      encode(Some("D"), "D+M", Some("JGT")),
+      Some(0b1111_0000_1001_0001)
+    );
+    assert_eq!(encode(None, "0", Some("jmp")), Some(0b1110_1010_1000_0111));
+    assert_eq!(encode(Some("Q"), "0", None), None);
+  }
+}
@@ -0,0 +1,279 @@
+use crate::script::{self, Command, Script};
+
+/// Words of RAM the CPU can address; the screen and keyboard are plain memory here
+pub const RAM_SIZE: usize = 32768;
+
+/// The Hack CPU with its ROM and RAM, run an instruction at a time
+pub struct Cpu {
+  pub rom: Vec<u16>,
+  pub ram: Vec<i16>,
+  pub a: i16,
+  pub d: i16,
+  pub pc: u16,
+  /// Instructions executed so far
+  pub cycles: u64,
+}
+
+impl Cpu {
+  pub fn new(rom: Vec<u16>) -> Cpu {
+    Cpu {
+      rom,
+      ram: vec![0; RAM_SIZE],
+      a: 0,
+      d: 0,
+      pc: 0,
+      cycles: 0,
+    }
+  }
+
+  /// Loads a program from the text of a .hack file
+  pub fn from_hack(text: &str) -> Result<Cpu, String> {
+    let mut rom = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+
+      match u16::from_str_radix(line, 2) {
+        Ok(word) if line.len() == 16 => rom.push(word),
+        _ => {
+          return Err(format!(
+            "line {}: '{}' is not a 16-bit word",
+            index + 1,
+            line
+          ))
+        }
+      }
+    }
+
+    Ok(Cpu::new(rom))
+  }
+
+  fn address(&self) -> usize {
+    self.a as u16 as usize % RAM_SIZE
+  }
+
+  /// Executes the instruction at `pc`
+  pub fn step(&mut self) {
+    let word = self.rom.get(self.pc as usize).copied().unwrap_or(0);
+    self.cycles += 1;
+
+    // A-instruction
+    if word & 0x8000 == 0 {
+      self.a = word as i16;
+      self.pc = self.pc.wrapping_add(1);
+      return;
+    }
+
+    let address = self.address();
+    let y = if word & 0x1000 != 0 {
+      self.ram[address]
+    } else {
+      self.a
+    };
+    let out = alu(self.d, y, word >> 6);
+
+    if word & 0b001000 != 0 {
+      self.ram[address] = out;
+    }
+    if word & 0b100000 != 0 {
+      self.a = out;
+    }
+    if word & 0b010000 != 0 {
+      self.d = out;
+    }
+
+    let jump = (word & 0b100 != 0 && out < 0)
+      || (word & 0b010 != 0 && out == 0)
+      || (word & 0b001 != 0 && out > 0);
+
+    self.pc = if jump {
+      address as u16
+    } else {
+      self.pc.wrapping_add(1)
+    };
+  }
+
+  /// Whether the program has finished: it has either run past the end of
+  /// ROM, or is spinning in an `(END) @END 0;JMP` loop
+  pub fn is_halted(&self) -> bool {
+    let pc = self.pc as usize;
+
+    if pc >= self.rom.len() {
+      return true;
+    }
+
+    // `0;JMP` to the `@n` at n just before it
+    pc > 0 && self.rom[pc] == 0b1110_1010_1000_0111 && self.rom[pc - 1] == (pc - 1) as u16
+  }
+
+  /// Runs until the program halts or `limit` more instructions have run,
+  /// returning whether it halted
+  pub fn run(&mut self, limit: u64) -> bool {
+    for _ in 0..limit {
+      if self.is_halted() {
+        return true;
+      }
+      self.step();
+    }
+
+    self.is_halted()
+  }
+
+  /// Runs a CPU emulator test script against the loaded program, returning
+  /// what it would write to its output file. Steps once the program has
+  /// halted are skipped, as they can't change memory.
+  pub fn run_script(&mut self, script: &Script) -> Result<String, String> {
+    let mut columns = Vec::new();
+    let mut output = String::new();
+
+    self.run_commands(&script.commands, &mut columns, &mut output)?;
+    Ok(output)
+  }
+
+  fn run_commands(
+    &mut self,
+    commands: &[Command],
+    columns: &mut Vec<script::Column>,
+    output: &mut String,
+  ) -> Result<(), String> {
+    for command in commands {
+      match command {
+        Command::Load(_) | Command::OutputFile(_) | Command::CompareTo(_) => (),
+        Command::OutputList(list) => {
+          *columns = list.clone();
+          output.push_str(&script::header(columns));
+        }
+        Command::Set { name, index, value } => match (name.as_str(), index) {
+          ("RAM", Some(index)) if (*index as usize) < RAM_SIZE => {
+            self.ram[*index as usize] = *value
+          }
+          ("PC", None) => self.pc = *value as u16,
+          ("A", None) => self.a = *value,
+          ("D", None) => self.d = *value,
+          _ => return Err(format!("can't set {}", script::target(name, *index))),
+        },
+        Command::Step(step) => match step.as_str() {
+          "ticktock" | "tock" => {
+            if !self.is_halted() {
+              self.step();
+            }
+          }
+          "tick" => (),
+          other => return Err(format!("the CPU can't '{}'", other)),
+        },
+        Command::Output => {
+          let mut values = Vec::new();
+          for column in columns.iter() {
+            values.push(self.read(&column.name, column.index)?);
+          }
+          output.push_str(&script::row(columns, &values));
+        }
+        Command::Repeat { count, body } => {
+          let count = count.ok_or("repeat without a count never ends")?;
+          for _ in 0..count {
+            self.run_commands(body, columns, output)?;
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  fn read(&self, name: &str, index: Option<u16>) -> Result<i16, String> {
+    match (name, index) {
+      ("RAM", Some(index)) if (index as usize) < RAM_SIZE => Ok(self.ram[index as usize]),
+      ("PC", None) => Ok(self.pc as i16),
+      ("A", None) => Ok(self.a),
+      ("D", None) => Ok(self.d),
+      _ => Err(format!("can't output {}", script::target(name, index))),
+    }
+  }
+}
+
+/// The Hack ALU, with `control` holding zx nx zy ny f no in its low 6 bits
+fn alu(x: i16, y: i16, control: u16) -> i16 {
+  let bit = |n: u16| control & (1 << n) != 0;
+
+  let mut x = if bit(5) { 0 } else { x };
+  if bit(4) {
+    x = !x;
+  }
+  let mut y = if bit(3) { 0 } else { y };
+  if bit(2) {
+    y = !y;
+  }
+
+  let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+  if bit(0) {
+    !out
+  } else {
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Parser;
+
+  fn load(asm: &str) -> Cpu {
+    Cpu::new(Parser::new().assemble(asm).unwrap())
+  }
+
+  #[test]
+  fn computes_every_comp() {
+    let cases = [
+      ("0", 0),
+      ("1", 1),
+      ("-1", -1),
+      ("D", 7),
+      ("A", -3),
+      ("!D", !7),
+      ("-A", 3),
+      ("D+1", 8),
+      ("A-1", -4),
+      ("D+A", 4),
+      ("D-A", 10),
+      ("A-D", -10),
+      ("D&A", 7 & -3),
+      ("D|A", 7 | -3),
+    ];
+
+    for (comp, expected) in cases.iter() {
+      let mut cpu = load(&format!("@7\nD=A\n@3\nA=-A\nD={}", comp));
+      cpu.run(100);
+      assert_eq!(cpu.d, *expected, "D={}", comp);
+    }
+  }
+
+  #[test]
+  fn runs_max_until_it_halts() {
+    let mut cpu = load(include_str!("../max/Max.asm"));
+    cpu.ram[0] = 12;
+    cpu.ram[1] = 31;
+
+    assert!(cpu.run(1000));
+    assert_eq!(cpu.ram[2], 31);
+    assert!(cpu.cycles < 100);
+  }
+
+  #[test]
+  fn runs_scripts() {
+    let mut cpu = load("@R0\nD=M\n@R1\nM=D+M");
+    let script = Script::parse(
+      "load Add.asm, output-list RAM[1]%D1.6.1;\n\
+       set RAM[0] 2, set RAM[1] 40;\n\
+       repeat 10 { ticktock; }\n\
+       output;",
+    )
+    .unwrap();
+
+    let output = cpu.run_script(&script).unwrap();
+    assert_eq!(output, "| RAM[1] |\n|     42 |\n");
+    assert!(script::compare(&output, "|RAM[1]|\r\n|  42  |\r\n").is_ok());
+  }
+}
@@ -0,0 +1,49 @@
+use crate::symbol_table::SymbolError;
+use std::fmt;
+
+/// An error found while assembling, located at a 1-based source line
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+  pub line: usize,
+  pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+  InvalidDest(String),
+  InvalidComp(String),
+  InvalidJump(String),
+  /// An `@` or `(...)` whose name is not a valid Hack symbol
+  InvalidSymbol(String),
+  /// A literal `@value` that does not fit in the 15 bits of an A-instruction
+  LiteralOutOfRange(String),
+  /// A line that is none of an A-instruction, C-instruction or label
+  UnknownInstruction(String),
+  DuplicateLabel(String),
+  Symbol(SymbolError),
+}
+
+impl fmt::Display for AsmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.kind)
+  }
+}
+
+impl fmt::Display for ErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use ErrorKind::*;
+
+    match self {
+      InvalidDest(token) => write!(f, "invalid dest '{}'", token),
+      InvalidComp(token) => write!(f, "invalid comp '{}'", token),
+      InvalidJump(token) => write!(f, "invalid jump '{}'", token),
+      InvalidSymbol(token) => write!(f, "invalid symbol '{}'", token),
+      LiteralOutOfRange(token) => write!(f, "literal '{}' is larger than 32767", token),
+      UnknownInstruction(line) => write!(f, "unknown instruction '{}'", line),
+      DuplicateLabel(label) => write!(f, "label '{}' is declared more than once", label),
+      Symbol(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl std::error::Error for AsmError {}
@@ -0,0 +1,240 @@
+use crate::code;
+use crate::error::AsmError;
+use crate::parser::{parse_lines, Address, Instruction, Line};
+
+/// How C-instruction mnemonics are cased. Symbols are case sensitive and
+/// are never changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case {
+  /// Keep lowercase mnemonics lowercase, everything else uppercase
+  Preserve,
+  Upper,
+  Lower,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+  /// Spaces before instructions and indented comments
+  pub indent: usize,
+  /// Column trailing comments start at, unless the code is already past it
+  pub comment_column: usize,
+  pub case: Case,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      indent: 4,
+      comment_column: 24,
+      case: Case::Preserve,
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+  Blank,
+  Comment,
+  Label,
+  Instruction,
+}
+
+/// Canonicalizes Hack assembly source. Formatting already formatted source
+/// returns it unchanged.
+///
+/// - labels are flush-left and instructions indented by `options.indent`
+/// - trailing comments start at `options.comment_column`
+/// - comment-only lines are either flush-left or indented like instructions
+/// - C-instructions use the spelling from the code tables: `M+D` is `D+M`
+/// - runs of blank lines collapse into one, and a blank line separates each
+///   labelled block (with the comments directly above it) from the code before
+pub fn format(input: &str, options: &Options) -> Result<String, AsmError> {
+  let lines = parse_lines(input).collect::<Result<Vec<Line>, AsmError>>()?;
+  let mut out: Vec<(Kind, String)> = Vec::new();
+
+  for line in lines {
+    match &line.instruction {
+      None => match line.comment {
+        None => {
+          let previous = out.last().map(|(kind, _)| *kind);
+          if previous.is_some() && previous != Some(Kind::Blank) && previous != Some(Kind::Label) {
+            out.push((Kind::Blank, String::new()));
+          }
+        }
+        Some(comment) => {
+          let indent = if line.indent == 0 { 0 } else { options.indent };
+          let text = format!("{}//{}", " ".repeat(indent), comment.trim_end());
+          out.push((Kind::Comment, text));
+        }
+      },
+      Some(instruction @ Instruction::Label(_)) => {
+        // comments directly above a label belong to its block
+        let mut start = out.len();
+        while start > 0 && out[start - 1].0 == Kind::Comment {
+          start -= 1;
+        }
+
+        if start > 0 && out[start - 1].0 == Kind::Instruction {
+          out.insert(start, (Kind::Blank, String::new()));
+        }
+
+        let text = with_comment(render(instruction, options.case), line.comment, options);
+        out.push((Kind::Label, text));
+      }
+      Some(instruction) => {
+        let code = format!(
+          "{}{}",
+          " ".repeat(options.indent),
+          render(instruction, options.case)
+        );
+        out.push((Kind::Instruction, with_comment(code, line.comment, options)));
+      }
+    }
+  }
+
+  while out.last().map(|(kind, _)| *kind) == Some(Kind::Blank) {
+    out.pop();
+  }
+
+  let mut formatted = String::new();
+  for (_, text) in out {
+    formatted.push_str(&text);
+    formatted.push('\n');
+  }
+
+  Ok(formatted)
+}
+
+fn with_comment(code: String, comment: Option<&str>, options: &Options) -> String {
+  match comment {
+    None => code,
+    Some(comment) => {
+      let padding = if code.len() < options.comment_column {
+        options.comment_column - code.len()
+      } else {
+        1
+      };
+
+      format!("{}{}//{}", code, " ".repeat(padding), comment.trim_end())
+    }
+  }
+}
+
+/// Renders an instruction in canonical form
+pub fn render(instruction: &Instruction, case: Case) -> String {
+  match instruction {
+    Instruction::A(Address::Literal(value)) => format!("@{}", value),
+    Instruction::A(Address::Symbol(symbol)) => format!("@{}", symbol),
+    Instruction::Label(symbol) => format!("({})", symbol),
+    Instruction::C { dest, comp, jump } => {
+      let mut text = String::new();
+
+      // mnemonics were validated when the line was parsed
+      if let Some(dest) = dest {
+        text.push_str(&cased(dest, code::canonical_dest(dest).unwrap(), case));
+        text.push('=');
+      }
+
+      text.push_str(&cased(comp, code::canonical_comp(comp).unwrap(), case));
+
+      if let Some(jump) = jump {
+        text.push(';');
+        text.push_str(&cased(jump, code::canonical_jump(jump).unwrap(), case));
+      }
+
+      text
+    }
+  }
+}
+
+fn cased(original: &str, canonical: &str, case: Case) -> String {
+  let lower = match case {
+    Case::Upper => false,
+    Case::Lower => true,
+    Case::Preserve => {
+      original.chars().any(|c| c.is_alphabetic()) && !original.chars().any(|c| c.is_uppercase())
+    }
+  };
+
+  if lower {
+    canonical.to_lowercase()
+  } else {
+    canonical.to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Parser;
+
+  fn fmt(input: &str) -> String {
+    format(input, &Options::default()).unwrap()
+  }
+
+  #[test]
+  fn indents_instructions_and_aligns_comments() {
+    let input = "   @R0\n   D=M              // D = first number\n(END)   // done\n";
+    let expected =
+      "    @R0\n    D=M                 // D = first number\n\n(END)                   // done\n";
+    assert_eq!(fmt(input), expected);
+  }
+
+  #[test]
+  fn canonicalizes_c_instructions() {
+    assert_eq!(fmt("DM = M + D ; JGT"), "    MD=D+M;JGT\n");
+    assert_eq!(fmt("dm=m+d"), "    md=d+m\n");
+
+    let upper = Options {
+      case: Case::Upper,
+      ..Options::default()
+    };
+    assert_eq!(format("d=!a", &upper).unwrap(), "    D=!A\n");
+
+    let lower = Options {
+      case: Case::Lower,
+      ..Options::default()
+    };
+    assert_eq!(
+      format("@LOOP\nD;JGT", &lower).unwrap(),
+      "    @LOOP\n    d;jgt\n"
+    );
+  }
+
+  #[test]
+  fn groups_labelled_blocks() {
+    let input = "\n\n@1\nD=A\n// start of the loop\n(LOOP)\n\n(ALSO)\n@2\n\n\n\n0;JMP\n\n";
+    let expected = "    @1\n    D=A\n\n// start of the loop\n(LOOP)\n(ALSO)\n    @2\n\n    0;JMP\n";
+    assert_eq!(fmt(input), expected);
+  }
+
+  #[test]
+  fn keeps_comment_lines_flush_or_indented() {
+    let input = "// header\n\n  // indented\n@0";
+    assert_eq!(fmt(input), "// header\n\n    // indented\n    @0\n");
+  }
+
+  #[test]
+  fn reports_parse_errors() {
+    let err = format("@0\nD=Q+1", &Options::default()).unwrap_err();
+    assert_eq!(err.line, 2);
+  }
+
+  #[test]
+  fn is_idempotent_and_preserves_the_program() {
+    let sources = [
+      include_str!("../max/Max.asm"),
+      include_str!("../rect/Rect.asm"),
+      include_str!("../pong/Pong.asm"),
+    ];
+
+    for source in sources.iter() {
+      let once = fmt(source);
+      assert_eq!(fmt(&once), once);
+
+      let before = Parser::new().parse(source).unwrap();
+      let after = Parser::new().parse(&once).unwrap();
+      assert_eq!(before, after);
+    }
+  }
+}
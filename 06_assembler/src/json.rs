@@ -0,0 +1,362 @@
+use std::fmt;
+
+/// A JSON value, just enough to speak the language server protocol and
+/// write the VM translator's graphs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  /// Keys keep their insertion order
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+      pairs
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect(),
+    )
+  }
+
+  pub fn get(&self, key: &str) -> Option<&Json> {
+    match self {
+      Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+      _ => None,
+    }
+  }
+
+  /// Follows a path of object keys, e.g. `["params", "textDocument", "uri"]`
+  pub fn path(&self, keys: &[&str]) -> Option<&Json> {
+    keys.iter().try_fold(self, |value, key| value.get(key))
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(s) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn as_u64(&self) -> Option<u64> {
+    match self {
+      Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Json::Bool(b) => Some(*b),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&Vec<Json>> {
+    match self {
+      Json::Array(items) => Some(items),
+      _ => None,
+    }
+  }
+
+  pub fn parse(input: &str) -> Result<Json, String> {
+    let mut reader = Reader {
+      chars: input.chars().collect(),
+      pos: 0,
+    };
+
+    let value = reader.value()?;
+    reader.whitespace();
+
+    if reader.pos != reader.chars.len() {
+      return Err(format!("unexpected trailing input at {}", reader.pos));
+    }
+
+    Ok(value)
+  }
+}
+
+impl From<&str> for Json {
+  fn from(s: &str) -> Json {
+    Json::String(s.to_string())
+  }
+}
+
+impl From<String> for Json {
+  fn from(s: String) -> Json {
+    Json::String(s)
+  }
+}
+
+impl From<usize> for Json {
+  fn from(n: usize) -> Json {
+    Json::Number(n as f64)
+  }
+}
+
+impl From<bool> for Json {
+  fn from(b: bool) -> Json {
+    Json::Bool(b)
+  }
+}
+
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Json::Null => write!(f, "null"),
+      Json::Bool(b) => write!(f, "{}", b),
+      Json::Number(n) => write!(f, "{}", n),
+      Json::String(s) => write_string(f, s),
+      Json::Array(items) => {
+        write!(f, "[")?;
+        for (index, item) in items.iter().enumerate() {
+          if index > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{}", item)?;
+        }
+        write!(f, "]")
+      }
+      Json::Object(pairs) => {
+        write!(f, "{{")?;
+        for (index, (key, value)) in pairs.iter().enumerate() {
+          if index > 0 {
+            write!(f, ",")?;
+          }
+          write_string(f, key)?;
+          write!(f, ":{}", value)?;
+        }
+        write!(f, "}}")
+      }
+    }
+  }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+  write!(f, "\"")?;
+  for c in s.chars() {
+    match c {
+      '"' => write!(f, "\\\"")?,
+      '\\' => write!(f, "\\\\")?,
+      '\n' => write!(f, "\\n")?,
+      '\r' => write!(f, "\\r")?,
+      '\t' => write!(f, "\\t")?,
+      c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+      c => write!(f, "{}", c)?,
+    }
+  }
+  write!(f, "\"")
+}
+
+struct Reader {
+  chars: Vec<char>,
+  pos: usize,
+}
+
+impl Reader {
+  fn whitespace(&mut self) {
+    while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).copied()
+  }
+
+  fn expect(&mut self, expected: char) -> Result<(), String> {
+    match self.peek() {
+      Some(c) if c == expected => {
+        self.pos += 1;
+        Ok(())
+      }
+      _ => Err(format!("expected '{}' at {}", expected, self.pos)),
+    }
+  }
+
+  fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+    for expected in word.chars() {
+      self.expect(expected)?;
+    }
+    Ok(value)
+  }
+
+  fn value(&mut self) -> Result<Json, String> {
+    self.whitespace();
+
+    match self.peek() {
+      Some('n') => self.keyword("null", Json::Null),
+      Some('t') => self.keyword("true", Json::Bool(true)),
+      Some('f') => self.keyword("false", Json::Bool(false)),
+      Some('"') => self.string().map(Json::String),
+      Some('[') => {
+        self.pos += 1;
+        let mut items = Vec::new();
+
+        self.whitespace();
+        if self.peek() == Some(']') {
+          self.pos += 1;
+          return Ok(Json::Array(items));
+        }
+
+        loop {
+          items.push(self.value()?);
+          self.whitespace();
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            Some(']') => {
+              self.pos += 1;
+              return Ok(Json::Array(items));
+            }
+            _ => return Err(format!("expected ',' or ']' at {}", self.pos)),
+          }
+        }
+      }
+      Some('{') => {
+        self.pos += 1;
+        let mut pairs = Vec::new();
+
+        self.whitespace();
+        if self.peek() == Some('}') {
+          self.pos += 1;
+          return Ok(Json::Object(pairs));
+        }
+
+        loop {
+          self.whitespace();
+          let key = self.string()?;
+          self.whitespace();
+          self.expect(':')?;
+          pairs.push((key, self.value()?));
+          self.whitespace();
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            Some('}') => {
+              self.pos += 1;
+              return Ok(Json::Object(pairs));
+            }
+            _ => return Err(format!("expected ',' or '}}' at {}", self.pos)),
+          }
+        }
+      }
+      Some(c) if c == '-' || c.is_ascii_digit() => {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+          if c.is_ascii_digit() || "+-.eE".contains(c) {
+            self.pos += 1;
+          } else {
+            break;
+          }
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text
+          .parse()
+          .map(Json::Number)
+          .map_err(|_| format!("invalid number '{}'", text))
+      }
+      _ => Err(format!("unexpected input at {}", self.pos)),
+    }
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    self.expect('"')?;
+    let mut s = String::new();
+
+    loop {
+      let c = self.peek().ok_or("unterminated string")?;
+      self.pos += 1;
+
+      match c {
+        '"' => return Ok(s),
+        '\\' => {
+          let escaped = self.peek().ok_or("unterminated string")?;
+          self.pos += 1;
+
+          match escaped {
+            'n' => s.push('\n'),
+            'r' => s.push('\r'),
+            't' => s.push('\t'),
+            'b' => s.push('\u{8}'),
+            'f' => s.push('\u{c}'),
+            'u' => {
+              let unit = self.hex4()?;
+              // surrogate pairs encode characters outside the basic plane
+              let c = if (0xd800..0xdc00).contains(&unit) {
+                self.expect('\\')?;
+                self.expect('u')?;
+                let low = self.hex4()?;
+                0x10000 + ((unit - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+              } else {
+                unit
+              };
+              s.push(std::char::from_u32(c).unwrap_or('\u{fffd}'));
+            }
+            other => s.push(other),
+          }
+        }
+        c => s.push(c),
+      }
+    }
+  }
+
+  fn hex4(&mut self) -> Result<u32, String> {
+    let end = self.pos + 4;
+    if end > self.chars.len() {
+      return Err("truncated \\u escape".to_string());
+    }
+
+    let digits: String = self.chars[self.pos..end].iter().collect();
+    self.pos = end;
+    u32::from_str_radix(&digits, 16).map_err(|_| format!("invalid \\u escape '{}'", digits))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_nested_values() {
+    let value = Json::parse(r#" {"a": [1, -2.5, true, null], "b": {"c": "d\"\nA"}} "#).unwrap();
+
+    assert_eq!(
+      value.path(&["b", "c"]).and_then(Json::as_str),
+      Some("d\"\nA")
+    );
+    assert_eq!(
+      value.get("a"),
+      Some(&Json::Array(vec![
+        Json::Number(1.0),
+        Json::Number(-2.5),
+        Json::Bool(true),
+        Json::Null
+      ]))
+    );
+  }
+
+  #[test]
+  fn round_trips_through_display() {
+    let value = Json::object(vec![
+      ("id", Json::from(3)),
+      ("text", Json::from("tab\there")),
+      ("items", Json::Array(vec![Json::Null, Json::from(false)])),
+    ]);
+
+    let text = value.to_string();
+    assert_eq!(text, r#"{"id":3,"text":"tab\there","items":[null,false]}"#);
+    assert_eq!(Json::parse(&text).unwrap(), value);
+  }
+
+  #[test]
+  fn rejects_malformed_input() {
+    assert!(Json::parse("{\"a\" 1}").is_err());
+    assert!(Json::parse("[1,]").is_err());
+    assert!(Json::parse("\"open").is_err());
+    assert!(Json::parse("1 2").is_err());
+  }
+}
@@ -0,0 +1,11 @@
+pub mod aot;
+pub mod code;
+pub mod emulator;
+pub mod error;
+pub mod format;
+pub mod json;
+pub mod lsp;
+pub mod parser;
+pub mod profile;
+pub mod script;
+pub mod symbol_table;
@@ -0,0 +1,505 @@
+use crate::code;
+use crate::error::{AsmError, ErrorKind};
+use crate::parser::{parse_lines, Address, Instruction, Parser};
+use crate::symbol_table::{Origin, SymbolTable};
+use std::collections::HashMap;
+
+/// A zero-based line and column, as the protocol counts them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+  pub line: usize,
+  pub character: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+  pub start: Position,
+  pub end: Position,
+}
+
+impl Range {
+  fn on_line(line: usize, start: usize, end: usize) -> Range {
+    Range {
+      start: Position {
+        line,
+        character: start,
+      },
+      end: Position {
+        line,
+        character: end,
+      },
+    }
+  }
+
+  fn contains(&self, position: Position) -> bool {
+    self.start.line == position.line
+      && self.start.character <= position.character
+      && position.character <= self.end.character
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+  pub range: Range,
+  pub message: String,
+}
+
+/// Where a symbol name appears in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+  pub name: String,
+  pub range: Range,
+  /// `(NAME)` rather than `@NAME`
+  pub declaration: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentSymbol {
+  pub name: String,
+  /// From the label to the line before the next one
+  pub range: Range,
+  pub selection_range: Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompletionKind {
+  Dest,
+  Comp,
+  Jump,
+  Symbol(Origin),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+  pub label: String,
+  pub kind: CompletionKind,
+}
+
+/// Everything the server knows about one .asm document, computed by
+/// assembling it the same way `Parser::parse` does but without stopping at
+/// the first error
+pub struct Analysis {
+  pub diagnostics: Vec<Diagnostic>,
+  lines: Vec<String>,
+  occurrences: Vec<Occurrence>,
+  /// ROM address and machine word of the instruction on each line
+  encoded: HashMap<usize, (u16, u16)>,
+  parser: Parser,
+}
+
+impl Analysis {
+  pub fn new(text: &str) -> Analysis {
+    let lines: Vec<String> = text.lines().map(String::from).collect();
+    let mut diagnostics = Vec::new();
+    let mut occurrences = Vec::new();
+    let mut table = SymbolTable::new();
+    let mut instructions = Vec::new();
+    let mut rom: u16 = 0;
+
+    let mut report = |err: AsmError, range: Option<Range>| {
+      let index = err.line - 1;
+      let range = range.unwrap_or_else(|| Range::on_line(index, 0, utf16_len(&lines[index])));
+      diagnostics.push(Diagnostic {
+        range,
+        message: err.kind.to_string(),
+      });
+    };
+
+    // first pass, labels
+    for line in parse_lines(text) {
+      let line = match line {
+        Ok(line) => line,
+        Err(err) => {
+          report(err, None);
+          continue;
+        }
+      };
+
+      let index = line.number - 1;
+
+      match line.instruction {
+        Some(Instruction::Label(name)) => {
+          let range = name_range(&lines[index], index, '(', &name);
+
+          if table.origin(&name) == Some(Origin::Label) {
+            let kind = ErrorKind::DuplicateLabel(name.clone());
+            report(
+              AsmError {
+                line: line.number,
+                kind,
+              },
+              Some(range),
+            );
+          } else {
+            table.add_label(&name, rom);
+          }
+
+          occurrences.push(Occurrence {
+            name,
+            range,
+            declaration: true,
+          });
+        }
+        Some(instruction) => {
+          instructions.push((index, rom, instruction));
+          rom = rom.wrapping_add(1);
+        }
+        None => {}
+      }
+    }
+
+    // second pass, variables and encodings
+    let mut parser = Parser::with_symbol_table(table);
+    let mut encoded = HashMap::new();
+
+    for (index, address, instruction) in instructions {
+      let range = match &instruction {
+        Instruction::A(Address::Symbol(name)) => {
+          let range = name_range(&lines[index], index, '@', name);
+          occurrences.push(Occurrence {
+            name: name.clone(),
+            range,
+            declaration: false,
+          });
+          Some(range)
+        }
+        _ => None,
+      };
+
+      match parser.encode(&instruction, index + 1) {
+        Ok(Some(word)) => {
+          encoded.insert(index, (address, word));
+        }
+        Ok(None) => {}
+        Err(err) => report(err, range),
+      }
+    }
+
+    Analysis {
+      diagnostics,
+      lines,
+      occurrences,
+      encoded,
+      parser,
+    }
+  }
+
+  pub fn symbol_table(&self) -> &SymbolTable {
+    self.parser.symbol_table()
+  }
+
+  pub fn occurrence_at(&self, position: Position) -> Option<&Occurrence> {
+    self
+      .occurrences
+      .iter()
+      .find(|occurrence| occurrence.range.contains(position))
+  }
+
+  /// Labels resolve to their `(LABEL)`, variables to their first use
+  pub fn definition(&self, position: Position) -> Option<Range> {
+    let name = &self.occurrence_at(position)?.name;
+
+    match self.symbol_table().origin(name)? {
+      Origin::Label => self
+        .occurrences
+        .iter()
+        .find(|occurrence| occurrence.declaration && &occurrence.name == name)
+        .map(|occurrence| occurrence.range),
+      Origin::Variable => self
+        .occurrences
+        .iter()
+        .filter(|occurrence| &occurrence.name == name)
+        .min_by_key(|occurrence| occurrence.range.start.line)
+        .map(|occurrence| occurrence.range),
+      Origin::Predefined => None,
+    }
+  }
+
+  pub fn references(&self, position: Position, include_declaration: bool) -> Vec<Range> {
+    let name = match self.occurrence_at(position) {
+      Some(occurrence) => &occurrence.name,
+      None => return Vec::new(),
+    };
+
+    self
+      .occurrences
+      .iter()
+      .filter(|occurrence| &occurrence.name == name)
+      .filter(|occurrence| include_declaration || !occurrence.declaration)
+      .map(|occurrence| occurrence.range)
+      .collect()
+  }
+
+  /// Markdown describing the symbol under the cursor and the instruction on
+  /// its line
+  pub fn hover(&self, position: Position) -> Option<(String, Range)> {
+    let mut parts = Vec::new();
+    let line_length = utf16_len(self.lines.get(position.line)?);
+    let mut range = Range::on_line(position.line, 0, line_length);
+
+    if let Some(occurrence) = self.occurrence_at(position) {
+      if let Some(symbol) = self.symbol_table().get(&occurrence.name) {
+        let description = match symbol.origin {
+          Origin::Predefined => format!("predefined symbol, `{}`", symbol.address),
+          Origin::Label => format!("label, `ROM[{}]`", symbol.address),
+          Origin::Variable => format!("variable, `RAM[{}]`", symbol.address),
+        };
+        parts.push(format!("**{}**: {}", occurrence.name, description));
+        range = occurrence.range;
+      }
+    }
+
+    if let Some((address, word)) = self.encoded.get(&position.line) {
+      parts.push(format!("```\nROM[{}]  {:016b}\n```", address, word));
+    }
+
+    if parts.is_empty() {
+      None
+    } else {
+      Some((parts.join("\n\n"), range))
+    }
+  }
+
+  pub fn document_symbols(&self) -> Vec<DocumentSymbol> {
+    let declarations: Vec<&Occurrence> = self
+      .occurrences
+      .iter()
+      .filter(|occurrence| occurrence.declaration)
+      .collect();
+
+    declarations
+      .iter()
+      .enumerate()
+      .map(|(index, occurrence)| {
+        let start = occurrence.range.start.line;
+        let end = match declarations.get(index + 1) {
+          Some(next) if next.range.start.line > start => next.range.start.line - 1,
+          Some(_) => start,
+          None => self.lines.len().saturating_sub(1).max(start),
+        };
+        let end_character = self.lines.get(end).map_or(0, |line| utf16_len(line));
+
+        DocumentSymbol {
+          name: occurrence.name.clone(),
+          range: Range {
+            start: Position {
+              line: start,
+              character: 0,
+            },
+            end: Position {
+              line: end,
+              character: end_character,
+            },
+          },
+          selection_range: occurrence.range,
+        }
+      })
+      .collect()
+  }
+
+  /// Offers symbols after `@`, and the mnemonics from the code tables for
+  /// whichever part of a C-instruction the cursor is in
+  pub fn completions(&self, position: Position) -> Vec<Completion> {
+    let line = match self.lines.get(position.line) {
+      Some(line) => line,
+      None => "",
+    };
+    let before = &line[..byte_offset(line, position.character)];
+
+    if before.contains("//") {
+      return Vec::new();
+    }
+
+    let before = before.trim_start();
+    let mnemonics = |names: &mut dyn Iterator<Item = &'static str>, kind| {
+      names
+        .map(|label| Completion {
+          label: label.to_string(),
+          kind,
+        })
+        .collect::<Vec<_>>()
+    };
+
+    if before.starts_with('@') {
+      let mut symbols: Vec<Completion> = self
+        .symbol_table()
+        .iter()
+        .map(|(name, symbol)| Completion {
+          label: name.to_string(),
+          kind: CompletionKind::Symbol(symbol.origin),
+        })
+        .collect();
+      symbols.sort_by(|a, b| a.label.cmp(&b.label));
+      symbols
+    } else if before.contains(';') {
+      mnemonics(&mut code::jump_mnemonics(), CompletionKind::Jump)
+    } else if before.contains('=') {
+      mnemonics(&mut code::comp_mnemonics(), CompletionKind::Comp)
+    } else {
+      let mut items = mnemonics(&mut code::dest_mnemonics(), CompletionKind::Dest);
+      items.extend(mnemonics(&mut code::comp_mnemonics(), CompletionKind::Comp));
+      items
+    }
+  }
+}
+
+/// Where a position's `character`, which counts UTF-16 code units as the
+/// protocol does, falls in `line`: at a character boundary, and at most the
+/// end of the line
+fn byte_offset(line: &str, character: usize) -> usize {
+  let mut units = 0;
+
+  for (offset, c) in line.char_indices() {
+    if units >= character {
+      return offset;
+    }
+    units += c.len_utf16();
+  }
+  line.len()
+}
+
+/// The length of text in the UTF-16 code units positions count
+fn utf16_len(text: &str) -> usize {
+  text.encode_utf16().count()
+}
+
+/// The range of `name` where it follows `marker` (`@` or `(`) on a line
+fn name_range(text: &str, line: usize, marker: char, name: &str) -> Range {
+  let after = text.find(marker).map_or(0, |index| index + 1);
+  let start = text[after..]
+    .find(name)
+    .map_or(after, |offset| after + offset);
+  let start = utf16_len(&text[..start]);
+
+  Range::on_line(line, start, start + utf16_len(name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SOURCE: &str = "\
+@i
+M=1 // i = 1
+(LOOP)
+  @i
+  D=M
+  @100
+  D=D-A
+  @END
+  D;JGT
+  @LOOP
+  0;JMP
+(END)
+  @END
+  0;JMP
+";
+
+  fn at(line: usize, character: usize) -> Position {
+    Position { line, character }
+  }
+
+  #[test]
+  fn goes_to_label_and_variable_definitions() {
+    let analysis = Analysis::new(SOURCE);
+
+    assert_eq!(analysis.definition(at(9, 4)), Some(Range::on_line(2, 1, 5)));
+    assert_eq!(analysis.definition(at(3, 3)), Some(Range::on_line(0, 1, 2)));
+    assert_eq!(analysis.definition(at(4, 2)), None);
+  }
+
+  #[test]
+  fn finds_references() {
+    let analysis = Analysis::new(SOURCE);
+
+    assert_eq!(
+      analysis.references(at(0, 1), false),
+      vec![Range::on_line(0, 1, 2), Range::on_line(3, 3, 4)]
+    );
+    assert_eq!(analysis.references(at(7, 4), true).len(), 3);
+    assert_eq!(analysis.references(at(7, 4), false).len(), 2);
+  }
+
+  #[test]
+  fn hovers_with_addresses_and_encodings() {
+    let analysis = Analysis::new(SOURCE);
+
+    let (text, range) = analysis.hover(at(3, 3)).unwrap();
+    assert_eq!(range, Range::on_line(3, 3, 4));
+    assert!(text.contains("**i**: variable, `RAM[16]`"));
+    assert!(text.contains("ROM[2]  0000000000010000"));
+
+    let (text, _) = analysis.hover(at(2, 2)).unwrap();
+    assert_eq!(text, "**LOOP**: label, `ROM[2]`");
+
+    let (text, _) = analysis.hover(at(1, 0)).unwrap();
+    assert_eq!(text, "```\nROM[1]  1110111111001000\n```");
+  }
+
+  #[test]
+  fn lists_labelled_blocks() {
+    let symbols = Analysis::new(SOURCE).document_symbols();
+
+    assert_eq!(symbols.len(), 2);
+    assert_eq!(symbols[0].name, "LOOP");
+    assert_eq!(symbols[0].range.start.line, 2);
+    assert_eq!(symbols[0].range.end.line, 10);
+    assert_eq!(symbols[1].range.end, at(13, 7));
+  }
+
+  #[test]
+  fn reports_every_error() {
+    let analysis = Analysis::new("D=Q\n(A)\n@A\n(A)\n0;JUMP");
+    let messages: Vec<_> = analysis
+      .diagnostics
+      .iter()
+      .map(|d| d.message.as_str())
+      .collect();
+
+    assert_eq!(
+      messages,
+      vec![
+        "invalid comp 'Q'",
+        "label 'A' is declared more than once",
+        "invalid jump 'JUMP'"
+      ]
+    );
+    assert_eq!(analysis.diagnostics[1].range, Range::on_line(3, 1, 2));
+  }
+
+  #[test]
+  fn completes_by_context() {
+    let analysis = Analysis::new("@\nD=\nD;\nD");
+    let kinds = |line| {
+      let items = analysis.completions(at(line, 2));
+      (items.len(), items[0].kind)
+    };
+
+    assert_eq!(analysis.completions(at(0, 1)).len(), 23);
+    assert_eq!(kinds(1), (28, CompletionKind::Comp));
+    assert_eq!(kinds(2), (7, CompletionKind::Jump));
+    assert_eq!(kinds(3), (35, CompletionKind::Dest));
+  }
+
+  #[test]
+  fn counts_positions_in_utf16() {
+    let analysis = Analysis::new("(A) // café\n@1 // 𝔸𝔸\nD=");
+
+    assert!(analysis.completions(at(0, 10)).is_empty());
+    assert!(analysis.completions(at(0, 11)).is_empty());
+    // inside the surrogate pair of the first 𝔸
+    assert!(analysis.completions(at(1, 7)).is_empty());
+    assert!(analysis.completions(at(1, 100)).is_empty());
+    assert_eq!(analysis.completions(at(2, 2)).len(), 28);
+
+    let symbols = analysis.document_symbols();
+    assert_eq!(symbols[0].range.end, at(2, 2));
+    let symbols = Analysis::new("(A)\n@1 // 𝔸").document_symbols();
+    assert_eq!(symbols[0].range.end, at(1, 8));
+
+    // 9 bytes, but 8 code units
+    let analysis = Analysis::new("D=Q // é\nD=1 // é");
+    assert_eq!(analysis.diagnostics[0].range, Range::on_line(0, 0, 8));
+    assert_eq!(analysis.hover(at(1, 0)).unwrap().1, Range::on_line(1, 0, 8));
+  }
+}
@@ -0,0 +1,459 @@
+//! A language server for Hack assembly, speaking JSON-RPC over stdio
+
+pub mod analysis;
+
+use crate::json::Json;
+use crate::symbol_table::Origin;
+use analysis::{Analysis, CompletionKind, Position, Range};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+pub struct Server {
+  documents: HashMap<String, Analysis>,
+  shutdown: bool,
+}
+
+impl Server {
+  pub fn new() -> Server {
+    Server {
+      documents: HashMap::new(),
+      shutdown: false,
+    }
+  }
+
+  /// Serves requests until the client sends `exit` or closes the input.
+  /// Returns whether `shutdown` was requested first. A message that isn't
+  /// JSON gets a parse error, as it has no id to answer.
+  pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<bool> {
+    while let Some(body) = read_body(&mut input)? {
+      let message = match String::from_utf8(body)
+        .map_err(|err| err.to_string())
+        .and_then(|body| Json::parse(&body))
+      {
+        Ok(message) => message,
+        Err(err) => {
+          let reply = error_response(Json::Null, PARSE_ERROR, format!("invalid JSON: {}", err));
+          write_message(&mut output, &reply)?;
+          continue;
+        }
+      };
+
+      if message.get("method").and_then(Json::as_str) == Some("exit") {
+        break;
+      }
+
+      for reply in self.handle(&message) {
+        write_message(&mut output, &reply)?;
+      }
+    }
+
+    Ok(self.shutdown)
+  }
+
+  /// Returns the responses and notifications to send for one message
+  pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+    let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+    let params = message.get("params").unwrap_or(&Json::Null);
+
+    let id = match message.get("id") {
+      Some(id) => id.clone(),
+      None => return self.notification(method, params),
+    };
+
+    let result = match method {
+      "initialize" => Ok(capabilities()),
+      "shutdown" => {
+        self.shutdown = true;
+        Ok(Json::Null)
+      }
+      "textDocument/definition" => self.with_position(params, |analysis, uri, position| {
+        match analysis.definition(position) {
+          Some(range) => location(uri, range),
+          None => Json::Null,
+        }
+      }),
+      "textDocument/references" => self.with_position(params, |analysis, uri, position| {
+        let include_declaration = params
+          .path(&["context", "includeDeclaration"])
+          .and_then(Json::as_bool)
+          .unwrap_or(true);
+
+        let locations = analysis
+          .references(position, include_declaration)
+          .into_iter()
+          .map(|range| location(uri, range))
+          .collect();
+        Json::Array(locations)
+      }),
+      "textDocument/hover" => self.with_position(params, |analysis, _, position| {
+        match analysis.hover(position) {
+          Some((markdown, range)) => Json::object(vec![
+            (
+              "contents",
+              Json::object(vec![
+                ("kind", "markdown".into()),
+                ("value", markdown.into()),
+              ]),
+            ),
+            ("range", range_json(range)),
+          ]),
+          None => Json::Null,
+        }
+      }),
+      "textDocument/documentSymbol" => self.with_document(params, |analysis, _| {
+        let symbols = analysis
+          .document_symbols()
+          .into_iter()
+          .map(|symbol| {
+            Json::object(vec![
+              ("name", symbol.name.into()),
+              ("kind", Json::from(SYMBOL_KIND_FUNCTION)),
+              ("range", range_json(symbol.range)),
+              ("selectionRange", range_json(symbol.selection_range)),
+            ])
+          })
+          .collect();
+        Json::Array(symbols)
+      }),
+      "textDocument/completion" => self.with_position(params, |analysis, _, position| {
+        let items = analysis
+          .completions(position)
+          .into_iter()
+          .map(|item| {
+            let (kind, detail) = match item.kind {
+              CompletionKind::Dest => (COMPLETION_KEYWORD, "dest"),
+              CompletionKind::Comp => (COMPLETION_KEYWORD, "comp"),
+              CompletionKind::Jump => (COMPLETION_KEYWORD, "jump"),
+              CompletionKind::Symbol(Origin::Label) => (COMPLETION_FUNCTION, "label"),
+              CompletionKind::Symbol(Origin::Variable) => (COMPLETION_VARIABLE, "variable"),
+              CompletionKind::Symbol(Origin::Predefined) => (COMPLETION_CONSTANT, "predefined"),
+            };
+
+            Json::object(vec![
+              ("label", item.label.into()),
+              ("kind", Json::from(kind)),
+              ("detail", detail.into()),
+            ])
+          })
+          .collect();
+        Json::Array(items)
+      }),
+      _ => Err((METHOD_NOT_FOUND, format!("unhandled method '{}'", method))),
+    };
+
+    let response = match result {
+      Ok(result) => Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        ("result", result),
+      ]),
+      Err((code, message)) => error_response(id, code, message),
+    };
+
+    vec![response]
+  }
+
+  fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+    let uri = match params.path(&["textDocument", "uri"]).and_then(Json::as_str) {
+      Some(uri) => uri.to_string(),
+      None => return Vec::new(),
+    };
+
+    let text = match method {
+      "textDocument/didOpen" => params.path(&["textDocument", "text"]),
+      // the server asks for full document sync, so the last change is the whole text
+      "textDocument/didChange" => params
+        .get("contentChanges")
+        .and_then(Json::as_array)
+        .and_then(|changes| changes.last())
+        .and_then(|change| change.get("text")),
+      "textDocument/didClose" => {
+        self.documents.remove(&uri);
+        return vec![diagnostics_notification(&uri, Json::Array(Vec::new()))];
+      }
+      _ => None,
+    };
+
+    let text = match text.and_then(Json::as_str) {
+      Some(text) => text,
+      None => return Vec::new(),
+    };
+
+    let analysis = Analysis::new(text);
+    let diagnostics = analysis
+      .diagnostics
+      .iter()
+      .map(|diagnostic| {
+        Json::object(vec![
+          ("range", range_json(diagnostic.range)),
+          ("severity", Json::from(SEVERITY_ERROR)),
+          ("source", "hack_assembler".into()),
+          ("message", diagnostic.message.clone().into()),
+        ])
+      })
+      .collect();
+
+    self.documents.insert(uri.clone(), analysis);
+    vec![diagnostics_notification(&uri, Json::Array(diagnostics))]
+  }
+
+  fn with_document<F>(&self, params: &Json, f: F) -> Result<Json, (i64, String)>
+  where
+    F: FnOnce(&Analysis, &str) -> Json,
+  {
+    let uri = params
+      .path(&["textDocument", "uri"])
+      .and_then(Json::as_str)
+      .ok_or((INVALID_PARAMS, "missing textDocument.uri".to_string()))?;
+
+    match self.documents.get(uri) {
+      Some(analysis) => Ok(f(analysis, uri)),
+      None => Ok(Json::Null),
+    }
+  }
+
+  fn with_position<F>(&self, params: &Json, f: F) -> Result<Json, (i64, String)>
+  where
+    F: FnOnce(&Analysis, &str, Position) -> Json,
+  {
+    let line = params.path(&["position", "line"]).and_then(Json::as_u64);
+    let character = params
+      .path(&["position", "character"])
+      .and_then(Json::as_u64);
+
+    let position = match (line, character) {
+      (Some(line), Some(character)) => Position {
+        line: line as usize,
+        character: character as usize,
+      },
+      _ => return Err((INVALID_PARAMS, "missing position".to_string())),
+    };
+
+    self.with_document(params, |analysis, uri| f(analysis, uri, position))
+  }
+}
+
+impl Default for Server {
+  fn default() -> Server {
+    Server::new()
+  }
+}
+
+const SEVERITY_ERROR: usize = 1;
+const SYMBOL_KIND_FUNCTION: usize = 12;
+const COMPLETION_FUNCTION: usize = 3;
+const COMPLETION_VARIABLE: usize = 6;
+const COMPLETION_KEYWORD: usize = 14;
+const COMPLETION_CONSTANT: usize = 21;
+/// Clients send the whole document on every change
+const SYNC_FULL: usize = 1;
+
+fn capabilities() -> Json {
+  Json::object(vec![
+    (
+      "capabilities",
+      Json::object(vec![
+        ("textDocumentSync", Json::from(SYNC_FULL)),
+        ("definitionProvider", true.into()),
+        ("referencesProvider", true.into()),
+        ("hoverProvider", true.into()),
+        ("documentSymbolProvider", true.into()),
+        (
+          "completionProvider",
+          Json::object(vec![(
+            "triggerCharacters",
+            Json::Array(vec!["@".into(), "=".into(), ";".into()]),
+          )]),
+        ),
+      ]),
+    ),
+    (
+      "serverInfo",
+      Json::object(vec![("name", "hack_lsp".into())]),
+    ),
+  ])
+}
+
+fn error_response(id: Json, code: i64, message: String) -> Json {
+  Json::object(vec![
+    ("jsonrpc", "2.0".into()),
+    ("id", id),
+    (
+      "error",
+      Json::object(vec![
+        ("code", Json::Number(code as f64)),
+        ("message", message.into()),
+      ]),
+    ),
+  ])
+}
+
+fn diagnostics_notification(uri: &str, diagnostics: Json) -> Json {
+  Json::object(vec![
+    ("jsonrpc", "2.0".into()),
+    ("method", "textDocument/publishDiagnostics".into()),
+    (
+      "params",
+      Json::object(vec![("uri", uri.into()), ("diagnostics", diagnostics)]),
+    ),
+  ])
+}
+
+fn location(uri: &str, range: Range) -> Json {
+  Json::object(vec![("uri", uri.into()), ("range", range_json(range))])
+}
+
+fn range_json(range: Range) -> Json {
+  let position = |position: Position| {
+    Json::object(vec![
+      ("line", position.line.into()),
+      ("character", position.character.into()),
+    ])
+  };
+
+  Json::object(vec![
+    ("start", position(range.start)),
+    ("end", position(range.end)),
+  ])
+}
+
+/// Reads one `Content-Length` framed message, `None` at end of input
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+  let body = match read_body(input)? {
+    Some(body) => body,
+    None => return Ok(None),
+  };
+
+  let body =
+    String::from_utf8(body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+  Json::parse(&body)
+    .map(Some)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Reads the body of the next framed message, or `None` at the end of input
+fn read_body<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+  let mut length = None;
+
+  loop {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+
+    let header = header.trim_end();
+    if header.is_empty() {
+      break;
+    }
+
+    if let Some(value) = header.strip_prefix("Content-Length:") {
+      length = value.trim().parse::<usize>().ok();
+    }
+  }
+
+  let length =
+    length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+  let mut body = vec![0; length];
+  input.read_exact(&mut body)?;
+  Ok(Some(body))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+  let body = message.to_string();
+  write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+  output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(message: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+  }
+
+  #[test]
+  fn serves_a_session_over_framed_messages() {
+    let input = [
+      r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+      r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.asm","text":"(LOOP)\n@LOOP\n0;JMP\nD=Q"}}}"#,
+      r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.asm"},"position":{"line":1,"character":2}}}"#,
+      r#"{"jsonrpc":"2.0","id":3,"method":"workspace/symbol","params":{}}"#,
+      r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+      r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ]
+    .iter()
+    .map(|message| frame(message))
+    .collect::<String>();
+
+    let mut output = Vec::new();
+    let shutdown = Server::new().run(input.as_bytes(), &mut output).unwrap();
+    assert!(shutdown);
+
+    let mut output = output.as_slice();
+    let mut replies = Vec::new();
+    while let Some(reply) = read_message(&mut output).unwrap() {
+      replies.push(reply);
+    }
+
+    assert_eq!(replies.len(), 5);
+    assert!(replies[0]
+      .path(&["result", "capabilities", "hoverProvider"])
+      .is_some());
+
+    let diagnostics = replies[1]
+      .path(&["params", "diagnostics"])
+      .and_then(Json::as_array)
+      .unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+      diagnostics[0]
+        .path(&["range", "start", "line"])
+        .and_then(Json::as_u64),
+      Some(3)
+    );
+
+    assert_eq!(
+      replies[2].path(&["result", "range", "start"]),
+      Some(&Json::object(vec![
+        ("line", 0.into()),
+        ("character", 1.into())
+      ]))
+    );
+    assert_eq!(
+      replies[3].path(&["error", "code"]),
+      Some(&Json::Number(METHOD_NOT_FOUND as f64))
+    );
+    assert_eq!(replies[4].get("result"), Some(&Json::Null));
+  }
+
+  #[test]
+  fn answers_malformed_json_and_keeps_serving() {
+    let input = [
+      r#"{"jsonrpc":"2.0","id":1,"method":"initialize""#,
+      r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+    ]
+    .iter()
+    .map(|message| frame(message))
+    .collect::<String>();
+
+    let mut output = Vec::new();
+    let shutdown = Server::new().run(input.as_bytes(), &mut output).unwrap();
+    assert!(shutdown);
+
+    let mut output = output.as_slice();
+    let error = read_message(&mut output).unwrap().unwrap();
+    assert_eq!(error.get("id"), Some(&Json::Null));
+    assert_eq!(
+      error.path(&["error", "code"]),
+      Some(&Json::Number(PARSE_ERROR as f64))
+    );
+
+    let reply = read_message(&mut output).unwrap().unwrap();
+    assert_eq!(reply.get("id"), Some(&Json::Number(2.0)));
+  }
+}
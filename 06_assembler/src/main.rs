@@ -1,26 +1,55 @@
-mod code;
-mod parser;
-mod symbol_table;
-
-use parser::Parser;
+use hack_assembler::parser::Parser;
+use hack_assembler::profile::Profile;
+use hack_assembler::symbol_table::SymbolTable;
 use std::fs;
 use std::io::Write;
+use std::process;
 
+/// Usage: `hack_assembler [--profile machine.sym] [--lenient] input.asm output.hack`
 pub fn main() {
-  let path = std::env::args()
-    .nth(1)
-    .expect("Path to .asm file is required");
+  let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+  // accept mnemonics in any form hack_fmt would canonicalise
+  let lenient = match args.iter().position(|arg| arg == "--lenient") {
+    Some(index) => {
+      args.remove(index);
+      true
+    }
+    None => false,
+  };
+
+  let profile = match args.iter().position(|arg| arg == "--profile") {
+    Some(index) => {
+      args.remove(index);
+      let profile_path = args
+        .get(index)
+        .cloned()
+        .expect("--profile requires a file path");
+      args.remove(index);
+
+      let source = fs::read_to_string(&profile_path).expect("problem reading profile");
+      Profile::parse(&source).unwrap_or_else(|err| {
+        eprintln!("{}: {}", profile_path, err);
+        process::exit(1);
+      })
+    }
+    None => Profile::default(),
+  };
 
-  let out_name = std::env::args()
-    .nth(2)
-    .expect("Output file path is required");
+  let path = args.first().expect("Path to .asm file is required");
+  let out_name = args.get(1).expect("Output file path is required");
 
   let input = fs::read_to_string(path).expect("problem reading path to string");
-  let mut out_file = fs::File::create(out_name).expect("problem creating output file");
 
-  let mut parser = Parser::new();
-  let parsed = parser.parse(&input);
+  let mut parser = Parser::with_symbol_table(SymbolTable::with_profile(&profile));
+  parser.set_lenient_mnemonics(lenient);
+  let parsed = parser.parse(&input).unwrap_or_else(|err| {
+    eprintln!("{}: {}", path, err);
+    process::exit(1);
+  });
+
+  let mut out_file = fs::File::create(out_name).expect("problem creating output file");
   out_file
-    .write(&parsed)
+    .write_all(&parsed)
     .expect("problem writing to output file");
 }
@@ -1,133 +1,151 @@
 use crate::code;
-use crate::symbol_table::SymbolTable;
+use crate::error::{AsmError, ErrorKind};
+use crate::profile::is_symbol;
+use crate::symbol_table::{Origin, SymbolTable};
 use std::io::Write;
 
 pub struct Parser {
   symbol_table: SymbolTable,
+  lenient_mnemonics: bool,
 }
 
 impl Parser {
   pub fn new() -> Parser {
+    Parser::with_symbol_table(SymbolTable::new())
+  }
+
+  /// Assembles against a pre-built table, e.g. one created from a machine profile
+  pub fn with_symbol_table(symbol_table: SymbolTable) -> Parser {
     Parser {
-      symbol_table: SymbolTable::new(),
+      symbol_table,
+      lenient_mnemonics: false,
     }
   }
-  pub fn parse(&mut self, input: &str) -> Vec<u8> {
-    let mut buf = Vec::new();
-    let mut var_counter = 0;
-
-    // first pass
-    self.resolve_labels(input);
-
-    for line in input.lines() {
-      let line = line.trim();
 
-      if line.starts_with("//") || line.is_empty() {
-        continue;
-      }
-
-      match Parser::command_type(line).unwrap() {
-        CommandType::ACommand => {
-          let line = line.trim().trim_matches('@');
-          let symbol: u16;
-
-          // if the line is a decimal number, use it as is
-          if Parser::is_literal(line) {
-            symbol = line.parse().unwrap();
-          } else {
-            // add the var to the symbol table if not already there
-            if !self.symbol_table.contains(line) {
-              self
-                .symbol_table
-                .add_entry(line, &(16 + var_counter).to_string());
-              var_counter += 1;
-            }
-
-            // lookup the symbol in the table, and get the value
-            symbol = self.symbol_table.get_addr(line).parse().unwrap();
-          }
-
-          // binary format
-          let bin_symbol = format!("{:b}", symbol);
+  /// Accepts C-instruction mnemonics in any form `hack_fmt` would
+  /// canonicalise, such as `dm=m+d`, rather than only those of the book
+  pub fn set_lenient_mnemonics(&mut self, lenient: bool) {
+    self.lenient_mnemonics = lenient;
+  }
 
-          // pad to 16 chars
-          writeln!(&mut buf, "{:0>16}", bin_symbol).expect("problem writing to buffer");
-        }
-        CommandType::CCommand => {
-          let mut tokens: Vec<&str> = line.split(|c| c == '=' || c == ';').collect();
+  pub fn symbol_table(&self) -> &SymbolTable {
+    &self.symbol_table
+  }
 
-          // Add null ops to short commands
-          if !line.contains('=') {
-            tokens.insert(0, "null");
-          }
+  pub fn parse(&mut self, input: &str) -> Result<Vec<u8>, AsmError> {
+    let mut buf = Vec::new();
 
-          if !line.contains(';') {
-            tokens.insert(2, "null")
-          }
+    for word in self.assemble(input)? {
+      // write binary instruction to buffer, padded to 16 chars
+      writeln!(&mut buf, "{:016b}", word).expect("problem writing to buffer");
+    }
 
-          // lookup tokens in code tables
-          let dest = match tokens.get(0) {
-            Some(v) => code::dest(v).expect("invalid DEST token"),
-            None => code::dest("null").unwrap(),
-          };
+    Ok(buf)
+  }
 
-          let comp = match tokens.get(1) {
-            Some(v) => code::comp(v).expect("invalid COMP token"),
-            None => code::comp("null").unwrap(),
-          };
+  /// Assembles the program into the machine words to load into ROM
+  pub fn assemble(&mut self, input: &str) -> Result<Vec<u16>, AsmError> {
+    let mut words = Vec::new();
 
-          let jump = match tokens.get(2) {
-            Some(v) => code::jump(v).expect("invalid JUMP token"),
-            None => code::jump("null").unwrap(),
-          };
+    // first pass
+    self.resolve_labels(input)?;
 
-          // convert tokens to binary (machine instruction)
-          let bin_line = format!("111{}{}{}", comp, dest, jump);
+    for line in parse_lines(input) {
+      let line = line?;
 
-          // write binary instruction to buffer,
-          writeln!(&mut buf, "{}", bin_line).expect("problem writing to buffer");
+      if let Some(instruction) = &line.instruction {
+        if let Some(word) = self.encode(instruction, line.number)? {
+          words.push(word);
         }
-        _ => {}
       }
     }
 
-    buf
+    Ok(words)
   }
 
-  fn is_literal(line: &str) -> bool {
-    if line.chars().any(|c| c.is_alphabetic()) {
-      return false;
-    }
+  /// Returns the machine word for an instruction, allocating a variable for
+  /// any symbol not yet in the table. Labels have no encoding.
+  pub fn encode(
+    &mut self,
+    instruction: &Instruction,
+    line: usize,
+  ) -> Result<Option<u16>, AsmError> {
+    match instruction {
+      Instruction::A(Address::Literal(value)) => Ok(Some(*value)),
+      Instruction::A(Address::Symbol(symbol)) => {
+        // lookup the symbol in the table, allocating a variable if not already there
+        match self.symbol_table.get_addr(symbol) {
+          Some(address) => Ok(Some(address)),
+          None => self
+            .symbol_table
+            .add_variable(symbol)
+            .map(Some)
+            .map_err(|err| AsmError {
+              line,
+              kind: ErrorKind::Symbol(err),
+            }),
+        }
+      }
+      Instruction::C { dest, comp, jump } => {
+        let error = |kind| AsmError { line, kind };
+        let dest = dest.as_deref().filter(|&token| token != "null");
+        let jump = jump.as_deref().filter(|&token| token != "null");
+
+        let lenient = self.lenient_mnemonics;
+        let known = |token, table, canonical| is_known(token, lenient, table, canonical);
 
-    true
+        if let Some(token) = dest {
+          if !known(token, code::dest, code::canonical_dest) {
+            return Err(error(ErrorKind::InvalidDest(token.to_string())));
+          }
+        }
+        if !known(comp, code::comp, code::canonical_comp) {
+          return Err(error(ErrorKind::InvalidComp(comp.clone())));
+        }
+        if let Some(token) = jump {
+          if !known(token, code::jump, code::canonical_jump) {
+            return Err(error(ErrorKind::InvalidJump(token.to_string())));
+          }
+        }
+
+        code::encode(dest, comp, jump)
+          .map(Some)
+          .ok_or_else(|| error(ErrorKind::InvalidComp(comp.clone())))
+      }
+      Instruction::Label(_) => Ok(None),
+    }
   }
 
-  fn resolve_labels(&mut self, input: &str) {
+  fn resolve_labels(&mut self, input: &str) -> Result<(), AsmError> {
     let mut counter = 0;
 
-    for line in input.lines() {
-      let line = line.trim();
+    for line in parse_lines(input) {
+      let line = line?;
 
-      if line.starts_with("//") || line.is_empty() {
-        continue;
-      }
+      match line.instruction {
+        Some(Instruction::Label(symbol)) => {
+          if self.symbol_table.origin(&symbol) == Some(Origin::Label) {
+            return Err(AsmError {
+              line: line.number,
+              kind: ErrorKind::DuplicateLabel(symbol),
+            });
+          }
 
-      match Parser::command_type(line).unwrap() {
-        CommandType::LCommand => {
-          let symbol = line.trim_matches(|c| c == '(' || c == ')');
-          let next_line = &counter.to_string();
-          self.symbol_table.add_entry(symbol, next_line)
+          self.symbol_table.add_label(&symbol, counter)
         }
-        _ => counter += 1,
+        Some(_) => counter += 1,
+        None => {}
       }
     }
+
+    Ok(())
   }
 
   /// Returns the type of a command
   fn command_type(line: &str) -> Option<CommandType> {
-    if line.trim().starts_with("@") {
+    if line.trim().starts_with('@') {
       return Some(CommandType::ACommand);
-    } else if line.trim().starts_with("(") {
+    } else if line.trim().starts_with('(') {
       return Some(CommandType::LCommand);
     } else if code::contains_comp(line) {
       return Some(CommandType::CCommand);
@@ -135,8 +153,164 @@ impl Parser {
 
     None
   }
+
+  fn is_literal(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| c.is_ascii_digit())
+  }
+}
+
+impl Default for Parser {
+  fn default() -> Parser {
+    Parser::new()
+  }
+}
+
+/// One line of assembly source, split into its instruction and comment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line<'a> {
+  /// 1-based line number
+  pub number: usize,
+  /// Number of whitespace characters before the line's content
+  pub indent: usize,
+  pub instruction: Option<Instruction>,
+  /// Everything after `//`, with the slashes removed
+  pub comment: Option<&'a str>,
 }
 
+/// An instruction or label declaration. C-instruction mnemonics keep the
+/// case they were written in, with whitespace removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+  /// `@value`
+  A(Address),
+  /// `dest=comp;jump`
+  C {
+    dest: Option<String>,
+    comp: String,
+    jump: Option<String>,
+  },
+  /// `(SYMBOL)`, marks the address of the next instruction
+  Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+  Literal(u16),
+  Symbol(String),
+}
+
+/// Parses every line of the source, each malformed line yielding its own error
+pub fn parse_lines(input: &str) -> impl Iterator<Item = Result<Line<'_>, AsmError>> {
+  input
+    .lines()
+    .enumerate()
+    .map(|(index, text)| parse_line(index + 1, text))
+}
+
+pub fn parse_line(number: usize, text: &str) -> Result<Line<'_>, AsmError> {
+  let (code, comment) = match text.find("//") {
+    Some(start) => (&text[..start], Some(&text[start + 2..])),
+    None => (text, None),
+  };
+
+  let indent = text.len() - text.trim_start().len();
+  let code = code.trim();
+  let error = |kind| AsmError { line: number, kind };
+
+  let instruction = if code.is_empty() {
+    None
+  } else {
+    let instruction = match Parser::command_type(&code.to_ascii_uppercase()) {
+      Some(CommandType::ACommand) => parse_address(code[1..].trim()).map(Instruction::A),
+      Some(CommandType::LCommand) => parse_label(code),
+      Some(CommandType::CCommand) => parse_c_command(code),
+      None => Err(ErrorKind::UnknownInstruction(code.to_string())),
+    };
+    Some(instruction.map_err(error)?)
+  };
+
+  Ok(Line {
+    number,
+    indent,
+    instruction,
+    comment,
+  })
+}
+
+fn parse_address(token: &str) -> Result<Address, ErrorKind> {
+  // if the token is a decimal number, use it as is
+  if Parser::is_literal(token) {
+    match token.parse::<u16>() {
+      Ok(value) if value <= 0x7fff => Ok(Address::Literal(value)),
+      _ => Err(ErrorKind::LiteralOutOfRange(token.to_string())),
+    }
+  } else if is_symbol(token) {
+    Ok(Address::Symbol(token.to_string()))
+  } else {
+    Err(ErrorKind::InvalidSymbol(token.to_string()))
+  }
+}
+
+fn parse_label(code: &str) -> Result<Instruction, ErrorKind> {
+  let symbol = code
+    .strip_prefix('(')
+    .and_then(|rest| rest.strip_suffix(')'))
+    .map(str::trim)
+    .ok_or_else(|| ErrorKind::UnknownInstruction(code.to_string()))?;
+
+  if !is_symbol(symbol) {
+    return Err(ErrorKind::InvalidSymbol(symbol.to_string()));
+  }
+
+  Ok(Instruction::Label(symbol.to_string()))
+}
+
+fn parse_c_command(code: &str) -> Result<Instruction, ErrorKind> {
+  let strip = |token: &str| {
+    token
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .collect::<String>()
+  };
+
+  let (dest, rest) = match code.find('=') {
+    Some(index) => (Some(strip(&code[..index])), &code[index + 1..]),
+    None => (None, code),
+  };
+
+  let (comp, jump) = match rest.find(';') {
+    Some(index) => (strip(&rest[..index]), Some(strip(&rest[index + 1..]))),
+    None => (strip(rest), None),
+  };
+
+  // lookup tokens in code tables
+  if let Some(dest) = &dest {
+    code::canonical_dest(dest).ok_or_else(|| ErrorKind::InvalidDest(dest.clone()))?;
+  }
+
+  code::canonical_comp(&comp).ok_or_else(|| ErrorKind::InvalidComp(comp.clone()))?;
+
+  if let Some(jump) = &jump {
+    code::canonical_jump(jump).ok_or_else(|| ErrorKind::InvalidJump(jump.clone()))?;
+  }
+
+  Ok(Instruction::C { dest, comp, jump })
+}
+
+/// A code table lookup, or a `code::canonical_*` function
+type Lookup = fn(&str) -> Option<&'static str>;
+
+/// Whether a mnemonic is spelled as in its code table, or if `lenient` in
+/// any form its canonical function accepts
+fn is_known(token: &str, lenient: bool, table: Lookup, canonical: Lookup) -> bool {
+  if lenient {
+    canonical(token).is_some()
+  } else {
+    table(token).is_some()
+  }
+}
+
+#[allow(clippy::enum_variant_names)]
 enum CommandType {
   /// For @xxx where xxx is a symbol or decimal number
   ACommand,
@@ -151,6 +325,7 @@ mod tests {
   use super::*;
 
   #[test]
+  #[allow(clippy::assertions_on_constants)]
   fn get_a_command() {
     match Parser::command_type("@255").unwrap() {
       CommandType::ACommand => assert!(true),
@@ -159,6 +334,7 @@ mod tests {
   }
 
   #[test]
+  #[allow(clippy::assertions_on_constants)]
   fn get_c_command() {
     match Parser::command_type("D=D+M;JGT").unwrap() {
       CommandType::CCommand => assert!(true),
@@ -167,6 +343,7 @@ mod tests {
   }
 
   #[test]
+  #[allow(clippy::assertions_on_constants)]
   fn get_l_command() {
     match Parser::command_type("(SYMBOL)").unwrap() {
       CommandType::LCommand => assert!(true),
@@ -177,20 +354,106 @@ mod tests {
   #[test]
   fn resolve_labels() {
     let mut p = Parser::new();
-    p.resolve_labels("(LOOP)");
+    p.resolve_labels("(LOOP)").unwrap();
 
     assert!(p.symbol_table.contains("LOOP"));
-    assert_eq!(p.symbol_table.get_addr("LOOP"), "0");
+    assert_eq!(p.symbol_table.get_addr("LOOP"), Some(0));
   }
 
   #[test]
   fn resolve_multiple_labels() {
     let mut p = Parser::new();
-    p.resolve_labels("(FIRST)\n@1234\nD=D+M;JGT\n(SECOND)\n@5678");
+    p.resolve_labels("(FIRST)\n@1234\nD=D+M;JGT\n(SECOND)\n@5678")
+      .unwrap();
 
     assert!(p.symbol_table.contains("FIRST"));
     assert!(p.symbol_table.contains("SECOND"));
-    assert_eq!(p.symbol_table.get_addr("FIRST"), "0");
-    assert_eq!(p.symbol_table.get_addr("SECOND"), "2");
+    assert_eq!(p.symbol_table.get_addr("FIRST"), Some(0));
+    assert_eq!(p.symbol_table.get_addr("SECOND"), Some(2));
+  }
+
+  #[test]
+  fn allocates_variables_after_labels() {
+    let mut p = Parser::new();
+    let out = p.parse("@i\n(LOOP)\n@LOOP\n@sum\n@i").unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert_eq!(
+      out.lines().collect::<Vec<_>>(),
+      vec![
+        "0000000000010000",
+        "0000000000000001",
+        "0000000000010001",
+        "0000000000010000"
+      ]
+    );
+  }
+
+  #[test]
+  fn accepts_only_the_book_mnemonics_unless_lenient() {
+    let source = "@1\ndm=m+d;jgt";
+    let err = Parser::new().assemble(source).unwrap_err();
+    assert_eq!(err.line, 2);
+    assert_eq!(err.kind, ErrorKind::InvalidDest(String::from("dm")));
+
+    let err = Parser::new().assemble("M+D").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidComp(String::from("M+D")));
+
+    let mut parser = Parser::new();
+    parser.set_lenient_mnemonics(true);
+    assert_eq!(
+      parser.assemble(source).unwrap(),
+      Parser::new().assemble("@1\nMD=D+M;JGT").unwrap()
+    );
+  }
+
+  #[test]
+  fn reports_invalid_c_instructions_from_callers() {
+    let instruction = Instruction::C {
+      dest: None,
+      comp: String::from("D*M"),
+      jump: None,
+    };
+    let err = Parser::new().encode(&instruction, 7).unwrap_err();
+    assert_eq!(err.line, 7);
+    assert_eq!(err.kind, ErrorKind::InvalidComp(String::from("D*M")));
+  }
+
+  #[test]
+  fn splits_instructions_from_comments() {
+    let line = parse_line(3, "   D=M   // D = first number").unwrap();
+    assert_eq!(line.number, 3);
+    assert_eq!(line.indent, 3);
+    assert_eq!(line.comment, Some(" D = first number"));
+    assert_eq!(
+      line.instruction,
+      Some(Instruction::C {
+        dest: Some("D".into()),
+        comp: "M".into(),
+        jump: None
+      })
+    );
+
+    let line = parse_line(1, "// only a comment").unwrap();
+    assert_eq!(line.instruction, None);
+  }
+
+  #[test]
+  fn reports_malformed_lines() {
+    let kind = |text| parse_line(7, text).unwrap_err().kind;
+
+    assert_eq!(kind("foo"), ErrorKind::UnknownInstruction("foo".into()));
+    assert_eq!(kind("X=D+1"), ErrorKind::InvalidDest("X".into()));
+    assert_eq!(kind("D=D+D"), ErrorKind::InvalidComp("D+D".into()));
+    assert_eq!(kind("0;JUMP"), ErrorKind::InvalidJump("JUMP".into()));
+    assert_eq!(kind("@40000"), ErrorKind::LiteralOutOfRange("40000".into()));
+    assert_eq!(kind("(1ST)"), ErrorKind::InvalidSymbol("1ST".into()));
+  }
+
+  #[test]
+  fn rejects_duplicate_labels() {
+    let err = Parser::new().parse("(A)\n@A\n(A)").unwrap_err();
+    assert_eq!(err.line, 3);
+    assert_eq!(err.kind, ErrorKind::DuplicateLabel("A".into()));
   }
 }
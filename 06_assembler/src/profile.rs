@@ -0,0 +1,205 @@
+use std::fmt;
+
+/// The symbols every Hack program can reference without declaring them
+pub const PREDEFINED: [(&str, u16); 23] = [
+  ("SP", 0),
+  ("LCL", 1),
+  ("ARG", 2),
+  ("THIS", 3),
+  ("THAT", 4),
+  ("R0", 0),
+  ("R1", 1),
+  ("R2", 2),
+  ("R3", 3),
+  ("R4", 4),
+  ("R5", 5),
+  ("R6", 6),
+  ("R7", 7),
+  ("R8", 8),
+  ("R9", 9),
+  ("R10", 10),
+  ("R11", 11),
+  ("R12", 12),
+  ("R13", 13),
+  ("R14", 14),
+  ("R15", 15),
+  ("SCREEN", 16384),
+  ("KBD", 24576),
+];
+
+/// First RAM address handed out to variables
+pub const VARIABLE_BASE: u16 = 16;
+
+/// Variables may not reach this address, it is where the VM stack begins
+pub const VARIABLE_LIMIT: u16 = 256;
+
+/// Describes the memory layout of the machine being assembled for: which
+/// symbols are predefined, and which RAM range variables are allocated from.
+///
+/// A profile file is plain text with one entry per line, `//` comments and
+/// blank lines are ignored:
+///
+/// ```text
+/// // extended Hack machine with a mouse
+/// MOUSE_X 24577
+/// MOUSE_Y 0x6002
+/// .variables 16 256
+/// ```
+///
+/// `NAME ADDRESS` adds or overrides a predefined symbol. `.variables BASE LIMIT`
+/// sets the variable range, `LIMIT` being exclusive. `.clear` drops every
+/// symbol defined so far, including the standard ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+  pub symbols: Vec<(String, u16)>,
+  pub variable_base: u16,
+  pub variable_limit: u16,
+}
+
+impl Profile {
+  pub fn parse(input: &str) -> Result<Profile, ProfileError> {
+    let mut profile = Profile::default();
+
+    for (index, line) in input.lines().enumerate() {
+      let line_number = index + 1;
+      let line = match line.find("//") {
+        Some(start) => &line[..start],
+        None => line,
+      }
+      .trim();
+
+      if line.is_empty() {
+        continue;
+      }
+
+      let tokens: Vec<&str> = line.split_whitespace().collect();
+      let error = |message: String| ProfileError {
+        line: line_number,
+        message,
+      };
+
+      match tokens.as_slice() {
+        [".clear"] => profile.symbols.clear(),
+        [".variables", base, limit] => {
+          let base = parse_address(base).map_err(error)?;
+          let limit = parse_address(limit).map_err(error)?;
+
+          if base > limit {
+            return Err(error(format!(
+              "variable base {} is above the limit {}",
+              base, limit
+            )));
+          }
+
+          profile.variable_base = base;
+          profile.variable_limit = limit;
+        }
+        [directive, ..] if directive.starts_with('.') => {
+          return Err(error(format!("unknown directive '{}'", line)));
+        }
+        [name, address] => {
+          if !is_symbol(name) {
+            return Err(error(format!("invalid symbol name '{}'", name)));
+          }
+
+          let address = parse_address(address).map_err(error)?;
+          profile.symbols.retain(|(existing, _)| existing != name);
+          profile.symbols.push((name.to_string(), address));
+        }
+        _ => return Err(error(format!("expected 'NAME ADDRESS', got '{}'", line))),
+      }
+    }
+
+    Ok(profile)
+  }
+}
+
+impl Default for Profile {
+  fn default() -> Profile {
+    Profile {
+      symbols: PREDEFINED
+        .iter()
+        .map(|(name, address)| (name.to_string(), *address))
+        .collect(),
+      variable_base: VARIABLE_BASE,
+      variable_limit: VARIABLE_LIMIT,
+    }
+  }
+}
+
+fn parse_address(token: &str) -> Result<u16, String> {
+  let parsed = match token.strip_prefix("0x") {
+    Some(hex) => u16::from_str_radix(hex, 16),
+    None => token.parse(),
+  };
+
+  parsed.map_err(|_| format!("'{}' is not a valid 16-bit address", token))
+}
+
+/// Hack symbols are letters, digits, `_`, `.`, `$` and `:`, not starting with a digit
+pub fn is_symbol(token: &str) -> bool {
+  let mut chars = token.chars();
+
+  match chars.next() {
+    Some(c) if !c.is_ascii_digit() => token
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c)),
+    _ => false,
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProfileError {
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ProfileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "profile line {}: {}", self.line, self.message)
+  }
+}
+
+impl std::error::Error for ProfileError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn default_profile_is_the_standard_machine() {
+    let p = Profile::default();
+    assert_eq!(p.symbols.len(), 23);
+    assert!(p.symbols.contains(&("KBD".to_string(), 24576)));
+    assert_eq!(p.variable_base, 16);
+    assert_eq!(p.variable_limit, 256);
+  }
+
+  #[test]
+  fn parses_extra_devices_and_variable_range() {
+    let p = Profile::parse("// mouse\nMOUSE 0x6001\n\n.variables 32 128\n").unwrap();
+    assert!(p.symbols.contains(&("MOUSE".to_string(), 24577)));
+    assert_eq!(p.variable_base, 32);
+    assert_eq!(p.variable_limit, 128);
+  }
+
+  #[test]
+  fn overrides_and_clears_symbols() {
+    let p = Profile::parse("SCREEN 8192").unwrap();
+    assert!(p.symbols.contains(&("SCREEN".to_string(), 8192)));
+    assert!(!p.symbols.contains(&("SCREEN".to_string(), 16384)));
+
+    let p = Profile::parse(".clear\nIO 100").unwrap();
+    assert_eq!(p.symbols, vec![("IO".to_string(), 100)]);
+  }
+
+  #[test]
+  fn reports_bad_lines() {
+    let err = Profile::parse("OK 1\nBAD 70000").unwrap_err();
+    assert_eq!(err.line, 2);
+
+    assert!(Profile::parse("1ABC 5").is_err());
+    assert!(Profile::parse(".variables 300 256").is_err());
+    assert!(Profile::parse(".stack 256").is_err());
+  }
+}
@@ -0,0 +1,409 @@
+//! The test scripts (.tst) the nand2tetris emulators run, and the output
+//! tables they write and compare against (.out, .cmp)
+
+/// One command of a test script
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+  /// `load` with the file or directory to load, if any
+  Load(Option<String>),
+  OutputFile(String),
+  CompareTo(String),
+  OutputList(Vec<Column>),
+  /// `set RAM[256] 7` or `set sp 261`
+  Set {
+    name: String,
+    index: Option<u16>,
+    value: i16,
+  },
+  /// `ticktock`, `vmstep` and the like, left for the emulator to interpret
+  Step(String),
+  Output,
+  /// `repeat n { ... }`, or forever without a count
+  Repeat {
+    count: Option<usize>,
+    body: Vec<Command>,
+  },
+}
+
+/// A column of the output table, e.g. `RAM[256]%D2.6.2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+  pub name: String,
+  pub index: Option<u16>,
+  /// Spaces before the value, its width and spaces after it
+  pub padding: (usize, usize, usize),
+}
+
+impl Column {
+  fn parse(spec: &str) -> Result<Column, String> {
+    let (target, format) = match spec.find('%') {
+      Some(split) => (&spec[..split], &spec[split + 1..]),
+      None => (spec, "D1.6.1"),
+    };
+    let (name, index) = parse_target(target)?;
+
+    let invalid = || format!("invalid output format '{}'", spec);
+    let numbers: Vec<usize> = format
+      .get(1..)
+      .ok_or_else(invalid)?
+      .split('.')
+      .map(|n| n.parse().map_err(|_| invalid()))
+      .collect::<Result<_, _>>()?;
+
+    match (format.chars().next(), numbers.as_slice()) {
+      (Some('D'), [left, width, right]) => Ok(Column {
+        name,
+        index,
+        padding: (*left, *width, *right),
+      }),
+      _ => Err(invalid()),
+    }
+  }
+
+  fn width(&self) -> usize {
+    self.padding.0 + self.padding.1 + self.padding.2
+  }
+}
+
+/// `RAM[5]` as ("RAM", Some(5)), `sp` as ("sp", None)
+fn parse_target(target: &str) -> Result<(String, Option<u16>), String> {
+  match target.find('[') {
+    Some(open) if target.ends_with(']') => {
+      let index = target[open + 1..target.len() - 1]
+        .parse()
+        .map_err(|_| format!("invalid index in '{}'", target))?;
+      Ok((String::from(&target[..open]), Some(index)))
+    }
+    Some(_) => Err(format!("invalid target '{}'", target)),
+    None => Ok((String::from(target), None)),
+  }
+}
+
+/// Writes a target back the way scripts do
+pub fn target(name: &str, index: Option<u16>) -> String {
+  match index {
+    Some(index) => format!("{}[{}]", name, index),
+    None => String::from(name),
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Script {
+  pub commands: Vec<Command>,
+}
+
+impl Script {
+  pub fn parse(input: &str) -> Result<Script, String> {
+    let tokens = tokenize(input);
+    let mut position = 0;
+    let commands = parse_commands(&tokens, &mut position)?;
+
+    match tokens.get(position) {
+      None => Ok(Script { commands }),
+      Some(token) => Err(format!("unexpected '{}'", token)),
+    }
+  }
+
+  /// RAM of `size` words as the script's `set RAM[n]` commands outside of
+  /// any `repeat` leave it, for running its program without the script
+  pub fn ram(&self, size: usize) -> Vec<i16> {
+    let mut ram = vec![0; size];
+
+    for command in &self.commands {
+      if let Command::Set {
+        name,
+        index: Some(index),
+        value,
+      } = command
+      {
+        if name == "RAM" && (*index as usize) < size {
+          ram[*index as usize] = *value;
+        }
+      }
+    }
+    ram
+  }
+}
+
+/// Splits a script into words and the `,` `;` `!` `{` `}` between them,
+/// dropping comments
+fn tokenize(input: &str) -> Vec<String> {
+  let mut tokens = Vec::new();
+  let mut word = String::new();
+  let mut chars = input.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    let comment = c == '/' && matches!(chars.peek(), Some('/') | Some('*'));
+
+    if (comment || c.is_whitespace() || ",;!{}".contains(c)) && !word.is_empty() {
+      tokens.push(std::mem::take(&mut word));
+    }
+
+    if comment {
+      if chars.next() == Some('/') {
+        chars.by_ref().find(|&c| c == '\n');
+      } else {
+        let mut last = ' ';
+        for c in chars.by_ref() {
+          if last == '*' && c == '/' {
+            break;
+          }
+          last = c;
+        }
+      }
+    } else if ",;!{}".contains(c) {
+      tokens.push(c.to_string());
+    } else if !c.is_whitespace() {
+      word.push(c);
+    }
+  }
+
+  if !word.is_empty() {
+    tokens.push(word);
+  }
+
+  tokens
+}
+
+fn parse_commands(tokens: &[String], position: &mut usize) -> Result<Vec<Command>, String> {
+  let mut commands = Vec::new();
+
+  while let Some(token) = tokens.get(*position) {
+    if token == "}" {
+      break;
+    }
+
+    // the words up to the next separator
+    let start = *position;
+    while let Some(token) = tokens.get(*position) {
+      if ",;!{}".contains(token.as_str()) {
+        break;
+      }
+      *position += 1;
+    }
+    let words = &tokens[start..*position];
+
+    if words.first().map(String::as_str) == Some("repeat") {
+      let count = match words.get(1) {
+        Some(count) => Some(
+          count
+            .parse()
+            .map_err(|_| format!("invalid repeat count '{}'", count))?,
+        ),
+        None => None,
+      };
+
+      if tokens.get(*position).map(String::as_str) != Some("{") {
+        return Err(String::from("expected '{' after repeat"));
+      }
+      *position += 1;
+
+      let body = parse_commands(tokens, position)?;
+      if tokens.get(*position).map(String::as_str) != Some("}") {
+        return Err(String::from("repeat is missing its '}'"));
+      }
+      *position += 1;
+
+      commands.push(Command::Repeat { count, body });
+      continue;
+    }
+
+    if !words.is_empty() {
+      commands.push(parse_command(words)?);
+    }
+
+    match tokens.get(*position).map(String::as_str) {
+      Some(",") | Some(";") | Some("!") => *position += 1,
+      Some("}") | None => (),
+      Some(other) => return Err(format!("unexpected '{}'", other)),
+    }
+  }
+
+  Ok(commands)
+}
+
+fn parse_command(words: &[String]) -> Result<Command, String> {
+  let argument = |n: usize| {
+    words
+      .get(n)
+      .cloned()
+      .ok_or_else(|| format!("'{}' is missing an argument", words[0]))
+  };
+
+  match words[0].as_str() {
+    "load" => Ok(Command::Load(words.get(1).cloned())),
+    "output-file" => Ok(Command::OutputFile(argument(1)?)),
+    "compare-to" => Ok(Command::CompareTo(argument(1)?)),
+    "output-list" => words[1..]
+      .iter()
+      .map(|spec| Column::parse(spec))
+      .collect::<Result<_, _>>()
+      .map(Command::OutputList),
+    "set" => {
+      let (name, index) = parse_target(&argument(1)?)?;
+      let value = argument(2)?;
+      let value = value
+        .parse::<i32>()
+        .ok()
+        .filter(|value| (-32768..=65535).contains(value))
+        .ok_or_else(|| format!("invalid value '{}'", value))?;
+
+      Ok(Command::Set {
+        name,
+        index,
+        value: value as i16,
+      })
+    }
+    "output" => Ok(Command::Output),
+    step if words.len() == 1 => Ok(Command::Step(String::from(step))),
+    other => Err(format!("unknown command '{}'", other)),
+  }
+}
+
+/// The table header written for an `output-list`, names centered in their
+/// columns and cut short where they don't fit
+pub fn header(columns: &[Column]) -> String {
+  let mut line = String::from("|");
+
+  for column in columns {
+    let width = column.width();
+    let mut name = target(&column.name, column.index);
+    name.truncate(width);
+    let left = (width - name.len()) / 2;
+
+    line.push_str(&format!(
+      "{}{}{}|",
+      " ".repeat(left),
+      name,
+      " ".repeat(width - name.len() - left)
+    ));
+  }
+
+  line.push('\n');
+  line
+}
+
+/// A table row for an `output`
+pub fn row(columns: &[Column], values: &[i16]) -> String {
+  let mut line = String::from("|");
+
+  for (column, value) in columns.iter().zip(values) {
+    let (left, width, right) = column.padding;
+    line.push_str(&format!(
+      "{}{:>width$}{}|",
+      " ".repeat(left),
+      value,
+      " ".repeat(right),
+      width = width
+    ));
+  }
+
+  line.push('\n');
+  line
+}
+
+/// Compares output with the expected table line by line, ignoring
+/// whitespace, as the emulators do
+pub fn compare(output: &str, expected: &str) -> Result<(), String> {
+  let squash = |line: &str| {
+    line
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .collect::<String>()
+  };
+  let mut output_lines = output.lines();
+
+  for (number, expected) in expected.lines().enumerate() {
+    let got = output_lines.next().unwrap_or("");
+
+    if squash(got) != squash(expected) {
+      return Err(format!(
+        "comparison failure at line {}: expected {}, got {}",
+        number + 1,
+        expected.trim(),
+        got.trim()
+      ));
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_scripts() {
+    let script = Script::parse(
+      "// Test file\nload StackTest.asm,\noutput-list RAM[0]%D2.6.2\n  RAM[256]%D1.6.1;\n\
+       set RAM[0] 256,  // the stack pointer\n/* no\n bootstrap */ set sp 261;\n\
+       repeat 1000 {\n  ticktock;\n}\noutput;",
+    )
+    .unwrap();
+
+    assert_eq!(
+      script.commands,
+      vec![
+        Command::Load(Some(String::from("StackTest.asm"))),
+        Command::OutputList(vec![
+          Column {
+            name: String::from("RAM"),
+            index: Some(0),
+            padding: (2, 6, 2)
+          },
+          Column {
+            name: String::from("RAM"),
+            index: Some(256),
+            padding: (1, 6, 1)
+          },
+        ]),
+        Command::Set {
+          name: String::from("RAM"),
+          index: Some(0),
+          value: 256
+        },
+        Command::Set {
+          name: String::from("sp"),
+          index: None,
+          value: 261
+        },
+        Command::Repeat {
+          count: Some(1000),
+          body: vec![Command::Step(String::from("ticktock"))]
+        },
+        Command::Output,
+      ]
+    );
+    assert_eq!(script.ram(4), vec![256, 0, 0, 0]);
+  }
+
+  #[test]
+  fn rejects_malformed_scripts() {
+    assert!(Script::parse("repeat 3 { ticktock;").is_err());
+    assert!(Script::parse("set RAM[x] 1;").is_err());
+    assert!(Script::parse("output-list RAM[0]%X1.2;").is_err());
+    assert!(Script::parse("tick tock;").is_err());
+  }
+
+  #[test]
+  fn writes_tables_like_the_emulators() {
+    let columns = match &Script::parse("output-list RAM[0]%D2.6.2 RAM[256]%D1.6.1;")
+      .unwrap()
+      .commands[0]
+    {
+      Command::OutputList(columns) => columns.clone(),
+      _ => unreachable!(),
+    };
+
+    assert_eq!(header(&columns), "|  RAM[0]  |RAM[256]|\n");
+    assert_eq!(header(&columns[1..2]), "|RAM[256]|\n");
+
+    let mut long = columns[1].clone();
+    long.index = Some(3006);
+    assert_eq!(header(&[long]), "|RAM[3006|\n");
+    assert_eq!(row(&columns, &[266, -1]), "|     266  |     -1 |\n");
+    assert!(compare("|  RAM[0]  |\n|     266  |\n", "|RAM[0]|\n|266|\n").is_ok());
+    assert!(compare("|  RAM[0]  |\n|     265  |\n", "|RAM[0]|\n|266|\n").is_err());
+  }
+}
@@ -1,77 +1,159 @@
+use crate::profile::Profile;
 use std::collections::HashMap;
+use std::fmt;
 
-const SYMBOLS: [(&str, &str); 22] = [
-  ("SP", "0"),
-  ("LCL", "1"),
-  ("ARG", "2"),
-  ("THIS", "3"),
-  ("THAT", "4"),
-  ("R0", "0"),
-  ("R1", "1"),
-  ("R2", "2"),
-  ("R3", "3"),
-  ("R4", "4"),
-  ("R5", "5"),
-  ("R6", "6"),
-  ("R7", "7"),
-  ("R8", "8"),
-  ("R9", "9"),
-  ("R10", "10"),
-  ("R11", "11"),
-  ("R13", "13"),
-  ("R14", "14"),
-  ("R15", "15"),
-  ("SCREEN", "16384"),
-  ("KBD", "24567"),
-];
+/// Where a symbol's address came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+  /// Defined by the machine profile, e.g. `SP` or `SCREEN`
+  Predefined,
+  /// Declared with `(LABEL)`, the address is a ROM location
+  Label,
+  /// First referenced with `@name`, allocated a RAM cell
+  Variable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Symbol {
+  pub address: u16,
+  pub origin: Origin,
+}
 
 pub struct SymbolTable {
-  table: HashMap<String, String>,
+  table: HashMap<String, Symbol>,
+  next_variable: u16,
+  variable_limit: u16,
 }
 
 impl SymbolTable {
   pub fn new() -> SymbolTable {
-    let table = SYMBOLS
+    SymbolTable::with_profile(&Profile::default())
+  }
+
+  pub fn with_profile(profile: &Profile) -> SymbolTable {
+    let table = profile
+      .symbols
       .iter()
-      .map(|pair| (pair.0.to_string(), pair.1.to_string()))
+      .map(|(name, address)| {
+        let symbol = Symbol {
+          address: *address,
+          origin: Origin::Predefined,
+        };
+        (name.clone(), symbol)
+      })
       .collect();
 
-    SymbolTable { table }
+    SymbolTable {
+      table,
+      next_variable: profile.variable_base,
+      variable_limit: profile.variable_limit,
+    }
   }
 
-  pub fn add_entry(&mut self, symbol: &str, value: &str) {
-    self.table.insert(symbol.into(), value.into());
+  pub fn add_label(&mut self, symbol: &str, address: u16) {
+    let entry = Symbol {
+      address,
+      origin: Origin::Label,
+    };
+    self.table.insert(symbol.into(), entry);
   }
 
-  pub fn contains(&self, key: &str) -> bool {
-    match &self.table.get(key) {
-      Some(_val) => return true,
-      None => return false,
+  /// Allocates the next free RAM cell to `symbol`, failing once the
+  /// allocation would reach the profile's variable limit
+  pub fn add_variable(&mut self, symbol: &str) -> Result<u16, SymbolError> {
+    if self.next_variable >= self.variable_limit {
+      return Err(SymbolError::VariableOverflow {
+        symbol: symbol.into(),
+        limit: self.variable_limit,
+      });
     }
+
+    let address = self.next_variable;
+    let entry = Symbol {
+      address,
+      origin: Origin::Variable,
+    };
+    self.table.insert(symbol.into(), entry);
+    self.next_variable += 1;
+
+    Ok(address)
+  }
+
+  pub fn contains(&self, key: &str) -> bool {
+    self.table.contains_key(key)
+  }
+
+  pub fn get_addr(&self, key: &str) -> Option<u16> {
+    self.table.get(key).map(|symbol| symbol.address)
   }
 
-  pub fn get_addr(&self, key: &str) -> &str {
-    &self.table.get(key).unwrap()
+  pub fn origin(&self, key: &str) -> Option<Origin> {
+    self.table.get(key).map(|symbol| symbol.origin)
+  }
+
+  pub fn get(&self, key: &str) -> Option<&Symbol> {
+    self.table.get(key)
+  }
+
+  /// Iterates over every symbol in the table, in no particular order
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+    self
+      .table
+      .iter()
+      .map(|(name, symbol)| (name.as_str(), symbol))
+  }
+}
+
+impl Default for SymbolTable {
+  fn default() -> SymbolTable {
+    SymbolTable::new()
   }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+  /// A variable would have been allocated at or above the limit
+  VariableOverflow { symbol: String, limit: u16 },
+}
+
+impl fmt::Display for SymbolError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SymbolError::VariableOverflow { symbol, limit } => write!(
+        f,
+        "no room for variable '{}', variables would reach RAM[{}]",
+        symbol, limit
+      ),
+    }
+  }
+}
+
+impl std::error::Error for SymbolError {}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::profile::PREDEFINED;
 
   #[test]
   fn creates_new_table() {
     let t = SymbolTable::new();
-    assert_eq!(
-      t.table,
-      SYMBOLS
-        .iter()
-        .map(|pair| (pair.0.to_string(), pair.1.to_string()))
-        .collect()
-    );
+    let expected: HashMap<String, Symbol> = PREDEFINED
+      .iter()
+      .map(|(name, address)| {
+        let symbol = Symbol {
+          address: *address,
+          origin: Origin::Predefined,
+        };
+        (name.to_string(), symbol)
+      })
+      .collect();
+
+    assert_eq!(t.table, expected);
   }
 
   #[test]
+  #[allow(clippy::bool_assert_comparison)]
   fn checks_if_val_exists() {
     let t = SymbolTable::new();
     assert_eq!(t.contains("R1"), true);
@@ -79,15 +161,41 @@ mod tests {
   }
 
   #[test]
-  fn adds_entry() {
+  fn adds_label() {
     let mut t = SymbolTable::new();
-    t.add_entry("test", "value");
-    assert_eq!(t.contains("test"), true)
+    t.add_label("test", 42);
+    assert_eq!(t.get_addr("test"), Some(42));
+    assert_eq!(t.origin("test"), Some(Origin::Label));
   }
 
   #[test]
   fn gets_value_at_address() {
     let t = SymbolTable::new();
-    assert_eq!(t.get_addr("SP"), "0");
+    assert_eq!(t.get_addr("SP"), Some(0));
+    assert_eq!(t.get_addr("Fooey"), None);
+    assert_eq!(t.origin("SP"), Some(Origin::Predefined));
+  }
+
+  #[test]
+  fn allocates_variables_from_the_base() {
+    let mut t = SymbolTable::new();
+    assert_eq!(t.add_variable("i"), Ok(16));
+    assert_eq!(t.add_variable("sum"), Ok(17));
+    assert_eq!(t.origin("sum"), Some(Origin::Variable));
+  }
+
+  #[test]
+  fn errors_when_variables_reach_the_limit() {
+    let profile = Profile::parse(".variables 16 18").unwrap();
+    let mut t = SymbolTable::with_profile(&profile);
+    assert_eq!(t.add_variable("a"), Ok(16));
+    assert_eq!(t.add_variable("b"), Ok(17));
+    assert_eq!(
+      t.add_variable("c"),
+      Err(SymbolError::VariableOverflow {
+        symbol: "c".into(),
+        limit: 18
+      })
+    );
   }
 }
@@ -0,0 +1,51 @@
+//! Counts the cycles each chapter 7 and 8 test program takes on an emulated
+//! Hack CPU, with the stack kept entirely in RAM and with its top cached in D.
+//! Run with `cargo bench`.
+
+use std::path::Path;
+use vm_translator::emulate::{run_test, test_programs};
+use vm_translator::writer::Codegen;
+use vm_translator::Options;
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let cached = Options {
+        codegen: Codegen::CachedTop,
+        ..Options::default()
+    };
+    let (mut stack_total, mut cached_total) = (0, 0);
+
+    println!(
+        "{:<20} {:>10} {:>10} {:>10}",
+        "program", "stack", "cached", "reduction"
+    );
+
+    for program in test_programs(&root).expect("couldn't list the test programs") {
+        let name = program.file_name().unwrap().to_string_lossy();
+        let stack_cycles = run_test(&program, &Options::default()).unwrap();
+        let cached_cycles = run_test(&program, &cached).unwrap();
+
+        println!(
+            "{:<20} {:>10} {:>10} {:>9.1}%",
+            name,
+            stack_cycles,
+            cached_cycles,
+            reduction(stack_cycles, cached_cycles)
+        );
+
+        stack_total += stack_cycles;
+        cached_total += cached_cycles;
+    }
+
+    println!(
+        "{:<20} {:>10} {:>10} {:>9.1}%",
+        "total",
+        stack_total,
+        cached_total,
+        reduction(stack_total, cached_total)
+    );
+}
+
+fn reduction(before: u64, after: u64) -> f64 {
+    100.0 * (before as f64 - after as f64) / before as f64
+}
@@ -0,0 +1,75 @@
+use std::io::{self, BufRead, Write};
+use std::process;
+use vm_translator::callgraph::CallGraph;
+use vm_translator::debugger::Debugger;
+use vm_translator::error::ErrorKind;
+use vm_translator::interpreter::Vm;
+use vm_translator::{collect_sources, load, os};
+
+/// Usage: `vmdbg INPUT...`
+///
+/// Loads the .vm files, directories or globs given, as the translator does,
+/// and runs them on the VM interpreter under a prompt; `help` lists the
+/// commands. Programs with a `Sys.init` start there with the stack set up as
+/// the bootstrap code leaves it, as do those with just a `Main.main` using
+/// the built-in OS's, and others at their first command with SP at 256.
+/// Calls to OS functions the files don't define run the built-in ones.
+fn main() {
+  let inputs: Vec<String> = std::env::args().skip(1).collect();
+  if inputs.is_empty() {
+    eprintln!("usage: vmdbg INPUT...");
+    process::exit(2);
+  }
+
+  let files = match collect_sources(&inputs).and_then(|paths| load(&paths)) {
+    Ok(files) => files,
+    Err(errors) => {
+      for err in &errors {
+        eprintln!("error: {}", err);
+      }
+      process::exit(1);
+    }
+  };
+  for err in CallGraph::build(&files).undefined_calls() {
+    match &err.kind {
+      ErrorKind::UndefinedFunction(name) if os::is_builtin(name) => (),
+      _ => eprintln!("warning: {}", err),
+    }
+  }
+
+  let mut vm = Vm::new(&files);
+  if vm.function_address("Sys.init").is_some() {
+    vm.boot();
+  } else {
+    vm.ram[0] = 256;
+  }
+
+  let mut debugger = Debugger::new(vm);
+  println!("{}", debugger.execute("where").unwrap());
+
+  let stdin = io::stdin();
+  let mut line = String::new();
+  loop {
+    print!("(vmdbg) ");
+    io::stdout().flush().expect("problem writing to stdout");
+
+    line.clear();
+    if stdin
+      .lock()
+      .read_line(&mut line)
+      .expect("problem reading stdin")
+      == 0
+    {
+      break;
+    }
+
+    match line.trim() {
+      "quit" | "q" => break,
+      command => match debugger.execute(command) {
+        Ok(output) if output.is_empty() => (),
+        Ok(output) => println!("{}", output),
+        Err(err) => println!("error: {}", err),
+      },
+    }
+  }
+}
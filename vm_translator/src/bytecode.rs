@@ -0,0 +1,434 @@
+//! `.vmb`, a binary encoding of VM programs, which loads without parsing
+//! any text. One `.vmb` file holds the commands of any number of .vm files,
+//! each with its namespace, so that a whole program can be one file.
+//!
+//! Numbers are little-endian, and indexes into the string table `u16`s:
+//!
+//! ```text
+//! magic     the bytes "VMB", then the version, 1
+//! strings   u16 count, then each as a u16 length and that many UTF-8 bytes:
+//!           every namespace, label and function name, each once
+//! files     u16 count, then for each
+//!             namespace   string index, e.g. Main for Main.vm
+//!             commands    u32 count, then for each its opcode byte, its
+//!                         operands and the line of the .vm file it came
+//!                         from as an unsigned LEB128
+//! ```
+//!
+//! | opcode        | command                         | operands                |
+//! |---------------|---------------------------------|-------------------------|
+//! | `0x00`-`0x10` | `add` `sub` `neg` `eq` `gt` `lt` `and` `or` `not` `mul` `div` `mod` `shl` `shr` `xor` `lt_u` `gt_u` | |
+//! | `0x20`        | `push`                          | segment byte, u16 index |
+//! | `0x21`        | `pop`                           | segment byte, u16 index |
+//! | `0x30`        | `label`                         | string index            |
+//! | `0x31`        | `goto`                          | string index            |
+//! | `0x32`        | `if-goto`                       | string index            |
+//! | `0x40`        | `function`                      | string index, u16 locals |
+//! | `0x41`        | `call`                          | string index, u16 arguments |
+//! | `0x42`        | `return`                        |                         |
+//!
+//! Segments are `argument` 0, `local` 1, `static` 2, `constant` 3, `this` 4,
+//! `that` 5, `pointer` 6 and `temp` 7. Decoding checks commands as the
+//! parser does, so a `.vmb` can't hold anything a .vm file couldn't.
+
+use crate::error::{ErrorKind, TranslateError};
+use crate::parser::{is_name, MathCommand, MemorySegment, SourceCommand, Span, VmCommand};
+use crate::SourceFile;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 3] = b"VMB";
+const VERSION: u8 = 1;
+
+const MATH: [MathCommand; 17] = [
+  MathCommand::Add,
+  MathCommand::Subtract,
+  MathCommand::Negate,
+  MathCommand::EqualTo,
+  MathCommand::GreaterThan,
+  MathCommand::LessThan,
+  MathCommand::And,
+  MathCommand::Or,
+  MathCommand::Not,
+  MathCommand::Multiply,
+  MathCommand::Divide,
+  MathCommand::Modulo,
+  MathCommand::ShiftLeft,
+  MathCommand::ShiftRight,
+  MathCommand::Xor,
+  MathCommand::LessThanUnsigned,
+  MathCommand::GreaterThanUnsigned,
+];
+
+const SEGMENTS: [MemorySegment; 8] = [
+  MemorySegment::Argument,
+  MemorySegment::Local,
+  MemorySegment::Static,
+  MemorySegment::Constant,
+  MemorySegment::This,
+  MemorySegment::That,
+  MemorySegment::Pointer,
+  MemorySegment::Temp,
+];
+
+const PUSH: u8 = 0x20;
+const POP: u8 = 0x21;
+const LABEL: u8 = 0x30;
+const GOTO: u8 = 0x31;
+const IF_GOTO: u8 = 0x32;
+const FUNCTION: u8 = 0x40;
+const CALL: u8 = 0x41;
+const RETURN: u8 = 0x42;
+
+/// Encodes the files as one `.vmb`
+pub fn encode(files: &[SourceFile]) -> Vec<u8> {
+  let mut strings = Strings::default();
+  let mut body = Vec::new();
+
+  body.extend_from_slice(&(files.len() as u16).to_le_bytes());
+  for file in files {
+    body.extend_from_slice(&strings.index(&file.namespace).to_le_bytes());
+    body.extend_from_slice(&(file.commands.len() as u32).to_le_bytes());
+
+    for command in &file.commands {
+      encode_command(&command.command, &mut strings, &mut body);
+      write_leb128(&mut body, command.span.line as u64);
+    }
+  }
+
+  let mut bytes = MAGIC.to_vec();
+  bytes.push(VERSION);
+  bytes.extend_from_slice(&(strings.list.len() as u16).to_le_bytes());
+  for string in &strings.list {
+    bytes.extend_from_slice(&(string.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+  }
+  bytes.extend(body);
+  bytes
+}
+
+fn encode_command(command: &VmCommand, strings: &mut Strings, bytes: &mut Vec<u8>) {
+  use VmCommand::*;
+
+  let mut named = |opcode: u8, name: &str, bytes: &mut Vec<u8>| {
+    bytes.push(opcode);
+    bytes.extend_from_slice(&strings.index(name).to_le_bytes());
+  };
+
+  match command {
+    Arithmetic(math) => bytes.push(MATH.iter().position(|m| m == math).unwrap() as u8),
+    Push { segment, index } | Pop { segment, index } => {
+      bytes.push(if let Push { .. } = command { PUSH } else { POP });
+      bytes.push(SEGMENTS.iter().position(|s| s == segment).unwrap() as u8);
+      bytes.extend_from_slice(&index.to_le_bytes());
+    }
+    Label(label) => named(LABEL, label, bytes),
+    Goto(label) => named(GOTO, label, bytes),
+    IfGoto(label) => named(IF_GOTO, label, bytes),
+    Function { name, nlocals } => {
+      named(FUNCTION, name, bytes);
+      bytes.extend_from_slice(&nlocals.to_le_bytes());
+    }
+    Call { name, nargs } => {
+      named(CALL, name, bytes);
+      bytes.extend_from_slice(&nargs.to_le_bytes());
+    }
+    Return => bytes.push(RETURN),
+  }
+}
+
+/// The string table, each string given an index the first time it's seen
+#[derive(Default)]
+struct Strings {
+  list: Vec<String>,
+  indexes: HashMap<String, u16>,
+}
+
+impl Strings {
+  fn index(&mut self, string: &str) -> u16 {
+    if let Some(index) = self.indexes.get(string) {
+      return *index;
+    }
+
+    let index = self.list.len() as u16;
+    self.list.push(String::from(string));
+    self.indexes.insert(String::from(string), index);
+    index
+  }
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: u64) {
+  loop {
+    let byte = (value & 0x7F) as u8;
+    value >>= 7;
+    if value == 0 {
+      bytes.push(byte);
+      return;
+    }
+    bytes.push(byte | 0x80);
+  }
+}
+
+/// Decodes a `.vmb`, named `file` in errors. Commands get spans in the .vm
+/// files they came from, such as `Main.vm:12`.
+pub fn decode(bytes: &[u8], file: &str) -> Result<Vec<SourceFile>, TranslateError> {
+  let mut reader = Reader {
+    bytes,
+    offset: 0,
+    last: 0,
+  };
+
+  reader.decode().map_err(|message| TranslateError {
+    file: String::from(file),
+    line: None,
+    kind: ErrorKind::InvalidBytecode {
+      offset: reader.last,
+      message,
+    },
+  })
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+  /// Where the last thing read starts, which errors point at
+  last: usize,
+}
+
+impl Reader<'_> {
+  fn decode(&mut self) -> Result<Vec<SourceFile>, String> {
+    if self.take(3)? != MAGIC {
+      return Err(String::from("not a .vmb file"));
+    }
+    let version = self.u8()?;
+    if version != VERSION {
+      return Err(format!("version {} isn't supported", version));
+    }
+
+    let mut strings = Vec::new();
+    for _ in 0..self.u16()? {
+      let length = self.u16()? as usize;
+      let string =
+        std::str::from_utf8(self.take(length)?).map_err(|_| String::from("string isn't UTF-8"))?;
+      strings.push(String::from(string));
+    }
+
+    let mut files = Vec::new();
+    for _ in 0..self.u16()? {
+      let namespace = self.string(&strings)?;
+      let file: Rc<str> = Rc::from(format!("{}.vm", namespace));
+      let mut commands = Vec::new();
+
+      for _ in 0..self.u32()? {
+        let command = self.command(&strings)?;
+        let line = self.leb128()? as usize;
+        commands.push(SourceCommand {
+          command,
+          span: Span {
+            file: Rc::clone(&file),
+            line,
+          },
+        });
+      }
+
+      files.push(SourceFile {
+        namespace: String::from(namespace),
+        commands,
+      });
+    }
+
+    if self.offset < self.bytes.len() {
+      self.last = self.offset;
+      return Err(String::from("bytes after the last file"));
+    }
+    Ok(files)
+  }
+
+  fn command(&mut self, strings: &[String]) -> Result<VmCommand, String> {
+    let opcode = self.u8()?;
+
+    let command = match opcode {
+      _ if (opcode as usize) < MATH.len() => VmCommand::Arithmetic(MATH[opcode as usize]),
+      PUSH | POP => {
+        let segment = *SEGMENTS
+          .get(self.u8()? as usize)
+          .ok_or("unknown memory segment")?;
+        let index = self.u16()?;
+
+        if let Some(max) = segment.max_index() {
+          if index > max {
+            return Err(
+              ErrorKind::IndexOutOfRange {
+                segment,
+                index,
+                max,
+              }
+              .to_string(),
+            );
+          }
+        }
+        match opcode {
+          POP if segment == MemorySegment::Constant => {
+            return Err(ErrorKind::PopConstant.to_string())
+          }
+          POP => VmCommand::Pop { segment, index },
+          _ => VmCommand::Push { segment, index },
+        }
+      }
+      LABEL => VmCommand::Label(self.name(strings)?),
+      GOTO => VmCommand::Goto(self.name(strings)?),
+      IF_GOTO => VmCommand::IfGoto(self.name(strings)?),
+      FUNCTION => VmCommand::Function {
+        name: self.name(strings)?,
+        nlocals: self.u16()?,
+      },
+      CALL => VmCommand::Call {
+        name: self.name(strings)?,
+        nargs: self.u16()?,
+      },
+      RETURN => VmCommand::Return,
+      _ => return Err(format!("unknown opcode {:#04x}", opcode)),
+    };
+
+    Ok(command)
+  }
+
+  fn take(&mut self, n: usize) -> Result<&[u8], String> {
+    self.last = self.offset;
+    let bytes = self
+      .bytes
+      .get(self.offset..self.offset + n)
+      .ok_or("unexpected end of file")?;
+    self.offset += n;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, String> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    let bytes = self.take(4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  fn leb128(&mut self) -> Result<u64, String> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+      let byte = self.u8()?;
+      value |= ((byte & 0x7F) as u64) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(String::from("line number too long"))
+  }
+
+  fn string<'a>(&mut self, strings: &'a [String]) -> Result<&'a str, String> {
+    let index = self.u16()?;
+
+    strings
+      .get(index as usize)
+      .map(String::as_str)
+      .ok_or_else(|| format!("no string {}", index))
+  }
+
+  /// A label or function name, which must be one the parser would accept
+  fn name(&mut self, strings: &[String]) -> Result<String, String> {
+    let name = self.string(strings)?;
+
+    if is_name(name) {
+      Ok(String::from(name))
+    } else {
+      Err(ErrorKind::InvalidName(String::from(name)).to_string())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn commands(files: &[SourceFile]) -> Vec<(String, String, usize)> {
+    files
+      .iter()
+      .flat_map(|file| &file.commands)
+      .map(|command| {
+        (
+          command.command.to_string(),
+          command.span.file.to_string(),
+          command.span.line,
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn round_trips_every_command() {
+    let main = parse_file(
+      "function Main.main 2\npush constant 7\npop local 1\n// a comment\n\n\
+       label LOOP\npush pointer 1\npop temp 7\nadd\nsub\nneg\neq\ngt\nlt\nand\nor\nnot\n\
+       mul\ndiv\nmod\nshl\nshr\nxor\nlt_u\ngt_u\nif-goto LOOP\ngoto LOOP\n\
+       call Main.main 3\nreturn",
+      "Main",
+    )
+    .unwrap();
+    let mut long = parse_file("push static 65535\npop that 300", "Long").unwrap();
+    long.commands[1].span.line = 1_000_000;
+    let files = vec![main, long];
+
+    let bytes = encode(&files);
+    assert_eq!(&bytes[..4], b"VMB\x01");
+    let decoded = decode(&bytes, "Program.vmb").unwrap();
+
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[1].namespace, "Long");
+    assert_eq!(commands(&decoded), commands(&files));
+    assert_eq!(
+      commands(&decoded)[1],
+      (String::from("push constant 7"), String::from("Main.vm"), 2)
+    );
+    // each name once in the table
+    assert_eq!(
+      bytes.windows(4).filter(|window| window == b"LOOP").count(),
+      1
+    );
+  }
+
+  #[test]
+  fn rejects_malformed_bytecode() {
+    let bytes = encode(&[parse_file("push temp 3\ngoto END", "Main").unwrap()]);
+    let error = |bytes: &[u8]| decode(bytes, "Main.vmb").unwrap_err().to_string();
+
+    assert_eq!(
+      error(b"VM"),
+      "Main.vmb: malformed bytecode at byte 0: unexpected end of file"
+    );
+    assert_eq!(
+      error(b"VMB\x02"),
+      "Main.vmb: malformed bytecode at byte 3: version 2 isn't supported"
+    );
+    assert!(error(&bytes[..bytes.len() - 1]).ends_with("unexpected end of file"));
+    assert!(error(b"ASM\x01").ends_with("not a .vmb file"));
+
+    // the commands start after the table's 2 strings, the file count, its
+    // namespace and its command count
+    let start = 4 + 2 + (2 + 4) + (2 + 3) + 2 + 2 + 4;
+    let mut changed = bytes.clone();
+    changed[start + 2] = 8;
+    assert!(error(&changed).ends_with("index 8 is out of range for the temp segment (0-7)"));
+    changed[start] = 0x7F;
+    assert!(error(&changed).ends_with("unknown opcode 0x7f"));
+    changed = bytes.clone();
+    changed[start] = POP;
+    changed[start + 1] = 3;
+    assert!(error(&changed).ends_with("cannot pop to the constant segment"));
+  }
+}
@@ -0,0 +1,250 @@
+use crate::error::{ErrorKind, TranslateError};
+use crate::parser::{Span, VmCommand};
+use crate::SourceFile;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The function every program with a bootstrap starts in
+pub const ENTRY_POINT: &str = "Sys.init";
+
+/// Which functions call which, across every file of a program
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+  /// Every function defined, with the functions it calls in the order called
+  pub functions: BTreeMap<String, Vec<String>>,
+  /// Functions called by code outside of any function
+  pub top_level: Vec<String>,
+  /// Every call, where it was made
+  calls: Vec<(String, Span)>,
+  /// Where each function is defined, the first time if more than once
+  definitions: BTreeMap<String, Span>,
+  /// Functions defined again, where they were
+  redefinitions: Vec<(String, Span)>,
+}
+
+impl CallGraph {
+  pub fn build(files: &[SourceFile]) -> CallGraph {
+    let mut graph = CallGraph::default();
+
+    for file in files {
+      let mut current: Option<&str> = None;
+
+      for command in &file.commands {
+        match &command.command {
+          VmCommand::Function { name, .. } => {
+            graph.functions.entry(name.clone()).or_default();
+            if graph.definitions.contains_key(name) {
+              graph
+                .redefinitions
+                .push((name.clone(), command.span.clone()));
+            } else {
+              graph.definitions.insert(name.clone(), command.span.clone());
+            }
+            current = Some(name);
+          }
+          VmCommand::Call { name, .. } => {
+            let callees = match current {
+              Some(function) => graph.functions.entry(String::from(function)).or_default(),
+              None => &mut graph.top_level,
+            };
+
+            if !callees.contains(name) {
+              callees.push(name.clone());
+            }
+            graph.calls.push((name.clone(), command.span.clone()));
+          }
+          _ => (),
+        }
+      }
+    }
+
+    graph
+  }
+
+  pub fn is_defined(&self, function: &str) -> bool {
+    self.functions.contains_key(function)
+  }
+
+  /// The functions that can run starting from `Sys.init` and from code
+  /// outside of any function
+  pub fn reachable(&self) -> BTreeSet<String> {
+    let mut roots = self.top_level.clone();
+    if self.is_defined(ENTRY_POINT) {
+      roots.push(String::from(ENTRY_POINT));
+    }
+
+    let mut reachable = BTreeSet::new();
+    while let Some(function) = roots.pop() {
+      if reachable.insert(function.clone()) {
+        if let Some(callees) = self.functions.get(&function) {
+          roots.extend(callees.iter().cloned());
+        }
+      }
+    }
+
+    reachable
+  }
+
+  /// An error for every call to a function no file defines
+  pub fn undefined_calls(&self) -> Vec<TranslateError> {
+    self
+      .calls
+      .iter()
+      .filter(|(name, _)| !self.is_defined(name))
+      .map(|(name, span)| TranslateError {
+        file: span.file.to_string(),
+        line: Some(span.line),
+        kind: ErrorKind::UndefinedFunction(name.clone()),
+      })
+      .collect()
+  }
+
+  /// An error for every function defined again, in the same file or another
+  pub fn duplicate_functions(&self) -> Vec<TranslateError> {
+    self
+      .redefinitions
+      .iter()
+      .map(|(name, span)| TranslateError {
+        file: span.file.to_string(),
+        line: Some(span.line),
+        kind: ErrorKind::DuplicateFunction {
+          name: name.clone(),
+          first: self.definitions[name].to_string(),
+        },
+      })
+      .collect()
+  }
+
+  /// The errors no translation can get past: functions defined twice,
+  /// then calls to functions never defined
+  pub fn errors(&self) -> Vec<TranslateError> {
+    let mut errors = self.duplicate_functions();
+    errors.extend(self.undefined_calls());
+    errors
+  }
+}
+
+/// Drops every function that can't be reached from `Sys.init`, returning the
+/// names of those dropped. Programs without a `Sys.init` are left alone, as
+/// there's no telling where they start.
+pub fn eliminate_dead_functions(files: &mut [SourceFile]) -> Vec<String> {
+  let graph = CallGraph::build(files);
+  if !graph.is_defined(ENTRY_POINT) {
+    return Vec::new();
+  }
+
+  let reachable = graph.reachable();
+  let mut removed = Vec::new();
+
+  for file in files {
+    let mut keep = true;
+
+    file.commands.retain(|command| {
+      if let VmCommand::Function { name, .. } = &command.command {
+        keep = reachable.contains(name);
+        if !keep {
+          removed.push(name.clone());
+        }
+      }
+
+      keep
+    });
+  }
+
+  removed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn program() -> Vec<SourceFile> {
+    vec![
+      parse_file(
+        "function Main.main 0\ncall Math.abs 1\nreturn\n\
+         function Main.unused 0\ncall Main.unused 0\ncall Math.max 2\nreturn",
+        "Main",
+      )
+      .unwrap(),
+      parse_file(
+        "function Math.abs 0\npush argument 0\nreturn\n\
+         function Math.max 0\npush argument 0\nreturn",
+        "Math",
+      )
+      .unwrap(),
+      parse_file(
+        "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END",
+        "Sys",
+      )
+      .unwrap(),
+    ]
+  }
+
+  #[test]
+  fn finds_functions_reachable_from_sys_init() {
+    let graph = CallGraph::build(&program());
+    let reachable: Vec<String> = graph.reachable().into_iter().collect();
+
+    assert_eq!(reachable, vec!["Main.main", "Math.abs", "Sys.init"]);
+    assert_eq!(
+      graph.functions["Main.unused"],
+      vec!["Main.unused", "Math.max"]
+    );
+  }
+
+  #[test]
+  fn eliminates_unreachable_functions() {
+    let mut files = program();
+    let removed = eliminate_dead_functions(&mut files);
+
+    assert_eq!(removed, vec!["Main.unused", "Math.max"]);
+    assert_eq!(files[0].commands.len(), 3);
+    assert_eq!(files[1].commands.len(), 3);
+    assert_eq!(files[2].commands.len(), 4);
+  }
+
+  #[test]
+  fn keeps_everything_without_sys_init() {
+    let mut files = program();
+    files.pop();
+
+    assert!(eliminate_dead_functions(&mut files).is_empty());
+  }
+
+  #[test]
+  fn reports_calls_to_undefined_functions() {
+    let mut files = program();
+    files.remove(1);
+
+    let errors: Vec<String> = CallGraph::build(&files)
+      .undefined_calls()
+      .iter()
+      .map(|err| err.to_string())
+      .collect();
+
+    assert_eq!(
+      errors,
+      vec![
+        "Main.vm:2: call to undefined function 'Math.abs'",
+        "Main.vm:6: call to undefined function 'Math.max'",
+      ]
+    );
+  }
+
+  #[test]
+  fn reports_functions_defined_twice() {
+    let mut files = program();
+    files.push(parse_file("push constant 1\nfunction Math.abs 0\nreturn", "Extra").unwrap());
+
+    let errors: Vec<String> = CallGraph::build(&files)
+      .errors()
+      .iter()
+      .map(|err| err.to_string())
+      .collect();
+
+    assert_eq!(
+      errors,
+      vec!["Extra.vm:2: function 'Math.abs' is already defined at Math.vm:1"]
+    );
+  }
+}
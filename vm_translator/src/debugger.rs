@@ -0,0 +1,458 @@
+//! The commands of `vmdbg`, a debugger for VM programs run on the
+//! interpreter, kept apart from its prompt so they can be tested
+
+use crate::interpreter::{Vm, RAM_SIZE};
+use crate::os::NEW_LINE;
+use crate::parser::VmCommand;
+use std::fmt::Write;
+
+/// Where execution stops
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+  /// The first command of a function
+  Function(String),
+  /// The first command at or after a line of a file, e.g. `Main.vm:12`
+  Line { file: String, line: usize },
+}
+
+impl Breakpoint {
+  pub fn parse(spec: &str) -> Breakpoint {
+    match spec.rfind(':') {
+      Some(colon) if spec.ends_with(|c: char| c.is_ascii_digit()) => {
+        match spec[colon + 1..].parse() {
+          Ok(line) => Breakpoint::Line {
+            file: String::from(&spec[..colon]),
+            line,
+          },
+          Err(_) => Breakpoint::Function(String::from(spec)),
+        }
+      }
+      _ => Breakpoint::Function(String::from(spec)),
+    }
+  }
+
+  /// The command it stops at
+  fn address(&self, vm: &Vm) -> Option<usize> {
+    match self {
+      Breakpoint::Function(function) => vm.function_address(function),
+      Breakpoint::Line { file, line } => vm
+        .commands()
+        .iter()
+        .position(|command| &*command.span.file == file && command.span.line >= *line),
+    }
+  }
+}
+
+/// Why running stopped
+enum Stop {
+  /// Where the command run asked to stop
+  Done,
+  Breakpoint(usize),
+  /// A watched address, with the value it had
+  Watchpoint(u16, i16),
+  Halted,
+  /// A `goto` to itself, which is how programs end
+  Loop,
+  Error(String),
+}
+
+pub struct Debugger {
+  pub vm: Vm,
+  /// Each breakpoint with the command it stops at
+  breakpoints: Vec<(Breakpoint, usize)>,
+  /// Each watched address with the value it was last seen to have
+  watchpoints: Vec<(u16, i16)>,
+}
+
+pub const HELP: &str = "\
+break FUNCTION | FILE.vm:LINE   stop at a function or line (b)
+delete N                        remove breakpoint N, or every one
+watch ADDRESS                   stop when RAM[ADDRESS] changes
+type TEXT                       give the keyboard a line to read
+step                            run one command, into calls (s)
+next                            run one command, over calls (n)
+finish                          run until the current function returns
+continue                        run until a breakpoint or watchpoint (c)
+backtrace                       list the calls the program is in (bt)
+print SEGMENT [INDEX]           print sp, local, argument, this, that, or a
+                                word of any segment, e.g. p local 2 (p)
+x/N ADDRESS                     dump N words of RAM from ADDRESS
+where                           show the next command (w)
+info                            list breakpoints and watchpoints
+quit                            leave (q)";
+
+impl Debugger {
+  pub fn new(vm: Vm) -> Debugger {
+    Debugger {
+      vm,
+      breakpoints: Vec::new(),
+      watchpoints: Vec::new(),
+    }
+  }
+
+  /// Runs one line typed at the prompt, returning what to print
+  pub fn execute(&mut self, line: &str) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let argument = |n: usize| {
+      words
+        .get(n)
+        .copied()
+        .ok_or_else(|| format!("'{}' needs an argument", words[0]))
+    };
+
+    match words.first().copied().unwrap_or("") {
+      "" => Ok(String::new()),
+      "help" | "h" => Ok(String::from(HELP)),
+      "break" | "b" => {
+        let breakpoint = Breakpoint::parse(argument(1)?);
+        let address = breakpoint
+          .address(&self.vm)
+          .ok_or_else(|| format!("no function or line {}", argument(1).unwrap()))?;
+        self.breakpoints.push((breakpoint, address));
+        Ok(format!(
+          "breakpoint {} at {}",
+          self.breakpoints.len(),
+          self.describe(address)
+        ))
+      }
+      "delete" | "d" => match words.get(1) {
+        Some(n) => {
+          let n: usize = n
+            .parse()
+            .map_err(|_| format!("'{}' is not a breakpoint", n))?;
+          if n == 0 || n > self.breakpoints.len() {
+            return Err(format!("no breakpoint {}", n));
+          }
+          self.breakpoints.remove(n - 1);
+          Ok(String::new())
+        }
+        None => {
+          self.breakpoints.clear();
+          Ok(String::new())
+        }
+      },
+      "type" => {
+        let text = line.trim_start()[4..].trim_start();
+        self.vm.input.extend(text.chars().map(|c| c as i16));
+        self.vm.input.push_back(NEW_LINE);
+        Ok(format!("{} keys to read", self.vm.input.len()))
+      }
+      "watch" => {
+        let address = parse_address(argument(1)?)?;
+        self
+          .watchpoints
+          .push((address, self.vm.ram[address as usize]));
+        Ok(format!(
+          "watching RAM[{}] = {}",
+          address, self.vm.ram[address as usize]
+        ))
+      }
+      "step" | "s" => Ok(self.run(|_| true)),
+      "next" | "n" => {
+        let depth = self.vm.depth;
+        Ok(self.run(move |vm| vm.depth <= depth))
+      }
+      "finish" => {
+        let depth = self.vm.depth;
+        if depth == 0 {
+          return Err(String::from("not in a call"));
+        }
+        Ok(self.run(move |vm| vm.depth < depth))
+      }
+      "continue" | "c" => Ok(self.run(|_| false)),
+      "backtrace" | "bt" => Ok(
+        self
+          .vm
+          .backtrace()
+          .iter()
+          .enumerate()
+          .map(|(n, frame)| {
+            format!(
+              "#{} {} (LCL {}, ARG {}) at {}",
+              n,
+              frame.function,
+              frame.local,
+              frame.argument,
+              self.location(frame.address)
+            )
+          })
+          .collect::<Vec<_>>()
+          .join("\n"),
+      ),
+      "print" | "p" => {
+        let name = argument(1)?;
+        let index = match words.get(2) {
+          Some(index) => Some(
+            index
+              .parse()
+              .map_err(|_| format!("'{}' is not an index", index))?,
+          ),
+          None => None,
+        };
+        let address = self.vm.variable(name, index)?;
+        Ok(format!("RAM[{}] = {}", address, self.vm.ram[address]))
+      }
+      "where" | "w" => Ok(self.describe(self.vm.pc)),
+      "info" => {
+        let mut info = String::new();
+        for (n, (_, address)) in self.breakpoints.iter().enumerate() {
+          let _ = writeln!(info, "breakpoint {} at {}", n + 1, self.describe(*address));
+        }
+        for (address, value) in &self.watchpoints {
+          let _ = writeln!(info, "watching RAM[{}] = {}", address, value);
+        }
+        Ok(String::from(info.trim_end()))
+      }
+      dump if dump.starts_with("x/") => {
+        let count: usize = dump[2..]
+          .parse()
+          .map_err(|_| format!("'{}' is not a count", &dump[2..]))?;
+        let start = parse_address(argument(1)?)? as usize;
+        let end = start.saturating_add(count).min(RAM_SIZE);
+
+        Ok(
+          (start..end)
+            .step_by(8)
+            .map(|row| {
+              let words: Vec<String> = self.vm.ram[row..(row + 8).min(end)]
+                .iter()
+                .map(|word| format!("{:>6}", word))
+                .collect();
+              format!("{:>5}:{}", row, words.join(""))
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        )
+      }
+      other => Err(format!("unknown command '{}', try help", other)),
+    }
+  }
+
+  /// Steps until `done` says to stop, given the machine after each step, or
+  /// something else stops it first
+  fn run(&mut self, done: impl Fn(&Vm) -> bool) -> String {
+    let stop = loop {
+      let command = match self.vm.current() {
+        Some(command) if !self.vm.is_halted() => command.command.clone(),
+        _ => break Stop::Halted,
+      };
+      let pc = self.vm.pc;
+
+      if let Err(err) = self.vm.step() {
+        break Stop::Error(err);
+      }
+      if self.vm.pc == pc && matches!(command, VmCommand::Goto(_)) {
+        break Stop::Loop;
+      }
+      if let Some(stop) = self.watched() {
+        break stop;
+      }
+      if done(&self.vm) {
+        break Stop::Done;
+      }
+      if let Some(n) = self
+        .breakpoints
+        .iter()
+        .position(|(_, address)| *address == self.vm.pc)
+      {
+        break Stop::Breakpoint(n + 1);
+      }
+    };
+
+    let here = self.describe(self.vm.pc);
+    match stop {
+      Stop::Done => here,
+      Stop::Breakpoint(n) => format!("breakpoint {}, {}", n, here),
+      Stop::Watchpoint(address, old) => format!(
+        "RAM[{}] changed from {} to {}\n{}",
+        address, old, self.vm.ram[address as usize], here
+      ),
+      Stop::Halted => match self.vm.halted() {
+        Some(reason) => format!(
+          "the program has halted after {} commands: {}",
+          self.vm.steps, reason
+        ),
+        None => format!("the program has halted after {} commands", self.vm.steps),
+      },
+      Stop::Loop => format!("the program is looping forever at {}", here),
+      Stop::Error(err) => format!("error: {}\n{}", err, here),
+    }
+  }
+
+  /// The first watchpoint whose value changed, updating them all
+  fn watched(&mut self) -> Option<Stop> {
+    let mut stop = None;
+
+    for (address, value) in self.watchpoints.iter_mut() {
+      let now = self.vm.ram[*address as usize];
+      if now != *value {
+        stop = stop.or(Some(Stop::Watchpoint(*address, *value)));
+        *value = now;
+      }
+    }
+
+    stop
+  }
+
+  /// A command's file, line and function
+  fn location(&self, address: usize) -> String {
+    match self.vm.commands().get(address) {
+      Some(command) => match self.vm.function_at(address) {
+        "" => command.span.to_string(),
+        function => format!("{} in {}", command.span, function),
+      },
+      None => String::from("the end of the program"),
+    }
+  }
+
+  /// A command's location and the command itself
+  fn describe(&self, address: usize) -> String {
+    match self.vm.commands().get(address) {
+      Some(command) => format!("{}: {}", self.location(address), command.command),
+      None => self.location(address),
+    }
+  }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+  text
+    .parse()
+    .ok()
+    .filter(|address| (*address as usize) < RAM_SIZE)
+    .ok_or_else(|| format!("'{}' is not an address in RAM", text))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn debugger() -> Debugger {
+    let mut vm = Vm::new(&[
+      parse_file(
+        "function Main.sum 1\npush argument 0\nif-goto MORE\npush constant 0\nreturn\n\
+         label MORE\npush argument 0\npush argument 0\npush constant 1\nsub\n\
+         call Main.sum 1\nadd\npop static 0\npush static 0\nreturn",
+        "Main",
+      )
+      .unwrap(),
+      parse_file(
+        "function Sys.init 0\npush constant 3\ncall Main.sum 1\nlabel END\ngoto END",
+        "Sys",
+      )
+      .unwrap(),
+    ]);
+    vm.boot();
+    Debugger::new(vm)
+  }
+
+  fn run(debugger: &mut Debugger, lines: &[&str]) -> Vec<String> {
+    lines
+      .iter()
+      .map(|line| debugger.execute(line).unwrap_or_else(|err| err))
+      .collect()
+  }
+
+  #[test]
+  fn stops_at_breakpoints_and_steps_over_calls() {
+    let mut debugger = debugger();
+
+    assert_eq!(
+      run(
+        &mut debugger,
+        &[
+          "b Main.vm:11",
+          "c",
+          "n",
+          "p argument 0",
+          "bt",
+          "finish",
+          "c"
+        ]
+      ),
+      vec![
+        "breakpoint 1 at Main.vm:11 in Main.sum: call Main.sum 1",
+        "breakpoint 1, Main.vm:11 in Main.sum: call Main.sum 1",
+        "breakpoint 1, Main.vm:11 in Main.sum: call Main.sum 1",
+        "RAM[269] = 2",
+        "#0 Main.sum (LCL 275, ARG 269) at Main.vm:11 in Main.sum\n\
+         #1 Main.sum (LCL 267, ARG 261) at Main.vm:11 in Main.sum\n\
+         #2 Sys.init (LCL 261, ARG 256) at Sys.vm:3 in Sys.init",
+        "breakpoint 1, Main.vm:11 in Main.sum: call Main.sum 1",
+        "the program is looping forever at Sys.vm:5 in Sys.init: goto END",
+      ]
+    );
+  }
+
+  #[test]
+  fn finishes_calls() {
+    let mut debugger = debugger();
+
+    assert_eq!(
+      run(
+        &mut debugger,
+        &[
+          "b Main.vm:13",
+          "c",
+          "p static 0",
+          "delete 1",
+          "finish",
+          "s",
+          "p local 0"
+        ]
+      ),
+      vec![
+        "breakpoint 1 at Main.vm:13 in Main.sum: pop static 0",
+        "breakpoint 1, Main.vm:13 in Main.sum: pop static 0",
+        "RAM[16] = 0",
+        "",
+        "Main.vm:12 in Main.sum: add",
+        "Main.vm:13 in Main.sum: pop static 0",
+        "RAM[275] = 0",
+      ]
+    );
+    assert_eq!(debugger.vm.depth, 2);
+    assert!(debugger.execute("delete 1").is_err());
+  }
+
+  #[test]
+  fn watches_memory() {
+    let mut debugger = debugger();
+
+    assert_eq!(
+      run(&mut debugger, &["watch 16", "c", "x/4 261", "p sp"]),
+      vec![
+        "watching RAM[16] = 0",
+        "RAM[16] changed from 0 to 1\nMain.vm:14 in Main.sum: push static 0",
+        "  261:     3    17   261   256",
+        "RAM[0] = 284",
+      ]
+    );
+    assert!(debugger.execute("x/4 40000").is_err());
+    let rest = debugger.execute("x/18446744073709551615 1").unwrap();
+    assert!(rest.starts_with("    1:"));
+    assert_eq!(rest.lines().count(), (RAM_SIZE - 1).div_ceil(8));
+    assert!(debugger.execute("frobnicate").is_err());
+  }
+
+  #[test]
+  fn gives_the_os_typed_lines() {
+    let mut vm = Vm::new(&[parse_file(
+      "function Main.main 0\npush constant 0\ncall String.new 1\n\
+       call Keyboard.readInt 1\npop static 0\npush constant 0\nreturn",
+      "Main",
+    )
+    .unwrap()]);
+    vm.boot();
+    let mut debugger = Debugger::new(vm);
+
+    assert_eq!(
+      run(&mut debugger, &["type 42", "c", "x/1 16"]),
+      vec![
+        "3 keys to read",
+        "the program has halted after 21 commands: Sys.halt was called",
+        "   16:    42",
+      ]
+    );
+  }
+}
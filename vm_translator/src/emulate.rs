@@ -0,0 +1,251 @@
+//! Runs translated programs on an emulated Hack CPU, for checking them
+//! against the nand2tetris test scripts and counting the cycles they take,
+//! and the programs themselves against the VM emulator's scripts
+
+use crate::{interpreter, Options};
+use hack_assembler::emulator::Cpu;
+use hack_assembler::parser::Parser;
+use hack_assembler::script::{self, Script};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Translates the .vm files of a test directory such as
+/// `08_vm_two/FunctionCalls/NestedCall`, runs its CPU emulator script
+/// against the result and compares the output with its .cmp file. Returns
+/// the instructions run before the program halted or the script ended.
+pub fn run_test(directory: &Path, options: &Options) -> Result<u64, String> {
+  let name = directory
+    .file_name()
+    .and_then(|name| name.to_str())
+    .ok_or_else(|| format!("{} is not a test directory", directory.display()))?;
+  let read = |extension: &str| {
+    let path = directory.join(name).with_extension(extension);
+    fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))
+  };
+
+  let rom = assemble(directory, options)?;
+  let script = Script::parse(&read("tst")?).map_err(|err| format!("{}.tst: {}", name, err))?;
+  let mut cpu = Cpu::new(rom);
+  let output = cpu.run_script(&script)?;
+
+  script::compare(&output, &read("cmp")?).map_err(|err| format!("{}: {}", name, err))?;
+  Ok(cpu.cycles)
+}
+
+/// Translates and assembles the .vm files of a test directory
+pub fn assemble(directory: &Path, options: &Options) -> Result<Vec<u16>, String> {
+  let (translation, _) =
+    crate::compile(&[directory.display().to_string()], options).map_err(|errors| {
+      errors
+        .iter()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+    })?;
+  let asm = String::from_utf8_lossy(&translation.asm);
+
+  Parser::new()
+    .assemble(&asm)
+    .map_err(|err| format!("{}: {}", directory.display(), err))
+}
+
+/// Runs the VM emulator script of a test directory, such as
+/// `StaticsTestVME.tst`, on the interpreter and compares the output with the
+/// directory's .cmp file. Returns the commands run.
+pub fn run_vm_test(directory: &Path) -> Result<u64, String> {
+  let name = directory
+    .file_name()
+    .and_then(|name| name.to_str())
+    .ok_or_else(|| format!("{} is not a test directory", directory.display()))?;
+  let read = |file: &str| {
+    let path = directory.join(file);
+    fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))
+  };
+
+  let script = Script::parse(&read(&format!("{}VME.tst", name))?)
+    .map_err(|err| format!("{}VME.tst: {}", name, err))?;
+  let (output, vm) = interpreter::run_script(&script, directory)?;
+
+  script::compare(&output, &read(&format!("{}.cmp", name))?)
+    .map_err(|err| format!("{}: {}", name, err))?;
+  Ok(vm.steps)
+}
+
+/// The test directories for chapters 7 and 8 of a checkout at `root`, in
+/// order, e.g. `07_vm_one/MemoryAccess/BasicTest`
+pub fn test_programs(root: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut programs = Vec::new();
+
+  for chapter in &["07_vm_one", "08_vm_two"] {
+    for group in fs::read_dir(root.join(chapter))? {
+      for program in fs::read_dir(group?.path())? {
+        programs.push(program?.path());
+      }
+    }
+  }
+
+  programs.sort();
+  Ok(programs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::optimizer::Passes;
+  use crate::writer::Codegen;
+  use hack_assembler::aot;
+  use hack_assembler::emulator::RAM_SIZE;
+
+  fn passes_every_test(options: &Options) -> Vec<u64> {
+    test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path())
+      .unwrap()
+      .iter()
+      .map(|program| run_test(program, options).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn passes_the_test_programs() {
+    passes_every_test(&Options::default());
+  }
+
+  #[test]
+  fn passes_the_vm_emulator_scripts() {
+    for program in
+      test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path()).unwrap()
+    {
+      run_vm_test(&program).unwrap();
+    }
+  }
+
+  #[test]
+  fn caching_the_top_of_the_stack_saves_cycles() {
+    let stack = passes_every_test(&Options::default());
+    let cached = passes_every_test(&Options {
+      codegen: Codegen::CachedTop,
+      ..Options::default()
+    });
+
+    for (stack, cached) in stack.iter().zip(&cached) {
+      assert!(cached < stack);
+    }
+  }
+
+  #[test]
+  fn passes_the_test_programs_with_shared_comparisons() {
+    for codegen in [Codegen::Stack, Codegen::CachedTop].iter() {
+      passes_every_test(&Options {
+        codegen: *codegen,
+        shared_comparisons: true,
+        ..Options::default()
+      });
+    }
+  }
+
+  #[test]
+  fn passes_the_test_programs_checked() {
+    passes_every_test(&Options {
+      checked: true,
+      shared_comparisons: true,
+      ..Options::default()
+    });
+  }
+
+  #[test]
+  fn passes_the_test_programs_optimized() {
+    passes_every_test(&Options {
+      passes: Passes::all(),
+      inline_limit: crate::inliner::DEFAULT_LIMIT,
+      codegen: Codegen::CachedTop,
+      ..Options::default()
+    });
+  }
+
+  #[test]
+  fn inverting_branches_keeps_compiled_jack_working_faster() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut files = vec![root.join("test_files/ConvertToBin").display().to_string()];
+    for os in &["Memory", "Math", "Array"] {
+      files.push(
+        root
+          .join("../tools/OS")
+          .join(os)
+          .with_extension("vm")
+          .display()
+          .to_string(),
+      );
+    }
+
+    // RAM[8001..8017] after converting RAM[8000], and the cycles it took
+    let convert = |passes: Passes, value: i16| {
+      let options = Options {
+        passes,
+        ..Options::default()
+      };
+      let (translation, _) = crate::compile(&files, &options).unwrap();
+      let rom = Parser::new()
+        .assemble(&String::from_utf8_lossy(&translation.asm))
+        .unwrap();
+
+      let mut cpu = Cpu::new(rom);
+      cpu.ram[8000] = value;
+      assert!(cpu.run(1_000_000));
+      (cpu.ram[8001..8017].to_vec(), cpu.cycles)
+    };
+
+    for &value in &[0, 1, 0x5a5a, -1, i16::MIN] {
+      let (bits, plain) = convert(Passes::default(), value);
+      let (inverted, fewer) = convert(Passes::parse("invert-branches").unwrap(), value);
+
+      let expected: Vec<i16> = (0..16).map(|bit| (value >> bit) & 1).collect();
+      assert_eq!(bits, expected);
+      assert_eq!(inverted, expected);
+      assert!(fewer < plain, "{} against {} cycles", fewer, plain);
+    }
+  }
+
+  #[test]
+  fn runs_translated_ahead_of_time_as_on_the_emulator() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    if std::process::Command::new(rustc)
+      .arg("--version")
+      .output()
+      .is_err()
+    {
+      eprintln!("skipping: no rustc");
+      return;
+    }
+
+    let build = std::env::temp_dir().join(format!("vm_translator_aot_{}", std::process::id()));
+    fs::create_dir_all(&build).unwrap();
+
+    for program in
+      test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path()).unwrap()
+    {
+      let name = program.file_name().unwrap().to_str().unwrap();
+      let rom = assemble(&program, &Options::default()).unwrap();
+
+      // RAM as the script sets it up, and the program run until it halts
+      let tst = fs::read_to_string(program.join(name).with_extension("tst")).unwrap();
+      let ram = Script::parse(&tst).unwrap().ram(RAM_SIZE);
+
+      let binary = build.join(name);
+      aot::build(&aot::translate(&rom), &binary).unwrap();
+      let native = aot::run(&binary, &rom, &ram, 1_000_000).unwrap();
+
+      let mut cpu = Cpu::new(rom);
+      cpu.ram = ram;
+      assert!(cpu.run(1_000_000), "{} never halts", name);
+      assert_eq!(
+        (native.cycles, native.pc, native.a, native.d),
+        (cpu.cycles, cpu.pc, cpu.a, cpu.d),
+        "{}",
+        name
+      );
+      assert!(native.ram == cpu.ram, "{} leaves RAM differently", name);
+    }
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+}
@@ -0,0 +1,110 @@
+use crate::parser::MemorySegment;
+use std::fmt;
+
+/// A problem with the translator's input, located in a file and, for
+/// problems with a single command, a 1-based line
+#[derive(Debug, PartialEq)]
+pub struct TranslateError {
+  pub file: String,
+  pub line: Option<usize>,
+  pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+  UnknownCommand(String),
+  UnknownSegment(String),
+  /// A command with fewer operands than it takes
+  MissingArgument {
+    command: String,
+    expected: usize,
+  },
+  /// Operands after the last one a command takes
+  UnexpectedArgument(String),
+  InvalidNumber(String),
+  /// A label or function name the assembler would not accept
+  InvalidName(String),
+  IndexOutOfRange {
+    segment: MemorySegment,
+    index: u16,
+    max: u16,
+  },
+  PopConstant,
+  /// A call to a function no input file defines
+  UndefinedFunction(String),
+  /// A function defined again, with where it was first
+  DuplicateFunction {
+    name: String,
+    first: String,
+  },
+  /// A jump to a label outside the function it's in, which only targets
+  /// that keep functions apart can't make
+  UndefinedLabel(String),
+  /// More static variables than fit between RAM[16] and RAM[255], counting
+  /// those of the shared routines
+  StaticOverflow {
+    needed: usize,
+    available: usize,
+  },
+  /// A `.vmb` file that can't be decoded, with where in it the problem is
+  InvalidBytecode {
+    offset: usize,
+    message: String,
+  },
+  /// An input file named on its own without the `.vm` or `.vmb` extension
+  NotVmFile,
+  Io(String),
+}
+
+impl fmt::Display for TranslateError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.line {
+      Some(line) => write!(f, "{}:{}: {}", self.file, line, self.kind),
+      None => write!(f, "{}: {}", self.file, self.kind),
+    }
+  }
+}
+
+impl fmt::Display for ErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use ErrorKind::*;
+
+    match self {
+      UnknownCommand(command) => write!(f, "unknown command '{}'", command),
+      UnknownSegment(segment) => write!(f, "unknown memory segment '{}'", segment),
+      MissingArgument { command, expected } => {
+        write!(f, "'{}' takes {} argument(s)", command, expected)
+      }
+      UnexpectedArgument(argument) => write!(f, "unexpected argument '{}'", argument),
+      InvalidNumber(token) => write!(f, "'{}' is not a number between 0 and 65535", token),
+      InvalidName(name) => write!(f, "'{}' is not a valid label or function name", name),
+      IndexOutOfRange {
+        segment,
+        index,
+        max,
+      } => write!(
+        f,
+        "index {} is out of range for the {} segment (0-{})",
+        index, segment, max
+      ),
+      PopConstant => write!(f, "cannot pop to the constant segment"),
+      UndefinedFunction(name) => write!(f, "call to undefined function '{}'", name),
+      DuplicateFunction { name, first } => {
+        write!(f, "function '{}' is already defined at {}", name, first)
+      }
+      UndefinedLabel(label) => write!(f, "jump to label '{}' outside the function", label),
+      InvalidBytecode { offset, message } => {
+        write!(f, "malformed bytecode at byte {}: {}", offset, message)
+      }
+      StaticOverflow { needed, available } => write!(
+        f,
+        "static variables need {} words of RAM, but only {} fit below the stack",
+        needed, available
+      ),
+      NotVmFile => write!(f, "source files must have the '.vm' or '.vmb' extension"),
+      Io(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for TranslateError {}
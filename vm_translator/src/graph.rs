@@ -0,0 +1,562 @@
+//! The call graph of a program and the control-flow graph of each of its
+//! functions, written as Graphviz DOT or as JSON for other tools
+
+use crate::callgraph::{CallGraph, ENTRY_POINT};
+use crate::parser::VmCommand;
+use crate::SourceFile;
+use hack_assembler::json::Json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// A run of commands entered only at its first and left only after its last
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+  /// The label the block starts with, if any
+  pub label: Option<String>,
+  /// The line of its first command
+  pub line: usize,
+  pub commands: Vec<String>,
+  /// The blocks control can go to next, by index
+  pub successors: Vec<usize>,
+  /// Whether the block ends in `return`
+  pub returns: bool,
+  /// Whether control can reach it from the start of the function
+  pub reachable: bool,
+}
+
+/// The control-flow graph of one function, or of the code before the first
+/// function of a file, which is named after the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowGraph {
+  pub name: String,
+  pub file: String,
+  /// The first block is where the function starts
+  pub blocks: Vec<Block>,
+}
+
+impl FlowGraph {
+  fn build(name: String, file: String, commands: &[(usize, &VmCommand)]) -> FlowGraph {
+    use VmCommand::*;
+
+    let mut blocks: Vec<Block> = Vec::new();
+    // the command each block ends with
+    let mut ends: Vec<&VmCommand> = Vec::new();
+
+    for (line, command) in commands {
+      let ended = match ends.last() {
+        Some(end) => matches!(end, Goto(_) | IfGoto(_) | Return),
+        None => true,
+      };
+
+      if ended || matches!(command, Label(_)) {
+        blocks.push(Block {
+          label: None,
+          line: *line,
+          commands: Vec::new(),
+          successors: Vec::new(),
+          returns: false,
+          reachable: false,
+        });
+        ends.push(command);
+      }
+
+      let block = blocks.last_mut().unwrap();
+      if let Label(label) = command {
+        block.label = Some(label.clone());
+      }
+      block.commands.push(command.to_string());
+      block.returns = matches!(command, Return);
+      *ends.last_mut().unwrap() = command;
+    }
+
+    let labels: HashMap<String, usize> = blocks
+      .iter()
+      .enumerate()
+      .filter_map(|(index, block)| block.label.clone().map(|label| (label, index)))
+      .collect();
+
+    for (index, end) in ends.iter().enumerate() {
+      let next = index + 1;
+      let successors = match end {
+        Goto(label) => labels.get(label).copied().into_iter().collect(),
+        IfGoto(label) => labels
+          .get(label)
+          .copied()
+          .into_iter()
+          .chain(Some(next).filter(|next| *next < blocks.len()))
+          .collect(),
+        Return => Vec::new(),
+        _ if next < blocks.len() => vec![next],
+        _ => Vec::new(),
+      };
+
+      let block = &mut blocks[index];
+      for successor in successors {
+        if !block.successors.contains(&successor) {
+          block.successors.push(successor);
+        }
+      }
+    }
+
+    let mut pending = if blocks.is_empty() { vec![] } else { vec![0] };
+    while let Some(index) = pending.pop() {
+      if !blocks[index].reachable {
+        blocks[index].reachable = true;
+        pending.extend(blocks[index].successors.iter().copied());
+      }
+    }
+
+    FlowGraph { name, file, blocks }
+  }
+
+  /// The blocks control can never reach
+  pub fn unreachable(&self) -> impl Iterator<Item = &Block> {
+    self.blocks.iter().filter(|block| !block.reachable)
+  }
+}
+
+/// Everything `graph` writes about a program
+#[derive(Clone, Debug)]
+pub struct ProgramGraph {
+  pub calls: CallGraph,
+  /// The control-flow graphs, in the order the files and functions came in
+  pub flows: Vec<FlowGraph>,
+  /// The sets of functions that call each other, directly or not, each in
+  /// order of name
+  pub cycles: Vec<Vec<String>>,
+  /// Functions nothing calls: with a `Sys.init`, those it can't lead to, and
+  /// without one, those no other function or top-level code calls
+  pub uncalled: BTreeSet<String>,
+}
+
+impl ProgramGraph {
+  pub fn build(files: &[SourceFile]) -> ProgramGraph {
+    let calls = CallGraph::build(files);
+    let mut flows = Vec::new();
+
+    for file in files {
+      let file_name = format!("{}.vm", file.namespace);
+      let mut name = file_name.clone();
+      let mut scope = Vec::new();
+
+      for command in &file.commands {
+        if let VmCommand::Function { name: function, .. } = &command.command {
+          if !scope.is_empty() {
+            flows.push(FlowGraph::build(name, file_name.clone(), &scope));
+          }
+          name = function.clone();
+          scope.clear();
+        }
+
+        scope.push((command.span.line, &command.command));
+      }
+
+      if !scope.is_empty() {
+        flows.push(FlowGraph::build(name, file_name.clone(), &scope));
+      }
+    }
+
+    let uncalled = if calls.is_defined(ENTRY_POINT) {
+      let reachable = calls.reachable();
+      calls
+        .functions
+        .keys()
+        .filter(|function| !reachable.contains(*function))
+        .cloned()
+        .collect()
+    } else {
+      let called: BTreeSet<&String> = calls
+        .functions
+        .values()
+        .chain(Some(&calls.top_level))
+        .flatten()
+        .collect();
+      calls
+        .functions
+        .keys()
+        .filter(|function| !called.contains(function))
+        .cloned()
+        .collect()
+    };
+
+    ProgramGraph {
+      cycles: cycles(&calls.functions),
+      calls,
+      flows,
+      uncalled,
+    }
+  }
+
+  pub fn is_recursive(&self, function: &str) -> bool {
+    self.cycle_of(function).is_some()
+  }
+
+  fn cycle_of(&self, function: &str) -> Option<usize> {
+    self
+      .cycles
+      .iter()
+      .position(|cycle| cycle.iter().any(|name| name == function))
+  }
+
+  /// The call graph, with recursive calls in red and functions nothing
+  /// calls dashed and grey
+  pub fn call_graph_dot(&self) -> String {
+    let mut dot = String::from("digraph calls {\n  node [shape=box];\n");
+
+    if !self.calls.top_level.is_empty() {
+      dot.push_str("  \"(top level)\" [shape=plaintext];\n");
+    }
+    for function in self.calls.functions.keys() {
+      let _ = writeln!(
+        dot,
+        "  {}{};",
+        quote(function),
+        attributes(self.function_style(function))
+      );
+    }
+
+    let callers = self
+      .calls
+      .functions
+      .iter()
+      .map(|(caller, callees)| (caller.as_str(), callees))
+      .chain(Some(("(top level)", &self.calls.top_level)));
+
+    for (caller, callees) in callers {
+      for callee in callees {
+        let recursive =
+          self.cycle_of(caller).is_some() && self.cycle_of(caller) == self.cycle_of(callee);
+        let style: &[&str] = if recursive {
+          &["color=red"]
+        } else if !self.calls.is_defined(callee) {
+          &["style=dotted"]
+        } else {
+          &[]
+        };
+        let _ = writeln!(
+          dot,
+          "  {} -> {}{};",
+          quote(caller),
+          quote(callee),
+          attributes(style)
+        );
+      }
+    }
+
+    dot.push_str("}\n");
+    dot
+  }
+
+  /// A cluster for each function's control-flow graph, with blocks control
+  /// can't reach dashed and grey and those that return drawn double
+  pub fn flow_graph_dot(&self) -> String {
+    let mut dot = String::from("digraph flow {\n  node [shape=box, fontname=monospace];\n");
+
+    for (cluster, flow) in self.flows.iter().enumerate() {
+      let _ = writeln!(dot, "  subgraph cluster_{} {{", cluster);
+      let _ = writeln!(
+        dot,
+        "    label={};",
+        quote(&format!("{} ({})", flow.name, flow.file))
+      );
+      for attribute in self.function_style(&flow.name) {
+        let _ = writeln!(dot, "    {};", attribute);
+      }
+
+      for (index, block) in flow.blocks.iter().enumerate() {
+        let text: String = block
+          .commands
+          .iter()
+          .map(|command| format!("{}\\l", escape(command)))
+          .collect();
+        let mut style = vec![format!("label=\"{}\"", text)];
+        if block.returns {
+          style.push(String::from("peripheries=2"));
+        }
+        if !block.reachable {
+          style.extend(vec![
+            String::from("style=dashed"),
+            String::from("color=grey"),
+          ]);
+        }
+        let _ = writeln!(dot, "    b{}_{} [{}];", cluster, index, style.join(", "));
+      }
+
+      for (index, block) in flow.blocks.iter().enumerate() {
+        for successor in &block.successors {
+          let _ = writeln!(
+            dot,
+            "    b{}_{} -> b{}_{};",
+            cluster, index, cluster, successor
+          );
+        }
+      }
+
+      dot.push_str("  }\n");
+    }
+
+    dot.push_str("}\n");
+    dot
+  }
+
+  fn function_style(&self, function: &str) -> &'static [&'static str] {
+    if self.uncalled.contains(function) {
+      &["style=dashed", "color=grey"]
+    } else if self.is_recursive(function) {
+      &["color=red"]
+    } else {
+      &[]
+    }
+  }
+
+  /// The call graph and every control-flow graph, along with the cycles
+  pub fn to_json(&self) -> Json {
+    let strings = |names: &[String]| Json::Array(names.iter().cloned().map(Json::String).collect());
+    let number = |n: usize| Json::Number(n as f64);
+
+    let functions = self
+      .calls
+      .functions
+      .iter()
+      .map(|(name, callees)| {
+        Json::object(vec![
+          ("name", Json::String(name.clone())),
+          ("calls", strings(callees)),
+          ("recursive", Json::Bool(self.is_recursive(name))),
+          ("called", Json::Bool(!self.uncalled.contains(name))),
+        ])
+      })
+      .collect();
+
+    let flows = self
+      .flows
+      .iter()
+      .map(|flow| {
+        let blocks = flow
+          .blocks
+          .iter()
+          .map(|block| {
+            Json::object(vec![
+              (
+                "label",
+                block.label.clone().map_or(Json::Null, Json::String),
+              ),
+              ("line", number(block.line)),
+              ("commands", strings(&block.commands)),
+              (
+                "successors",
+                Json::Array(block.successors.iter().copied().map(number).collect()),
+              ),
+              ("returns", Json::Bool(block.returns)),
+              ("reachable", Json::Bool(block.reachable)),
+            ])
+          })
+          .collect();
+
+        Json::object(vec![
+          ("name", Json::String(flow.name.clone())),
+          ("file", Json::String(flow.file.clone())),
+          ("blocks", Json::Array(blocks)),
+        ])
+      })
+      .collect();
+
+    Json::object(vec![
+      ("functions", Json::Array(functions)),
+      ("top_level", strings(&self.calls.top_level)),
+      (
+        "cycles",
+        Json::Array(self.cycles.iter().map(|cycle| strings(cycle)).collect()),
+      ),
+      ("flows", Json::Array(flows)),
+    ])
+  }
+}
+
+/// The strongly connected components of the call graph that contain a
+/// call, found with Tarjan's algorithm
+fn cycles(functions: &BTreeMap<String, Vec<String>>) -> Vec<Vec<String>> {
+  struct Search<'a> {
+    functions: &'a BTreeMap<String, Vec<String>>,
+    index: HashMap<&'a str, usize>,
+    low: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    cycles: Vec<Vec<String>>,
+  }
+
+  impl<'a> Search<'a> {
+    fn visit(&mut self, function: &'a str) {
+      let index = self.index.len();
+      self.index.insert(function, index);
+      self.low.insert(function, index);
+      self.stack.push(function);
+
+      let functions = self.functions;
+      for callee in functions.get(function).into_iter().flatten() {
+        let callee = callee.as_str();
+        if !functions.contains_key(callee) {
+          continue;
+        }
+
+        if !self.index.contains_key(callee) {
+          self.visit(callee);
+          let low = self.low[function].min(self.low[callee]);
+          self.low.insert(function, low);
+        } else if self.stack.contains(&callee) {
+          let low = self.low[function].min(self.index[callee]);
+          self.low.insert(function, low);
+        }
+      }
+
+      if self.low[function] == index {
+        let start = self
+          .stack
+          .iter()
+          .position(|name| *name == function)
+          .unwrap();
+        let mut component: Vec<String> = self.stack.drain(start..).map(String::from).collect();
+        let calls_itself = functions[function].iter().any(|callee| callee == function);
+
+        if component.len() > 1 || calls_itself {
+          component.sort();
+          self.cycles.push(component);
+        }
+      }
+    }
+  }
+
+  let mut search = Search {
+    functions,
+    index: HashMap::new(),
+    low: HashMap::new(),
+    stack: Vec::new(),
+    cycles: Vec::new(),
+  };
+
+  for function in functions.keys() {
+    if !search.index.contains_key(function.as_str()) {
+      search.visit(function);
+    }
+  }
+
+  search.cycles.sort();
+  search.cycles
+}
+
+/// A node or edge's attributes in brackets, if it has any
+fn attributes(list: &[&str]) -> String {
+  if list.is_empty() {
+    String::new()
+  } else {
+    format!(" [{}]", list.join(", "))
+  }
+}
+
+fn escape(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(name: &str) -> String {
+  format!("\"{}\"", escape(name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn program() -> ProgramGraph {
+    ProgramGraph::build(&[
+      parse_file(
+        "function Main.even 0\npush argument 0\nif-goto ODD\npush constant 0\nnot\nreturn\n\
+         label ODD\ncall Main.odd 1\nreturn\npush constant 1\nreturn\n\
+         function Main.odd 0\ncall Main.even 1\nreturn\n\
+         function Main.loop 0\nlabel TOP\ncall Main.loop 0\ngoto TOP\n\
+         function Main.unused 0\npush constant 0\nreturn",
+        "Main",
+      )
+      .unwrap(),
+      parse_file(
+        "function Sys.init 0\ncall Main.even 1\ncall Main.loop 0\nlabel END\ngoto END",
+        "Sys",
+      )
+      .unwrap(),
+    ])
+  }
+
+  #[test]
+  fn splits_functions_into_blocks() {
+    let graph = program();
+    let even = &graph.flows[0];
+
+    assert_eq!(even.name, "Main.even");
+    let starts: Vec<(Option<&str>, usize)> = even
+      .blocks
+      .iter()
+      .map(|block| (block.label.as_deref(), block.line))
+      .collect();
+    let successors: Vec<&[usize]> = even
+      .blocks
+      .iter()
+      .map(|block| block.successors.as_slice())
+      .collect();
+    let ends: Vec<(bool, bool)> = even
+      .blocks
+      .iter()
+      .map(|block| (block.returns, block.reachable))
+      .collect();
+
+    assert_eq!(
+      starts,
+      vec![(None, 1), (None, 4), (Some("ODD"), 7), (None, 10)]
+    );
+    assert_eq!(successors, vec![&[2, 1][..], &[], &[], &[]]);
+    assert_eq!(
+      ends,
+      vec![(false, true), (true, true), (true, true), (true, false)]
+    );
+    assert_eq!(even.unreachable().count(), 1);
+
+    let sys = &graph.flows[4];
+    assert_eq!(sys.blocks.len(), 2);
+    assert_eq!(sys.blocks[1].successors, vec![1]);
+  }
+
+  #[test]
+  fn finds_recursion_and_functions_never_called() {
+    let graph = program();
+
+    assert_eq!(
+      graph.cycles,
+      vec![vec!["Main.even", "Main.odd"], vec!["Main.loop"]]
+    );
+    assert!(!graph.is_recursive("Sys.init"));
+    assert_eq!(
+      graph.uncalled.iter().collect::<Vec<_>>(),
+      vec!["Main.unused"]
+    );
+
+    let dot = graph.call_graph_dot();
+    assert!(dot.contains("\"Main.even\" -> \"Main.odd\" [color=red];"));
+    assert!(dot.contains("\"Sys.init\" -> \"Main.even\";"));
+    assert!(dot.contains("\"Main.unused\" [style=dashed, color=grey];"));
+  }
+
+  #[test]
+  fn writes_json() {
+    let json = program().to_json();
+
+    assert_eq!(
+      json.path(&["cycles"]).unwrap().to_string(),
+      r#"[["Main.even","Main.odd"],["Main.loop"]]"#
+    );
+
+    let odd = &json.get("flows").unwrap().as_array().unwrap()[1];
+    assert_eq!(
+      odd.to_string(),
+      r#"{"name":"Main.odd","file":"Main.vm","blocks":[{"label":null,"line":12,"#.to_string()
+        + r#""commands":["function Main.odd 0","call Main.even 1","return"],"#
+        + r#""successors":[],"returns":true,"reachable":true}]}"#
+    );
+  }
+}
@@ -0,0 +1,460 @@
+use crate::parser::{MathCommand, MemorySegment, SourceCommand, VmCommand};
+use crate::SourceFile;
+use std::collections::HashMap;
+
+/// The largest function, in commands, inlined wherever it's called
+pub const DEFAULT_LIMIT: usize = 12;
+
+/// Functions called from a single place are inlined up to this many times
+/// the limit, since the original can then usually be dropped altogether
+const SINGLE_CALL_FACTOR: usize = 4;
+
+/// How many times inlining is repeated, as functions become leaves once
+/// everything they call is inlined into them
+const MAX_ROUNDS: usize = 4;
+
+const TEMP_SLOTS: u16 = 8;
+
+/// A function that can be inlined: one that calls nothing, so can't recurse,
+/// doesn't set `pointer`, which `return` would restore, and always returns
+/// with just the return value on its stack
+struct Candidate {
+  namespace: String,
+  nlocals: u16,
+  /// The commands after `function`
+  body: Vec<SourceCommand>,
+  uses_static: bool,
+  /// The temp slots the body uses itself
+  temps: Vec<u16>,
+  /// The highest argument the body refers to, plus one
+  args_used: u16,
+}
+
+impl Candidate {
+  fn new(namespace: &str, nlocals: u16, body: &[SourceCommand]) -> Option<Candidate> {
+    use MemorySegment::*;
+    use VmCommand::*;
+
+    let mut candidate = Candidate {
+      namespace: String::from(namespace),
+      nlocals,
+      body: body.to_vec(),
+      uses_static: false,
+      temps: Vec::new(),
+      args_used: 0,
+    };
+
+    for command in body {
+      match &command.command {
+        Call { .. }
+        | Pop {
+          segment: Pointer, ..
+        } => return None,
+        Push { segment, index } | Pop { segment, index } => match segment {
+          Static => candidate.uses_static = true,
+          Temp if !candidate.temps.contains(index) => candidate.temps.push(*index),
+          Argument => candidate.args_used = candidate.args_used.max(index + 1),
+          Local if *index >= nlocals => return None,
+          _ => (),
+        },
+        _ => (),
+      }
+    }
+
+    if returns_one_value(body) {
+      Some(candidate)
+    } else {
+      None
+    }
+  }
+}
+
+/// How many values the command takes off the stack and puts on it
+pub(crate) fn stack_effect(command: &VmCommand) -> (usize, usize) {
+  use VmCommand::*;
+
+  match command {
+    Push { .. } => (0, 1),
+    Pop { .. } | IfGoto(_) => (1, 0),
+    Arithmetic(MathCommand::Negate) | Arithmetic(MathCommand::Not) => (1, 1),
+    Arithmetic(_) => (2, 1),
+    Call { nargs, .. } => (*nargs as usize, 1),
+    Return => (1, 0),
+    Label(_) | Goto(_) | Function { .. } => (0, 0),
+  }
+}
+
+/// Whether, along every path through the body, the stack never goes below
+/// where it started and holds exactly the return value at each `return`,
+/// which is what lets the body run on top of its caller's stack
+fn returns_one_value(body: &[SourceCommand]) -> bool {
+  let labels: HashMap<&str, usize> = body
+    .iter()
+    .enumerate()
+    .filter_map(|(index, command)| match &command.command {
+      VmCommand::Label(label) => Some((label.as_str(), index)),
+      _ => None,
+    })
+    .collect();
+
+  let mut depths: Vec<Option<usize>> = vec![None; body.len()];
+  let mut pending = vec![(0, 0)];
+
+  while let Some((index, depth)) = pending.pop() {
+    let command = match body.get(index) {
+      Some(command) => &command.command,
+      // running off the end of a function
+      None => return false,
+    };
+
+    match depths[index] {
+      Some(seen) if seen == depth => continue,
+      Some(_) => return false,
+      None => depths[index] = Some(depth),
+    }
+
+    let (taken, pushed) = stack_effect(command);
+    if taken > depth {
+      return false;
+    }
+    let after = depth - taken + pushed;
+
+    match command {
+      VmCommand::Return => {
+        if depth != 1 {
+          return false;
+        }
+      }
+      VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+        match labels.get(label.as_str()) {
+          Some(&target) => pending.push((target, after)),
+          None => return false,
+        }
+        if let VmCommand::IfGoto(_) = command {
+          pending.push((index + 1, after));
+        }
+      }
+      _ => pending.push((index + 1, after)),
+    }
+  }
+
+  true
+}
+
+/// Inlines the bodies of small functions that call nothing into the places
+/// they're called from, returning how many calls were replaced. Functions
+/// no longer called are left for dead function elimination to drop.
+pub fn inline(files: &mut [SourceFile], limit: usize) -> usize {
+  let mut inlined = 0;
+  let mut expansions = 0;
+
+  for _ in 0..MAX_ROUNDS {
+    let candidates = find_candidates(files);
+    let mut call_sites: HashMap<String, usize> = HashMap::new();
+
+    for command in files.iter().flat_map(|file| &file.commands) {
+      if let VmCommand::Call { name, .. } = &command.command {
+        *call_sites.entry(name.clone()).or_default() += 1;
+      }
+    }
+
+    // small enough that inlining doesn't cost much more ROM than calling
+    let worth_it = |name: &str, candidate: &Candidate| {
+      let size = candidate.body.len();
+      size <= limit || (call_sites.get(name) == Some(&1) && size <= limit * SINGLE_CALL_FACTOR)
+    };
+
+    let before = inlined;
+
+    for file in files.iter_mut() {
+      let mut commands = Vec::with_capacity(file.commands.len());
+
+      for command in std::mem::take(&mut file.commands) {
+        let expansion = match &command.command {
+          VmCommand::Call { name, nargs } => candidates
+            .get(name)
+            .filter(|candidate| worth_it(name, candidate))
+            .and_then(|candidate| {
+              expand(
+                candidate,
+                name,
+                *nargs,
+                &file.namespace,
+                &command,
+                expansions,
+              )
+            }),
+          _ => None,
+        };
+
+        match expansion {
+          Some(mut expansion) => {
+            commands.append(&mut expansion);
+            inlined += 1;
+            expansions += 1;
+          }
+          None => commands.push(command),
+        }
+      }
+
+      file.commands = commands;
+    }
+
+    if inlined == before {
+      break;
+    }
+  }
+
+  inlined
+}
+
+fn find_candidates(files: &[SourceFile]) -> HashMap<String, Candidate> {
+  let mut candidates = HashMap::new();
+
+  for file in files {
+    let starts: Vec<usize> = file
+      .commands
+      .iter()
+      .enumerate()
+      .filter(|(_, command)| matches!(command.command, VmCommand::Function { .. }))
+      .map(|(index, _)| index)
+      .collect();
+
+    for (position, &start) in starts.iter().enumerate() {
+      let end = starts
+        .get(position + 1)
+        .copied()
+        .unwrap_or(file.commands.len());
+
+      if let VmCommand::Function { name, nlocals } = &file.commands[start].command {
+        let body = &file.commands[start + 1..end];
+
+        if let Some(candidate) = Candidate::new(&file.namespace, *nlocals, body) {
+          candidates.insert(name.clone(), candidate);
+        }
+      }
+    }
+  }
+
+  candidates
+}
+
+/// The commands replacing a call: the arguments are popped into free temp
+/// slots, the locals are zeroed in others, and the body runs with its
+/// references to them rewritten, its labels renamed apart and its returns
+/// jumping to the end
+fn expand(
+  candidate: &Candidate,
+  name: &str,
+  nargs: u16,
+  namespace: &str,
+  call: &SourceCommand,
+  id: usize,
+) -> Option<Vec<SourceCommand>> {
+  use MemorySegment::*;
+  use VmCommand::*;
+
+  // statics belong to the file they're used in
+  if candidate.uses_static && candidate.namespace != namespace {
+    return None;
+  }
+  if candidate.args_used > nargs {
+    return None;
+  }
+
+  let mut free = (0..TEMP_SLOTS).filter(|slot| !candidate.temps.contains(slot));
+  let args: Vec<u16> = free.by_ref().take(nargs as usize).collect();
+  let locals: Vec<u16> = free.take(candidate.nlocals as usize).collect();
+
+  if args.len() != nargs as usize || locals.len() != candidate.nlocals as usize {
+    return None;
+  }
+
+  let at_call = |command| SourceCommand {
+    command,
+    span: call.span.clone(),
+  };
+  let rename = |label: &str| format!("{}$inline{}${}", name, id, label);
+  let end = format!("{}$inline{}$return", name, id);
+
+  let mut commands = Vec::new();
+
+  for &slot in args.iter().rev() {
+    commands.push(at_call(Pop {
+      segment: Temp,
+      index: slot,
+    }));
+  }
+  for &slot in &locals {
+    commands.push(at_call(Push {
+      segment: Constant,
+      index: 0,
+    }));
+    commands.push(at_call(Pop {
+      segment: Temp,
+      index: slot,
+    }));
+  }
+
+  let last = candidate.body.len() - 1;
+  let mut jumps_to_end = false;
+
+  for (index, command) in candidate.body.iter().enumerate() {
+    let rewritten = match &command.command {
+      Push {
+        segment: Argument,
+        index,
+      } => Push {
+        segment: Temp,
+        index: args[*index as usize],
+      },
+      Pop {
+        segment: Argument,
+        index,
+      } => Pop {
+        segment: Temp,
+        index: args[*index as usize],
+      },
+      Push {
+        segment: Local,
+        index,
+      } => Push {
+        segment: Temp,
+        index: locals[*index as usize],
+      },
+      Pop {
+        segment: Local,
+        index,
+      } => Pop {
+        segment: Temp,
+        index: locals[*index as usize],
+      },
+      Label(label) => Label(rename(label)),
+      Goto(label) => Goto(rename(label)),
+      IfGoto(label) => IfGoto(rename(label)),
+      // the return value is already where the caller expects it
+      Return if index == last => continue,
+      Return => {
+        jumps_to_end = true;
+        Goto(end.clone())
+      }
+      other => other.clone(),
+    };
+
+    commands.push(SourceCommand {
+      command: rewritten,
+      span: command.span.clone(),
+    });
+  }
+
+  if jumps_to_end {
+    commands.push(at_call(Label(end)));
+  }
+
+  Some(commands)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn render(file: &SourceFile) -> Vec<String> {
+    file
+      .commands
+      .iter()
+      .map(|command| command.command.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn inlines_small_leaf_functions() {
+    let mut files = vec![
+      parse_file(
+        "function Main.main 0\npush constant 5\nneg\ncall Math.abs 1\nreturn",
+        "Main",
+      )
+      .unwrap(),
+      parse_file(
+        "function Math.abs 1\npush argument 0\npop local 0\npush local 0\n\
+         push constant 0\nlt\nif-goto NEG\npush local 0\nreturn\n\
+         label NEG\npush local 0\nneg\nreturn",
+        "Math",
+      )
+      .unwrap(),
+    ];
+
+    assert_eq!(inline(&mut files, DEFAULT_LIMIT), 1);
+    assert_eq!(
+      render(&files[0]),
+      vec![
+        "function Main.main 0",
+        "push constant 5",
+        "neg",
+        "pop temp 0",
+        "push constant 0",
+        "pop temp 1",
+        "push temp 0",
+        "pop temp 1",
+        "push temp 1",
+        "push constant 0",
+        "lt",
+        "if-goto Math.abs$inline0$NEG",
+        "push temp 1",
+        "goto Math.abs$inline0$return",
+        "label Math.abs$inline0$NEG",
+        "push temp 1",
+        "neg",
+        "label Math.abs$inline0$return",
+        "return",
+      ]
+    );
+  }
+
+  #[test]
+  fn inlines_functions_that_become_leaves() {
+    let mut files = vec![parse_file(
+      "function Main.main 0\npush constant 2\ncall Main.twice 1\nreturn\n\
+       function Main.twice 0\npush argument 0\ncall Main.double 1\nreturn\n\
+       function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn",
+      "Main",
+    )
+    .unwrap()];
+
+    assert_eq!(inline(&mut files, DEFAULT_LIMIT), 2);
+    assert_eq!(
+      render(&files[0])[..8],
+      [
+        "function Main.main 0",
+        "push constant 2",
+        "pop temp 1",
+        "push temp 1",
+        "pop temp 0",
+        "push temp 0",
+        "push temp 0",
+        "add",
+      ]
+    );
+  }
+
+  #[test]
+  fn leaves_unsuitable_functions_alone() {
+    let source = "function Main.main 0\npush constant 1\ncall Main.loop 1\n\
+                  call Main.setThis 0\ncall Math.big 0\ncall Main.leaky 0\n\
+                  call Util.counter 0\nreturn\n\
+                  function Main.loop 0\npush argument 0\ncall Main.loop 1\nreturn\n\
+                  function Main.setThis 0\npush constant 0\npop pointer 0\npush constant 0\nreturn\n\
+                  function Main.leaky 0\npush constant 1\npush constant 2\nreturn";
+    let mut files = vec![
+      parse_file(source, "Main").unwrap(),
+      parse_file(
+        "function Util.counter 0\npush static 0\npush constant 1\nadd\npop static 0\n\
+         push constant 0\nreturn",
+        "Util",
+      )
+      .unwrap(),
+    ];
+
+    assert_eq!(inline(&mut files, 2), 0);
+  }
+}
@@ -0,0 +1,655 @@
+//! Runs VM programs directly, one command at a time, as the nand2tetris VM
+//! emulator does, and the VM emulator test scripts (`*VME.tst`) with them.
+//! Calls to functions no file defines run the OS built into `os`.
+
+use crate::callgraph::ENTRY_POINT;
+use crate::optimizer::{evaluate_binary, evaluate_unary};
+use crate::os;
+use crate::parser::{MathCommand, MemorySegment, SourceCommand, VmCommand};
+use crate::{parse_file, SourceFile};
+use hack_assembler::script::{self, Command, Script};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// The words of RAM the VM emulator has
+pub const RAM_SIZE: usize = 32768;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: usize = 5;
+/// Where the first file's static variables start
+const STATIC_BASE: usize = 16;
+
+/// A loaded program and the state of the machine running it. Labels aren't
+/// commands here, as they aren't in the VM emulator: going to one goes to
+/// the command after it, and stepping never stops on one.
+pub struct Vm {
+  pub ram: Vec<i16>,
+  /// The index of the next command to run
+  pub pc: usize,
+  /// The commands run so far
+  pub steps: u64,
+  /// The calls made and not yet returned from
+  pub depth: usize,
+  /// Keys for the built-in `Keyboard` to read, as though typed, `String.newLine`
+  /// ending a line
+  pub input: VecDeque<i16>,
+  /// Why the program stopped, once `Sys.halt` or `Sys.error` stops it
+  halted: Option<String>,
+  pub(crate) os: os::State,
+  commands: Vec<SourceCommand>,
+  /// The base of each command's static segment
+  statics: Vec<usize>,
+  /// The function each command is in, empty outside of functions
+  scopes: Vec<String>,
+  functions: HashMap<String, usize>,
+  /// Where each label goes, scoped as the writer scopes them
+  labels: HashMap<String, usize>,
+}
+
+impl Vm {
+  /// Loads the files in order, each with its own static segment, ready to
+  /// start at `Sys.init` if there is one and at the first command if not.
+  /// Programs with a `Main.main` and no `Sys.init` get the OS's, which sets
+  /// up the other classes and calls it.
+  pub fn new(files: &[SourceFile]) -> Vm {
+    let mut vm = Vm {
+      ram: vec![0; RAM_SIZE],
+      pc: 0,
+      steps: 0,
+      depth: 0,
+      input: VecDeque::new(),
+      halted: None,
+      os: os::State::default(),
+      commands: Vec::new(),
+      statics: Vec::new(),
+      scopes: Vec::new(),
+      functions: HashMap::new(),
+      labels: HashMap::new(),
+    };
+    let mut static_base = STATIC_BASE;
+    let defines = |function: &str| {
+      files.iter().flat_map(|file| &file.commands).any(
+        |command| matches!(&command.command, VmCommand::Function { name, .. } if name == function),
+      )
+    };
+    let sys = if defines("Main.main") && !defines(ENTRY_POINT) {
+      Some(parse_file(os::SYS_INIT, "Sys").expect("the built-in Sys.init parses"))
+    } else {
+      None
+    };
+
+    for file in files.iter().chain(&sys) {
+      let mut scope = String::new();
+      let mut statics = 0;
+
+      for command in &file.commands {
+        match &command.command {
+          VmCommand::Label(label) => {
+            vm.labels
+              .insert(scoped_label(&scope, label), vm.commands.len());
+            continue;
+          }
+          VmCommand::Function { name, .. } => {
+            scope = name.clone();
+            vm.functions.insert(name.clone(), vm.commands.len());
+          }
+          VmCommand::Push {
+            segment: MemorySegment::Static,
+            index,
+          }
+          | VmCommand::Pop {
+            segment: MemorySegment::Static,
+            index,
+          } => statics = statics.max(*index as usize + 1),
+          _ => (),
+        }
+
+        vm.commands.push(command.clone());
+        vm.statics.push(static_base);
+        vm.scopes.push(scope.clone());
+      }
+
+      static_base += statics;
+    }
+
+    vm.pc = vm.functions.get(ENTRY_POINT).copied().unwrap_or(0);
+    vm
+  }
+
+  /// Sets up the stack as the bootstrap code does, with a frame for the
+  /// call to `Sys.init` that returns past the end of the program
+  pub fn boot(&mut self) {
+    self.ram[SP] = 256;
+    self.ram[LCL] = 0;
+    self.ram[ARG] = 0;
+    let _ = self.push(self.commands.len() as i16);
+    for pointer in &[LCL, ARG, THIS, THAT] {
+      let _ = self.push(self.ram[*pointer]);
+    }
+    self.ram[ARG] = 256;
+    self.ram[LCL] = self.ram[SP];
+  }
+
+  /// Every command loaded, where `pc` indexes
+  pub fn commands(&self) -> &[SourceCommand] {
+    &self.commands
+  }
+
+  /// Where a function starts, if it's defined
+  pub fn function_address(&self, function: &str) -> Option<usize> {
+    self.functions.get(function).copied()
+  }
+
+  /// The function the command at `address` is in, empty outside of functions
+  pub fn function_at(&self, address: usize) -> &str {
+    self.scopes.get(address).map_or("", String::as_str)
+  }
+
+  /// The calls the program is in, innermost first, found by following the
+  /// frames each call saves on the stack from the current one back
+  pub fn backtrace(&self) -> Vec<Frame> {
+    let mut frames = vec![Frame {
+      function: String::from(self.current_function()),
+      address: self.pc,
+      local: self.ram[LCL],
+      argument: self.ram[ARG],
+    }];
+
+    for _ in 0..self.depth {
+      let frame = frames.last().unwrap().local as u16 as usize;
+      let saved = |offset: usize| self.load(frame.wrapping_sub(offset)).ok();
+
+      match (saved(5), saved(4), saved(3)) {
+        (Some(return_address), Some(local), Some(argument))
+          if return_address > 0 && (return_address as usize) <= self.commands.len() =>
+        {
+          let address = return_address as usize - 1;
+          frames.push(Frame {
+            function: String::from(self.function_at(address)),
+            address,
+            local,
+            argument,
+          })
+        }
+        _ => break,
+      }
+    }
+
+    frames
+  }
+
+  /// Whether the program has run off its end, returned somewhere that isn't
+  /// a command or been halted by the OS
+  pub fn is_halted(&self) -> bool {
+    self.pc >= self.commands.len() || self.halted.is_some()
+  }
+
+  /// Why the OS halted the program, if it has
+  pub fn halted(&self) -> Option<&str> {
+    self.halted.as_deref()
+  }
+
+  /// Stops the program, unless it already has stopped for another reason
+  pub(crate) fn halt(&mut self, reason: &str) -> Result<i16, Trap> {
+    if self.halted.is_none() {
+      self.halted = Some(String::from(reason));
+    }
+    Err(Trap::Halt)
+  }
+
+  /// Calls a function for the OS: the program's if it defines one, running
+  /// it to its return, and the built-in one if not
+  pub(crate) fn call(&mut self, function: &str, args: &[i16]) -> Result<i16, Trap> {
+    let address = match self.functions.get(function) {
+      Some(address) => *address,
+      None => {
+        return os::call(self, function, args).unwrap_or_else(|| {
+          Err(Trap::Error(format!(
+            "call to undefined function '{}'",
+            function
+          )))
+        })
+      }
+    };
+
+    for arg in args {
+      self.push(*arg)?;
+    }
+    let (pc, depth) = (self.pc, self.depth);
+    // returns nowhere, as the loop stops before it gets there
+    self.push(-1)?;
+    for pointer in &[LCL, ARG, THIS, THAT] {
+      self.push(self.ram[*pointer])?;
+    }
+    self.ram[ARG] = self.ram[SP].wrapping_sub(5 + args.len() as i16);
+    self.ram[LCL] = self.ram[SP];
+    self.depth += 1;
+    self.pc = address;
+
+    while self.depth > depth {
+      if self.is_halted() {
+        return Err(Trap::Halt);
+      }
+      self.step().map_err(Trap::Error)?;
+    }
+
+    self.pc = pc;
+    Ok(self.pop()?)
+  }
+
+  /// The command `step` runs next
+  pub fn current(&self) -> Option<&SourceCommand> {
+    self.commands.get(self.pc)
+  }
+
+  /// The function the next command is in, empty outside of functions
+  pub fn current_function(&self) -> &str {
+    self.function_at(self.pc)
+  }
+
+  /// Runs the next command, doing nothing once halted. A call to a built-in
+  /// function is a single step, however many it makes itself.
+  pub fn step(&mut self) -> Result<(), String> {
+    use VmCommand::*;
+
+    let command = match self.commands.get(self.pc) {
+      Some(command) if self.halted.is_none() => command.command.clone(),
+      _ => return Ok(()),
+    };
+    let mut next = self.pc + 1;
+
+    match &command {
+      Arithmetic(math) => self.arithmetic(*math)?,
+      Push { segment, index } => {
+        let value = match segment {
+          MemorySegment::Constant => *index as i16,
+          _ => self.load(self.address(*segment, *index)?)?,
+        };
+        self.push(value)?;
+      }
+      Pop { segment, index } => {
+        let address = self.address(*segment, *index)?;
+        let value = self.pop()?;
+        self.store(address, value)?;
+      }
+      Label(_) => (),
+      Goto(label) => next = self.label(label)?,
+      IfGoto(label) => {
+        if self.pop()? != 0 {
+          next = self.label(label)?;
+        }
+      }
+      Function { nlocals, .. } => {
+        for _ in 0..*nlocals {
+          self.push(0)?;
+        }
+      }
+      Call { name, nargs } if !self.functions.contains_key(name) => {
+        let sp = self.ram[SP].wrapping_sub(*nargs as i16);
+        let args = (0..*nargs as usize)
+          .map(|i| self.load((sp as u16 as usize).wrapping_add(i)))
+          .collect::<Result<Vec<_>, _>>()?;
+        self.ram[SP] = sp;
+
+        match os::call(self, name, &args) {
+          Some(Ok(value)) => self.push(value)?,
+          Some(Err(Trap::Halt)) => (),
+          Some(Err(Trap::Error(err))) => return Err(err),
+          None => return Err(format!("call to undefined function '{}'", name)),
+        }
+      }
+      Call { name, nargs } => {
+        next = self.functions[name];
+
+        self.push((self.pc + 1) as i16)?;
+        for pointer in &[LCL, ARG, THIS, THAT] {
+          self.push(self.ram[*pointer])?;
+        }
+        self.ram[ARG] = self.ram[SP].wrapping_sub(5 + *nargs as i16);
+        self.ram[LCL] = self.ram[SP];
+        self.depth += 1;
+      }
+      Return => {
+        let frame = self.ram[LCL] as u16 as usize;
+        let return_address = self.load(frame.wrapping_sub(5))?;
+        let value = self.pop()?;
+        let arg = self.ram[ARG] as u16 as usize;
+
+        self.store(arg, value)?;
+        self.ram[SP] = (arg + 1) as i16;
+        for (offset, pointer) in [THAT, THIS, ARG, LCL].iter().enumerate() {
+          self.ram[*pointer] = self.load(frame.wrapping_sub(offset + 1))?;
+        }
+        next = return_address as u16 as usize;
+        self.depth = self.depth.saturating_sub(1);
+      }
+    }
+
+    self.pc = next;
+    self.steps += 1;
+    Ok(())
+  }
+
+  fn arithmetic(&mut self, math: MathCommand) -> Result<(), String> {
+    let y = self.pop()?;
+
+    let result = match math {
+      MathCommand::Negate | MathCommand::Not => evaluate_unary(math, y),
+      _ => {
+        let x = self.pop()?;
+        // as the writer's routine does
+        evaluate_binary(math, x, y).or(match math {
+          MathCommand::Modulo => Some(x),
+          _ => Some(if x < 0 { 1 } else { -1 }),
+        })
+      }
+    };
+
+    self.push(result.unwrap())
+  }
+
+  fn label(&self, label: &str) -> Result<usize, String> {
+    let label = scoped_label(self.current_function(), label);
+
+    self
+      .labels
+      .get(&label)
+      .copied()
+      .ok_or_else(|| format!("no label '{}'", label))
+  }
+
+  /// The RAM address of a segment's word
+  fn address(&self, segment: MemorySegment, index: u16) -> Result<usize, String> {
+    let index = index as usize;
+    let base = |pointer: usize| self.ram[pointer] as u16 as usize;
+
+    match segment {
+      MemorySegment::Local => Ok(base(LCL) + index),
+      MemorySegment::Argument => Ok(base(ARG) + index),
+      MemorySegment::This => Ok(base(THIS) + index),
+      MemorySegment::That => Ok(base(THAT) + index),
+      MemorySegment::Pointer => Ok(THIS + index),
+      MemorySegment::Temp => Ok(TEMP + index),
+      MemorySegment::Static => self
+        .statics
+        .get(self.pc)
+        .map(|base| base + index)
+        .ok_or_else(|| String::from("no static segment once the program has halted")),
+      MemorySegment::Constant => Err(String::from("can't pop to constant")),
+    }
+  }
+
+  pub(crate) fn load(&self, address: usize) -> Result<i16, String> {
+    self
+      .ram
+      .get(address)
+      .copied()
+      .ok_or_else(|| format!("address {} is outside RAM", address))
+  }
+
+  pub(crate) fn store(&mut self, address: usize, value: i16) -> Result<(), String> {
+    match self.ram.get_mut(address) {
+      Some(word) => {
+        *word = value;
+        Ok(())
+      }
+      None => Err(format!("address {} is outside RAM", address)),
+    }
+  }
+
+  fn push(&mut self, value: i16) -> Result<(), String> {
+    let sp = self.ram[SP] as u16 as usize;
+    self.store(sp, value)?;
+    self.ram[SP] = self.ram[SP].wrapping_add(1);
+    Ok(())
+  }
+
+  fn pop(&mut self) -> Result<i16, String> {
+    self.ram[SP] = self.ram[SP].wrapping_sub(1);
+    self.load(self.ram[SP] as u16 as usize)
+  }
+
+  /// The address of a variable of the VM emulator's scripts: `RAM[i]`, the
+  /// pointers `sp`, `local`, `argument`, `this` and `that`, and the words
+  /// of the segments, such as `local[2]`, `temp[0]` or `static[1]` in the
+  /// current file
+  pub fn variable(&self, name: &str, index: Option<u16>) -> Result<usize, String> {
+    let segment = match name {
+      "local" => Some(MemorySegment::Local),
+      "argument" => Some(MemorySegment::Argument),
+      "this" => Some(MemorySegment::This),
+      "that" => Some(MemorySegment::That),
+      "temp" => Some(MemorySegment::Temp),
+      "pointer" => Some(MemorySegment::Pointer),
+      "static" => Some(MemorySegment::Static),
+      _ => None,
+    };
+
+    let address = match (name, segment, index) {
+      ("RAM", _, Some(index)) => index as usize,
+      ("sp", _, None) => SP,
+      ("local", _, None) => LCL,
+      ("argument", _, None) => ARG,
+      ("this", _, None) => THIS,
+      ("that", _, None) => THAT,
+      (_, Some(segment), Some(index)) => self.address(segment, index)?,
+      _ => return Err(format!("no variable {}", script::target(name, index))),
+    };
+
+    if address < RAM_SIZE {
+      Ok(address)
+    } else {
+      Err(format!("{} is outside RAM", script::target(name, index)))
+    }
+  }
+
+  pub fn read(&self, name: &str, index: Option<u16>) -> Result<i16, String> {
+    Ok(self.ram[self.variable(name, index)?])
+  }
+
+  pub fn set(&mut self, name: &str, index: Option<u16>, value: i16) -> Result<(), String> {
+    let address = self.variable(name, index)?;
+    self.ram[address] = value;
+    Ok(())
+  }
+}
+
+/// Why a built-in function didn't return
+pub(crate) enum Trap {
+  /// The program halted, as `Sys.halt` does
+  Halt,
+  Error(String),
+}
+
+impl From<String> for Trap {
+  fn from(err: String) -> Trap {
+    Trap::Error(err)
+  }
+}
+
+/// A call the program is in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+  pub function: String,
+  /// The command running in it: the next for the innermost call, and the
+  /// call it's waiting on for the rest
+  pub address: usize,
+  pub local: i16,
+  pub argument: i16,
+}
+
+/// As the writer scopes labels, so that `LOOP` in `Main.run` is `Main.run$LOOP`
+fn scoped_label(scope: &str, label: &str) -> String {
+  if scope.is_empty() {
+    String::from(label)
+  } else {
+    format!("{}${}", scope, label)
+  }
+}
+
+/// Runs a VM emulator test script, loading the files it names from
+/// `directory`, or every .vm file there for a bare `load`. Returns what it
+/// would write to its output file and the machine as the script left it.
+pub fn run_script(script: &Script, directory: &Path) -> Result<(String, Vm), String> {
+  let mut runner = Runner {
+    directory,
+    vm: Vm::new(&[]),
+    columns: Vec::new(),
+    output: String::new(),
+  };
+
+  runner.run(&script.commands)?;
+  Ok((runner.output, runner.vm))
+}
+
+struct Runner<'a> {
+  directory: &'a Path,
+  vm: Vm,
+  columns: Vec<script::Column>,
+  output: String,
+}
+
+impl Runner<'_> {
+  fn run(&mut self, commands: &[Command]) -> Result<(), String> {
+    for command in commands {
+      match command {
+        Command::Load(file) => {
+          let paths = match file {
+            Some(file) => vec![self.directory.join(file)],
+            None => {
+              crate::collect_sources(&[self.directory.display().to_string()]).map_err(errors)?
+            }
+          };
+          self.vm = Vm::new(&crate::load(&paths).map_err(errors)?);
+        }
+        Command::OutputFile(_) | Command::CompareTo(_) => (),
+        Command::OutputList(list) => {
+          self.columns = list.clone();
+          self.output.push_str(&script::header(&self.columns));
+        }
+        Command::Set { name, index, value } => self.vm.set(name, *index, *value)?,
+        Command::Step(step) => match step.as_str() {
+          "vmstep" => self.vm.step().map_err(|err| self.located(err))?,
+          other => return Err(format!("the VM emulator can't '{}'", other)),
+        },
+        Command::Output => {
+          let values = self
+            .columns
+            .iter()
+            .map(|column| self.vm.read(&column.name, column.index))
+            .collect::<Result<Vec<_>, _>>()?;
+          self.output.push_str(&script::row(&self.columns, &values));
+        }
+        Command::Repeat { count, body } => {
+          let count = count.ok_or("repeat without a count never ends")?;
+          for _ in 0..count {
+            self.run(body)?;
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// An error the program ran into, with the command it was running
+  fn located(&self, err: String) -> String {
+    match self.vm.current() {
+      Some(command) => format!("{}: {}: {}", command.span, command.command, err),
+      None => err,
+    }
+  }
+}
+
+fn errors(errors: Vec<crate::error::TranslateError>) -> String {
+  errors
+    .iter()
+    .map(|err| err.to_string())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// The files a script's `output-file` and `compare-to` name, if it does
+pub fn script_files(script: &Script, directory: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+  let mut output = None;
+  let mut compare = None;
+
+  for command in &script.commands {
+    match command {
+      Command::OutputFile(file) => output = Some(directory.join(file)),
+      Command::CompareTo(file) => compare = Some(directory.join(file)),
+      _ => (),
+    }
+  }
+
+  (output, compare)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn run(vm: &str, steps: usize) -> Vm {
+    let mut vm = Vm::new(&[parse_file(vm, "Main").unwrap()]);
+    vm.ram[SP] = 256;
+    for _ in 0..steps {
+      vm.step().unwrap();
+    }
+    vm
+  }
+
+  #[test]
+  fn runs_commands_as_the_vm_emulator_does() {
+    let vm = run(
+      "push constant 7\npush constant 3\nmod\n\
+       push constant 7\npush constant 0\ndiv\n\
+       push constant 1\npush constant 15\nshl\nlt_u\nlabel END\ngoto END",
+      11,
+    );
+
+    assert_eq!(vm.steps, 11);
+    assert_eq!(vm.pc, 10);
+    assert_eq!(&vm.ram[..3], &[258, 0, 0]);
+    assert_eq!(&vm.ram[256..258], &[1, 0]);
+    assert!(!vm.is_halted());
+  }
+
+  #[test]
+  fn calls_and_returns() {
+    let mut vm = Vm::new(&[parse_file(
+      "function Main.double 1\npush argument 0\npush argument 0\nadd\nreturn\n\
+       function Sys.init 0\npush constant 21\ncall Main.double 1\npop static 3\n\
+       label END\ngoto END",
+      "Main",
+    )
+    .unwrap()]);
+    vm.set("sp", None, 261).unwrap();
+
+    assert_eq!(vm.current_function(), "Sys.init");
+    while vm.steps < 9 {
+      vm.step().unwrap();
+    }
+
+    assert_eq!(vm.read("sp", None), Ok(261));
+    assert_eq!(vm.read("RAM", Some(19)), Ok(42));
+    assert_eq!(
+      vm.current().unwrap().command,
+      VmCommand::Goto(String::from("END"))
+    );
+  }
+
+  #[test]
+  fn reports_bad_programs() {
+    let mut vm = run("push constant 1\ngoto NOWHERE", 1);
+    assert_eq!(vm.step(), Err(String::from("no label 'NOWHERE'")));
+
+    let mut vm = run("call Main.missing 0", 0);
+    assert!(vm.step().is_err());
+    assert!(Vm::new(&[]).set("local", Some(40000), 1).is_err());
+  }
+}
@@ -0,0 +1,62 @@
+//! Calls to Jack OS functions that the extended VM operations do the same
+//! job as, in a fraction of the time
+
+use crate::parser::{MathCommand, VmCommand};
+use crate::SourceFile;
+
+/// The OS functions replaced, with the number of arguments they take
+const MATH_FUNCTIONS: [(&str, u16, MathCommand); 2] = [
+  ("Math.multiply", 2, MathCommand::Multiply),
+  ("Math.divide", 2, MathCommand::Divide),
+];
+
+/// Replaces calls to `Math.multiply` and `Math.divide` with `mul` and `div`,
+/// returning how many were replaced. The functions themselves are left for
+/// dead function elimination. Unlike `Math.divide`, `div` doesn't report
+/// division by zero.
+pub fn lower_math_calls(files: &mut [SourceFile]) -> usize {
+  let mut lowered = 0;
+
+  for command in files.iter_mut().flat_map(|file| &mut file.commands) {
+    if let VmCommand::Call { name, nargs } = &command.command {
+      let math = MATH_FUNCTIONS
+        .iter()
+        .find(|(function, args, _)| function == name && args == nargs)
+        .map(|(_, _, math)| *math);
+
+      if let Some(math) = math {
+        command.command = VmCommand::Arithmetic(math);
+        lowered += 1;
+      }
+    }
+  }
+
+  lowered
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  #[test]
+  fn lowers_math_calls() {
+    let mut files = vec![parse_file(
+      "function Main.main 0\npush constant 6\npush constant 7\ncall Math.multiply 2\n\
+       push constant 2\ncall Math.divide 2\ncall Math.abs 1\nreturn",
+      "Main",
+    )
+    .unwrap()];
+
+    assert_eq!(lower_math_calls(&mut files), 2);
+
+    let commands: Vec<String> = files[0]
+      .commands
+      .iter()
+      .map(|command| command.command.to_string())
+      .collect();
+    assert_eq!(commands[3], "mul");
+    assert_eq!(commands[5], "div");
+    assert_eq!(commands[6], "call Math.abs 1");
+  }
+}
@@ -0,0 +1,760 @@
+pub mod bytecode;
+pub mod callgraph;
+pub mod debugger;
+pub mod emulate;
+pub mod error;
+pub mod graph;
+pub mod inliner;
+pub mod interpreter;
+pub mod intrinsics;
+pub mod optimizer;
+pub mod os;
+pub mod parser;
+pub mod profiler;
+pub mod sourcemap;
+pub mod statics;
+pub mod targets;
+pub mod writer;
+
+use callgraph::CallGraph;
+use error::{ErrorKind, TranslateError};
+use graph::ProgramGraph;
+use optimizer::Passes;
+use parser::{MathCommand, Parser, SourceCommand, VmCommand};
+use sourcemap::{MapEntry, SourceMap};
+use statics::StaticMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use writer::{Codegen, Writer};
+
+/// Whether to emit the bootstrap code that sets up the stack and calls `Sys.init`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Bootstrap {
+    /// Only when some file declares `Sys.init`
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub bootstrap: Bootstrap,
+    /// Precede each command's assembly with the VM command and where it came from
+    pub annotate: bool,
+    /// Write a `.vmmap` source map next to the output
+    pub source_map: bool,
+    /// Write a `.statics` map of static variables to RAM addresses next to
+    /// the output
+    pub static_map: bool,
+    /// The optimizations to make to each file before writing it
+    pub passes: Passes,
+    /// Drop functions that can't be reached from `Sys.init`
+    pub eliminate_dead_functions: bool,
+    /// Inline functions of up to this many commands where they're called,
+    /// or none if 0
+    pub inline_limit: usize,
+    pub codegen: Codegen,
+    /// Write `eq`, `gt` and `lt` as calls to routines shared by the whole
+    /// program, which also get `gt` and `lt` right when x - y overflows
+    pub shared_comparisons: bool,
+    /// Replace calls to `Math.multiply` and `Math.divide` with the extended
+    /// `mul` and `div` operations
+    pub native_math: bool,
+    /// Guard every command against overflowing the stack and other
+    /// mistakes, halting with a code in `writer::PANIC_ADDRESS`
+    pub checked: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            bootstrap: Bootstrap::Auto,
+            annotate: false,
+            source_map: false,
+            static_map: false,
+            passes: Passes::default(),
+            eliminate_dead_functions: true,
+            inline_limit: 0,
+            codegen: Codegen::Stack,
+            shared_comparisons: false,
+            native_math: false,
+            checked: false,
+        }
+    }
+}
+
+impl Options {
+    /// The first address past the RAM static variables can take, which
+    /// stops short of `writer::PANIC_ADDRESS` in checked programs
+    pub fn static_limit(&self) -> u16 {
+        if self.checked {
+            writer::PANIC_ADDRESS
+        } else {
+            statics::VARIABLE_LIMIT
+        }
+    }
+}
+
+/// The assembly for a program and the map back to its VM source
+pub struct Translation {
+    pub asm: Vec<u8>,
+    pub map: SourceMap,
+    /// The number of instructions written
+    pub size: usize,
+}
+
+/// What `compile_to_target` did to the program on the way to writing it
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// Calls replaced by the body of the function called
+    pub inlined: usize,
+    /// Functions dropped as unreachable, and the ROM each would have taken
+    pub removed: Vec<(String, usize)>,
+    /// The ROM the output takes
+    pub size: usize,
+    /// Where the assembler will put each static variable
+    pub statics: StaticMap,
+}
+
+impl Report {
+    /// The ROM saved by dropping unreachable functions
+    pub fn saved(&self) -> usize {
+        self.removed.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// The parsed commands of one .vm file
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// The file stem, which scopes the file's static variables
+    pub namespace: String,
+    pub commands: Vec<SourceCommand>,
+}
+
+/// Parses one file's worth of VM code, reporting every malformed line
+pub fn parse_file(contents: &str, namespace: &str) -> Result<SourceFile, Vec<TranslateError>> {
+    let file_name = format!("{}.vm", namespace);
+    let mut commands = Vec::new();
+    let mut errors = Vec::new();
+
+    for command in Parser::with_file(contents, &file_name) {
+        match command {
+            Ok(command) => commands.push(command),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(SourceFile {
+            namespace: String::from(namespace),
+            commands,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+/// Translates one file's worth of VM code, without any bootstrap
+pub fn parse(contents: &str, namespace: &str) -> Result<Vec<u8>, Vec<TranslateError>> {
+    let file = parse_file(contents, namespace)?;
+    let options = Options {
+        bootstrap: Bootstrap::Never,
+        ..Options::default()
+    };
+
+    Ok(write_program(&[file], &options))
+}
+
+/// Resolves the command line inputs to the .vm files to translate. A
+/// directory stands for the .vm files directly inside it, and a path whose
+/// file name contains `*` or `?` for the files matching it, both in sorted
+/// order. Other files are taken as given, in the order given, and must be
+/// .vm or .vmb files.
+pub fn collect_sources(inputs: &[String]) -> Result<Vec<PathBuf>, Vec<TranslateError>> {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+        let pattern = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+
+        let expanded = if path.is_dir() {
+            list_dir(path, |name| name.ends_with(".vm"))
+        } else if pattern.contains(['*', '?']) {
+            let dir = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            list_dir(dir, |name| glob_match(pattern, name))
+        } else if path.is_file() {
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("vm") | Some("vmb") => Ok(vec![path.to_path_buf()]),
+                _ => Err(TranslateError {
+                    file: input.clone(),
+                    line: None,
+                    kind: ErrorKind::NotVmFile,
+                }),
+            }
+        } else {
+            Err(TranslateError {
+                file: input.clone(),
+                line: None,
+                kind: ErrorKind::Io(String::from("no such file or directory")),
+            })
+        };
+
+        match expanded {
+            Ok(expanded) => {
+                for path in expanded {
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(paths)
+    } else {
+        Err(errors)
+    }
+}
+
+fn list_dir<F>(dir: &Path, matches: F) -> Result<Vec<PathBuf>, TranslateError>
+where
+    F: Fn(&str) -> bool,
+{
+    let entries = fs::read_dir(dir).map_err(|err| io_error(dir, err))?;
+    let mut paths = Vec::new();
+
+    for entry in entries {
+        let path = entry.map_err(|err| io_error(dir, err))?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+
+        if path.is_file() && matches(name) {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Matches a file name against a pattern where `*` is any run of
+/// characters and `?` any single character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+            Some(('?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+        }
+    }
+
+    matches(&pattern, &name)
+}
+
+/// Reads and parses every file, collecting the errors of all of them.
+/// `.vmb` files are decoded instead, into every file they hold.
+pub fn load(paths: &[PathBuf]) -> Result<Vec<SourceFile>, Vec<TranslateError>> {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+
+    for path in paths {
+        if path.extension() == Some("vmb".as_ref()) {
+            match fs::read(path) {
+                Ok(bytes) => match bytecode::decode(&bytes, &path.display().to_string()) {
+                    Ok(mut decoded) => files.append(&mut decoded),
+                    Err(err) => errors.push(err),
+                },
+                Err(err) => errors.push(io_error(path, err)),
+            }
+            continue;
+        }
+
+        let namespace = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                errors.push(io_error(path, err));
+                continue;
+            }
+        };
+
+        match parse_file(&source, namespace) {
+            Ok(file) => files.push(file),
+            Err(mut file_errors) => errors.append(&mut file_errors),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(files)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Whether any file declares `Sys.init`
+pub fn has_sys_init(files: &[SourceFile]) -> bool {
+    files.iter().flat_map(|file| &file.commands).any(|command| {
+        matches!(&command.command, VmCommand::Function { name, .. } if name == "Sys.init")
+    })
+}
+
+/// Writes the assembly for a whole program, the files in the order given
+pub fn write_program(files: &[SourceFile], options: &Options) -> Vec<u8> {
+    translate(files, options).asm
+}
+
+/// Translates a whole program, the files in the order given, recording the
+/// ROM addresses each command ends up at
+pub fn translate(files: &[SourceFile], options: &Options) -> Translation {
+    let bootstrap = match options.bootstrap {
+        Bootstrap::Auto => has_sys_init(files),
+        Bootstrap::Always => true,
+        Bootstrap::Never => false,
+    };
+
+    // One writer for every file, so generated labels stay unique
+    let mut writer = Writer::new("Sys");
+    writer.codegen = options.codegen;
+    writer.shared_comparisons = options.shared_comparisons;
+    writer.checked = options.checked;
+    let mut map = SourceMap::new();
+
+    if bootstrap {
+        writer.write_init();
+    }
+
+    let used: Vec<MathCommand> = files
+        .iter()
+        .flat_map(|file| &file.commands)
+        .filter_map(|command| match command.command {
+            VmCommand::Arithmetic(math) => Some(math),
+            _ => None,
+        })
+        .collect();
+    // Sys.init never returns, so nothing runs into code after the bootstrap
+    writer.write_routines(&used, !bootstrap);
+
+    for file in files {
+        writer.namespace = file.namespace.clone();
+        writer.current_function = String::new();
+
+        let commands = optimizer::optimize(file.commands.clone(), &options.passes);
+
+        for SourceCommand { command, span } in &commands {
+            if options.annotate {
+                writer.write_comment(&format!("{} {}", span, command));
+            }
+
+            let start = writer.address;
+            writer.write_command(command);
+
+            map.push(MapEntry {
+                start,
+                end: writer.address,
+                file: span.file.to_string(),
+                line: span.line,
+                function: writer.current_function.clone(),
+                command: command.to_string(),
+            });
+        }
+
+        // Code after the last command of a file can't expect anything in D
+        writer.flush();
+    }
+
+    Translation {
+        size: writer.address,
+        asm: writer.output,
+        map,
+    }
+}
+
+/// Translates the given .vm files, directories and globs into one program,
+/// with every optimization asked for. Calls to functions no input defines
+/// are errors, along with every malformed line of every input and static
+/// variables that don't fit below the stack.
+pub fn compile(
+    inputs: &[String],
+    options: &Options,
+) -> Result<(Translation, Report), Vec<TranslateError>> {
+    let paths = collect_sources(inputs)?;
+    let mut files = load(&paths)?;
+
+    if options.native_math {
+        intrinsics::lower_math_calls(&mut files);
+    }
+
+    let errors = CallGraph::build(&files).errors();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut report = Report::default();
+
+    if options.inline_limit > 0 {
+        report.inlined = inliner::inline(&mut files, options.inline_limit);
+    }
+
+    let mut translation = translate(&files, options);
+
+    if options.eliminate_dead_functions {
+        let removed = callgraph::eliminate_dead_functions(&mut files);
+
+        if !removed.is_empty() {
+            report.removed = removed
+                .into_iter()
+                .map(|name| {
+                    let size = translation.map.function_size(&name);
+                    (name, size)
+                })
+                .collect();
+            translation = translate(&files, options);
+        }
+    }
+
+    report.size = translation.size;
+    report.statics = StaticMap::allocate(&String::from_utf8_lossy(&translation.asm));
+
+    let limit = options.static_limit();
+    if let Some(variable) = report.statics.first_overflow_below(limit) {
+        return Err(vec![TranslateError {
+            file: format!("{}.vm", statics::owner(variable)),
+            line: None,
+            kind: ErrorKind::StaticOverflow {
+                needed: report.statics.variables.len(),
+                available: (limit - statics::VARIABLE_BASE) as usize,
+            },
+        }]);
+    }
+
+    Ok((translation, report))
+}
+
+/// Translates the given .vm files, directories and globs into one .asm
+/// file, and its .vmmap and .statics if asked for. Nothing is written if any input has
+/// errors, including calls to functions no input defines; all of them are
+/// returned.
+pub fn compile_to_target(
+    inputs: &[String],
+    output_path: &str,
+    options: &Options,
+) -> Result<Report, Vec<TranslateError>> {
+    let (translation, report) = compile(inputs, options)?;
+
+    let output_path = Path::new(output_path);
+    fs::write(output_path, translation.asm).map_err(|err| vec![io_error(output_path, err)])?;
+
+    if options.source_map {
+        let map_path = output_path.with_extension("vmmap");
+        fs::write(&map_path, translation.map.to_string())
+            .map_err(|err| vec![io_error(&map_path, err)])?;
+    }
+
+    if options.static_map {
+        let map_path = output_path.with_extension("statics");
+        fs::write(&map_path, report.statics.to_string())
+            .map_err(|err| vec![io_error(&map_path, err)])?;
+    }
+
+    Ok(report)
+}
+
+/// Writes the given .vm files, directories and globs as one `.vmb`, as
+/// parsed and without any optimization, returning the files written
+pub fn bytecode_to_target(
+    inputs: &[String],
+    output_path: &str,
+) -> Result<Vec<SourceFile>, Vec<TranslateError>> {
+    let files = load(&collect_sources(inputs)?)?;
+
+    let output_path = Path::new(output_path);
+    fs::write(output_path, bytecode::encode(&files))
+        .map_err(|err| vec![io_error(output_path, err)])?;
+
+    Ok(files)
+}
+
+/// Writes the given .vm files, directories and globs as one C source
+/// file, as `targets::c` lays out, returning the program written
+pub fn c_to_target(
+    inputs: &[String],
+    output_path: &str,
+    options: &Options,
+) -> Result<targets::Program, Vec<TranslateError>> {
+    let program = targets::prepare(inputs, options)?;
+
+    let output_path = Path::new(output_path);
+    fs::write(output_path, targets::c::write(&program, options.annotate))
+        .map_err(|err| vec![io_error(output_path, err)])?;
+
+    Ok(program)
+}
+
+/// Writes the given .vm files, directories and globs as GNU assembler
+/// source for x86-64 Linux, as `targets::x86_64` lays out, returning the
+/// program written
+pub fn x86_64_to_target(
+    inputs: &[String],
+    output_path: &str,
+    options: &Options,
+) -> Result<targets::Program, Vec<TranslateError>> {
+    let program = targets::prepare(inputs, options)?;
+
+    let output_path = Path::new(output_path);
+    fs::write(
+        output_path,
+        targets::x86_64::write(&program, options.annotate),
+    )
+    .map_err(|err| vec![io_error(output_path, err)])?;
+
+    Ok(program)
+}
+
+/// Writes the given .vm files, directories and globs as a WebAssembly text
+/// module, as `targets::wasm` lays out, returning the program written
+pub fn wasm_to_target(
+    inputs: &[String],
+    output_path: &str,
+    options: &Options,
+) -> Result<targets::Program, Vec<TranslateError>> {
+    let program = targets::prepare(inputs, options)?;
+
+    let output_path = Path::new(output_path);
+    fs::write(output_path, targets::wasm::write(&program, options.annotate))
+        .map_err(|err| vec![io_error(output_path, err)])?;
+
+    Ok(program)
+}
+
+/// Writes the call graph of the given .vm files, directories and globs as
+/// DOT, or with `flow` the control-flow graph of each function, or both as
+/// JSON when `output_path` ends in `.json`
+pub fn graph_to_target(
+    inputs: &[String],
+    output_path: &str,
+    flow: bool,
+) -> Result<ProgramGraph, Vec<TranslateError>> {
+    let paths = collect_sources(inputs)?;
+    let graph = ProgramGraph::build(&load(&paths)?);
+
+    let output_path = Path::new(output_path);
+    let output = if output_path.extension() == Some("json".as_ref()) {
+        graph.to_json().to_string()
+    } else if flow {
+        graph.flow_graph_dot()
+    } else {
+        graph.call_graph_dot()
+    };
+    fs::write(output_path, output).map_err(|err| vec![io_error(output_path, err)])?;
+
+    Ok(graph)
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> TranslateError {
+    TranslateError {
+        file: path.display().to_string(),
+        line: None,
+        kind: ErrorKind::Io(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_every_error_in_a_file() {
+        let errors =
+            parse_file("push constant 1\npop temp 9\nfoo\npush pointer 2\n", "Main").unwrap_err();
+        let lines: Vec<Option<usize>> = errors.iter().map(|err| err.line).collect();
+
+        assert_eq!(lines, vec![Some(2), Some(3), Some(4)]);
+        assert!(errors.iter().all(|err| err.file == "Main.vm"));
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*.vm", "Main.vm"));
+        assert!(glob_match("M?th.vm", "Math.vm"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("*.vm", "Main.vm.bak"));
+        assert!(!glob_match("S*.vm", "Main.vm"));
+    }
+
+    #[test]
+    fn bootstraps_only_programs_with_sys_init() {
+        let simple = parse_file("push constant 7\npush constant 8\nadd", "SimpleAdd").unwrap();
+        let sys = parse_file("function Sys.init 0\nlabel END\ngoto END", "Sys").unwrap();
+
+        let bootstraps = |files: Vec<SourceFile>, bootstrap| {
+            let output = write_program(
+                &files,
+                &Options {
+                    bootstrap,
+                    ..Options::default()
+                },
+            );
+            output.starts_with(b"@256\n")
+        };
+
+        assert!(!bootstraps(vec![simple.clone()], Bootstrap::Auto));
+        assert!(bootstraps(vec![simple.clone()], Bootstrap::Always));
+        assert!(bootstraps(vec![simple, sys.clone()], Bootstrap::Auto));
+        assert!(!bootstraps(vec![sys], Bootstrap::Never));
+    }
+
+    #[test]
+    fn orders_directory_sources_and_skips_other_files() {
+        let dir =
+            std::env::temp_dir().join(format!("vm_translator_sources_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["Sys.vm", "Main.vm", "Main.jack", "Array.vm", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let names = |inputs: Vec<String>| -> Vec<String> {
+            collect_sources(&inputs)
+                .unwrap()
+                .iter()
+                .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+                .collect()
+        };
+
+        let dir_input = dir.display().to_string();
+        assert_eq!(
+            names(vec![dir_input.clone()]),
+            vec!["Array.vm", "Main.vm", "Sys.vm"]
+        );
+
+        let glob = dir.join("M*").display().to_string();
+        assert_eq!(names(vec![glob]), vec!["Main.jack", "Main.vm"]);
+
+        let sys = dir.join("Sys.vm").display().to_string();
+        assert_eq!(
+            names(vec![sys, dir_input]),
+            vec!["Sys.vm", "Array.vm", "Main.vm"]
+        );
+
+        assert!(collect_sources(&[dir.join("Missing.vm").display().to_string()]).is_err());
+        let errors = collect_sources(&[dir.join("notes.txt").display().to_string()]).unwrap_err();
+        assert_eq!(errors[0].kind, ErrorKind::NotVmFile);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn maps_addresses_back_to_commands() {
+        let file = parse_file(
+            "function Main.main 0\npush constant 7\nlabel END\ngoto END",
+            "Main",
+        )
+        .unwrap();
+        let options = Options {
+            annotate: true,
+            ..Options::default()
+        };
+        let translation = translate(&[file], &options);
+        let asm = String::from_utf8(translation.asm).unwrap();
+
+        assert!(asm.contains("// Main.vm:2 push constant 7\n"));
+
+        let push = translation.map.lookup(0).unwrap();
+        assert_eq!(push.line, 2);
+        assert_eq!(push.command, "push constant 7");
+        assert_eq!(push.function, "Main.main");
+
+        let goto = translation.map.lookup(push.end).unwrap();
+        assert_eq!(goto.command, "goto END");
+        assert_eq!(goto.end, push.end + 2);
+        assert_eq!(translation.map.entries.len(), 2);
+    }
+
+    #[test]
+    fn rejects_statics_past_the_stack() {
+        let dir =
+            std::env::temp_dir().join(format!("vm_translator_statics_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let pops = |count: u16| -> String {
+            (0..count)
+                .map(|n| format!("push constant 1\npop static {}\n", n))
+                .collect()
+        };
+        fs::write(dir.join("Main.vm"), pops(200)).unwrap();
+        fs::write(dir.join("Math.vm"), pops(40)).unwrap();
+
+        let inputs = vec![dir.display().to_string()];
+        let (_, report) = compile(&inputs, &Options::default()).unwrap();
+        assert!(report.statics.fits());
+        assert_eq!(report.statics.variables[200], (String::from("Math.0"), 216));
+
+        // checked programs keep the last word for the panic code
+        let checked = Options {
+            checked: true,
+            ..Options::default()
+        };
+        let errors = compile(&inputs, &checked).err().unwrap();
+        assert_eq!(
+            errors[0].to_string(),
+            "Math.vm: static variables need 240 words of RAM, but only 239 fit below the stack"
+        );
+
+        fs::write(dir.join("Math.vm"), pops(41)).unwrap();
+        let errors = compile(&inputs, &Options::default()).err().unwrap();
+        assert_eq!(
+            errors[0].to_string(),
+            "Math.vm: static variables need 241 words of RAM, but only 240 fit below the stack"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn translates_bytecode_as_its_source() {
+        let dir =
+            std::env::temp_dir().join(format!("vm_translator_bytecode_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Main.vm"),
+            "function Main.main 0\npush static 1\ncall Sys.halt 0\nreturn",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Sys.vm"),
+            "function Sys.init 0\ncall Main.main 0\nfunction Sys.halt 0\nlabel END\ngoto END",
+        )
+        .unwrap();
+
+        let inputs = vec![dir.display().to_string()];
+        let program = dir.join("Program.vmb").display().to_string();
+        let files = bytecode_to_target(&inputs, &program).unwrap();
+        assert_eq!(files.len(), 2);
+
+        let options = Options::default();
+        let (text, _) = compile(&inputs, &options).unwrap();
+        let (bytecode, _) = compile(&[program], &options).unwrap();
+        assert_eq!(bytecode.asm, text.asm);
+        assert_eq!(bytecode.map, text.map);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
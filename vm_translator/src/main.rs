@@ -1,150 +1,396 @@
-mod parser;
-mod writer;
-
 use std::env::args;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-/// Requires two arguments, a `.vm` file as input, such as `input.vm`
-/// and a `.asm` file to output to, such as `output/bin.asm`
-fn main() {
-    let input_path = args().nth(1).expect("must supply an input path");
-    let output_path = args().nth(2).expect("must supply an output file path");
+use hack_assembler::script::{self, Script};
+
+use vm_translator::error::TranslateError;
+use vm_translator::inliner::DEFAULT_LIMIT;
+use vm_translator::interpreter::{self, Vm};
+use vm_translator::optimizer::{Passes, PASS_NAMES};
+use vm_translator::profiler;
+use vm_translator::statics::VARIABLE_BASE;
+use vm_translator::writer::Codegen;
+use vm_translator::{collect_sources, load, Bootstrap, Options};
+
+/// The languages a program can be translated to, Hack assembly first
+const TARGETS: [&str; 4] = ["hack", "c", "x86_64", "wasm"];
+
+/// The commands or cycles `profile` runs programs for at most
+const PROFILE_LIMIT: u64 = 100_000_000;
+
+fn usage() -> String {
+    format!(
+        "usage: vm_translator [OPTIONS] INPUT... OUTPUT.asm
+       vm_translator [OPTIONS] --target x86_64 INPUT... OUTPUT.s
+       vm_translator [OPTIONS] INPUT... OUTPUT.c|OUTPUT.wat
+       vm_translator INPUT... OUTPUT.vmb
+       vm_translator graph [--flow] INPUT... OUTPUT.dot|OUTPUT.json
+       vm_translator test SCRIPT.tst...
+       vm_translator profile [--hack] [-O] [--limit N] [--folded OUTPUT] INPUT...
+
+Each INPUT is a .vm file, a directory of .vm files, or a glob such as src/*.vm.
+Besides the commands of the book, inputs may use mul, div, mod, shl, shr, xor,
+lt_u and gt_u. A .vmb file, as written to OUTPUT.vmb, is bytecode holding the
+commands of any number of .vm files, and can be given wherever they can.
+Besides Hack assembly, programs can be translated for other targets, taking the
+same options: --target c writes a C program, as does an OUTPUT ending in .c,
+--target x86_64 GNU assembler source for x86-64 Linux, either of which runs
+with -h for its own options, and --target wasm a WebAssembly text module, as
+does an OUTPUT ending in .wat, importing the keyboard and screen from its host.
+
+options:
+  --bootstrap      always emit the bootstrap code that calls Sys.init
+  --no-bootstrap   never emit it; by default it is emitted when some input
+                   declares Sys.init
+  --annotate       precede each command's assembly with the VM command and
+                   its file and line
+  --source-map     also write OUTPUT.vmmap, mapping ROM addresses to VM
+                   commands
+  --static-map     also write OUTPUT.statics, mapping static variables to
+                   RAM addresses
+  --keep-dead-functions
+                   keep functions that can't be reached from Sys.init
+  -v, --verbose    report inlining and static variables, and list the
+                   functions dropped as unreachable
+  --inline N       inline functions of up to N commands that call nothing
+                   where they're called, and ones called once up to 4N
+  --cache-top      keep the top of the stack in D within basic blocks
+  --shared-compare write eq, gt and lt as calls to shared routines, which
+                   also compare values too far apart to subtract
+  --checked        guard against stack overflow and underflow, objects outside
+                   the heap, this and that outside RAM and division by zero,
+                   halting with a code in RAM[255], which statics then leave
+                   free; the stack stays in RAM
+  --native-math    replace calls to Math.multiply and Math.divide with the
+                   mul and div operations, which are far faster but don't
+                   report division by zero
+  -O, --optimize   run every optimization pass, inline functions of up to
+                   {} commands and cache the top of the stack
+  --passes LIST    run only the comma separated passes in LIST, any of
+                   all, {}
+  --target NAME    translate for NAME, one of {}
+
+graph writes the call graph of the inputs in Graphviz DOT, with recursive
+calls in red and functions that are never called dashed; with --flow it writes
+the basic blocks of each function instead, dashing those control can't reach.
+Written as JSON, the output holds both.
+
+test runs VM emulator scripts such as StaticsTestVME.tst on the VM commands
+themselves, without translating them, writing each script's output file and
+comparing it with the file the script names.
 
-    lib::compile_to_target(&input_path, &output_path);
+profile runs the program on the VM interpreter, with the OS built in for
+functions the inputs don't define, and reports the commands run in each
+function, by itself and with everything it calls, how often it was called and
+how deeply it recursed. With --hack it translates the program, optimized with
+-O, and runs it on the Hack CPU, counting cycles too. Programs stop after N
+commands or cycles, {} by default. --folded writes the cost of each stack of
+calls for flame graph tools such as flamegraph.pl.",
+        DEFAULT_LIMIT,
+        PASS_NAMES.join(", "),
+        TARGETS.join(", "),
+        PROFILE_LIMIT
+    )
 }
 
-mod lib {
-    use crate::parser;
-    use crate::parser::Parser;
-    use crate::writer::Writer;
-    use std::fs;
-    use std::io::{Result as IOResult, Write};
-    use std::path::Path;
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, usage());
+    process::exit(2);
+}
+
+/// Reports the errors that stopped a translation, and exits
+fn report(errors: &[TranslateError]) -> ! {
+    for err in errors {
+        eprintln!("error: {}", err);
+    }
+
+    eprintln!("{} error(s), no output written", errors.len());
+    process::exit(1);
+}
 
-    pub fn parse(contents: &str, namespace: &str) -> Vec<u8> {
-        use parser::CommandType;
+/// `vm_translator graph`, which takes the same inputs as a translation
+fn graph(args: impl Iterator<Item = String>) {
+    let mut paths = Vec::new();
+    let mut flow = false;
 
-        let mut parser = Parser::new(contents);
-        let mut writer = Writer::new(namespace);
+    for arg in args {
+        match arg.as_str() {
+            "--flow" => flow = true,
+            flag if flag.starts_with('-') => fail(&format!("unknown option '{}'", flag)),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() < 2 {
+        fail("expected at least one input and an output path");
+    }
 
-        while parser.has_more_commands() {
-            let line = parser.advance();
+    let output_path = paths.pop().unwrap();
 
-            if line.starts_with("//") || line.is_empty() {
-                continue;
+    match vm_translator::graph_to_target(&paths, &output_path, flow) {
+        Ok(graph) => {
+            for cycle in &graph.cycles {
+                eprintln!("recursive: {}", cycle.join(", "));
             }
+            for function in &graph.uncalled {
+                eprintln!("never called: {}", function);
+            }
+            for flow in &graph.flows {
+                for block in flow.unreachable() {
+                    eprintln!("unreachable: {}:{} in {}", flow.file, block.line, flow.name);
+                }
+            }
+        }
+        Err(errors) => report(&errors),
+    }
+}
 
-            let command_type = Parser::command_type(line);
+/// `vm_translator test`, which runs each script on the interpreter
+fn test(args: impl Iterator<Item = String>) {
+    let scripts: Vec<String> = args.collect();
+    if scripts.is_empty() {
+        fail("expected at least one script");
+    }
 
-            dbg!(&line);
+    let mut failed = false;
+    for path in &scripts {
+        if let Err(err) = run_script(Path::new(path)) {
+            eprintln!("error: {}: {}", path, err);
+            failed = true;
+        }
+    }
 
-            match command_type {
-                CommandType::Math(command) => writer.write_math(command),
-                CommandType::Memory(command) => {
-                    let first_arg = Parser::first_arg(line);
-                    let segment = Parser::segment_type(first_arg);
-                    let index = Parser::second_arg(line);
+    if failed {
+        process::exit(1);
+    }
+}
 
-                    writer.write_push_pop(command, segment, index)
-                }
-                CommandType::Branching(command) => {
-                    // Oof
-                    use parser::BranchingCommand::*;
-                    let mut label = String::from(Parser::first_arg(line));
-
-                    if !writer.current_function.is_empty() {
-                        let mut scoped_label = String::from(&writer.current_function);
-                        scoped_label.push_str("$");
-                        scoped_label.push_str(&label);
-
-                        label = scoped_label;
-                    }
-
-                    match command {
-                        Label => writer.write_label(&label),
-                        If => writer.write_if(&label),
-                        Goto => writer.write_goto(&label),
-                    }
+/// `vm_translator profile`
+fn profile(mut args: impl Iterator<Item = String>) {
+    let mut paths = Vec::new();
+    let mut hack = false;
+    let mut options = Options::default();
+    let mut limit = PROFILE_LIMIT;
+    let mut folded = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hack" => hack = true,
+            "-O" | "--optimize" => {
+                options.passes = Passes::all();
+                options.inline_limit = DEFAULT_LIMIT;
+                options.codegen = Codegen::CachedTop;
+            }
+            "--limit" => {
+                let n = args
+                    .next()
+                    .unwrap_or_else(|| fail("--limit needs a number"));
+                limit = n
+                    .parse()
+                    .unwrap_or_else(|_| fail(&format!("'{}' is not a number", n)));
+            }
+            "--folded" => {
+                folded = Some(args.next().unwrap_or_else(|| fail("--folded needs a path")))
+            }
+            flag if flag.starts_with('-') => fail(&format!("unknown option '{}'", flag)),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        fail("expected at least one input");
+    }
+
+    let result = if hack {
+        profiler::profile_hack(&paths, &options, limit)
+    } else {
+        collect_sources(&paths)
+            .and_then(|paths| load(&paths))
+            .map(|files| {
+                let mut vm = Vm::new(&files);
+                if vm.function_address("Sys.init").is_some() {
+                    vm.boot();
+                } else {
+                    vm.ram[0] = 256;
                 }
-                CommandType::Function(command) => {
-                    use parser::FunctionCommand::*;
-
-                    match command {
-                        Declare => {
-                            let name = Parser::first_arg(line);
-                            let num_locals = Parser::second_arg(line);
-
-                            writer.write_function(name, num_locals);
-                        }
-                        Call => {
-                            let name = Parser::first_arg(line);
-                            let num_args = Parser::second_arg(line);
-
-                            writer.write_call(name, num_args);
-                        }
-                        Return => writer.write_return(),
-                    }
+                profiler::profile_vm(&mut vm, limit)
+            })
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+    };
+
+    let profile = result.unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    });
+    print!("{}", profile);
+
+    if let Some(path) = folded {
+        if let Err(err) = fs::write(&path, profile.folded()) {
+            eprintln!("error: {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_script(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let script = Script::parse(&source)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let (output, _) = interpreter::run_script(&script, directory)?;
+    let (output_file, compare_file) = interpreter::script_files(&script, directory);
+
+    if let Some(output_file) = output_file {
+        fs::write(&output_file, &output)
+            .map_err(|err| format!("{}: {}", output_file.display(), err))?;
+    }
+    if let Some(compare_file) = compare_file {
+        let expected = fs::read_to_string(&compare_file)
+            .map_err(|err| format!("{}: {}", compare_file.display(), err))?;
+        script::compare(&output, &expected)?;
+        println!("{}: comparison ended successfully", path.display());
+    }
+
+    Ok(())
+}
+
+/// Takes one or more `.vm` files, directories or globs as input, such as
+/// `Main.vm` or `programs/Pong`, followed by a `.asm` file to output to, such
+/// as `output/bin.asm`
+fn main() {
+    let mut options = Options::default();
+    let mut paths = Vec::new();
+    let mut verbose = false;
+    let mut target = None;
+    let mut args = args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("graph") {
+        args.next();
+        return graph(args);
+    }
+    if args.peek().map(String::as_str) == Some("test") {
+        args.next();
+        return test(args);
+    }
+    if args.peek().map(String::as_str) == Some("profile") {
+        args.next();
+        return profile(args);
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bootstrap" => options.bootstrap = Bootstrap::Always,
+            "--no-bootstrap" => options.bootstrap = Bootstrap::Never,
+            "--annotate" => options.annotate = true,
+            "--source-map" => options.source_map = true,
+            "--static-map" => options.static_map = true,
+            "--keep-dead-functions" => options.eliminate_dead_functions = false,
+            "-v" | "--verbose" => verbose = true,
+            "--cache-top" => options.codegen = Codegen::CachedTop,
+            "--shared-compare" => options.shared_comparisons = true,
+            "--native-math" => options.native_math = true,
+            "--checked" => options.checked = true,
+            "-O" | "--optimize" => {
+                options.passes = Passes::all();
+                options.inline_limit = DEFAULT_LIMIT;
+                options.codegen = Codegen::CachedTop;
+            }
+            "--inline" => {
+                let limit = args
+                    .next()
+                    .unwrap_or_else(|| fail("--inline needs a number of commands"));
+                options.inline_limit = limit
+                    .parse()
+                    .unwrap_or_else(|_| fail(&format!("'{}' is not a number", limit)));
+            }
+            "--passes" => {
+                let list = args
+                    .next()
+                    .unwrap_or_else(|| fail("--passes needs a list of passes"));
+                options.passes = Passes::parse(&list).unwrap_or_else(|err| fail(&err));
+            }
+            "--target" => {
+                let name = args
+                    .next()
+                    .unwrap_or_else(|| fail("--target needs a target"));
+                if !TARGETS.contains(&name.as_str()) {
+                    fail(&format!("unknown target '{}'", name));
                 }
+                target = Some(name);
             }
+            "-h" | "--help" => {
+                println!("{}", usage());
+                return;
+            }
+            flag if flag.starts_with('-') => fail(&format!("unknown option '{}'", flag)),
+            _ => paths.push(arg),
         }
+    }
+
+    if paths.len() < 2 {
+        fail("expected at least one input and an output path");
+    }
 
-        writer.output
-    }
-
-    pub fn compile_to_target(input_path: &str, output_path: &str) {
-        let input_path = Path::new(input_path);
-        let mut output_buffer = Vec::new();
-
-        // Write preamble
-        let mut preamble_writer = Writer::new("Sys");
-        preamble_writer.write_init();
-        output_buffer.append(&mut preamble_writer.output);
-
-        if Path::is_dir(input_path) {
-            fs::read_dir(input_path)
-                .unwrap()
-                .for_each(|result: IOResult<fs::DirEntry>| {
-                    let entry = result.unwrap();
-                    let entry_path = entry.path();
-                    let os_file_name = entry.file_name();
-                    let path = entry.path();
-                    let file_name = os_file_name.to_str().expect("problem getting filename");
-
-                    let file_stem = entry_path
-                        .file_stem()
-                        .unwrap()
-                        .to_str()
-                        .expect("problem getting file_stem");
-
-                    if !file_name.ends_with(".vm") {
-                        // TODO: Just skip different filetypes
-                        panic!("all source files must have the '.vm' extension")
-                    }
-
-                    let contents =
-                        fs::read_to_string(path).expect("problem reading file contents to string");
-
-                    output_buffer.append(&mut parse(&contents, file_stem));
-                });
-        } else {
-            let file_stem = input_path
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .expect("problem getting filename");
-            let source =
-                std::fs::read_to_string(input_path).expect("problem reading contents of file");
-
-            output_buffer.append(&mut parse(&source, file_stem));
+    let output_path = paths.pop().unwrap();
+
+    if output_path.ends_with(".vmb") {
+        if let Err(errors) = vm_translator::bytecode_to_target(&paths, &output_path) {
+            report(&errors);
         }
+        return;
+    }
 
-        fs::File::create(output_path)
-            .expect("problem creating output file")
-            .write(&output_buffer)
-            .expect("problem writing buffer to output file");
+    let target = match target.as_deref() {
+        Some(target) => target,
+        None if output_path.ends_with(".c") => "c",
+        None if output_path.ends_with(".wat") => "wasm",
+        None => "hack",
+    };
+    if target != "hack" {
+        let written = match target {
+            "c" => vm_translator::c_to_target(&paths, &output_path, &options),
+            "x86_64" => vm_translator::x86_64_to_target(&paths, &output_path, &options),
+            _ => vm_translator::wasm_to_target(&paths, &output_path, &options),
+        };
+        if let Err(errors) = written {
+            report(&errors);
+        }
+        return;
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
+    match vm_translator::compile_to_target(&paths, &output_path, &options) {
+        Ok(report) => {
+            if verbose {
+                if report.inlined > 0 {
+                    eprintln!("inlined {} call(s)", report.inlined);
+                }
+                for (function, size) in &report.removed {
+                    eprintln!("removed {} ({} words)", function, size);
+                }
+                eprintln!(
+                    "static variables take {} of {} words of RAM",
+                    report.statics.variables.len(),
+                    options.static_limit() - VARIABLE_BASE
+                );
+            }
+
+            if !report.removed.is_empty() {
+                eprintln!(
+                    "removed {} unreachable function(s), saving {} of {} words of ROM",
+                    report.removed.len(),
+                    report.saved(),
+                    report.saved() + report.size
+                );
+            }
+        }
+        Err(errors) => report(&errors),
     }
 }
@@ -0,0 +1,874 @@
+use crate::parser::{MathCommand, MemorySegment, SourceCommand, Span, VmCommand};
+use std::collections::{HashMap, HashSet};
+
+/// The optimization passes to run over the VM commands before they're written
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Passes {
+  /// `push constant 7, push constant 8, add` becomes `push constant 15`
+  pub fold: bool,
+  /// Drops operations that leave their operand unchanged, like `push constant 0, add`
+  pub simplify: bool,
+  /// `eq, not, if-goto A, goto B, label A` becomes `eq, if-goto B, label A`,
+  /// and `while` loops test their condition at the bottom
+  pub invert_branches: bool,
+  /// Jumps to a `goto` go straight to its target, and jumps to the next command are dropped
+  pub thread_jumps: bool,
+  /// Drops unlabelled commands after `goto` and `return`, which can never run
+  pub dead_code: bool,
+  /// Drops labels nothing jumps to
+  pub unused_labels: bool,
+}
+
+/// The names `Passes::parse` accepts, in the order the passes run
+pub const PASS_NAMES: [&str; 6] = [
+  "fold",
+  "simplify",
+  "invert-branches",
+  "thread-jumps",
+  "dead-code",
+  "unused-labels",
+];
+
+impl Passes {
+  pub fn all() -> Passes {
+    Passes {
+      fold: true,
+      simplify: true,
+      invert_branches: true,
+      thread_jumps: true,
+      dead_code: true,
+      unused_labels: true,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    *self == Passes::default()
+  }
+
+  /// Parses a comma separated list of pass names, e.g. `fold,dead-code`
+  pub fn parse(list: &str) -> Result<Passes, String> {
+    let mut passes = Passes::default();
+
+    for name in list
+      .split(',')
+      .map(str::trim)
+      .filter(|name| !name.is_empty())
+    {
+      match name {
+        "all" => passes = Passes::all(),
+        "fold" => passes.fold = true,
+        "simplify" => passes.simplify = true,
+        "invert-branches" => passes.invert_branches = true,
+        "thread-jumps" => passes.thread_jumps = true,
+        "dead-code" => passes.dead_code = true,
+        "unused-labels" => passes.unused_labels = true,
+        _ => {
+          return Err(format!(
+            "unknown pass '{}', expected one of: all, {}",
+            name,
+            PASS_NAMES.join(", ")
+          ))
+        }
+      }
+    }
+
+    Ok(passes)
+  }
+}
+
+/// The most times the passes are run over a function before giving up on a fixed point
+const MAX_ROUNDS: usize = 16;
+
+/// Optimizes one file's commands. Every function is optimized on its own,
+/// since labels are local to the function they're in; the commands before
+/// the first function are too, except that their labels are never removed,
+/// as they aren't scoped and other files may jump to them.
+pub fn optimize(commands: Vec<SourceCommand>, passes: &Passes) -> Vec<SourceCommand> {
+  if passes.is_empty() {
+    return commands;
+  }
+
+  let mut optimized = Vec::with_capacity(commands.len());
+  let mut scope = Vec::new();
+
+  for command in commands {
+    if let VmCommand::Function { .. } = command.command {
+      optimize_scope(&mut scope, passes);
+      optimized.append(&mut scope);
+    }
+
+    scope.push(command);
+  }
+
+  optimize_scope(&mut scope, passes);
+  optimized.append(&mut scope);
+
+  optimized
+}
+
+fn optimize_scope(commands: &mut Vec<SourceCommand>, passes: &Passes) {
+  let in_function = matches!(
+    commands.first().map(|command| &command.command),
+    Some(VmCommand::Function { .. })
+  );
+
+  for _ in 0..MAX_ROUNDS {
+    let mut changed = false;
+
+    if passes.fold {
+      changed |= fold_constants(commands);
+    }
+    if passes.simplify {
+      changed |= simplify(commands);
+    }
+    if passes.invert_branches {
+      changed |= invert_branches(commands);
+    }
+    if passes.thread_jumps {
+      changed |= thread_jumps(commands);
+    }
+    if passes.dead_code {
+      changed |= remove_dead_code(commands);
+    }
+    if passes.unused_labels && in_function {
+      changed |= remove_unused_labels(commands);
+    }
+
+    if !changed {
+      break;
+    }
+  }
+}
+
+/// The value the commands at the start of the slice push, and how many
+/// commands push it: either `push constant n`, or that followed by `neg` or
+/// `not`, which is how negative constants are written
+fn constant(commands: &[SourceCommand]) -> Option<(i16, usize)> {
+  use VmCommand::*;
+
+  let value = match commands.first()?.command {
+    Push {
+      segment: MemorySegment::Constant,
+      index,
+    } => index as i16,
+    _ => return None,
+  };
+
+  match commands.get(1).map(|command| &command.command) {
+    Some(Arithmetic(MathCommand::Negate)) => Some((value.wrapping_neg(), 2)),
+    Some(Arithmetic(MathCommand::Not)) => Some((!value, 2)),
+    _ => Some((value, 1)),
+  }
+}
+
+/// The fewest commands that push the value
+fn push_constant(value: i16) -> Vec<VmCommand> {
+  let push = |index: i16| VmCommand::Push {
+    segment: MemorySegment::Constant,
+    index: index as u16,
+  };
+
+  if value >= 0 {
+    vec![push(value)]
+  } else if value == i16::MIN {
+    vec![push(i16::MAX), VmCommand::Arithmetic(MathCommand::Not)]
+  } else if value == -1 {
+    // `push constant 0, not` is how true is usually written
+    vec![push(0), VmCommand::Arithmetic(MathCommand::Not)]
+  } else {
+    vec![push(-value), VmCommand::Arithmetic(MathCommand::Negate)]
+  }
+}
+
+fn arithmetic(command: &SourceCommand) -> Option<MathCommand> {
+  match command.command {
+    VmCommand::Arithmetic(math) => Some(math),
+    _ => None,
+  }
+}
+
+fn boolean(value: bool) -> i16 {
+  if value {
+    -1
+  } else {
+    0
+  }
+}
+
+/// Evaluates a binary operation as the Hack platform would, or the writer's
+/// routines for the extended ones
+pub(crate) fn evaluate_binary(math: MathCommand, x: i16, y: i16) -> Option<i16> {
+  use MathCommand::*;
+
+  match math {
+    Add => Some(x.wrapping_add(y)),
+    Subtract => Some(x.wrapping_sub(y)),
+    EqualTo => Some(boolean(x == y)),
+    GreaterThan => Some(boolean(x > y)),
+    LessThan => Some(boolean(x < y)),
+    And => Some(x & y),
+    Or => Some(x | y),
+    Multiply => Some(x.wrapping_mul(y)),
+    // left for the writer's routines, which don't fail
+    Divide | Modulo if y == 0 => None,
+    Divide => Some(x.wrapping_div(y)),
+    Modulo => Some(x.wrapping_rem(y)),
+    ShiftLeft => Some(x.checked_shl(y as u16 as u32).unwrap_or(0)),
+    ShiftRight => Some(x.checked_shr(y as u16 as u32).unwrap_or(x >> 15)),
+    Xor => Some(x ^ y),
+    LessThanUnsigned => Some(boolean((x as u16) < (y as u16))),
+    GreaterThanUnsigned => Some(boolean(x as u16 > y as u16)),
+    Negate | Not => None,
+  }
+}
+
+pub(crate) fn evaluate_unary(math: MathCommand, x: i16) -> Option<i16> {
+  match math {
+    MathCommand::Negate => Some(x.wrapping_neg()),
+    MathCommand::Not => Some(!x),
+    _ => None,
+  }
+}
+
+/// Replaces `len` commands at `index` with the commands pushing `value`,
+/// which take on the span of the last command replaced
+fn replace_with_constant(commands: &mut Vec<SourceCommand>, index: usize, len: usize, value: i16) {
+  let span = commands[index + len - 1].span.clone();
+  let replacement = push_constant(value)
+    .into_iter()
+    .map(|command| SourceCommand {
+      command,
+      span: span.clone(),
+    });
+
+  commands.splice(index..index + len, replacement);
+}
+
+fn fold_constants(commands: &mut Vec<SourceCommand>) -> bool {
+  let mut changed = false;
+  let mut index = 0;
+
+  while index < commands.len() {
+    let (x, x_len) = match constant(&commands[index..]) {
+      Some(constant) => constant,
+      None => {
+        index += 1;
+        continue;
+      }
+    };
+
+    // a binary operation on two constants
+    if let Some((y, y_len)) = constant(&commands[index + x_len..]) {
+      let op = index + x_len + y_len;
+      let value = commands
+        .get(op)
+        .and_then(arithmetic)
+        .and_then(|math| evaluate_binary(math, x, y));
+
+      if let Some(value) = value {
+        replace_with_constant(commands, index, x_len + y_len + 1, value);
+        changed = true;
+        continue;
+      }
+    }
+
+    // a unary operation on a negative constant, like `push constant 1, neg, neg`
+    if x_len == 2 {
+      let value = commands
+        .get(index + 2)
+        .and_then(arithmetic)
+        .and_then(|math| evaluate_unary(math, x));
+
+      if let Some(value) = value {
+        replace_with_constant(commands, index, 3, value);
+        changed = true;
+        continue;
+      }
+    }
+
+    index += 1;
+  }
+
+  changed
+}
+
+fn simplify(commands: &mut Vec<SourceCommand>) -> bool {
+  use MathCommand::*;
+
+  let mut changed = false;
+  let mut index = 0;
+
+  while index < commands.len() {
+    let identity = constant(&commands[index..]).and_then(|(value, len)| {
+      let op = commands.get(index + len).and_then(arithmetic)?;
+
+      match (value, op) {
+        // x + 0, x - 0, x | 0 and x & true are all x, as are x * 1,
+        // x / 1, x ^ 0 and x shifted by 0
+        (0, Add) | (0, Subtract) | (0, Or) | (-1, And) => Some(len + 1),
+        (1, Multiply) | (1, Divide) | (0, Xor) | (0, ShiftLeft) | (0, ShiftRight) => Some(len + 1),
+        _ => None,
+      }
+    });
+
+    // applying neg or not twice is a no-op
+    let involution = match (
+      commands.get(index).and_then(arithmetic),
+      commands.get(index + 1).and_then(arithmetic),
+    ) {
+      (Some(Negate), Some(Negate)) | (Some(Not), Some(Not)) => Some(2),
+      _ => None,
+    };
+
+    match identity.or(involution) {
+      Some(len) => {
+        commands.drain(index..index + len);
+        changed = true;
+      }
+      None => index += 1,
+    }
+  }
+
+  changed
+}
+
+/// Whether the command always leaves true (-1) or false (0) on the stack
+fn pushes_boolean(command: &SourceCommand) -> bool {
+  matches!(
+    arithmetic(command),
+    Some(MathCommand::EqualTo)
+      | Some(MathCommand::GreaterThan)
+      | Some(MathCommand::LessThan)
+      | Some(MathCommand::LessThanUnsigned)
+      | Some(MathCommand::GreaterThanUnsigned)
+  )
+}
+
+fn invert_branches(commands: &mut Vec<SourceCommand>) -> bool {
+  use VmCommand::*;
+
+  let mut changed = rotate_loops(commands);
+  let mut index = 0;
+
+  // `not` only inverts the truth of booleans, so this is limited to
+  // comparisons; and `sub` is only non-zero when `eq` would be false
+  while index < commands.len() {
+    let window: Vec<&VmCommand> = commands[index..]
+      .iter()
+      .take(5)
+      .map(|command| &command.command)
+      .collect();
+    let rewrite = match window.as_slice() {
+      [_, Arithmetic(MathCommand::Not), IfGoto(skip), Goto(target), Label(label)]
+        if pushes_boolean(&commands[index]) && skip == label =>
+      {
+        Some((1..4, vec![(2, IfGoto(target.clone()))]))
+      }
+      [Arithmetic(MathCommand::EqualTo), IfGoto(skip), Goto(target), Label(label), ..]
+        if skip == label =>
+      {
+        Some((
+          0..3,
+          vec![
+            (0, Arithmetic(MathCommand::Subtract)),
+            (1, IfGoto(target.clone())),
+          ],
+        ))
+      }
+      [Arithmetic(MathCommand::EqualTo), Arithmetic(MathCommand::Not), IfGoto(_), ..] => {
+        Some((0..2, vec![(0, Arithmetic(MathCommand::Subtract))]))
+      }
+      _ => None,
+    };
+
+    match rewrite {
+      Some((range, replacement)) => {
+        let replacement: Vec<SourceCommand> = replacement
+          .into_iter()
+          .map(|(offset, command)| SourceCommand {
+            command,
+            span: commands[index + offset].span.clone(),
+          })
+          .collect();
+        commands.splice(index + range.start..index + range.end, replacement);
+        changed = true;
+      }
+      None => index += 1,
+    }
+  }
+
+  changed
+}
+
+/// Moves the test of loops shaped like the compiler's `while` statements,
+/// `label EXP, <test>, not, if-goto END, <body>, goto EXP, label END`, to
+/// their bottom, as `goto EXP, label BODY, <body>, label EXP, <test>,
+/// if-goto BODY, label END`. Each time round then skips the `not` and the
+/// `goto`. The test has to leave a boolean, and not branch or have labels
+/// in it that could be jumped to.
+fn rotate_loops(commands: &mut Vec<SourceCommand>) -> bool {
+  use VmCommand::*;
+
+  let mut changed = false;
+
+  for index in 0..commands.len() {
+    let top = match &commands[index].command {
+      Label(label) => label.clone(),
+      _ => continue,
+    };
+    // the test runs straight on into `not, if-goto END`
+    let branch = index
+      + 1
+      + commands[index + 1..]
+        .iter()
+        .take_while(|command| {
+          matches!(
+            command.command,
+            Push { .. } | Pop { .. } | Arithmetic(_) | Call { .. }
+          )
+        })
+        .count();
+    let end = match &commands.get(branch).map(|command| &command.command) {
+      Some(IfGoto(end)) if branch >= index + 3 => end.clone(),
+      _ => continue,
+    };
+    let test_end = branch - 1;
+    if commands[test_end].command != Arithmetic(MathCommand::Not)
+      || !pushes_boolean(&commands[test_end - 1])
+    {
+      continue;
+    }
+
+    let bottom = (test_end + 2..commands.len().saturating_sub(1)).find(|&bottom| {
+      commands[bottom].command == Goto(top.clone())
+        && commands[bottom + 1].command == Label(end.clone())
+    });
+    let bottom = match bottom {
+      Some(bottom) => bottom,
+      None => continue,
+    };
+
+    let body = fresh_label(commands, &format!("{}.BODY", top));
+    let branch = &commands[test_end + 1].span;
+    let at = |command, span: &Span| SourceCommand {
+      command,
+      span: span.clone(),
+    };
+
+    let mut rotated = vec![
+      at(Goto(top), &commands[bottom].span),
+      at(Label(body.clone()), branch),
+    ];
+    rotated.extend_from_slice(&commands[test_end + 2..bottom]);
+    rotated.extend_from_slice(&commands[index..test_end]);
+    rotated.push(at(IfGoto(body), branch));
+
+    commands.splice(index..=bottom, rotated);
+    changed = true;
+  }
+
+  changed
+}
+
+/// A label starting with `base` that isn't in the commands yet
+fn fresh_label(commands: &[SourceCommand], base: &str) -> String {
+  let taken: HashSet<&str> = commands
+    .iter()
+    .filter_map(|command| match &command.command {
+      VmCommand::Label(label) => Some(label.as_str()),
+      _ => None,
+    })
+    .collect();
+
+  let mut label = String::from(base);
+  let mut suffix = 1;
+  while taken.contains(label.as_str()) {
+    label = format!("{}{}", base, suffix);
+    suffix += 1;
+  }
+  label
+}
+
+fn thread_jumps(commands: &mut Vec<SourceCommand>) -> bool {
+  use VmCommand::*;
+
+  // where jumping to each label ends up going, if it's straight on to a goto
+  let mut forwards = HashMap::new();
+  for (index, command) in commands.iter().enumerate() {
+    if let Label(label) = &command.command {
+      let next = commands[index + 1..]
+        .iter()
+        .find(|command| !matches!(command.command, Label(_)));
+
+      if let Some(SourceCommand {
+        command: Goto(target),
+        ..
+      }) = next
+      {
+        forwards.insert(label.clone(), target.clone());
+      }
+    }
+  }
+
+  let resolve = |label: &String| {
+    let mut target = label;
+    let mut seen = HashSet::new();
+
+    // a cycle of gotos is an infinite loop, which is left as it is
+    while let Some(next) = forwards.get(target) {
+      if !seen.insert(target) {
+        return label.clone();
+      }
+      target = next;
+    }
+
+    target.clone()
+  };
+
+  let mut changed = false;
+
+  for command in commands.iter_mut() {
+    if let Goto(label) | IfGoto(label) = &mut command.command {
+      let target = resolve(label);
+
+      if target != *label {
+        *label = target;
+        changed = true;
+      }
+    }
+  }
+
+  // a goto to one of the labels right after it does nothing
+  let mut index = 0;
+  while index < commands.len() {
+    let to_next = match &commands[index].command {
+      Goto(target) => commands[index + 1..]
+        .iter()
+        .map_while(|command| match &command.command {
+          Label(label) => Some(label),
+          _ => None,
+        })
+        .any(|label| label == target),
+      _ => false,
+    };
+
+    if to_next {
+      commands.remove(index);
+      changed = true;
+    } else {
+      index += 1;
+    }
+  }
+
+  changed
+}
+
+fn remove_dead_code(commands: &mut Vec<SourceCommand>) -> bool {
+  use VmCommand::*;
+
+  let before = commands.len();
+  let mut reachable = true;
+
+  commands.retain(|command| {
+    match command.command {
+      Label(_) | Function { .. } => reachable = true,
+      _ if !reachable => return false,
+      Goto(_) | Return => reachable = false,
+      _ => (),
+    }
+
+    true
+  });
+
+  commands.len() != before
+}
+
+fn remove_unused_labels(commands: &mut Vec<SourceCommand>) -> bool {
+  use VmCommand::*;
+
+  let used: HashSet<String> = commands
+    .iter()
+    .filter_map(|command| match &command.command {
+      Goto(label) | IfGoto(label) => Some(label.clone()),
+      _ => None,
+    })
+    .collect();
+
+  let before = commands.len();
+  commands.retain(|command| match &command.command {
+    Label(label) => used.contains(label),
+    _ => true,
+  });
+
+  commands.len() != before
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Parser;
+
+  fn parse(source: &str) -> Vec<SourceCommand> {
+    Parser::new(source).map(Result::unwrap).collect()
+  }
+
+  fn render(commands: &[SourceCommand]) -> Vec<String> {
+    commands
+      .iter()
+      .map(|command| command.command.to_string())
+      .collect()
+  }
+
+  fn optimized(source: &str, passes: Passes) -> Vec<String> {
+    render(&optimize(parse(source), &passes))
+  }
+
+  /// Runs code that only uses the stack, temp and branching, returning the
+  /// final stack and temp segment
+  fn run(commands: &[SourceCommand]) -> (Vec<i16>, [i16; 8]) {
+    use VmCommand::*;
+
+    let mut stack: Vec<i16> = Vec::new();
+    let mut temp = [0; 8];
+    let mut pc = 0;
+    let jump = |label: &str| {
+      commands
+        .iter()
+        .position(|command| command.command == Label(String::from(label)))
+        .unwrap()
+    };
+
+    for _ in 0..10_000 {
+      let command = match commands.get(pc) {
+        Some(command) => &command.command,
+        None => break,
+      };
+      pc += 1;
+
+      match command {
+        Push {
+          segment: MemorySegment::Constant,
+          index,
+        } => stack.push(*index as i16),
+        Push {
+          segment: MemorySegment::Temp,
+          index,
+        } => stack.push(temp[*index as usize]),
+        Pop {
+          segment: MemorySegment::Temp,
+          index,
+        } => temp[*index as usize] = stack.pop().unwrap(),
+        Arithmetic(math) => {
+          let y = stack.pop().unwrap();
+          let value = match evaluate_unary(*math, y) {
+            Some(value) => value,
+            None => evaluate_binary(*math, stack.pop().unwrap(), y).unwrap(),
+          };
+          stack.push(value);
+        }
+        Goto(label) => pc = jump(label),
+        IfGoto(label) => {
+          if stack.pop().unwrap() != 0 {
+            pc = jump(label);
+          }
+        }
+        Label(_) | Function { .. } => (),
+        Return => break,
+        other => panic!("can't run {}", other),
+      }
+    }
+
+    (stack, temp)
+  }
+
+  const PROGRAMS: [&str; 6] = [
+    "push constant 7\npush constant 8\nadd\npush constant 3\nneg\nsub\npop temp 0\n\
+     push constant 0\nnot\npush constant 5\nand\npush constant 32767\nnot\neq",
+    "push constant 0\npush constant 4\nsub\npush constant 0\nadd\nnot\nnot\npop temp 1\n\
+     push temp 1\nneg\nneg\npush constant 0\nor",
+    "push constant 3\npop temp 0\nlabel LOOP\npush temp 0\npush constant 0\neq\nnot\n\
+     if-goto BODY\ngoto END\nlabel BODY\npush temp 0\npush constant 1\nsub\npop temp 0\n\
+     goto LOOP\nlabel END\npush temp 0",
+    "goto A\npush constant 1\npop temp 0\nlabel A\ngoto B\nlabel C\npush constant 2\n\
+     label B\npush constant 9\npush constant 9\ngt\nif-goto C\ngoto D\nlabel D\npush constant 4",
+    "function Test.main 0\npush constant 1\nlabel UNUSED\nif-goto SKIP\npush constant 5\n\
+     pop temp 2\nlabel SKIP\npush temp 2\nreturn\npush constant 6",
+    "push constant 4\npop temp 0\nlabel WHILE_EXP0\npush temp 0\npush constant 0\ngt\nnot\n\
+     if-goto WHILE_END0\npush temp 1\npush temp 0\nadd\npop temp 1\npush temp 0\n\
+     push constant 1\nsub\npop temp 0\ngoto WHILE_EXP0\nlabel WHILE_END0\npush temp 1\n\
+     push constant 10\neq\nif-goto IF_TRUE0\ngoto IF_FALSE0\nlabel IF_TRUE0\npush constant 1\n\
+     pop temp 2\nlabel IF_FALSE0\npush temp 2",
+  ];
+
+  #[test]
+  fn every_pass_preserves_semantics() {
+    let mut single = Vec::new();
+    for name in PASS_NAMES.iter() {
+      single.push(Passes::parse(name).unwrap());
+    }
+
+    for source in PROGRAMS.iter() {
+      let commands = parse(source);
+      let expected = run(&commands);
+
+      for passes in single.iter().chain(Some(&Passes::all())) {
+        let optimized = optimize(commands.clone(), passes);
+
+        assert_eq!(run(&optimized), expected, "{:?} on {}", passes, source);
+        assert!(optimized.len() <= commands.len());
+      }
+    }
+  }
+
+  #[test]
+  fn folds_constants() {
+    let fold = Passes::parse("fold").unwrap();
+
+    assert_eq!(
+      optimized("push constant 7\npush constant 8\nadd", fold),
+      vec!["push constant 15"]
+    );
+    assert_eq!(
+      optimized("push constant 7\npush constant 9\nsub", fold),
+      vec!["push constant 2", "neg"]
+    );
+    assert_eq!(
+      optimized("push constant 1\npush constant 1\neq", fold),
+      vec!["push constant 0", "not"]
+    );
+    assert_eq!(
+      optimized("push constant 2\nneg\nneg\npush local 0\nadd", fold),
+      vec!["push constant 2", "push local 0", "add"]
+    );
+    assert_eq!(
+      optimized("push constant 32767\npush constant 1\nadd", fold),
+      vec!["push constant 32767", "not"]
+    );
+  }
+
+  #[test]
+  fn simplifies_identities() {
+    let simplify = Passes::parse("simplify").unwrap();
+
+    assert_eq!(
+      optimized(
+        "push local 0\npush constant 0\nadd\npush constant 0\nnot\nand\nnot\nnot",
+        simplify
+      ),
+      vec!["push local 0"]
+    );
+    assert_eq!(
+      optimized("push local 0\npush constant 1\nadd", simplify),
+      vec!["push local 0", "push constant 1", "add"]
+    );
+  }
+
+  #[test]
+  fn inverts_negated_comparisons() {
+    let invert = Passes::parse("invert-branches").unwrap();
+
+    assert_eq!(
+      optimized("lt\nnot\nif-goto ELSE\ngoto THEN\nlabel ELSE", invert),
+      vec!["lt", "if-goto THEN", "label ELSE"]
+    );
+    // `not` of an arbitrary value isn't its logical negation
+    assert_eq!(
+      optimized("push local 0\nnot\nif-goto A\ngoto B\nlabel A", invert).len(),
+      5
+    );
+    // `sub` is non-zero exactly when `eq` is false
+    assert_eq!(
+      optimized(
+        "eq\nif-goto IF_TRUE0\ngoto IF_FALSE0\nlabel IF_TRUE0",
+        invert
+      ),
+      vec!["sub", "if-goto IF_FALSE0", "label IF_TRUE0"]
+    );
+    assert_eq!(
+      optimized("eq\nnot\nif-goto A\npush constant 1", invert),
+      vec!["sub", "if-goto A", "push constant 1"]
+    );
+  }
+
+  #[test]
+  fn rotates_while_loops() {
+    let invert = Passes::parse("invert-branches").unwrap();
+
+    assert_eq!(
+      optimized(
+        "label WHILE_EXP0\npush local 0\npush constant 0\ngt\nnot\nif-goto WHILE_END0\n\
+         call Main.step 0\npop local 0\ngoto WHILE_EXP0\nlabel WHILE_END0",
+        invert
+      ),
+      vec![
+        "goto WHILE_EXP0",
+        "label WHILE_EXP0.BODY",
+        "call Main.step 0",
+        "pop local 0",
+        "label WHILE_EXP0",
+        "push local 0",
+        "push constant 0",
+        "gt",
+        "if-goto WHILE_EXP0.BODY",
+        "label WHILE_END0"
+      ]
+    );
+    // the test of `while (flag)` needn't be a boolean
+    let flag = "label WHILE_EXP0\npush local 0\nnot\nif-goto WHILE_END0\n\
+                goto WHILE_EXP0\nlabel WHILE_END0";
+    assert_eq!(optimized(flag, invert), render(&parse(flag)));
+  }
+
+  #[test]
+  fn threads_jumps() {
+    let thread = Passes::parse("thread-jumps").unwrap();
+
+    assert_eq!(
+      optimized(
+        "if-goto A\ngoto C\nlabel A\nlabel B\ngoto C\nlabel C\npush constant 1",
+        thread
+      ),
+      vec![
+        "if-goto C",
+        "label A",
+        "label B",
+        "label C",
+        "push constant 1"
+      ]
+    );
+    // loops of gotos still loop
+    assert_eq!(
+      optimized("label A\ngoto B\nlabel B\ngoto A", thread),
+      vec!["label A", "label B", "goto A"]
+    );
+    assert_eq!(
+      optimized("label A\ngoto A", thread),
+      vec!["label A", "goto A"]
+    );
+  }
+
+  #[test]
+  fn removes_dead_code_and_unused_labels() {
+    let passes = Passes::parse("dead-code, unused-labels").unwrap();
+
+    assert_eq!(
+      optimized(
+        "function F.f 0\nlabel TOP\ngoto TOP\npush constant 1\nlabel UNUSED\nreturn\npop local 0",
+        passes
+      ),
+      vec!["function F.f 0", "label TOP", "goto TOP"]
+    );
+    // labels outside of functions may be jumped to from other files
+    assert_eq!(
+      optimized("label UNUSED\npush constant 1", passes),
+      vec!["label UNUSED", "push constant 1"]
+    );
+  }
+
+  #[test]
+  fn parses_pass_lists() {
+    assert_eq!(Passes::parse("all"), Ok(Passes::all()));
+    assert!(Passes::parse("").unwrap().is_empty());
+    assert!(Passes::parse("fold,inline").is_err());
+  }
+}
@@ -0,0 +1,563 @@
+//! The Jack OS built into the interpreter, as the VM emulator builds in its
+//! Java one. A call to a function no loaded file defines runs the built-in
+//! of that name, working on the emulated RAM with the heap, screen and
+//! keyboard where the OS keeps them, so a .vm file defining some of these
+//! functions replaces just those. Built-ins call each other through the
+//! interpreter where the Java ones do, so they use the replacements too.
+
+use crate::interpreter::{Trap, Vm};
+
+mod output;
+mod screen;
+
+use output::Output;
+
+/// Every built-in function and the arguments it takes
+const FUNCTIONS: [(&str, usize); 48] = [
+  ("Array.new", 1),
+  ("Array.dispose", 1),
+  ("Keyboard.init", 0),
+  ("Keyboard.keyPressed", 0),
+  ("Keyboard.readChar", 0),
+  ("Keyboard.readLine", 1),
+  ("Keyboard.readInt", 1),
+  ("Math.init", 0),
+  ("Math.abs", 1),
+  ("Math.multiply", 2),
+  ("Math.divide", 2),
+  ("Math.min", 2),
+  ("Math.max", 2),
+  ("Math.sqrt", 1),
+  ("Memory.init", 0),
+  ("Memory.peek", 1),
+  ("Memory.poke", 2),
+  ("Memory.alloc", 1),
+  ("Memory.deAlloc", 1),
+  ("Output.init", 0),
+  ("Output.moveCursor", 2),
+  ("Output.printChar", 1),
+  ("Output.printString", 1),
+  ("Output.printInt", 1),
+  ("Output.println", 0),
+  ("Output.backSpace", 0),
+  ("Screen.init", 0),
+  ("Screen.clearScreen", 0),
+  ("Screen.setColor", 1),
+  ("Screen.drawPixel", 2),
+  ("Screen.drawLine", 4),
+  ("Screen.drawRectangle", 4),
+  ("Screen.drawCircle", 3),
+  ("String.new", 1),
+  ("String.dispose", 1),
+  ("String.length", 1),
+  ("String.charAt", 2),
+  ("String.setCharAt", 3),
+  ("String.appendChar", 2),
+  ("String.eraseLastChar", 1),
+  ("String.intValue", 1),
+  ("String.setInt", 2),
+  ("String.newLine", 0),
+  ("String.backSpace", 0),
+  ("String.doubleQuote", 0),
+  ("Sys.halt", 0),
+  ("Sys.error", 1),
+  ("Sys.wait", 1),
+];
+
+/// `Sys.init` for programs that don't define one, in VM code so that
+/// stepping goes on into `Main.main`
+pub(crate) const SYS_INIT: &str = "\
+function Sys.init 0
+call Memory.init 0
+pop temp 0
+call Math.init 0
+pop temp 0
+call Screen.init 0
+pop temp 0
+call Output.init 0
+pop temp 0
+call Keyboard.init 0
+pop temp 0
+call Main.main 0
+pop temp 0
+call Sys.halt 0
+";
+
+/// Where the heap starts, and its last word
+const HEAP: i32 = 2048;
+const HEAP_END: i32 = 16383;
+/// The first word of the screen's memory map
+const SCREEN: i32 = 16384;
+/// The keyboard's memory map
+const KEYBOARD: i32 = 24576;
+
+pub(crate) const NEW_LINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+const DOUBLE_QUOTE: i16 = 34;
+
+/// The state the built-in classes keep outside of RAM, as the Java ones
+/// keep it in static fields
+pub(crate) struct State {
+  output: Output,
+  /// The color `Screen` draws in
+  black: bool,
+}
+
+impl Default for State {
+  fn default() -> State {
+    State {
+      output: Output::default(),
+      black: true,
+    }
+  }
+}
+
+/// Whether `function` is built in, and so needn't be defined
+pub fn is_builtin(function: &str) -> bool {
+  FUNCTIONS.iter().any(|(name, _)| *name == function)
+}
+
+/// Runs the built-in `function`, or returns `None` if there is none
+pub(crate) fn call(vm: &mut Vm, function: &str, args: &[i16]) -> Option<Result<i16, Trap>> {
+  let nargs = FUNCTIONS.iter().find(|(name, _)| *name == function)?.1;
+  if args.len() != nargs {
+    return Some(Err(Trap::Error(format!(
+      "{} takes {} arguments, not {}",
+      function,
+      nargs,
+      args.len()
+    ))));
+  }
+
+  let arg = |n: usize| args[n] as i32;
+  Some(match function {
+    "Array.new" => array_new(vm, arg(0)),
+    "Array.dispose" => vm.call("Memory.deAlloc", &[args[0]]),
+    "Keyboard.init" | "Math.init" => Ok(0),
+    "Keyboard.keyPressed" => key_pressed(vm),
+    "Keyboard.readChar" => read_char(vm),
+    "Keyboard.readLine" => read_line(vm, args[0]).and_then(|line| new_string(vm, &line)),
+    "Keyboard.readInt" => read_line(vm, args[0]).map(|line| int_value(&line)),
+    "Math.abs" => Ok(args[0].wrapping_abs()),
+    "Math.multiply" => Ok(args[0].wrapping_mul(args[1])),
+    "Math.divide" => divide(vm, arg(0), arg(1)),
+    "Math.min" => Ok(args[0].min(args[1])),
+    "Math.max" => Ok(args[0].max(args[1])),
+    "Math.sqrt" => sqrt(vm, args[0]),
+    "Memory.init" => memory_init(vm),
+    "Memory.peek" => peek(vm, arg(0)),
+    "Memory.poke" => poke(vm, arg(0), arg(1)),
+    "Memory.alloc" => alloc(vm, arg(0)),
+    "Memory.deAlloc" => de_alloc(vm, arg(0)),
+    "Output.init" => {
+      vm.os.output = Output::default();
+      Ok(0)
+    }
+    "Output.moveCursor" => output::move_cursor(vm, arg(0), arg(1)),
+    "Output.printChar" => output::print_char(vm, args[0]),
+    "Output.printString" => output::print_string(vm, args[0]),
+    "Output.printInt" => output::print_int(vm, args[0]),
+    "Output.println" => output::println(vm),
+    "Output.backSpace" => output::back_space(vm),
+    "Screen.init" => {
+      vm.os.black = true;
+      Ok(0)
+    }
+    "Screen.clearScreen" => screen::clear(vm),
+    "Screen.setColor" => {
+      vm.os.black = args[0] != 0;
+      Ok(0)
+    }
+    "Screen.drawPixel" => screen::draw_pixel(vm, arg(0), arg(1)),
+    "Screen.drawLine" => screen::draw_line(vm, arg(0), arg(1), arg(2), arg(3)),
+    "Screen.drawRectangle" => screen::draw_rectangle(vm, arg(0), arg(1), arg(2), arg(3)),
+    "Screen.drawCircle" => screen::draw_circle(vm, arg(0), arg(1), arg(2)),
+    "String.new" => string_new(vm, arg(0)),
+    "String.dispose" => vm.call("Memory.deAlloc", &[args[0]]),
+    "String.length" => peek(vm, arg(0) + 1),
+    "String.charAt" => char_at(vm, arg(0), arg(1)),
+    "String.setCharAt" => set_char_at(vm, arg(0), arg(1), arg(2)),
+    "String.appendChar" => append_char(vm, arg(0), arg(1)),
+    "String.eraseLastChar" => erase_last_char(vm, arg(0)),
+    "String.intValue" => read_string(vm, args[0]).map(|text| int_value(&text)),
+    "String.setInt" => set_int(vm, arg(0), args[1]),
+    "String.newLine" => Ok(NEW_LINE),
+    "String.backSpace" => Ok(BACKSPACE),
+    "String.doubleQuote" => Ok(DOUBLE_QUOTE),
+    "Sys.halt" => vm.halt("Sys.halt was called"),
+    "Sys.error" => sys_error(vm, args[0]),
+    // there's no point making the program wait
+    "Sys.wait" if args[0] < 0 => error(vm, 1),
+    "Sys.wait" => Ok(0),
+    _ => unreachable!("{} is missing from FUNCTIONS", function),
+  })
+}
+
+/// The Java OS's descriptions of the codes it passes `Sys.error`
+fn describe(code: i16) -> &'static str {
+  match code {
+    1 => "Duration must be positive",
+    2 => "Array size must be positive",
+    3 => "Division by zero",
+    4 => "Cannot compute square root of a negative number",
+    5 => "Allocated memory size must be positive",
+    6 => "Heap overflow",
+    7 => "Illegal pixel coordinates",
+    8 => "Illegal line coordinates",
+    9 => "Illegal rectangle coordinates",
+    12 => "Illegal center coordinates",
+    13 => "Illegal radius",
+    14 => "Maximum length must be non-negative",
+    15 | 16 => "String index out of bounds",
+    17 => "String is full",
+    18 => "String is empty",
+    19 => "Insufficient string capacity",
+    20 => "Illegal cursor location",
+    _ => "Unknown error",
+  }
+}
+
+/// Prints `ERR` and the code where the cursor is, and halts
+fn sys_error(vm: &mut Vm, code: i16) -> Result<i16, Trap> {
+  let err = new_string(vm, "ERR")?;
+  vm.call("Output.printString", &[err])?;
+  vm.call("Output.printInt", &[code])?;
+  vm.halt(&format!("Sys.error {}: {}", code, describe(code)))
+}
+
+/// Calls `Sys.error`, returning 0 in case a replacement returns instead of
+/// halting
+fn error(vm: &mut Vm, code: i16) -> Result<i16, Trap> {
+  vm.call("Sys.error", &[code])?;
+  Ok(0)
+}
+
+fn peek(vm: &mut Vm, address: i32) -> Result<i16, Trap> {
+  Ok(vm.load(ram_address(address)?)?)
+}
+
+fn poke(vm: &mut Vm, address: i32, value: i32) -> Result<i16, Trap> {
+  vm.store(ram_address(address)?, value as i16)?;
+  Ok(0)
+}
+
+fn ram_address(address: i32) -> Result<usize, Trap> {
+  if address < 0 {
+    Err(Trap::Error(format!("address {} is outside RAM", address)))
+  } else {
+    Ok(address as usize)
+  }
+}
+
+fn divide(vm: &mut Vm, x: i32, y: i32) -> Result<i16, Trap> {
+  if y == 0 {
+    return error(vm, 3);
+  }
+  Ok((x / y) as i16)
+}
+
+fn sqrt(vm: &mut Vm, x: i16) -> Result<i16, Trap> {
+  if x < 0 {
+    return error(vm, 4);
+  }
+  Ok(f64::from(x).sqrt() as i16)
+}
+
+/// The heap starts as one free block of all of it. Each block has its size
+/// in its first word, 0 once allocated, and the address of the next block
+/// in its second.
+fn memory_init(vm: &mut Vm) -> Result<i16, Trap> {
+  poke(vm, HEAP, HEAP_END - HEAP - 1)?;
+  poke(vm, HEAP + 1, HEAP_END + 1)
+}
+
+/// First fit, splitting the block found if there's room for another
+fn alloc(vm: &mut Vm, size: i32) -> Result<i16, Trap> {
+  if size < 1 {
+    return error(vm, 5);
+  }
+
+  let mut block = HEAP;
+  let mut free = 0;
+  while block <= HEAP_END {
+    free = peek(vm, block)? as i32;
+    if free >= size {
+      break;
+    }
+    block = peek(vm, block + 1)? as i32;
+  }
+  if block > HEAP_END {
+    return error(vm, 6);
+  }
+
+  if free > size + 2 {
+    let rest = block + size + 2;
+    poke(vm, rest, free - size - 2)?;
+    let next = peek(vm, block + 1)? as i32;
+    poke(vm, rest + 1, next)?;
+    poke(vm, block + 1, rest)?;
+  }
+  poke(vm, block, 0)?;
+  Ok((block + 2) as i16)
+}
+
+/// Frees the block, merging it with the next if that one is free too
+fn de_alloc(vm: &mut Vm, object: i32) -> Result<i16, Trap> {
+  let block = object - 2;
+  let next = peek(vm, block + 1)? as i32;
+  let next_free = match next {
+    next if next > HEAP_END => 0,
+    next => peek(vm, next)? as i32,
+  };
+
+  if next_free == 0 {
+    poke(vm, block, next - block - 2)
+  } else {
+    poke(vm, block, next - block + next_free)?;
+    let after = peek(vm, next + 1)? as i32;
+    poke(vm, block + 1, after)
+  }
+}
+
+fn array_new(vm: &mut Vm, size: i32) -> Result<i16, Trap> {
+  if size <= 0 {
+    return error(vm, 2);
+  }
+  vm.call("Memory.alloc", &[size as i16])
+}
+
+// Strings keep their capacity in their first word, their length in the
+// second and their characters after that.
+
+fn string_new(vm: &mut Vm, capacity: i32) -> Result<i16, Trap> {
+  if capacity < 0 {
+    return error(vm, 14);
+  }
+
+  let string = vm.call("Memory.alloc", &[(capacity + 2) as i16])? as i32;
+  poke(vm, string, capacity)?;
+  poke(vm, string + 1, 0)?;
+  Ok(string as i16)
+}
+
+/// A Jack string of `text`, made by calling `String.new` and `appendChar`
+pub(crate) fn new_string(vm: &mut Vm, text: &str) -> Result<i16, Trap> {
+  let capacity = text.chars().count().max(1);
+  let string = vm.call("String.new", &[capacity as i16])?;
+
+  for c in text.chars() {
+    vm.call("String.appendChar", &[string, c as i16])?;
+  }
+  Ok(string)
+}
+
+/// The characters of a Jack string, read with `String.length` and `charAt`
+fn read_string(vm: &mut Vm, string: i16) -> Result<String, Trap> {
+  let length = vm.call("String.length", &[string])?;
+
+  (0..length)
+    .map(|i| Ok(vm.call("String.charAt", &[string, i])? as u8 as char))
+    .collect()
+}
+
+fn char_at(vm: &mut Vm, string: i32, index: i32) -> Result<i16, Trap> {
+  if index < 0 || index >= peek(vm, string + 1)? as i32 {
+    return error(vm, 15);
+  }
+  peek(vm, string + 2 + index)
+}
+
+fn set_char_at(vm: &mut Vm, string: i32, index: i32, c: i32) -> Result<i16, Trap> {
+  if index < 0 || index >= peek(vm, string + 1)? as i32 {
+    return error(vm, 16);
+  }
+  poke(vm, string + 2 + index, c)
+}
+
+fn append_char(vm: &mut Vm, string: i32, c: i32) -> Result<i16, Trap> {
+  let length = peek(vm, string + 1)? as i32;
+  if length == peek(vm, string)? as i32 {
+    return error(vm, 17);
+  }
+
+  poke(vm, string + 2 + length, c)?;
+  poke(vm, string + 1, length + 1)?;
+  Ok(string as i16)
+}
+
+fn erase_last_char(vm: &mut Vm, string: i32) -> Result<i16, Trap> {
+  let length = peek(vm, string + 1)? as i32;
+  if length == 0 {
+    return error(vm, 18);
+  }
+  poke(vm, string + 1, length - 1)
+}
+
+fn set_int(vm: &mut Vm, string: i32, value: i16) -> Result<i16, Trap> {
+  let digits = value.to_string();
+  if (peek(vm, string)? as usize) < digits.len() {
+    return error(vm, 19);
+  }
+
+  for (i, c) in digits.bytes().enumerate() {
+    poke(vm, string + 2 + i as i32, c as i32)?;
+  }
+  poke(vm, string + 1, digits.len() as i32)
+}
+
+/// The number the text starts with, after an optional minus sign
+fn int_value(text: &str) -> i16 {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text),
+  };
+  let value = digits
+    .bytes()
+    .take_while(u8::is_ascii_digit)
+    .fold(0i16, |value, digit| {
+      value.wrapping_mul(10).wrapping_add((digit - b'0') as i16)
+    });
+
+  if negative {
+    value.wrapping_neg()
+  } else {
+    value
+  }
+}
+
+/// The key held down, or the next one the program is to be given
+fn key_pressed(vm: &mut Vm) -> Result<i16, Trap> {
+  match peek(vm, KEYBOARD)? {
+    0 => Ok(vm.input.front().copied().unwrap_or(0)),
+    key => Ok(key),
+  }
+}
+
+/// Takes the next key of the input, as there's nobody to press one
+fn next_key(vm: &mut Vm) -> Result<i16, Trap> {
+  vm.input.pop_front().ok_or_else(|| {
+    Trap::Error(String::from(
+      "the program is waiting for a key, but there's no more input",
+    ))
+  })
+}
+
+/// Shows the cursor while waiting for a key, then echoes it
+fn read_char(vm: &mut Vm) -> Result<i16, Trap> {
+  vm.call("Output.printChar", &[0])?;
+  let key = next_key(vm)?;
+  vm.call("Output.printChar", &[BACKSPACE])?;
+  vm.call("Output.printChar", &[key])?;
+  Ok(key)
+}
+
+/// Prints the message, then echoes keys until a new line, erasing one for
+/// each backspace
+fn read_line(vm: &mut Vm, message: i16) -> Result<String, Trap> {
+  vm.call("Output.printString", &[message])?;
+  vm.call("Output.printChar", &[0])?;
+
+  let mut line = String::new();
+  loop {
+    match next_key(vm)? {
+      NEW_LINE => {
+        vm.call("Output.printChar", &[BACKSPACE])?;
+        vm.call("Output.printChar", &[NEW_LINE])?;
+        return Ok(line);
+      }
+      BACKSPACE if line.is_empty() => (),
+      BACKSPACE => {
+        vm.call("Output.printChar", &[BACKSPACE])?;
+        vm.call("Output.printChar", &[BACKSPACE])?;
+        vm.call("Output.printChar", &[0])?;
+        line.pop();
+      }
+      key => {
+        vm.call("Output.printChar", &[BACKSPACE])?;
+        vm.call("Output.printChar", &[key])?;
+        vm.call("Output.printChar", &[0])?;
+        line.push(key as u8 as char);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  fn boot(vm: &str) -> Vm {
+    let mut vm = Vm::new(&[parse_file(vm, "Main").unwrap()]);
+    vm.boot();
+    vm
+  }
+
+  fn run(vm: &mut Vm) {
+    while !vm.is_halted() {
+      vm.step().unwrap();
+    }
+  }
+
+  #[test]
+  fn allocates_as_the_java_os_does() {
+    let mut vm = boot(
+      "function Main.main 0\n\
+       push constant 3\ncall Memory.alloc 1\npop static 0\n\
+       push constant 5\ncall Array.new 1\npop static 1\n\
+       push static 0\ncall Memory.deAlloc 1\npop temp 0\n\
+       push constant 2\ncall Memory.alloc 1\npop static 2\n\
+       push constant 0\nreturn",
+    );
+    run(&mut vm);
+
+    assert_eq!(vm.halted(), Some("Sys.halt was called"));
+    assert_eq!(&vm.ram[16..19], &[2050, 2055, 2050]);
+    assert_eq!(&vm.ram[2048..2050], &[0, 2053]);
+    assert_eq!(&vm.ram[2053..2055], &[0, 2060]);
+    assert_eq!(&vm.ram[2060..2062], &[14334 - 12, 16384]);
+  }
+
+  #[test]
+  fn prints_strings_on_the_screen() {
+    let mut vm = boot(
+      "function Main.main 0\n\
+       push constant 2\ncall String.new 1\n\
+       push constant 65\ncall String.appendChar 2\n\
+       push constant 66\ncall String.appendChar 2\n\
+       call Output.printString 1\npop temp 0\n\
+       push constant 7\ncall Math.sqrt 1\npush constant 0\ncall Math.divide 2\n\
+       return",
+    );
+    run(&mut vm);
+
+    // A and B side by side in the first word, a row down the screen
+    let word = |row: usize| vm.ram[16384 + 32 + 32 * row] as u16;
+    assert_eq!(word(0), 12 | 31 << 8);
+    assert_eq!(word(1), 30 | 51 << 8);
+    assert_eq!(vm.halted(), Some("Sys.error 3: Division by zero"));
+    // and ERR3 after them
+    assert_eq!(vm.ram[16384 + 33] as u16, 63 | 31 << 8);
+  }
+
+  #[test]
+  fn uses_functions_the_program_defines_instead() {
+    let mut vm = boot(
+      "function Main.main 0\n\
+       push constant 6\npush constant 7\ncall Math.multiply 2\npop static 0\n\
+       push constant 0\ncall String.new 1\ncall Keyboard.readLine 1\npop static 1\n\
+       push static 1\ncall String.intValue 1\npop static 2\n\
+       push constant 0\nreturn\n\
+       function Math.multiply 0\npush constant 1\nreturn\n\
+       function Memory.alloc 0\npush constant 3000\nreturn",
+    );
+    vm.input
+      .extend("-12x\u{81}3\u{80}".chars().map(|c| c as i16));
+    run(&mut vm);
+
+    assert_eq!(&vm.ram[16..19], &[1, 3000, -123]);
+    // the line went where our Memory.alloc put it, over the empty message
+    assert_eq!(&vm.ram[3000..3006], &[4, 4, 45, 49, 50, 51]);
+    assert!(vm.input.is_empty());
+  }
+}
@@ -0,0 +1,245 @@
+//! `Output`, which prints characters in 23 rows of 64 columns, each 11
+//! pixels high and 8 wide, so that a word of the screen holds two
+
+use super::{error, peek, poke, BACKSPACE, NEW_LINE, SCREEN};
+use crate::interpreter::{Trap, Vm};
+
+/// The words of the screen a row of characters takes
+const ROW: i32 = 11 * 32;
+/// Where the cursor's line starts, past the screen's first row of pixels
+const TOP: i32 = 32;
+/// Where the line past the last one starts
+const BOTTOM: i32 = TOP + 23 * ROW;
+
+/// Where the cursor is
+pub(crate) struct Output {
+  /// The word of the screen the cursor's character starts in
+  address: i32,
+  /// The word of the line the cursor is in
+  word_in_line: i32,
+  /// Whether the cursor is in the left half of the word, its low byte
+  first_in_word: bool,
+}
+
+impl Default for Output {
+  fn default() -> Output {
+    Output {
+      address: TOP,
+      word_in_line: 0,
+      first_in_word: true,
+    }
+  }
+}
+
+/// The bitmaps of the characters, 11 rows of 8 pixels each, the low bit
+/// leftmost: the black square `Output` prints for anything it has no
+/// glyph for, then 32 (space) to 126 (`~`)
+const FONT: [[u8; 11]; 96] = [
+  [63, 63, 63, 63, 63, 63, 63, 63, 63, 0, 0],  // square
+  [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],           // ' '
+  [12, 30, 30, 30, 12, 12, 0, 12, 12, 0, 0],   // '!'
+  [54, 54, 20, 0, 0, 0, 0, 0, 0, 0, 0],        // '"'
+  [0, 18, 18, 63, 18, 18, 63, 18, 18, 0, 0],   // '#'
+  [12, 30, 51, 3, 30, 48, 51, 30, 12, 12, 0],  // '$'
+  [0, 0, 35, 51, 24, 12, 6, 51, 49, 0, 0],     // '%'
+  [12, 30, 30, 12, 54, 27, 27, 27, 54, 0, 0],  // '&'
+  [12, 12, 6, 0, 0, 0, 0, 0, 0, 0, 0],         // '\''
+  [24, 12, 6, 6, 6, 6, 6, 12, 24, 0, 0],       // '('
+  [6, 12, 24, 24, 24, 24, 24, 12, 6, 0, 0],    // ')'
+  [0, 0, 0, 51, 30, 63, 30, 51, 0, 0, 0],      // '*'
+  [0, 0, 0, 12, 12, 63, 12, 12, 0, 0, 0],      // '+'
+  [0, 0, 0, 0, 0, 0, 0, 12, 12, 6, 0],         // ','
+  [0, 0, 0, 0, 0, 63, 0, 0, 0, 0, 0],          // '-'
+  [0, 0, 0, 0, 0, 0, 0, 12, 12, 0, 0],         // '.'
+  [0, 0, 32, 48, 24, 12, 6, 3, 1, 0, 0],       // '/'
+  [12, 30, 51, 51, 51, 51, 51, 30, 12, 0, 0],  // '0'
+  [12, 14, 15, 12, 12, 12, 12, 12, 63, 0, 0],  // '1'
+  [30, 51, 48, 24, 12, 6, 3, 51, 63, 0, 0],    // '2'
+  [30, 51, 48, 48, 28, 48, 48, 51, 30, 0, 0],  // '3'
+  [16, 24, 28, 26, 25, 63, 24, 24, 60, 0, 0],  // '4'
+  [63, 3, 3, 31, 48, 48, 48, 51, 30, 0, 0],    // '5'
+  [28, 6, 3, 3, 31, 51, 51, 51, 30, 0, 0],     // '6'
+  [63, 49, 48, 48, 24, 12, 12, 12, 12, 0, 0],  // '7'
+  [30, 51, 51, 51, 30, 51, 51, 51, 30, 0, 0],  // '8'
+  [30, 51, 51, 51, 62, 48, 48, 24, 14, 0, 0],  // '9'
+  [0, 0, 12, 12, 0, 0, 12, 12, 0, 0, 0],       // ':'
+  [0, 0, 12, 12, 0, 0, 12, 12, 6, 0, 0],       // ';'
+  [0, 0, 24, 12, 6, 3, 6, 12, 24, 0, 0],       // '<'
+  [0, 0, 0, 63, 0, 0, 63, 0, 0, 0, 0],         // '='
+  [0, 0, 3, 6, 12, 24, 12, 6, 3, 0, 0],        // '>'
+  [30, 51, 51, 24, 12, 12, 0, 12, 12, 0, 0],   // '?'
+  [30, 51, 51, 59, 59, 59, 27, 3, 30, 0, 0],   // '@'
+  [12, 30, 51, 51, 63, 51, 51, 51, 51, 0, 0],  // 'A'
+  [31, 51, 51, 51, 31, 51, 51, 51, 31, 0, 0],  // 'B'
+  [28, 54, 35, 3, 3, 3, 35, 54, 28, 0, 0],     // 'C'
+  [15, 27, 51, 51, 51, 51, 51, 27, 15, 0, 0],  // 'D'
+  [63, 51, 35, 11, 15, 11, 35, 51, 63, 0, 0],  // 'E'
+  [63, 51, 35, 11, 15, 11, 3, 3, 3, 0, 0],     // 'F'
+  [28, 54, 35, 3, 59, 51, 51, 54, 44, 0, 0],   // 'G'
+  [51, 51, 51, 51, 63, 51, 51, 51, 51, 0, 0],  // 'H'
+  [30, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0],  // 'I'
+  [60, 24, 24, 24, 24, 24, 27, 27, 14, 0, 0],  // 'J'
+  [51, 51, 51, 27, 15, 27, 51, 51, 51, 0, 0],  // 'K'
+  [3, 3, 3, 3, 3, 3, 35, 51, 63, 0, 0],        // 'L'
+  [33, 51, 63, 63, 51, 51, 51, 51, 51, 0, 0],  // 'M'
+  [51, 51, 55, 55, 63, 59, 59, 51, 51, 0, 0],  // 'N'
+  [30, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0],  // 'O'
+  [31, 51, 51, 51, 31, 3, 3, 3, 3, 0, 0],      // 'P'
+  [30, 51, 51, 51, 51, 51, 63, 59, 30, 48, 0], // 'Q'
+  [31, 51, 51, 51, 31, 27, 51, 51, 51, 0, 0],  // 'R'
+  [30, 51, 51, 6, 28, 48, 51, 51, 30, 0, 0],   // 'S'
+  [63, 63, 45, 12, 12, 12, 12, 12, 30, 0, 0],  // 'T'
+  [51, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0],  // 'U'
+  [51, 51, 51, 51, 51, 30, 30, 12, 12, 0, 0],  // 'V'
+  [51, 51, 51, 51, 51, 63, 63, 63, 18, 0, 0],  // 'W'
+  [51, 51, 30, 30, 12, 30, 30, 51, 51, 0, 0],  // 'X'
+  [51, 51, 51, 51, 30, 12, 12, 12, 30, 0, 0],  // 'Y'
+  [63, 51, 49, 24, 12, 6, 35, 51, 63, 0, 0],   // 'Z'
+  [30, 6, 6, 6, 6, 6, 6, 6, 30, 0, 0],         // '['
+  [0, 0, 1, 3, 6, 12, 24, 48, 32, 0, 0],       // '\\'
+  [30, 24, 24, 24, 24, 24, 24, 24, 30, 0, 0],  // ']'
+  [8, 28, 54, 0, 0, 0, 0, 0, 0, 0, 0],         // '^'
+  [0, 0, 0, 0, 0, 0, 0, 0, 0, 63, 0],          // '_'
+  [6, 12, 24, 0, 0, 0, 0, 0, 0, 0, 0],         // '`'
+  [0, 0, 0, 14, 24, 30, 27, 27, 54, 0, 0],     // 'a'
+  [3, 3, 3, 15, 27, 51, 51, 51, 30, 0, 0],     // 'b'
+  [0, 0, 0, 30, 51, 3, 3, 51, 30, 0, 0],       // 'c'
+  [48, 48, 48, 60, 54, 51, 51, 51, 30, 0, 0],  // 'd'
+  [0, 0, 0, 30, 51, 63, 3, 51, 30, 0, 0],      // 'e'
+  [28, 54, 38, 6, 15, 6, 6, 6, 15, 0, 0],      // 'f'
+  [0, 0, 30, 51, 51, 51, 62, 48, 51, 30, 0],   // 'g'
+  [3, 3, 3, 27, 55, 51, 51, 51, 51, 0, 0],     // 'h'
+  [12, 12, 0, 14, 12, 12, 12, 12, 30, 0, 0],   // 'i'
+  [48, 48, 0, 56, 48, 48, 48, 48, 51, 30, 0],  // 'j'
+  [3, 3, 3, 51, 27, 15, 15, 27, 51, 0, 0],     // 'k'
+  [14, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0],  // 'l'
+  [0, 0, 0, 29, 63, 43, 43, 43, 43, 0, 0],     // 'm'
+  [0, 0, 0, 29, 51, 51, 51, 51, 51, 0, 0],     // 'n'
+  [0, 0, 0, 30, 51, 51, 51, 51, 30, 0, 0],     // 'o'
+  [0, 0, 0, 30, 51, 51, 51, 31, 3, 3, 0],      // 'p'
+  [0, 0, 0, 30, 51, 51, 51, 62, 48, 48, 0],    // 'q'
+  [0, 0, 0, 29, 55, 51, 3, 3, 7, 0, 0],        // 'r'
+  [0, 0, 0, 30, 51, 6, 24, 51, 30, 0, 0],      // 's'
+  [4, 6, 6, 15, 6, 6, 6, 54, 28, 0, 0],        // 't'
+  [0, 0, 0, 27, 27, 27, 27, 27, 54, 0, 0],     // 'u'
+  [0, 0, 0, 51, 51, 51, 51, 30, 12, 0, 0],     // 'v'
+  [0, 0, 0, 51, 51, 51, 63, 63, 18, 0, 0],     // 'w'
+  [0, 0, 0, 51, 30, 12, 12, 30, 51, 0, 0],     // 'x'
+  [0, 0, 0, 51, 51, 51, 62, 48, 24, 15, 0],    // 'y'
+  [0, 0, 0, 63, 27, 12, 6, 51, 63, 0, 0],      // 'z'
+  [56, 12, 12, 12, 7, 12, 12, 12, 56, 0, 0],   // '{'
+  [12, 12, 12, 12, 12, 12, 12, 12, 12, 0, 0],  // '|'
+  [7, 12, 12, 12, 56, 12, 12, 12, 7, 0, 0],    // '}'
+  [38, 45, 25, 0, 0, 0, 0, 0, 0, 0, 0],        // '~'
+];
+
+/// Draws the character at the cursor, leaving the cursor where it is
+fn draw_char(vm: &mut Vm, c: i16) -> Result<(), Trap> {
+  let glyph = match c {
+    32..=126 => &FONT[c as usize - 31],
+    _ => &FONT[0],
+  };
+  // the left character is the low byte
+  let (mask, shift) = if vm.os.output.first_in_word {
+    (0xFF00, 0)
+  } else {
+    (0x00FF, 8)
+  };
+  let mut address = SCREEN + vm.os.output.address;
+
+  for row in glyph {
+    let word = peek(vm, address)? as i32;
+    poke(vm, address, (word & mask) | (*row as i32) << shift)?;
+    address += 32;
+  }
+  Ok(())
+}
+
+pub(super) fn move_cursor(vm: &mut Vm, row: i32, column: i32) -> Result<i16, Trap> {
+  if !(0..23).contains(&row) || !(0..64).contains(&column) {
+    return error(vm, 20);
+  }
+
+  let output = &mut vm.os.output;
+  output.word_in_line = column / 2;
+  output.address = TOP + row * ROW + output.word_in_line;
+  output.first_in_word = column % 2 == 0;
+  draw_char(vm, 32)?;
+  Ok(0)
+}
+
+/// Prints the character and moves on, to the next line past the last
+/// column, or does what a new line or backspace does
+pub(super) fn print_char(vm: &mut Vm, c: i16) -> Result<i16, Trap> {
+  match c {
+    NEW_LINE => return println(vm),
+    BACKSPACE => return back_space(vm),
+    _ => draw_char(vm, c)?,
+  }
+
+  let output = &mut vm.os.output;
+  if output.first_in_word {
+    output.first_in_word = false;
+  } else {
+    output.word_in_line += 1;
+    output.address += 1;
+    if output.word_in_line == 32 {
+      return println(vm);
+    }
+    output.first_in_word = true;
+  }
+  Ok(0)
+}
+
+pub(super) fn print_string(vm: &mut Vm, string: i16) -> Result<i16, Trap> {
+  let length = vm.call("String.length", &[string])?;
+  for i in 0..length {
+    let c = vm.call("String.charAt", &[string, i])?;
+    print_char(vm, c)?;
+  }
+  Ok(0)
+}
+
+pub(super) fn print_int(vm: &mut Vm, value: i16) -> Result<i16, Trap> {
+  for c in value.to_string().bytes() {
+    print_char(vm, c as i16)?;
+  }
+  Ok(0)
+}
+
+/// Moves the cursor to the start of the next line, or of the first past
+/// the last
+pub(super) fn println(vm: &mut Vm) -> Result<i16, Trap> {
+  let output = &mut vm.os.output;
+  output.address += ROW - output.word_in_line;
+  output.word_in_line = 0;
+  output.first_in_word = true;
+  if output.address == BOTTOM {
+    output.address = TOP;
+  }
+  Ok(0)
+}
+
+/// Moves the cursor back a column, to the end of the line before from the
+/// start of one, and erases the character there
+pub(super) fn back_space(vm: &mut Vm) -> Result<i16, Trap> {
+  let output = &mut vm.os.output;
+  if output.first_in_word {
+    if output.word_in_line > 0 {
+      output.word_in_line -= 1;
+      output.address -= 1;
+    } else {
+      output.word_in_line = 31;
+      if output.address == TOP {
+        output.address = BOTTOM;
+      }
+      output.address -= ROW - 31;
+    }
+    output.first_in_word = false;
+  } else {
+    output.first_in_word = true;
+  }
+
+  draw_char(vm, 32)?;
+  Ok(0)
+}
@@ -0,0 +1,148 @@
+//! `Screen`, which draws on the 512 by 256 pixels of the screen's memory
+//! map, 16 to a word with the low bit leftmost
+
+use super::{error, peek, poke, SCREEN};
+use crate::interpreter::{Trap, Vm};
+
+const WIDTH: i32 = 512;
+const HEIGHT: i32 = 256;
+
+pub(super) fn clear(vm: &mut Vm) -> Result<i16, Trap> {
+  for address in SCREEN..SCREEN + HEIGHT * 32 {
+    poke(vm, address, 0)?;
+  }
+  Ok(0)
+}
+
+/// Sets the bits of `mask` in the screen's word at `address` to the color
+fn update(vm: &mut Vm, address: i32, mask: i32) -> Result<(), Trap> {
+  let word = peek(vm, SCREEN + address)? as i32;
+  let word = if vm.os.black {
+    word | mask
+  } else {
+    word & !mask
+  };
+  poke(vm, SCREEN + address, word)?;
+  Ok(())
+}
+
+fn on_screen(x: i32, y: i32) -> bool {
+  (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y)
+}
+
+pub(super) fn draw_pixel(vm: &mut Vm, x: i32, y: i32) -> Result<i16, Trap> {
+  if !on_screen(x, y) {
+    return error(vm, 7);
+  }
+  update(vm, (y * WIDTH + x) >> 4, 1 << (x & 15))?;
+  Ok(0)
+}
+
+/// Bresenham's, stepping along whichever of x and y changes most
+pub(super) fn draw_line(vm: &mut Vm, x1: i32, y1: i32, x2: i32, y2: i32) -> Result<i16, Trap> {
+  if !on_screen(x1, y1) || !on_screen(x2, y2) {
+    return error(vm, 8);
+  }
+
+  let (mut dx, mut dy) = ((x2 - x1).abs(), (y2 - y1).abs());
+  let transposed = dx < dy;
+  let ((x1, y1), (x2, y2)) = if (transposed && y2 < y1) || (!transposed && x2 < x1) {
+    ((x2, y2), (x1, y1))
+  } else {
+    ((x1, y1), (x2, y2))
+  };
+
+  // a steps by one to the end, and b by `step` as it falls behind
+  let (mut a, mut b, end, step);
+  if transposed {
+    std::mem::swap(&mut dx, &mut dy);
+    a = y1;
+    b = x1;
+    end = y2;
+    step = if x1 > x2 { -1 } else { 1 };
+  } else {
+    a = x1;
+    b = y1;
+    end = x2;
+    step = if y1 > y2 { -1 } else { 1 };
+  }
+  let draw = |vm: &mut Vm, a: i32, b: i32| {
+    if transposed {
+      update(vm, (a * WIDTH + b) >> 4, 1 << (b & 15))
+    } else {
+      update(vm, (b * WIDTH + a) >> 4, 1 << (a & 15))
+    }
+  };
+
+  draw(vm, a, b)?;
+  let mut e = 2 * dy - dx;
+  while a < end {
+    if e < 0 {
+      e += 2 * dy;
+    } else {
+      e += 2 * (dy - dx);
+      b += step;
+    }
+    a += 1;
+    draw(vm, a, b)?;
+  }
+  Ok(0)
+}
+
+pub(super) fn draw_rectangle(vm: &mut Vm, x1: i32, y1: i32, x2: i32, y2: i32) -> Result<i16, Trap> {
+  if x1 > x2 || y1 > y2 || !on_screen(x1, y1) || !on_screen(x2, y2) {
+    return error(vm, 9);
+  }
+
+  for y in y1..=y2 {
+    draw_row(vm, y, x1, x2)?;
+  }
+  Ok(0)
+}
+
+/// Fills a row from x1 to x2, a word at a time between the partial words
+/// at the ends
+fn draw_row(vm: &mut Vm, y: i32, x1: i32, x2: i32) -> Result<(), Trap> {
+  let first = 0xFFFF << (x1 & 15);
+  let last = 0xFFFF >> (15 - (x2 & 15));
+  let start = y * 32 + (x1 >> 4);
+  let end = y * 32 + (x2 >> 4);
+
+  if start == end {
+    return update(vm, start, first & last);
+  }
+  update(vm, start, first)?;
+  for address in start + 1..end {
+    update(vm, address, 0xFFFF)?;
+  }
+  update(vm, end, last)
+}
+
+/// Fills the circle a pair of rows at a time, from the middle out
+pub(super) fn draw_circle(vm: &mut Vm, x: i32, y: i32, r: i32) -> Result<i16, Trap> {
+  if !on_screen(x, y) {
+    return error(vm, 12);
+  }
+  if !on_screen(x - r, y - r) || !on_screen(x + r, y + r) {
+    return error(vm, 13);
+  }
+
+  let (mut a, mut b, mut d) = (0, r, 1 - r);
+  loop {
+    draw_row(vm, y - b, x - a, x + a)?;
+    draw_row(vm, y + b, x - a, x + a)?;
+    draw_row(vm, y - a, x - b, x + b)?;
+    draw_row(vm, y + a, x - b, x + b)?;
+    if b <= a {
+      return Ok(0);
+    }
+
+    if d < 0 {
+      d += 2 * a + 3;
+    } else {
+      d += 2 * (a - b) + 5;
+      b -= 1;
+    }
+    a += 1;
+  }
+}
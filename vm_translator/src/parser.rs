@@ -1,46 +1,38 @@
+use crate::error::{ErrorKind, TranslateError};
+use std::fmt;
+use std::iter::Enumerate;
+use std::rc::Rc;
 use std::str::Lines;
 
+/// Yields the commands of one .vm file, skipping blank lines and comments
 pub struct Parser<'a> {
-  lines: Lines<'a>,
+  lines: Enumerate<Lines<'a>>,
+  file: Rc<str>,
 }
 
 impl<'a> Parser<'a> {
   pub fn new(file_contents: &'a str) -> Parser<'a> {
-    Parser {
-      lines: file_contents.lines(),
-    }
+    Parser::with_file(file_contents, "")
   }
 
-  pub fn has_more_commands(&self) -> bool {
-    // This seems suboptimal?
-    let mut lookahead = self.lines.clone().peekable();
-    match lookahead.peek() {
-      Some(_v) => return true,
-      None => return false,
+  /// Creates a parser whose spans name `file`, e.g. `Main.vm`
+  pub fn with_file(file_contents: &'a str, file: &str) -> Parser<'a> {
+    Parser {
+      lines: file_contents.lines().enumerate(),
+      file: Rc::from(file),
     }
   }
 
-  pub fn advance(&mut self) -> &str {
-    self
-      .lines
-      .next()
-      .expect("cannot advance, no more lines")
-      .trim()
-  }
-
-  pub fn command_type(line: &str) -> CommandType {
+  pub fn command_type(line: &str) -> Result<CommandType, ErrorKind> {
     use BranchingCommand::*;
     use CommandType::*;
     use FunctionCommand::*;
     use MathCommand::*;
     use MemoryCommand::*;
 
-    let command = line
-      .split_whitespace()
-      .nth(0)
-      .expect(&format!("invalid line: '{}'", line));
+    let command = line.split_whitespace().next().unwrap_or("");
 
-    match command {
+    let command_type = match command {
       "add" => Math(Add),
       "sub" => Math(Subtract),
       "neg" => Math(Negate),
@@ -50,6 +42,14 @@ impl<'a> Parser<'a> {
       "and" => Math(And),
       "or" => Math(Or),
       "not" => Math(Not),
+      "mul" => Math(Multiply),
+      "div" => Math(Divide),
+      "mod" => Math(Modulo),
+      "shl" => Math(ShiftLeft),
+      "shr" => Math(ShiftRight),
+      "xor" => Math(Xor),
+      "lt_u" => Math(LessThanUnsigned),
+      "gt_u" => Math(GreaterThanUnsigned),
 
       "push" => Memory(Push),
       "pop" => Memory(Pop),
@@ -62,46 +62,210 @@ impl<'a> Parser<'a> {
       "call" => Function(Call),
       "return" => Function(Return),
 
-      _ => panic!("invalid command: '{}'", line),
-    }
+      _ => return Err(ErrorKind::UnknownCommand(command.to_string())),
+    };
+
+    Ok(command_type)
   }
 
-  pub fn segment_type(segment: &str) -> MemorySegment {
+  pub fn segment_type(segment: &str) -> Result<MemorySegment, ErrorKind> {
     use MemorySegment::*;
 
     match segment {
-      "argument" => Argument,
-      "local" => Local,
-      "static" => Static,
-      "constant" => Constant,
-      "this" => This,
-      "that" => That,
-      "pointer" => Pointer,
-      "temp" => Temp,
-      _ => panic!("invalid memory segment type: '{}'", segment),
+      "argument" => Ok(Argument),
+      "local" => Ok(Local),
+      "static" => Ok(Static),
+      "constant" => Ok(Constant),
+      "this" => Ok(This),
+      "that" => Ok(That),
+      "pointer" => Ok(Pointer),
+      "temp" => Ok(Temp),
+      _ => Err(ErrorKind::UnknownSegment(segment.to_string())),
+    }
+  }
+
+  /// Parses a single line with its comment already removed, checking that
+  /// it has exactly the operands its command takes and that they are in range
+  pub fn parse_command(line: &str) -> Result<VmCommand, ErrorKind> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let command_type = Parser::command_type(line)?;
+
+    let expected = match command_type {
+      CommandType::Math(_) | CommandType::Function(FunctionCommand::Return) => 0,
+      CommandType::Branching(_) => 1,
+      CommandType::Memory(_) | CommandType::Function(_) => 2,
+    };
+
+    if tokens.len() <= expected {
+      return Err(ErrorKind::MissingArgument {
+        command: tokens[0].to_string(),
+        expected,
+      });
+    }
+
+    if let Some(extra) = tokens.get(expected + 1) {
+      return Err(ErrorKind::UnexpectedArgument(extra.to_string()));
+    }
+
+    let number = |n: usize| -> Result<u16, ErrorKind> {
+      tokens[n]
+        .parse()
+        .map_err(|_| ErrorKind::InvalidNumber(tokens[n].to_string()))
+    };
+    let name = |n: usize| -> Result<String, ErrorKind> {
+      if is_name(tokens[n]) {
+        Ok(tokens[n].to_string())
+      } else {
+        Err(ErrorKind::InvalidName(tokens[n].to_string()))
+      }
+    };
+
+    let command = match command_type {
+      CommandType::Math(command) => VmCommand::Arithmetic(command),
+      CommandType::Memory(command) => {
+        let segment = Parser::segment_type(tokens[1])?;
+        let index = number(2)?;
+
+        if let Some(max) = segment.max_index() {
+          if index > max {
+            return Err(ErrorKind::IndexOutOfRange {
+              segment,
+              index,
+              max,
+            });
+          }
+        }
+
+        match command {
+          MemoryCommand::Push => VmCommand::Push { segment, index },
+          MemoryCommand::Pop if segment == MemorySegment::Constant => {
+            return Err(ErrorKind::PopConstant)
+          }
+          MemoryCommand::Pop => VmCommand::Pop { segment, index },
+        }
+      }
+      CommandType::Branching(command) => {
+        let label = name(1)?;
+
+        match command {
+          BranchingCommand::Label => VmCommand::Label(label),
+          BranchingCommand::Goto => VmCommand::Goto(label),
+          BranchingCommand::If => VmCommand::IfGoto(label),
+        }
+      }
+      CommandType::Function(FunctionCommand::Declare) => VmCommand::Function {
+        name: name(1)?,
+        nlocals: number(2)?,
+      },
+      CommandType::Function(FunctionCommand::Call) => VmCommand::Call {
+        name: name(1)?,
+        nargs: number(2)?,
+      },
+      CommandType::Function(FunctionCommand::Return) => VmCommand::Return,
+    };
+
+    Ok(command)
+  }
+}
+
+/// Labels and function names are letters, digits, `_`, `.`, `:` and `$`,
+/// not starting with a digit, so that they are also valid assembler symbols
+pub(crate) fn is_name(token: &str) -> bool {
+  match token.chars().next() {
+    Some(first) if !first.is_ascii_digit() => token
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "_.:$".contains(c)),
+    _ => false,
+  }
+}
+
+impl<'a> Iterator for Parser<'a> {
+  type Item = Result<SourceCommand, TranslateError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    for (index, line) in &mut self.lines {
+      let line = match line.find("//") {
+        Some(start) => &line[..start],
+        None => line,
+      }
+      .trim();
+
+      if line.is_empty() {
+        continue;
+      }
+
+      let command = Parser::parse_command(line).map_err(|kind| TranslateError {
+        file: self.file.to_string(),
+        line: Some(index + 1),
+        kind,
+      });
+
+      return Some(command.map(|command| SourceCommand {
+        command,
+        span: Span {
+          file: Rc::clone(&self.file),
+          line: index + 1,
+        },
+      }));
     }
+
+    None
   }
+}
+
+/// Where a command came from: a file name and 1-based line number
+#[derive(Clone, PartialEq, Debug)]
+pub struct Span {
+  pub file: Rc<str>,
+  pub line: usize,
+}
 
-  pub fn first_arg(line: &str) -> &str {
-    line
-      .split_whitespace()
-      .nth(1)
-      .expect(&format!("first argument required: '{}'", line))
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.file, self.line)
   }
+}
 
-  pub fn second_arg(line: &str) -> usize {
-    let argument = line
-      .split_whitespace()
-      .nth(2)
-      .expect(&format!("second argument required: '{}'", line));
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceCommand {
+  pub command: VmCommand,
+  pub span: Span,
+}
 
-    argument
-      .parse()
-      .expect(&format!("argument is not an integer: '{}'", line))
+/// A fully parsed VM command
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmCommand {
+  Arithmetic(MathCommand),
+  Push { segment: MemorySegment, index: u16 },
+  Pop { segment: MemorySegment, index: u16 },
+  Label(String),
+  Goto(String),
+  IfGoto(String),
+  Function { name: String, nlocals: u16 },
+  Call { name: String, nargs: u16 },
+  Return,
+}
+
+/// Prints the command as it would be written in a .vm file
+impl fmt::Display for VmCommand {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use VmCommand::*;
+
+    match self {
+      Arithmetic(command) => write!(f, "{}", command),
+      Push { segment, index } => write!(f, "push {} {}", segment, index),
+      Pop { segment, index } => write!(f, "pop {} {}", segment, index),
+      Label(label) => write!(f, "label {}", label),
+      Goto(label) => write!(f, "goto {}", label),
+      IfGoto(label) => write!(f, "if-goto {}", label),
+      Function { name, nlocals } => write!(f, "function {} {}", name, nlocals),
+      Call { name, nargs } => write!(f, "call {} {}", name, nargs),
+      Return => write!(f, "return"),
+    }
   }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum MemorySegment {
   Argument,
   Local,
@@ -113,6 +277,37 @@ pub enum MemorySegment {
   Temp,
 }
 
+impl MemorySegment {
+  /// The largest index the segment can be addressed with, if it is bounded
+  pub fn max_index(&self) -> Option<u16> {
+    match self {
+      MemorySegment::Constant => Some(32767),
+      MemorySegment::Pointer => Some(1),
+      MemorySegment::Temp => Some(7),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for MemorySegment {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use MemorySegment::*;
+
+    let name = match self {
+      Argument => "argument",
+      Local => "local",
+      Static => "static",
+      Constant => "constant",
+      This => "this",
+      That => "that",
+      Pointer => "pointer",
+      Temp => "temp",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum CommandType {
   Math(MathCommand),
@@ -135,7 +330,7 @@ pub enum BranchingCommand {
   If,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum MathCommand {
   Add,
   Subtract,
@@ -146,6 +341,49 @@ pub enum MathCommand {
   And,
   Or,
   Not,
+  // Extensions to the VM of the book, which the writer lowers to routines
+  // shared by the whole program
+  Multiply,
+  /// Rounds towards zero
+  Divide,
+  /// The remainder of `div`, with the sign of x
+  Modulo,
+  /// Shifts every bit out for counts of 16 and over, taken as unsigned
+  ShiftLeft,
+  /// An arithmetic shift, copying the sign bit
+  ShiftRight,
+  Xor,
+  /// `lt` and `gt` on the operands taken as unsigned
+  LessThanUnsigned,
+  GreaterThanUnsigned,
+}
+
+impl fmt::Display for MathCommand {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use MathCommand::*;
+
+    let name = match self {
+      Add => "add",
+      Subtract => "sub",
+      Negate => "neg",
+      EqualTo => "eq",
+      GreaterThan => "gt",
+      LessThan => "lt",
+      And => "and",
+      Or => "or",
+      Not => "not",
+      Multiply => "mul",
+      Divide => "div",
+      Modulo => "mod",
+      ShiftLeft => "shl",
+      ShiftRight => "shr",
+      Xor => "xor",
+      LessThanUnsigned => "lt_u",
+      GreaterThanUnsigned => "gt_u",
+    };
+
+    write!(f, "{}", name)
+  }
 }
 
 #[derive(PartialEq, Debug)]
@@ -157,59 +395,158 @@ pub enum MemoryCommand {
 #[cfg(test)]
 mod tests {
   use super::*;
-  #[test]
-  fn creates_a_new_parser() {
-    let mut p = Parser::new("First line\nthis is the second line\nfin");
-    assert_eq!(p.lines.next().unwrap(), "First line");
-    assert_eq!(p.lines.next().unwrap(), "this is the second line");
-    assert_eq!(p.lines.next().unwrap(), "fin");
-  }
 
   #[test]
-  fn checks_for_more_lines() {
-    let mut p = Parser::new("First line\nthis is the second line\nfin");
-    assert_eq!(p.has_more_commands(), true);
-    p.lines.next();
-    p.lines.next();
-    p.lines.next();
-    assert_eq!(p.has_more_commands(), false);
+  fn parses_commands_in_order() {
+    let p = Parser::new("push constant 1\npush constant 2\nadd");
+    let commands: Vec<VmCommand> = p.map(|c| c.unwrap().command).collect();
+
+    assert_eq!(
+      commands,
+      vec![
+        VmCommand::Push {
+          segment: MemorySegment::Constant,
+          index: 1
+        },
+        VmCommand::Push {
+          segment: MemorySegment::Constant,
+          index: 2
+        },
+        VmCommand::Arithmetic(MathCommand::Add),
+      ]
+    );
   }
 
   #[test]
-  fn advances_to_next_command() {
-    let mut p = Parser::new("push constant 1\npush constant 2\nadd");
-    assert_eq!(p.advance(), "push constant 1");
-    assert_eq!(p.advance(), "push constant 2");
-    assert_eq!(p.advance(), "add");
+  fn skips_comments_and_blank_lines_keeping_spans() {
+    let mut p = Parser::with_file(
+      "// header\n\npop pointer 1   // that = argument[1]\n",
+      "Fib.vm",
+    );
+    let command = p.next().unwrap().unwrap();
+
+    assert_eq!(
+      command.command,
+      VmCommand::Pop {
+        segment: MemorySegment::Pointer,
+        index: 1
+      }
+    );
+    assert_eq!(command.span.to_string(), "Fib.vm:3");
+    assert_eq!(p.next(), None);
   }
 
   #[test]
   fn parses_correct_command_type() {
     assert_eq!(
       Parser::command_type("add"),
-      CommandType::Math(MathCommand::Add)
+      Ok(CommandType::Math(MathCommand::Add))
     );
 
     assert_eq!(
       Parser::command_type("sub"),
-      CommandType::Math(MathCommand::Subtract)
+      Ok(CommandType::Math(MathCommand::Subtract))
     );
 
     assert_eq!(
       Parser::command_type("push"),
-      CommandType::Memory(MemoryCommand::Push)
+      Ok(CommandType::Memory(MemoryCommand::Push))
+    );
+  }
+
+  #[test]
+  fn parses_operands() {
+    assert_eq!(
+      Parser::parse_command("call Main.fibonacci 1"),
+      Ok(VmCommand::Call {
+        name: "Main.fibonacci".into(),
+        nargs: 1
+      })
+    );
+    assert_eq!(
+      Parser::parse_command("function Sys.init 2"),
+      Ok(VmCommand::Function {
+        name: "Sys.init".into(),
+        nlocals: 2
+      })
+    );
+    assert_eq!(
+      Parser::parse_command("if-goto LOOP"),
+      Ok(VmCommand::IfGoto("LOOP".into()))
     );
+    assert_eq!(Parser::parse_command("return"), Ok(VmCommand::Return));
   }
 
   #[test]
-  fn gets_first_command_arg() {
-    let first = Parser::first_arg("push local 1");
-    assert_eq!(first, "local");
+  fn displays_commands_as_source() {
+    for line in &[
+      "push local 1",
+      "pop that 0",
+      "label END",
+      "call Math.multiply 2",
+      "not",
+    ] {
+      assert_eq!(Parser::parse_command(line).unwrap().to_string(), *line);
+    }
   }
 
   #[test]
-  fn gets_second_command_arg() {
-    let second = Parser::second_arg("push local 1");
-    assert_eq!(second, 1);
+  fn rejects_malformed_commands() {
+    let err = |line| Parser::parse_command(line).unwrap_err();
+
+    assert_eq!(err("jump LOOP"), ErrorKind::UnknownCommand("jump".into()));
+    assert_eq!(err("push locl 0"), ErrorKind::UnknownSegment("locl".into()));
+    assert_eq!(
+      err("push local"),
+      ErrorKind::MissingArgument {
+        command: "push".into(),
+        expected: 2
+      }
+    );
+    assert_eq!(err("add 1"), ErrorKind::UnexpectedArgument("1".into()));
+    assert_eq!(err("push local -1"), ErrorKind::InvalidNumber("-1".into()));
+    assert_eq!(err("label 1ST"), ErrorKind::InvalidName("1ST".into()));
+    assert_eq!(err("pop constant 1"), ErrorKind::PopConstant);
+  }
+
+  #[test]
+  fn checks_segment_index_ranges() {
+    use MemorySegment::*;
+
+    let out_of_range = |segment, index, max| {
+      Err(ErrorKind::IndexOutOfRange {
+        segment,
+        index,
+        max,
+      })
+    };
+
+    assert_eq!(
+      Parser::parse_command("pop temp 9"),
+      out_of_range(Temp, 9, 7)
+    );
+    assert_eq!(
+      Parser::parse_command("push pointer 2"),
+      out_of_range(Pointer, 2, 1)
+    );
+    assert_eq!(
+      Parser::parse_command("push constant 32768"),
+      out_of_range(Constant, 32768, 32767)
+    );
+    assert!(Parser::parse_command("push temp 7").is_ok());
+    assert!(Parser::parse_command("push constant 32767").is_ok());
+  }
+
+  #[test]
+  fn locates_errors() {
+    let mut p = Parser::with_file("push constant 1\n\npop temp 8", "Main.vm");
+    assert!(p.next().unwrap().is_ok());
+
+    let err = p.next().unwrap().unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Main.vm:3: index 8 is out of range for the temp segment (0-7)"
+    );
+    assert!(p.next().is_none());
   }
 }
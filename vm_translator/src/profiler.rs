@@ -0,0 +1,507 @@
+//! Profiles programs function by function, run either on the interpreter,
+//! counting VM commands, or translated on the Hack CPU, counting cycles as
+//! well. Each function gets the cost of its own code and of everything it
+//! calls, how often it was called and how deeply it recursed, and each
+//! stack of calls its own cost, in the folded format flame graph tools
+//! such as `flamegraph.pl` and `inferno` read.
+
+use crate::error::TranslateError;
+use crate::interpreter::Vm;
+use crate::parser::VmCommand;
+use crate::targets::{halt_lines, HALT};
+use crate::{collect_sources, compile, load, Options};
+use hack_assembler::emulator::Cpu;
+use hack_assembler::parser::Parser;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// The instructions the Hack CPU has room for
+const ROM_SIZE: usize = 32768;
+
+/// The frame code outside of any function runs in, such as the bootstrap
+pub const TOP_LEVEL: &str = "(top level)";
+
+/// What one function cost
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+  pub name: String,
+  pub calls: u64,
+  /// VM commands run in the function itself
+  pub commands: u64,
+  /// VM commands run in the function and everything it called
+  pub total_commands: u64,
+  pub cycles: u64,
+  pub total_cycles: u64,
+  /// The most calls to the function in progress at once, more than one
+  /// only if it's recursive
+  pub max_depth: usize,
+}
+
+/// The cost of a whole run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+  /// Most expensive first, by cycles if counted and by commands if not
+  pub functions: Vec<FunctionProfile>,
+  /// The cost of each stack of calls, outermost first and joined by `;`,
+  /// in code of the innermost itself
+  pub stacks: BTreeMap<String, (u64, u64)>,
+  pub commands: u64,
+  pub cycles: u64,
+  /// How the run ended
+  pub ended: String,
+}
+
+impl Profile {
+  /// The folded stacks, one `stack count` line each, counting cycles if
+  /// they were counted and commands if not
+  pub fn folded(&self) -> String {
+    self
+      .stacks
+      .iter()
+      .map(|(stack, (commands, cycles))| {
+        let count = if self.cycles > 0 { cycles } else { commands };
+        format!("{} {}\n", stack, count)
+      })
+      .collect()
+  }
+}
+
+impl fmt::Display for Profile {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let percent = |part: u64, whole: u64| match whole {
+      0 => 0.0,
+      whole => 100.0 * part as f64 / whole as f64,
+    };
+
+    write!(f, "{} commands", self.commands)?;
+    if self.cycles > 0 {
+      write!(f, ", {} cycles", self.cycles)?;
+    }
+    writeln!(f, "; {}\n", self.ended)?;
+
+    if self.cycles > 0 {
+      writeln!(
+        f,
+        "{:>6} {:>12} {:>6} {:>12} {:>12} {:>10} {:>5}  function",
+        "self%", "self", "total%", "total", "commands", "calls", "depth"
+      )?;
+    } else {
+      writeln!(
+        f,
+        "{:>6} {:>12} {:>6} {:>12} {:>10} {:>5}  function",
+        "self%", "self", "total%", "total", "calls", "depth"
+      )?;
+    }
+
+    for function in &self.functions {
+      if self.cycles > 0 {
+        writeln!(
+          f,
+          "{:>6.2} {:>12} {:>6.2} {:>12} {:>12} {:>10} {:>5}  {}",
+          percent(function.cycles, self.cycles),
+          function.cycles,
+          percent(function.total_cycles, self.cycles),
+          function.total_cycles,
+          function.commands,
+          function.calls,
+          function.max_depth,
+          function.name
+        )?;
+      } else {
+        writeln!(
+          f,
+          "{:>6.2} {:>12} {:>6.2} {:>12} {:>10} {:>5}  {}",
+          percent(function.commands, self.commands),
+          function.commands,
+          percent(function.total_commands, self.commands),
+          function.total_commands,
+          function.calls,
+          function.max_depth,
+          function.name
+        )?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// A call in progress
+struct Frame {
+  function: usize,
+  /// Where the call returns to, for spotting the return in Hack code
+  return_address: usize,
+  /// The run's totals when the call was made
+  commands: u64,
+  cycles: u64,
+  /// The length of `Profiler::stack` outside of this call
+  outer: usize,
+}
+
+/// Follows calls and returns, charging what's run to the innermost call
+struct Profiler {
+  functions: Vec<FunctionProfile>,
+  index: HashMap<String, usize>,
+  frames: Vec<Frame>,
+  /// The calls of each function in progress
+  active: Vec<usize>,
+  /// The functions called, outermost first, as `Profile::stacks` keys them
+  stack: String,
+  stacks: BTreeMap<String, (u64, u64)>,
+  commands: u64,
+  cycles: u64,
+  /// Charged since the last call or return, kept apart so that charging
+  /// is cheap
+  pending: (u64, u64),
+}
+
+impl Profiler {
+  fn new() -> Profiler {
+    let mut profiler = Profiler {
+      functions: Vec::new(),
+      index: HashMap::new(),
+      frames: Vec::new(),
+      active: Vec::new(),
+      stack: String::new(),
+      stacks: BTreeMap::new(),
+      commands: 0,
+      cycles: 0,
+      pending: (0, 0),
+    };
+
+    profiler.enter(TOP_LEVEL, usize::MAX);
+    profiler.functions[0].calls = 0;
+    profiler
+  }
+
+  fn charge(&mut self, commands: u64, cycles: u64) {
+    self.commands += commands;
+    self.cycles += cycles;
+    self.pending.0 += commands;
+    self.pending.1 += cycles;
+  }
+
+  /// Hands what's pending to the innermost call
+  fn settle(&mut self) {
+    let (commands, cycles) = std::mem::take(&mut self.pending);
+    if commands == 0 && cycles == 0 {
+      return;
+    }
+
+    if let Some(frame) = self.frames.last() {
+      let function = &mut self.functions[frame.function];
+      function.commands += commands;
+      function.cycles += cycles;
+    }
+    let stack = match self.stack.as_str() {
+      "" => TOP_LEVEL,
+      stack => stack,
+    };
+    let stack = self.stacks.entry(String::from(stack)).or_default();
+    stack.0 += commands;
+    stack.1 += cycles;
+  }
+
+  fn enter(&mut self, name: &str, return_address: usize) {
+    self.settle();
+
+    let function = match self.index.get(name) {
+      Some(function) => *function,
+      None => {
+        self.index.insert(String::from(name), self.functions.len());
+        self.functions.push(FunctionProfile {
+          name: String::from(name),
+          ..FunctionProfile::default()
+        });
+        self.active.push(0);
+        self.functions.len() - 1
+      }
+    };
+
+    self.active[function] += 1;
+    let profile = &mut self.functions[function];
+    profile.calls += 1;
+    profile.max_depth = profile.max_depth.max(self.active[function]);
+
+    self.frames.push(Frame {
+      function,
+      return_address,
+      commands: self.commands,
+      cycles: self.cycles,
+      outer: self.stack.len(),
+    });
+    // stacks leave out the top level, which every one would start with
+    if self.frames.len() > 1 {
+      if !self.stack.is_empty() {
+        self.stack.push(';');
+      }
+      self.stack.push_str(name);
+    }
+  }
+
+  /// Ends the innermost call, leaving the top level alone
+  fn exit(&mut self) {
+    if self.frames.len() < 2 {
+      return;
+    }
+    self.settle();
+    self.pop();
+  }
+
+  fn pop(&mut self) {
+    let frame = self.frames.pop().unwrap();
+    self.stack.truncate(frame.outer);
+
+    // only the outermost of recursive calls counts towards the total, which
+    // takes in the inner ones
+    self.active[frame.function] -= 1;
+    if self.active[frame.function] == 0 {
+      let function = &mut self.functions[frame.function];
+      function.total_commands += self.commands - frame.commands;
+      function.total_cycles += self.cycles - frame.cycles;
+    }
+  }
+
+  /// Ends the innermost call if it returns to `address`
+  fn returned_to(&mut self, address: usize) {
+    if self.frames.last().map(|frame| frame.return_address) == Some(address) {
+      self.exit();
+    }
+  }
+
+  /// Ends the calls still in progress, as though they returned now
+  fn finish(mut self, ended: String) -> Profile {
+    self.settle();
+    while !self.frames.is_empty() {
+      self.pop();
+    }
+
+    let by_cycles = self.cycles > 0;
+    let mut functions: Vec<FunctionProfile> = self
+      .functions
+      .into_iter()
+      .filter(|function| function.calls > 0 || function.commands > 0 || function.cycles > 0)
+      .collect();
+    functions.sort_by(|a, b| {
+      let cost = |function: &FunctionProfile| {
+        if by_cycles {
+          (function.cycles, function.total_cycles)
+        } else {
+          (function.commands, function.total_commands)
+        }
+      };
+      cost(b).cmp(&cost(a)).then_with(|| a.name.cmp(&b.name))
+    });
+
+    Profile {
+      functions,
+      stacks: self.stacks,
+      commands: self.commands,
+      cycles: self.cycles,
+      ended,
+    }
+  }
+}
+
+/// Runs a loaded program on the interpreter for at most `limit` commands,
+/// charging each to the function it's in. A call to a built-in OS function
+/// is charged to that function, with whatever the OS runs of the program's
+/// code for it.
+pub fn profile_vm(vm: &mut Vm, limit: u64) -> Profile {
+  let mut profiler = Profiler::new();
+  if !vm.current_function().is_empty() {
+    profiler.enter(vm.current_function(), usize::MAX);
+  }
+
+  let ended = loop {
+    if vm.is_halted() {
+      break match vm.halted() {
+        Some(reason) => format!("halted: {}", reason),
+        None => String::from("halted"),
+      };
+    }
+    if vm.steps >= limit {
+      break format!("stopped after {} commands", limit);
+    }
+
+    let (pc, steps) = (vm.pc, vm.steps);
+    let command = vm.current().unwrap().command.clone();
+    let builtin = match &command {
+      VmCommand::Call { name, .. } => vm.function_address(name).is_none(),
+      _ => false,
+    };
+    if let (true, VmCommand::Call { name, .. }) = (builtin, &command) {
+      profiler.enter(name, pc + 1);
+    }
+
+    let result = vm.step();
+    profiler.charge(vm.steps - steps, 0);
+
+    if let Err(err) = result {
+      break format!("error: {}", err);
+    }
+    match command {
+      _ if builtin => profiler.exit(),
+      VmCommand::Call { name, .. } if name == HALT => {
+        break String::from("halted: Sys.halt was called")
+      }
+      VmCommand::Call { name, .. } => profiler.enter(&name, pc + 1),
+      VmCommand::Return => profiler.exit(),
+      VmCommand::Goto(_) if vm.pc == pc => break String::from("looping forever"),
+      _ => (),
+    }
+  };
+
+  profiler.finish(ended)
+}
+
+/// Translates the program and runs it on the Hack CPU for at most `limit`
+/// cycles, charging each instruction to the function whose call it's in. A
+/// call starts when control reaches the function's label, and ends when it
+/// reaches the return address the call saved. Code of `Sys.halt` ends the
+/// run, inlined or not.
+pub fn profile_hack(inputs: &[String], options: &Options, limit: u64) -> Result<Profile, String> {
+  let errors = |errors: Vec<TranslateError>| {
+    errors
+      .iter()
+      .map(|err| err.to_string())
+      .collect::<Vec<_>>()
+      .join("\n")
+  };
+  let files = load(&collect_sources(inputs).map_err(errors)?).map_err(errors)?;
+  let (translation, _) = compile(inputs, options).map_err(errors)?;
+  let mut parser = Parser::new();
+  let rom = parser
+    .assemble(&String::from_utf8_lossy(&translation.asm))
+    .map_err(|err| err.to_string())?;
+  if rom.len() > ROM_SIZE {
+    return Err(format!(
+      "the program takes {} words, more than the {} of ROM",
+      rom.len(),
+      ROM_SIZE
+    ));
+  }
+
+  // the lines of Sys.halt, which inlined code keeps
+  let halt_lines = halt_lines(&files);
+
+  let mut entries = HashMap::new();
+  let mut starts = vec![false; rom.len()];
+  let mut halts = vec![false; rom.len()];
+  for entry in &translation.map.entries {
+    if let Some(address) = parser.symbol_table().get_addr(&entry.function) {
+      entries.insert(address as usize, entry.function.as_str());
+    }
+    if entry.start < rom.len() {
+      starts[entry.start] = true;
+      halts[entry.start] = halt_lines.contains(&(entry.file.clone(), entry.line));
+    }
+  }
+
+  let mut cpu = Cpu::new(rom);
+  // as the test scripts of programs without bootstrap code do
+  cpu.ram[0] = 256;
+
+  let mut profiler = Profiler::new();
+  let ended = loop {
+    let pc = cpu.pc as usize;
+    if cpu.is_halted() {
+      break String::from("halted");
+    }
+    if halts[pc] {
+      break String::from("halted: Sys.halt was called");
+    }
+    if cpu.cycles >= limit {
+      break format!("stopped after {} cycles", limit);
+    }
+
+    profiler.returned_to(pc);
+    if let Some(function) = entries.get(&pc) {
+      let frame = cpu.ram[1] as u16 as usize;
+      let return_address = frame
+        .checked_sub(5)
+        .map_or(usize::MAX, |address| cpu.ram[address] as u16 as usize);
+      profiler.enter(function, return_address);
+    }
+
+    cpu.step();
+    profiler.charge(starts[pc] as u64, 1);
+  };
+
+  Ok(profiler.finish(ended))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+  use std::fs;
+
+  const PROGRAM: &str = "function Sys.init 0\npush constant 3\ncall Main.sum 1\npop temp 0\n\
+                         call Sys.halt 0\n\
+                         function Sys.halt 0\nlabel LOOP\ngoto LOOP\n\
+                         function Main.sum 0\npush argument 0\nif-goto MORE\npush constant 0\n\
+                         return\nlabel MORE\npush argument 0\npush argument 0\npush constant 1\n\
+                         sub\ncall Main.sum 1\nadd\nreturn";
+
+  fn function<'a>(profile: &'a Profile, name: &str) -> &'a FunctionProfile {
+    profile
+      .functions
+      .iter()
+      .find(|function| function.name == name)
+      .unwrap()
+  }
+
+  #[test]
+  fn profiles_the_interpreter() {
+    let mut vm = Vm::new(&[parse_file(PROGRAM, "Main").unwrap()]);
+    vm.boot();
+    let profile = profile_vm(&mut vm, 1000);
+
+    assert_eq!(profile.ended, "halted: Sys.halt was called");
+    assert_eq!(profile.commands, 40);
+    let names: Vec<&str> = profile.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["Main.sum", "Sys.init"]);
+
+    let sum = function(&profile, "Main.sum");
+    assert_eq!((sum.calls, sum.max_depth), (4, 4));
+    // the recursive calls count once towards the total
+    assert_eq!((sum.commands, sum.total_commands), (35, 35));
+    let init = function(&profile, "Sys.init");
+    assert_eq!((init.commands, init.total_commands), (5, 40));
+
+    assert_eq!(
+      profile.folded(),
+      "Sys.init 5\nSys.init;Main.sum 10\nSys.init;Main.sum;Main.sum 10\n\
+       Sys.init;Main.sum;Main.sum;Main.sum 10\nSys.init;Main.sum;Main.sum;Main.sum;Main.sum 5\n"
+    );
+  }
+
+  #[test]
+  fn profiles_the_hack_cpu() {
+    let dir = std::env::temp_dir().join(format!("vm_translator_profile_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("Main.vm"), PROGRAM).unwrap();
+
+    let inputs = vec![dir.display().to_string()];
+    let profile = profile_hack(&inputs, &Options::default(), 100_000).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(profile.ended, "halted: Sys.halt was called");
+    let sum = function(&profile, "Main.sum");
+    assert_eq!((sum.calls, sum.max_depth), (4, 4));
+    assert!(sum.cycles > 4 * sum.commands);
+
+    let init = function(&profile, "Sys.init");
+    let top = function(&profile, TOP_LEVEL);
+    assert_eq!(init.total_cycles + top.cycles, profile.cycles);
+    assert_eq!(
+      profile
+        .stacks
+        .values()
+        .map(|(_, cycles)| cycles)
+        .sum::<u64>(),
+      profile.cycles
+    );
+  }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+
+/// The ROM addresses generated for one VM command
+#[derive(Clone, PartialEq, Debug)]
+pub struct MapEntry {
+  /// First ROM address of the command's instructions
+  pub start: usize,
+  /// One past the last ROM address
+  pub end: usize,
+  pub file: String,
+  pub line: usize,
+  /// The function the command belongs to, empty outside of any function
+  pub function: String,
+  /// The command as VM source, e.g. `push local 0`
+  pub command: String,
+}
+
+/// Maps ROM addresses back to the VM commands they were translated from.
+///
+/// Written as a `.vmmap` file next to the `.asm`, one tab separated entry per
+/// line: `start end file line function command`. Commands that generate no
+/// instructions, such as labels, have no entry.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SourceMap {
+  pub entries: Vec<MapEntry>,
+}
+
+impl SourceMap {
+  pub fn new() -> SourceMap {
+    SourceMap::default()
+  }
+
+  /// Adds an entry, skipping ones that cover no addresses
+  pub fn push(&mut self, entry: MapEntry) {
+    if entry.start < entry.end {
+      self.entries.push(entry);
+    }
+  }
+
+  /// The ROM taken up by a function's instructions
+  pub fn function_size(&self, function: &str) -> usize {
+    self
+      .entries
+      .iter()
+      .filter(|entry| entry.function == function)
+      .map(|entry| entry.end - entry.start)
+      .sum()
+  }
+
+  /// Finds the command whose instructions include the address
+  pub fn lookup(&self, address: usize) -> Option<&MapEntry> {
+    let index = self.entries.partition_point(|entry| entry.end <= address);
+
+    self
+      .entries
+      .get(index)
+      .filter(|entry| entry.start <= address)
+  }
+
+  pub fn parse(input: &str) -> Result<SourceMap, String> {
+    let mut map = SourceMap::new();
+
+    for (index, line) in input.lines().enumerate() {
+      if line.trim().is_empty() || line.starts_with("//") {
+        continue;
+      }
+
+      let invalid = || format!("line {}: invalid source map entry", index + 1);
+      let fields: Vec<&str> = line.splitn(6, '\t').collect();
+
+      if fields.len() != 6 {
+        return Err(invalid());
+      }
+
+      map.push(MapEntry {
+        start: fields[0].parse().map_err(|_| invalid())?,
+        end: fields[1].parse().map_err(|_| invalid())?,
+        file: String::from(fields[2]),
+        line: fields[3].parse().map_err(|_| invalid())?,
+        function: String::from(fields[4]),
+        command: String::from(fields[5]),
+      });
+    }
+
+    Ok(map)
+  }
+}
+
+impl fmt::Display for SourceMap {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "// start\tend\tfile\tline\tfunction\tcommand")?;
+
+    for entry in &self.entries {
+      writeln!(
+        f,
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        entry.start, entry.end, entry.file, entry.line, entry.function, entry.command
+      )?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(start: usize, end: usize, line: usize, command: &str) -> MapEntry {
+    MapEntry {
+      start,
+      end,
+      file: String::from("Main.vm"),
+      line,
+      function: String::from("Main.main"),
+      command: String::from(command),
+    }
+  }
+
+  #[test]
+  fn looks_up_addresses() {
+    let mut map = SourceMap::new();
+    map.push(entry(0, 7, 2, "push constant 7"));
+    map.push(entry(7, 7, 3, "label LOOP"));
+    map.push(entry(7, 12, 4, "add"));
+
+    assert_eq!(map.entries.len(), 2);
+    assert_eq!(map.lookup(0).map(|e| e.line), Some(2));
+    assert_eq!(map.lookup(6).map(|e| e.line), Some(2));
+    assert_eq!(map.lookup(7).map(|e| e.line), Some(4));
+    assert_eq!(map.lookup(12), None);
+    assert_eq!(map.function_size("Main.main"), 12);
+  }
+
+  #[test]
+  fn round_trips_through_display() {
+    let mut map = SourceMap::new();
+    map.push(entry(0, 7, 2, "push constant 7"));
+    map.push(entry(7, 12, 4, "call Math.multiply 2"));
+
+    assert_eq!(SourceMap::parse(&map.to_string()), Ok(map));
+    assert!(SourceMap::parse("0\t7\tMain.vm\n").is_err());
+  }
+}
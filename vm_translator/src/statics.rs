@@ -0,0 +1,143 @@
+//! Where the assembler will put each static variable, which it allocates
+//! from RAM[16] up in the order the program first mentions them
+
+use hack_assembler::parser::{parse_lines, Address, Instruction};
+use hack_assembler::profile::PREDEFINED;
+pub use hack_assembler::profile::{VARIABLE_BASE, VARIABLE_LIMIT};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// The RAM address of every variable in a program, `Main.0` for static 0 of
+/// `Main.vm` and `$$mul.x` and the like for the shared routines
+#[derive(Clone, Debug, Default)]
+pub struct StaticMap {
+  pub variables: Vec<(String, u16)>,
+}
+
+impl StaticMap {
+  /// Allocates the variables of translated assembly as the assembler would
+  pub fn allocate(asm: &str) -> StaticMap {
+    let instructions: Vec<Instruction> = parse_lines(asm)
+      .filter_map(|line| line.ok()?.instruction)
+      .collect();
+    let mut taken: HashSet<&str> = PREDEFINED.iter().map(|(name, _)| *name).collect();
+
+    for instruction in &instructions {
+      if let Instruction::Label(label) = instruction {
+        taken.insert(label);
+      }
+    }
+
+    let mut variables = Vec::new();
+    for instruction in &instructions {
+      if let Instruction::A(Address::Symbol(symbol)) = instruction {
+        if taken.insert(symbol) {
+          let address = VARIABLE_BASE + variables.len() as u16;
+          variables.push((symbol.clone(), address));
+        }
+      }
+    }
+
+    StaticMap { variables }
+  }
+
+  /// The first address past the variables
+  pub fn end(&self) -> usize {
+    VARIABLE_BASE as usize + self.variables.len()
+  }
+
+  /// Whether the variables stay below the stack at RAM[256]
+  pub fn fits(&self) -> bool {
+    self.end() <= VARIABLE_LIMIT as usize
+  }
+
+  /// The first variable that doesn't fit, if any
+  pub fn first_overflow(&self) -> Option<&str> {
+    self.first_overflow_below(VARIABLE_LIMIT)
+  }
+
+  /// The first variable that doesn't fit below `limit`, if any
+  pub fn first_overflow_below(&self, limit: u16) -> Option<&str> {
+    self
+      .variables
+      .get(limit.saturating_sub(VARIABLE_BASE) as usize)
+      .map(|(name, _)| name.as_str())
+  }
+
+  /// How many variables each file has, with `$$` standing for the routines
+  pub fn per_file(&self) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+
+    for (name, _) in &self.variables {
+      *counts.entry(owner(name)).or_insert(0) += 1;
+    }
+
+    counts
+  }
+}
+
+/// The file a variable belongs to, its name up to the last `.`
+pub fn owner(variable: &str) -> &str {
+  if variable.starts_with("$$") {
+    "$$"
+  } else {
+    variable.rsplit_once('.').map_or(variable, |(file, _)| file)
+  }
+}
+
+/// The map as written to a `.statics` file: a summary per file in comments,
+/// then each variable with its address, tab separated
+impl fmt::Display for StaticMap {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(
+      f,
+      "// {} of {} words from RAM[{}] to RAM[{}]",
+      self.variables.len(),
+      VARIABLE_LIMIT - VARIABLE_BASE,
+      VARIABLE_BASE,
+      VARIABLE_LIMIT - 1
+    )?;
+    for (file, count) in self.per_file() {
+      writeln!(f, "// {}\t{}", file, count)?;
+    }
+
+    writeln!(f, "// variable\taddress")?;
+    for (name, address) in &self.variables {
+      writeln!(f, "{}\t{}", name, address)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn allocates_like_the_assembler() {
+    let asm = "@Main.1\nD=M\n@LOOP\n0;JMP\n(LOOP)\n@Math.0\nM=D\n@Main.1\n@SP\n@$$mul.x\n@Main.0";
+    let map = StaticMap::allocate(asm);
+
+    assert_eq!(
+      map.variables,
+      vec![
+        (String::from("Main.1"), 16),
+        (String::from("Math.0"), 17),
+        (String::from("$$mul.x"), 18),
+        (String::from("Main.0"), 19),
+      ]
+    );
+    assert_eq!(map.end(), 20);
+    assert!(map.fits());
+    assert_eq!(
+      map.per_file().into_iter().collect::<Vec<_>>(),
+      vec![("$$", 1), ("Main", 2), ("Math", 1)]
+    );
+
+    let crowded: String = (0..241).map(|n| format!("@Big.{}\n", n)).collect();
+    let map = StaticMap::allocate(&crowded);
+    assert!(!map.fits());
+    assert_eq!(map.first_overflow(), Some("Big.240"));
+  }
+}
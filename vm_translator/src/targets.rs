@@ -0,0 +1,304 @@
+//! Targets besides Hack assembly, which translate whole programs into the
+//! source of another language for a host toolchain to build. They share
+//! the translator's front end, so take the same optimizations, and see the
+//! program as `Program`: each function's commands apart, as the languages
+//! they write keep functions apart.
+
+use crate::callgraph::{self, CallGraph, ENTRY_POINT};
+use crate::error::{ErrorKind, TranslateError};
+use crate::parser::{MemorySegment, SourceCommand, VmCommand};
+use crate::{collect_sources, inliner, intrinsics, load, optimizer, Options, SourceFile};
+use std::collections::HashSet;
+use std::ops::Range;
+
+pub mod c;
+pub mod wasm;
+pub mod x86_64;
+
+#[cfg(test)]
+mod testing;
+
+/// The function that halts the program, which targets do at once rather
+/// than with the OS's loop, and where profiled runs end rather than add the
+/// time spent waiting to be stopped
+pub const HALT: &str = "Sys.halt";
+
+/// The code of one function, or of one file outside of any function
+#[derive(Clone, Debug)]
+pub struct Routine {
+  /// `None` for code outside of functions
+  pub name: Option<String>,
+  /// The file it's in, whose static variables it uses
+  pub namespace: String,
+  pub nlocals: u16,
+  /// The commands after the `function` one
+  pub commands: Vec<SourceCommand>,
+}
+
+/// A program divided into routines, ready to write out
+#[derive(Clone, Debug)]
+pub struct Program {
+  /// Top-level code first, in the order of its files, then the functions
+  pub routines: Vec<Routine>,
+  /// The static variables of each file, as many as its highest index
+  /// needs, which for index 65535 is more than a `u16` holds
+  pub statics: Vec<(String, u32)>,
+  /// Whether the program starts by calling `Sys.init`, as the bootstrap
+  /// code does, rather than with its top-level code
+  pub bootstrap: bool,
+  /// The file and line of each command of `Sys.halt`, which end the
+  /// program wherever they have been inlined
+  pub halt_lines: HashSet<(String, usize)>,
+}
+
+impl Program {
+  /// Divides the files into routines, failing on jumps to labels outside
+  /// the routine they're in, which no target can make
+  pub fn build(files: &[SourceFile]) -> Result<Program, Vec<TranslateError>> {
+    let mut top_level = Vec::new();
+    let mut functions = Vec::new();
+    let mut statics = Vec::new();
+
+    for file in files {
+      let mut routine = Routine {
+        name: None,
+        namespace: file.namespace.clone(),
+        nlocals: 0,
+        commands: Vec::new(),
+      };
+      let mut words = 0;
+
+      for command in &file.commands {
+        match &command.command {
+          VmCommand::Function { name, nlocals } => {
+            let done = std::mem::replace(
+              &mut routine,
+              Routine {
+                name: Some(name.clone()),
+                namespace: file.namespace.clone(),
+                nlocals: *nlocals,
+                commands: Vec::new(),
+              },
+            );
+            match done.name {
+              Some(_) => functions.push(done),
+              None if !done.commands.is_empty() => top_level.push(done),
+              None => (),
+            }
+            continue;
+          }
+          VmCommand::Push {
+            segment: MemorySegment::Static,
+            index,
+          }
+          | VmCommand::Pop {
+            segment: MemorySegment::Static,
+            index,
+          } => words = words.max(u32::from(*index) + 1),
+          _ => (),
+        }
+
+        routine.commands.push(command.clone());
+      }
+
+      match routine.name {
+        Some(_) => functions.push(routine),
+        None if !routine.commands.is_empty() => top_level.push(routine),
+        None => (),
+      }
+      statics.push((file.namespace.clone(), words));
+    }
+
+    let bootstrap = functions
+      .iter()
+      .any(|routine| routine.name.as_deref() == Some(ENTRY_POINT));
+    top_level.append(&mut functions);
+    let program = Program {
+      routines: top_level,
+      statics,
+      bootstrap,
+      halt_lines: halt_lines(files),
+    };
+
+    let errors = program.undefined_labels();
+    if errors.is_empty() {
+      Ok(program)
+    } else {
+      Err(errors)
+    }
+  }
+
+  /// The routines a program without the bootstrap code runs in turn: its
+  /// top-level code, or if it has none its first function, which the Hack
+  /// CPU would start in
+  pub fn start(&self) -> Range<usize> {
+    let top_level = self
+      .routines
+      .iter()
+      .take_while(|routine| routine.name.is_none())
+      .count();
+
+    match top_level {
+      0 => 0..self.routines.len().min(1),
+      _ => 0..top_level,
+    }
+  }
+
+  /// Whether the command came from `Sys.halt`
+  pub fn halts(&self, command: &SourceCommand) -> bool {
+    self
+      .halt_lines
+      .contains(&(command.span.file.to_string(), command.span.line))
+  }
+
+  fn undefined_labels(&self) -> Vec<TranslateError> {
+    let mut errors = Vec::new();
+
+    for routine in &self.routines {
+      let labels: HashSet<&str> = routine
+        .commands
+        .iter()
+        .filter_map(|command| match &command.command {
+          VmCommand::Label(label) => Some(label.as_str()),
+          _ => None,
+        })
+        .collect();
+
+      for command in &routine.commands {
+        if let VmCommand::Goto(label) | VmCommand::IfGoto(label) = &command.command {
+          if !labels.contains(label.as_str()) {
+            errors.push(TranslateError {
+              file: command.span.file.to_string(),
+              line: Some(command.span.line),
+              kind: ErrorKind::UndefinedLabel(label.clone()),
+            });
+          }
+        }
+      }
+    }
+
+    errors
+  }
+}
+
+/// Loads the inputs and runs the front end over them as `compile` does:
+/// inlining, dropping dead functions and the optimizer's passes
+pub fn prepare(inputs: &[String], options: &Options) -> Result<Program, Vec<TranslateError>> {
+  let mut files = load(&collect_sources(inputs)?)?;
+
+  if options.native_math {
+    intrinsics::lower_math_calls(&mut files);
+  }
+
+  let errors = CallGraph::build(&files).errors();
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let halts = halt_lines(&files);
+  if options.inline_limit > 0 {
+    inliner::inline(&mut files, options.inline_limit);
+  }
+  if options.eliminate_dead_functions {
+    callgraph::eliminate_dead_functions(&mut files);
+  }
+  for file in &mut files {
+    file.commands = optimizer::optimize(std::mem::take(&mut file.commands), &options.passes);
+  }
+
+  let mut program = Program::build(&files)?;
+  program.halt_lines.extend(halts);
+  Ok(program)
+}
+
+/// The file and line of each command of `Sys.halt`, which inlined copies
+/// of it keep
+pub(crate) fn halt_lines(files: &[SourceFile]) -> HashSet<(String, usize)> {
+  let mut lines = HashSet::new();
+
+  for file in files {
+    let mut in_halt = false;
+    for command in &file.commands {
+      if let VmCommand::Function { name, .. } = &command.command {
+        in_halt = name == HALT;
+      }
+      if in_halt {
+        lines.insert((command.span.file.to_string(), command.span.line));
+      }
+    }
+  }
+  lines
+}
+
+/// A label, function or file name as an identifier of letters, digits and
+/// underscores. Underscores are doubled, so that escaping the rest with a
+/// single one keeps different names apart.
+pub fn mangle(name: &str) -> String {
+  let mut mangled = String::with_capacity(name.len());
+
+  for c in name.chars() {
+    match c {
+      '_' => mangled.push_str("__"),
+      '.' => mangled.push_str("_d"),
+      '$' => mangled.push_str("_s"),
+      ':' => mangled.push_str("_c"),
+      c => mangled.push(c),
+    }
+  }
+  mangled
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_file;
+
+  #[test]
+  fn divides_files_into_routines() {
+    let main = parse_file(
+      "push constant 1\npop static 4\n\
+       function Main.main 1\nlabel LOOP\ngoto LOOP\n\
+       function Main.other 0\ngoto LOOP",
+      "Main",
+    )
+    .unwrap();
+
+    let errors = Program::build(std::slice::from_ref(&main)).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, Some(7));
+    assert_eq!(
+      errors[0].kind,
+      ErrorKind::UndefinedLabel(String::from("LOOP"))
+    );
+
+    let mut main = main;
+    main.commands.pop();
+    let program = Program::build(&[main]).unwrap();
+    let names: Vec<Option<&str>> = program
+      .routines
+      .iter()
+      .map(|routine| routine.name.as_deref())
+      .collect();
+    assert_eq!(names, vec![None, Some("Main.main"), Some("Main.other")]);
+    assert_eq!(program.routines[1].nlocals, 1);
+    assert_eq!(program.statics, vec![(String::from("Main"), 5)]);
+    assert!(!program.bootstrap);
+  }
+
+  #[test]
+  fn counts_statics_up_to_the_highest_index() {
+    let main = parse_file("push constant 1\npop static 65535", "Main").unwrap();
+
+    let program = Program::build(&[main]).unwrap();
+    assert_eq!(program.statics, vec![(String::from("Main"), 65536)]);
+    assert!(c::write(&program, false).contains("static int16_t s_Main[65536];"));
+    assert!(x86_64::write(&program, false).contains("s_Main+131070(%rip)"));
+  }
+
+  #[test]
+  fn mangles_names_apart() {
+    assert_eq!(mangle("Main.main"), "Main_dmain");
+    assert_eq!(mangle("a_b$c:d"), "a__b_sc_cd");
+    assert_ne!(mangle("a._d"), mangle("a_d.d"));
+  }
+}
@@ -0,0 +1,499 @@
+//! Writes programs as portable C: a function for each VM function and
+//! labels as `goto`s, over RAM kept as an array of 32K words, laid out as
+//! on the Hack machine so that the heap, screen and keyboard are where the
+//! OS expects them. Static variables are arrays of their own, one per file.
+//!
+//! The program ends when it reaches the code of `Sys.halt`, inlined or
+//! not, jumps to the label it's at or returns from `Sys.init`. A small
+//! runtime comes with it, taking these arguments:
+//!
+//! ```text
+//! -s ADDRESS=VALUE      set a word of RAM before starting
+//! -p ADDRESS[:COUNT]    print words of RAM once halted, as RAM[ADDRESS] = VALUE
+//! -o SCREEN.ppm         write the screen as a PPM image once halted
+//! -k KEYS               press the keys of the file in turn, a new line being
+//!                       the Enter key
+//! -n CALLS              stop after so many calls, failing
+//! ```
+//!
+//! The keyboard and screen go through `vm_key` and `vm_screen`, which a
+//! program built with `-DVM_HOOKS` can provide itself.
+
+use super::{mangle, Program, Routine, HALT};
+use crate::callgraph::ENTRY_POINT;
+use crate::parser::{MathCommand, MemorySegment, VmCommand};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+const RUNTIME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define RAM_SIZE 32768
+#define SCREEN 16384
+#define KBD 24576
+#define M(address) ram[(address) & (RAM_SIZE - 1)]
+
+static int16_t ram[RAM_SIZE];
+
+/* The key held down, asked for whenever the program reads the keyboard */
+int16_t vm_key(void);
+/* The screen's 8192 words, once the program halts */
+void vm_screen(const int16_t *screen);
+
+static FILE *vm_keys;
+static const char *vm_screen_path;
+static unsigned long vm_calls, vm_call_limit;
+static int vm_prints[64][2], vm_print_count;
+
+static inline int16_t w(int value) { return (int16_t)(uint16_t)value; }
+static inline int16_t vm_read(int address) {
+  address &= RAM_SIZE - 1;
+  return address == KBD ? (ram[KBD] = vm_key()) : ram[address];
+}
+static inline void push(int value) { M(ram[0]) = w(value); ram[0] = w(ram[0] + 1); }
+static inline int pop(void) { ram[0] = w(ram[0] - 1); return M(ram[0]); }
+
+static void vm_exit(int status) {
+  int i, j;
+  for (i = 0; i < vm_print_count; i++)
+    for (j = 0; j < vm_prints[i][1]; j++)
+      printf("RAM[%d] = %d\n", vm_prints[i][0] + j, M(vm_prints[i][0] + j));
+  vm_screen(&ram[SCREEN]);
+  exit(status);
+}
+
+static inline void call(void (*function)(void), int nargs) {
+  if (vm_call_limit && ++vm_calls > vm_call_limit) {
+    fprintf(stderr, "stopped after %lu calls\n", vm_call_limit);
+    vm_exit(2);
+  }
+  push(0);
+  push(ram[1]);
+  push(ram[2]);
+  push(ram[3]);
+  push(ram[4]);
+  ram[2] = w(ram[0] - 5 - nargs);
+  ram[1] = ram[0];
+  function();
+}
+
+static inline void enter(int nlocals) {
+  while (nlocals--) push(0);
+}
+
+static inline void ret(void) {
+  int frame = ram[1];
+  M(ram[2]) = w(pop());
+  ram[0] = w(ram[2] + 1);
+  ram[4] = M(frame - 1);
+  ram[3] = M(frame - 2);
+  ram[2] = M(frame - 3);
+  ram[1] = M(frame - 4);
+}
+
+#ifndef VM_HOOKS
+/* The reads a key stays down for, and then up */
+#define KEY_HOLD 256
+
+int16_t vm_key(void) {
+  static int16_t key;
+  static unsigned long reads;
+  if (vm_keys == NULL) return 0;
+  if (reads % (2 * KEY_HOLD) == 0) {
+    int c = fgetc(vm_keys);
+    key = c == EOF ? 0 : c == '\n' ? 128 : c;
+  }
+  return reads++ % (2 * KEY_HOLD) < KEY_HOLD ? key : 0;
+}
+
+void vm_screen(const int16_t *screen) {
+  FILE *file;
+  int x, y;
+  if (vm_screen_path == NULL) return;
+  if ((file = fopen(vm_screen_path, "wb")) == NULL) {
+    perror(vm_screen_path);
+    return;
+  }
+  fprintf(file, "P6\n512 256\n255\n");
+  for (y = 0; y < 256; y++)
+    for (x = 0; x < 512; x++) {
+      int shade = screen[y * 32 + x / 16] >> (x % 16) & 1 ? 0 : 255;
+      fputc(shade, file);
+      fputc(shade, file);
+      fputc(shade, file);
+    }
+  fclose(file);
+}
+#endif
+
+static void vm_start(int argc, char **argv) {
+  int i, address, value;
+  for (i = 1; i < argc; i++) {
+    const char *arg = i + 1 < argc ? argv[i + 1] : NULL;
+    if (arg && !strcmp(argv[i], "-s") && sscanf(arg, "%d=%d", &address, &value) == 2) {
+      M(address) = w(value);
+    } else if (arg && !strcmp(argv[i], "-p") && vm_print_count < 64
+               && sscanf(arg, "%d", &address) == 1) {
+      vm_prints[vm_print_count][0] = address;
+      vm_prints[vm_print_count][1] = strchr(arg, ':') ? atoi(strchr(arg, ':') + 1) : 1;
+      vm_print_count++;
+    } else if (arg && !strcmp(argv[i], "-o")) {
+      vm_screen_path = arg;
+    } else if (arg && !strcmp(argv[i], "-k")) {
+      if ((vm_keys = fopen(arg, "rb")) == NULL) {
+        perror(arg);
+        exit(1);
+      }
+    } else if (arg && !strcmp(argv[i], "-n")) {
+      vm_call_limit = strtoul(arg, NULL, 10);
+    } else {
+      fprintf(stderr, "usage: %s [-s ADDRESS=VALUE]... [-p ADDRESS[:COUNT]]... "
+                      "[-o SCREEN.ppm] [-k KEYS] [-n CALLS]\n", argv[0]);
+      exit(1);
+    }
+    i++;
+  }
+}
+"#;
+
+/// The C source of the whole program, with each command preceded by a
+/// comment naming it and where it came from if `annotate` is set
+pub fn write(program: &Program, annotate: bool) -> String {
+  let mut c = String::from("/* Translated from VM code by vm_translator */\n");
+  c.push_str(RUNTIME);
+
+  c.push('\n');
+  for (namespace, words) in &program.statics {
+    if *words > 0 {
+      writeln!(c, "static int16_t s_{}[{}];", mangle(namespace), words).unwrap();
+    }
+  }
+
+  // calls to Sys.halt halt at once, so it needn't be written
+  let routines: Vec<(&Routine, String)> = (0..program.routines.len())
+    .filter(|&n| program.routines[n].name.as_deref() != Some(HALT))
+    .map(|n| (&program.routines[n], routine_name(program, n)))
+    .collect();
+  c.push('\n');
+  for (_, name) in &routines {
+    writeln!(c, "static void {}(void);", name).unwrap();
+  }
+
+  for (routine, name) in &routines {
+    write_routine(&mut c, program, routine, name, annotate);
+  }
+
+  // as the bootstrap code does, with the segments' pointers set apart,
+  // whatever RAM was set to; without it, SP only starts at 256 unless set
+  c.push_str("\nint main(int argc, char **argv) {\n");
+  if program.bootstrap {
+    c.push_str("  vm_start(argc, argv);\n  ram[0] = 256;\n");
+    c.push_str("  ram[1] = -1;\n  ram[2] = -2;\n  ram[3] = -3;\n  ram[4] = -4;\n");
+    writeln!(c, "  call(f_{}, 0);", mangle(ENTRY_POINT)).unwrap();
+  } else {
+    c.push_str("  ram[0] = 256;\n  vm_start(argc, argv);\n");
+    for n in program.start() {
+      if program.routines[n].name.as_deref() != Some(HALT) {
+        writeln!(c, "  {}();", routine_name(program, n)).unwrap();
+      }
+    }
+  }
+  c.push_str("  vm_exit(0);\n  return 0;\n}\n");
+
+  c
+}
+
+fn routine_name(program: &Program, n: usize) -> String {
+  match &program.routines[n].name {
+    Some(name) => format!("f_{}", mangle(name)),
+    None => format!("top_{}", n),
+  }
+}
+
+fn write_routine(c: &mut String, program: &Program, routine: &Routine, name: &str, annotate: bool) {
+  writeln!(c, "\nstatic void {}(void) {{", name).unwrap();
+  if routine.nlocals > 0 {
+    writeln!(c, "  enter({});", routine.nlocals).unwrap();
+  }
+
+  let statics = format!("s_{}", mangle(&routine.namespace));
+  // a `goto` straight after its label halts instead of jumping
+  let targets: HashSet<&str> = routine
+    .commands
+    .iter()
+    .enumerate()
+    .filter(|(_, command)| !program.halts(command))
+    .filter_map(|(n, command)| match &command.command {
+      VmCommand::Goto(label)
+        if n > 0 && routine.commands[n - 1].command == VmCommand::Label(label.clone()) =>
+      {
+        None
+      }
+      VmCommand::Goto(label) | VmCommand::IfGoto(label) => Some(label.as_str()),
+      _ => None,
+    })
+    .collect();
+  let mut at_label = None;
+  let mut halted = false;
+
+  for command in &routine.commands {
+    if annotate {
+      writeln!(c, "  /* {} {} */", command.span, command.command).unwrap();
+    }
+
+    let halts = program.halts(command);
+    let line = match &command.command {
+      // inlined code of Sys.halt, which halts once reached and can only be
+      // jumped into at its labels
+      VmCommand::Label(label) if halts && targets.contains(label.as_str()) => {
+        format!("l_{}:;\n  vm_exit(0);", mangle(label))
+      }
+      _ if halts && halted => String::new(),
+      _ if halts => String::from("vm_exit(0);"),
+      VmCommand::Arithmetic(math) => arithmetic(*math),
+      VmCommand::Push { segment, index } => {
+        // the keyboard can only be read through a pointer
+        let value = match (segment, address(*segment, *index)) {
+          (MemorySegment::Constant, _) => index.to_string(),
+          (_, Some(address)) => format!("vm_read({})", address),
+          (_, None) => location(*segment, *index, &statics),
+        };
+        format!("push({});", value)
+      }
+      VmCommand::Pop { segment, index } => {
+        format!("{} = w(pop());", location(*segment, *index, &statics))
+      }
+      VmCommand::Label(label) if targets.contains(label.as_str()) => {
+        format!("l_{}:;", mangle(label))
+      }
+      VmCommand::Label(_) => String::new(),
+      VmCommand::Goto(label) if at_label == Some(label) => String::from("vm_exit(0);"),
+      VmCommand::Goto(label) => format!("goto l_{};", mangle(label)),
+      VmCommand::IfGoto(label) => format!("if (pop()) goto l_{};", mangle(label)),
+      VmCommand::Call { name, .. } if name == HALT => String::from("vm_exit(0);"),
+      VmCommand::Call { name, nargs } => format!("call(f_{}, {});", mangle(name), nargs),
+      VmCommand::Return if routine.name.as_deref() == Some(ENTRY_POINT) => {
+        String::from("vm_exit(0);")
+      }
+      VmCommand::Return => String::from("ret();\n  return;"),
+      VmCommand::Function { .. } => unreachable!("routines start after their function command"),
+    };
+    if !line.is_empty() {
+      writeln!(c, "  {}", line).unwrap();
+    }
+
+    halted = halts;
+    at_label = match &command.command {
+      VmCommand::Label(label) => Some(label),
+      _ => None,
+    };
+  }
+
+  c.push_str("}\n");
+}
+
+/// The address of a word of a segment kept behind a pointer
+fn address(segment: MemorySegment, index: u16) -> Option<String> {
+  let pointer = match segment {
+    MemorySegment::Local => 1,
+    MemorySegment::Argument => 2,
+    MemorySegment::This => 3,
+    MemorySegment::That => 4,
+    _ => return None,
+  };
+
+  Some(match index {
+    0 => format!("ram[{}]", pointer),
+    _ => format!("ram[{}] + {}", pointer, index),
+  })
+}
+
+/// The C lvalue for a word of a segment
+fn location(segment: MemorySegment, index: u16, statics: &str) -> String {
+  if let Some(address) = address(segment, index) {
+    return format!("M({})", address);
+  }
+
+  match segment {
+    MemorySegment::Pointer => format!("ram[{}]", 3 + index),
+    MemorySegment::Temp => format!("ram[{}]", 5 + index),
+    MemorySegment::Static => format!("{}[{}]", statics, index),
+    _ => unreachable!("constants aren't locations"),
+  }
+}
+
+/// The statement for an arithmetic command, as the interpreter evaluates it
+fn arithmetic(math: MathCommand) -> String {
+  use MathCommand::*;
+
+  let result = match math {
+    Negate => return String::from("push(-pop());"),
+    Not => return String::from("push(~pop());"),
+    Add => "x + y",
+    Subtract => "x - y",
+    EqualTo => "-(x == y)",
+    GreaterThan => "-(x > y)",
+    LessThan => "-(x < y)",
+    And => "x & y",
+    Or => "x | y",
+    Multiply => "x * y",
+    // as the writer's routine does
+    Divide => "y ? x / y : x < 0 ? 1 : -1",
+    Modulo => "y ? x % y : x",
+    ShiftLeft => "(uint16_t)y < 16 ? (uint16_t)x << y : 0",
+    ShiftRight => "(uint16_t)y < 16 ? (x < 0 ? ~(~x >> y) : x >> y) : -(x < 0)",
+    Xor => "x ^ y",
+    LessThanUnsigned => "-((uint16_t)x < (uint16_t)y)",
+    GreaterThanUnsigned => "-((uint16_t)x > (uint16_t)y)",
+  };
+
+  format!("{{ int y = pop(), x = pop(); push({}); }}", result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulate::test_programs;
+  use crate::targets::testing;
+  use crate::{parse_file, targets, Options};
+  use std::fs;
+  use std::path::{Path, PathBuf};
+  use std::process::Command;
+
+  /// A directory to build in, or none without a C compiler to build with
+  fn build_directory(name: &str) -> Option<PathBuf> {
+    if Command::new("cc").arg("--version").output().is_err() {
+      eprintln!("skipping: no cc");
+      return None;
+    }
+
+    let build = std::env::temp_dir().join(format!("vm_translator_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&build).unwrap();
+    Some(build)
+  }
+
+  /// Compiles the program in `build`, warnings being errors
+  fn compile(program: &Program, build: &Path) -> Result<(), String> {
+    fs::write(build.join("main.c"), write(program, true)).unwrap();
+
+    let status = Command::new("cc")
+      .current_dir(build)
+      .args(["-std=c99", "-Wall", "-Werror", "-o", "main", "main.c"])
+      .status()
+      .map_err(|err| err.to_string())?;
+    if status.success() {
+      Ok(())
+    } else {
+      Err(format!("cc failed with {}", status))
+    }
+  }
+
+  /// Compiles the program and runs it from the given RAM, returning RAM as
+  /// it halted
+  fn run(program: &Program, build: &Path, ram: Vec<i16>) -> Result<Vec<i16>, String> {
+    compile(program, build)?;
+
+    let mut command = Command::new(build.join("main"));
+    for (address, value) in ram.iter().enumerate() {
+      if *value != 0 {
+        command.arg("-s").arg(format!("{}={}", address, value));
+      }
+    }
+    let output = command
+      .args(["-p", &format!("0:{}", ram.len())])
+      .output()
+      .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+      return Err(format!("main failed with {}", output.status));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+      .lines()
+      .map(|line| {
+        line
+          .rsplit(" = ")
+          .next()
+          .and_then(|value| value.parse().ok())
+          .ok_or_else(|| format!("unexpected output '{}'", line))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn builds_and_runs_with_a_c_compiler() {
+    let build = match build_directory("c") {
+      Some(build) => build,
+      None => return,
+    };
+
+    let main = parse_file(
+      "function Main.fib 0\n\
+       push argument 0\npush constant 2\nlt\nif-goto BASE\n\
+       push argument 0\npush constant 1\nsub\ncall Main.fib 1\n\
+       push argument 0\npush constant 2\nsub\ncall Main.fib 1\nadd\nreturn\n\
+       label BASE\npush argument 0\nreturn\n\
+       function Sys.init 0\n\
+       push constant 12\ncall Main.fib 1\npop temp 0\n\
+       push constant 7\npush constant 0\ndiv\npop temp 1\n\
+       push constant 8\nneg\npush constant 1\nshr\npop temp 2\n\
+       push constant 1\npush constant 16\nshl\npop temp 3\n\
+       push constant 1\nneg\npush constant 1\nlt_u\npop temp 4\n\
+       push constant 200\npush constant 200\nmul\npop temp 5\n\
+       push constant 3000\npop pointer 1\npush constant 9\npop that 0\n\
+       call Sys.halt 0\n\
+       function Sys.halt 0\nlabel LOOP\ngoto LOOP",
+      "Main",
+    )
+    .unwrap();
+    let program = Program::build(&[main]).unwrap();
+    compile(&program, &build).unwrap();
+
+    let output = Command::new(build.join("main"))
+      .args(["-s", "2999=5", "-p", "5:6", "-p", "2999:2"])
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+      String::from_utf8(output.stdout).unwrap(),
+      "RAM[5] = 144\nRAM[6] = -1\nRAM[7] = -4\nRAM[8] = 0\nRAM[9] = 0\n\
+       RAM[10] = -25536\nRAM[2999] = 5\nRAM[3000] = 9\n"
+    );
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+
+  #[test]
+  fn does_arithmetic_as_the_interpreter_does() {
+    let build = match build_directory("c_math") {
+      Some(build) => build,
+      None => return,
+    };
+
+    let (file, expected) = testing::arithmetic();
+    let program = Program::build(&[file]).unwrap();
+    let ram = run(&program, &build, vec![0; 32768]).unwrap();
+    testing::check_arithmetic(&ram, &expected);
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+
+  #[test]
+  fn passes_the_test_programs_compiled() {
+    let build = match build_directory("c_programs") {
+      Some(build) => build,
+      None => return,
+    };
+
+    for directory in
+      test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path()).unwrap()
+    {
+      let program =
+        targets::prepare(&[directory.display().to_string()], &Options::default()).unwrap();
+      testing::run_script(&directory, |ram| run(&program, &build, ram)).unwrap();
+    }
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+}
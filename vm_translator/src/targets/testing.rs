@@ -0,0 +1,115 @@
+//! What the targets' tests share: running the nand2tetris test programs
+//! and checking arithmetic against the interpreter
+
+use crate::interpreter::Vm;
+use crate::{parse_file, SourceFile};
+use hack_assembler::script::{self, Command, Script};
+use std::fs;
+use std::path::Path;
+
+/// Where `arithmetic` leaves its results
+pub const RESULTS: usize = 3000;
+
+const VALUES: [i16; 10] = [0, 1, -1, 3, 7, -7, 16, 17, 32767, -32768];
+const OPERATIONS: [&str; 15] = [
+  "add", "sub", "eq", "gt", "lt", "and", "or", "mul", "div", "mod", "shl", "shr", "xor", "lt_u",
+  "gt_u",
+];
+
+/// Runs the CPU emulator script of a test directory, such as
+/// `08_vm_two/FunctionCalls/NestedCall`, against its program built for a
+/// target. `run` takes the RAM the script's `set`s make and returns it as
+/// the program halted, which the script's outputs read.
+pub fn run_script(
+  directory: &Path,
+  run: impl FnOnce(Vec<i16>) -> Result<Vec<i16>, String>,
+) -> Result<(), String> {
+  let name = directory.file_name().unwrap().to_str().unwrap();
+  let read = |extension: &str| {
+    let path = directory.join(name).with_extension(extension);
+    fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))
+  };
+
+  let script = Script::parse(&read("tst")?)?;
+  let mut ram = vec![0; 32768];
+  let mut run = Some(run);
+  let mut columns = Vec::new();
+  let mut output = String::new();
+
+  for command in &script.commands {
+    match command {
+      Command::Set {
+        name,
+        index: Some(index),
+        value,
+      } if name == "RAM" => ram[*index as usize] = *value,
+      Command::OutputList(list) => {
+        columns = list.clone();
+        output.push_str(&script::header(&columns));
+      }
+      Command::Output => {
+        if let Some(run) = run.take() {
+          ram = run(ram).map_err(|err| format!("{}: {}", name, err))?;
+        }
+        let values: Vec<i16> = columns
+          .iter()
+          .map(|column| ram[column.index.unwrap() as usize])
+          .collect();
+        output.push_str(&script::row(&columns, &values));
+      }
+      _ => (),
+    }
+  }
+
+  script::compare(&output, &read("cmp")?).map_err(|err| format!("{}: {}", name, err))
+}
+
+/// A program running every arithmetic command on pairs of awkward values,
+/// leaving the results in turn from `RESULTS`, and the results the
+/// interpreter gets
+pub fn arithmetic() -> (SourceFile, Vec<i16>) {
+  let push = |value: i16| match value {
+    -32768 => String::from("push constant 32767\nneg\npush constant 1\nsub\n"),
+    _ if value < 0 => format!("push constant {}\nneg\n", -value),
+    _ => format!("push constant {}\n", value),
+  };
+
+  let mut source = format!("push constant {}\npop pointer 1\n", RESULTS);
+  let mut results = 0;
+  for operation in &OPERATIONS {
+    for x in &VALUES {
+      for y in &VALUES {
+        source.push_str(&push(*x));
+        source.push_str(&push(*y));
+        source.push_str(&format!("{}\npop that {}\n", operation, results));
+        results += 1;
+      }
+    }
+  }
+
+  let file = parse_file(&source, "Main").unwrap();
+  let mut vm = Vm::new(std::slice::from_ref(&file));
+  vm.set("sp", None, 256).unwrap();
+  while !vm.is_halted() {
+    vm.step().unwrap();
+  }
+
+  let expected = (0..results)
+    .map(|result| vm.read("RAM", Some((RESULTS + result) as u16)).unwrap())
+    .collect();
+  (file, expected)
+}
+
+/// Checks RAM as a program from `arithmetic` halted with it
+pub fn check_arithmetic(ram: &[i16], expected: &[i16]) {
+  for (result, expected) in expected.iter().enumerate() {
+    assert_eq!(
+      ram[RESULTS + result],
+      *expected,
+      "{} {} {}",
+      VALUES[result / VALUES.len() % VALUES.len()],
+      OPERATIONS[result / (VALUES.len() * VALUES.len())],
+      VALUES[result % VALUES.len()]
+    );
+  }
+}
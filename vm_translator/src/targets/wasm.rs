@@ -0,0 +1,503 @@
+//! Writes programs as a WebAssembly text module, for sharing them as
+//! browser demos. RAM is the module's one page of linear memory, exported
+//! as `memory`, holding each word at twice its address, as on the Hack
+//! machine, so the OS finds the heap and screen where it expects them.
+//! Values are kept as `i32`s, loaded sign-extended and stored truncated,
+//! which wraps them to 16 bits.
+//!
+//! Each VM function is a wasm function. One with labels to jump to runs its
+//! code in a dispatch loop, a block for each label, which jumps set the
+//! index of. Calls push the same frames as the book's translation, leaving
+//! only the return address to wasm, and the stack pointer is a global,
+//! stored back in RAM[0] when the program stops. Static variables are
+//! globals of their own.
+//!
+//! The module imports the host's side of the machine from `env`:
+//!
+//! ```text
+//! key() -> i32              the key held down, asked for whenever the
+//!                           program reads the keyboard
+//! screen(address, value)    called when the program writes to the screen
+//! halt()                    called when the program halts, which should
+//!                           throw to stop it; returning traps
+//! ```
+//!
+//! and exports `run`, which starts the program with RAM as the host left it,
+//! the stack pointer being 256 unless the host sets RAM[0]. Programs halt
+//! when they reach the code of `Sys.halt`, inlined or not, jump to the label
+//! they're at or return from `Sys.init`.
+
+use super::{mangle, Program, Routine, HALT};
+use crate::callgraph::ENTRY_POINT;
+use crate::parser::{MathCommand, MemorySegment, SourceCommand, VmCommand};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+const RUNTIME: &str = r#"  (import "env" "key" (func $key (result i32)))
+  (import "env" "screen" (func $screen (param i32 i32)))
+  (import "env" "halt" (func $halt))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\00\01")
+  (global $sp (mut i32) (i32.const 0))
+
+  (func $push (param $value i32)
+    (i32.store16 (i32.shl (global.get $sp) (i32.const 1)) (local.get $value))
+    (global.set $sp (i32.and (i32.add (global.get $sp) (i32.const 1)) (i32.const 0x7fff))))
+
+  (func $pop (result i32)
+    (global.set $sp (i32.and (i32.sub (global.get $sp) (i32.const 1)) (i32.const 0x7fff)))
+    (i32.load16_s (i32.shl (global.get $sp) (i32.const 1))))
+
+  ;; a word behind a pointer, which may be the keyboard
+  (func $load (param $address i32) (result i32)
+    (local.set $address (i32.and (local.get $address) (i32.const 0x7fff)))
+    (if (i32.eq (local.get $address) (i32.const 24576))
+      (then (i32.store16 (i32.const 49152) (call $key))))
+    (i32.load16_s (i32.shl (local.get $address) (i32.const 1))))
+
+  ;; a word behind a pointer, which may be on the screen
+  (func $store (param $address i32) (param $value i32)
+    (local.set $address (i32.and (local.get $address) (i32.const 0x7fff)))
+    (i32.store16 (i32.shl (local.get $address) (i32.const 1)) (local.get $value))
+    (if (i32.and (i32.ge_u (local.get $address) (i32.const 16384))
+                 (i32.lt_u (local.get $address) (i32.const 24576)))
+      (then (call $screen (local.get $address) (local.get $value)))))
+
+  ;; pushes a frame as the book's translation does, before a call
+  (func $frame (param $nargs i32)
+    (call $push (i32.const 0))
+    (call $push (i32.load16_s (i32.const 2)))
+    (call $push (i32.load16_s (i32.const 4)))
+    (call $push (i32.load16_s (i32.const 6)))
+    (call $push (i32.load16_s (i32.const 8)))
+    (i32.store16 (i32.const 4) (i32.sub (global.get $sp) (i32.add (local.get $nargs) (i32.const 5))))
+    (i32.store16 (i32.const 2) (global.get $sp)))
+
+  ;; leaves the return value in place of the arguments and restores the
+  ;; caller's frame
+  (func $return
+    (local $frame i32)
+    (local.set $frame (i32.load16_u (i32.const 2)))
+    (call $store (i32.load16_s (i32.const 4)) (call $pop))
+    (global.set $sp (i32.and (i32.add (i32.load16_s (i32.const 4)) (i32.const 1)) (i32.const 0x7fff)))
+    (i32.store (i32.const 2) (i32.load (i32.shl (i32.sub (local.get $frame) (i32.const 4)) (i32.const 1))))
+    (i32.store (i32.const 6) (i32.load (i32.shl (i32.sub (local.get $frame) (i32.const 2)) (i32.const 1)))))
+
+  (func $stop
+    (i32.store16 (i32.const 0) (global.get $sp))
+    (call $halt)
+    (unreachable))
+"#;
+
+/// The text of the whole module, with each command preceded by a comment
+/// naming it and where it came from if `annotate` is set
+pub fn write(program: &Program, annotate: bool) -> String {
+  let mut wat = String::from(";; Translated from VM code by vm_translator\n(module\n");
+  wat.push_str(RUNTIME);
+
+  wat.push('\n');
+  for (namespace, words) in &program.statics {
+    for index in 0..*words {
+      writeln!(
+        wat,
+        "  (global {} (mut i32) (i32.const 0))",
+        static_name(namespace, index)
+      )
+      .unwrap();
+    }
+  }
+
+  wat.push_str("\n  (func (export \"run\")\n    (global.set $sp (i32.load16_u (i32.const 0)))\n");
+  if program.bootstrap {
+    // as the bootstrap code does, with the segments' pointers set apart
+    wat.push_str(
+      "    (global.set $sp (i32.const 256))\n    (i32.store (i32.const 2) (i32.const 0xfffeffff))\n    \
+       (i32.store (i32.const 6) (i32.const 0xfffcfffd))\n",
+    );
+    writeln!(
+      wat,
+      "    (call $frame (i32.const 0))\n    (call $f_{})",
+      mangle(ENTRY_POINT)
+    )
+    .unwrap();
+  } else {
+    for n in program.start() {
+      writeln!(wat, "    (call ${})", routine_name(program, n)).unwrap();
+    }
+  }
+  wat.push_str("    (call $stop))\n");
+
+  for n in 0..program.routines.len() {
+    write_routine(&mut wat, program, n, annotate);
+  }
+
+  wat.push_str(")\n");
+  wat
+}
+
+fn routine_name(program: &Program, n: usize) -> String {
+  match &program.routines[n].name {
+    Some(name) => format!("f_{}", mangle(name)),
+    None => format!("top_{}", n),
+  }
+}
+
+fn static_name(namespace: &str, index: u32) -> String {
+  format!("$s_{}_{}", mangle(namespace), index)
+}
+
+/// The labels jumped to, which start blocks of the dispatch loop, numbered
+/// from 1 after the code before the first of them
+fn blocks(program: &Program, routine: &Routine) -> HashMap<String, usize> {
+  let targets: HashSet<&str> = routine
+    .commands
+    .iter()
+    .filter(|command| !program.halts(command))
+    .filter_map(|command| match &command.command {
+      VmCommand::Goto(label) | VmCommand::IfGoto(label) => Some(label.as_str()),
+      _ => None,
+    })
+    .collect();
+
+  let mut blocks = HashMap::new();
+  for command in &routine.commands {
+    if let VmCommand::Label(label) = &command.command {
+      if targets.contains(label.as_str()) && !blocks.contains_key(label) {
+        blocks.insert(label.clone(), blocks.len() + 1);
+      }
+    }
+  }
+  blocks
+}
+
+fn write_routine(wat: &mut String, program: &Program, n: usize, annotate: bool) {
+  let routine = &program.routines[n];
+  let blocks = blocks(program, routine);
+  writeln!(
+    wat,
+    "\n  (func ${}\n    (local $x i32) (local $y i32) (local $block i32)",
+    routine_name(program, n)
+  )
+  .unwrap();
+  for _ in 0..routine.nlocals {
+    wat.push_str("    (call $push (i32.const 0))\n");
+  }
+
+  let indent = if blocks.is_empty() {
+    "    "
+  } else {
+    wat.push_str("    (loop $dispatch\n");
+    for block in (0..=blocks.len()).rev() {
+      writeln!(wat, "    (block $b{}", block).unwrap();
+    }
+    let table: Vec<String> = (0..=blocks.len())
+      .map(|block| format!("$b{}", block))
+      .collect();
+    writeln!(
+      wat,
+      "      (br_table {} (local.get $block)))",
+      table.join(" ")
+    )
+    .unwrap();
+    "      "
+  };
+
+  let mut at_label = None;
+  let mut halted = false;
+
+  for command in &routine.commands {
+    if annotate {
+      writeln!(wat, "{};; {} {}", indent, command.span, command.command).unwrap();
+    }
+
+    let halts = program.halts(command);
+    if let VmCommand::Label(label) = &command.command {
+      if let Some(block) = blocks.get(label) {
+        // jumps to the label's block land after its end
+        writeln!(wat, "    ) ;; $b{}", block).unwrap();
+        halted = false;
+      }
+    }
+
+    let code = match &command.command {
+      // inlined code of Sys.halt, which halts once reached and can only be
+      // jumped into at its labels
+      _ if halts && halted => String::new(),
+      _ if halts => String::from("(call $stop)"),
+      VmCommand::Label(_) => String::new(),
+      VmCommand::Goto(label) if at_label == Some(label) => String::from("(call $stop)"),
+      VmCommand::Goto(label) => format!(
+        "(local.set $block (i32.const {})) (br $dispatch)",
+        blocks[label]
+      ),
+      VmCommand::IfGoto(label) => format!(
+        "(if (call $pop) (then (local.set $block (i32.const {})) (br $dispatch)))",
+        blocks[label]
+      ),
+      VmCommand::Call { name, .. } if name == HALT => String::from("(call $stop)"),
+      VmCommand::Call { name, nargs } => format!(
+        "(call $frame (i32.const {})) (call $f_{})",
+        nargs,
+        mangle(name)
+      ),
+      VmCommand::Return if routine.name.as_deref() == Some(ENTRY_POINT) => {
+        String::from("(call $stop)")
+      }
+      VmCommand::Return => String::from("(call $return) (return)"),
+      _ => command_code(command, &routine.namespace),
+    };
+    if !code.is_empty() {
+      writeln!(wat, "{}{}", indent, code).unwrap();
+    }
+
+    halted = halts;
+    at_label = match &command.command {
+      VmCommand::Label(label) => Some(label),
+      _ => None,
+    };
+  }
+
+  if !blocks.is_empty() {
+    wat.push_str("    ) ;; $dispatch\n");
+  }
+  wat.push_str("  )\n");
+}
+
+/// The code of a push, pop or arithmetic command
+fn command_code(command: &SourceCommand, namespace: &str) -> String {
+  match &command.command {
+    VmCommand::Push { segment, index } => {
+      let value = match segment {
+        MemorySegment::Constant => format!("(i32.const {})", index),
+        MemorySegment::Static => {
+          format!("(global.get {})", static_name(namespace, u32::from(*index)))
+        }
+        _ => match direct(*segment, *index) {
+          Some(address) => format!("(i32.load16_s (i32.const {}))", address),
+          None => format!("(call $load {})", address(*segment, *index)),
+        },
+      };
+      format!("(call $push {})", value)
+    }
+    VmCommand::Pop { segment, index } => match segment {
+      MemorySegment::Static => format!(
+        "(global.set {} (call $pop))",
+        static_name(namespace, u32::from(*index))
+      ),
+      _ => match direct(*segment, *index) {
+        Some(address) => format!("(i32.store16 (i32.const {}) (call $pop))", address),
+        None => format!("(call $store {} (call $pop))", address(*segment, *index)),
+      },
+    },
+    VmCommand::Arithmetic(math) => arithmetic(*math),
+    _ => unreachable!("control flow is written apart"),
+  }
+}
+
+/// The byte address of a word of the pointer and temp segments
+fn direct(segment: MemorySegment, index: u16) -> Option<u16> {
+  match segment {
+    MemorySegment::Pointer => Some(2 * (3 + index)),
+    MemorySegment::Temp => Some(2 * (5 + index)),
+    _ => None,
+  }
+}
+
+/// The word address of a word of a segment kept behind a pointer
+fn address(segment: MemorySegment, index: u16) -> String {
+  let pointer = match segment {
+    MemorySegment::Local => 1,
+    MemorySegment::Argument => 2,
+    MemorySegment::This => 3,
+    MemorySegment::That => 4,
+    _ => unreachable!("only local, argument, this and that are behind pointers"),
+  };
+
+  match index {
+    0 => format!("(i32.load16_s (i32.const {}))", 2 * pointer),
+    _ => format!(
+      "(i32.add (i32.load16_s (i32.const {})) (i32.const {}))",
+      2 * pointer,
+      index
+    ),
+  }
+}
+
+/// The code for an arithmetic command, as the interpreter evaluates it,
+/// with y and x taken off the stack into locals
+fn arithmetic(math: MathCommand) -> String {
+  use MathCommand::*;
+
+  let result = match math {
+    Negate => return String::from("(call $push (i32.sub (i32.const 0) (call $pop)))"),
+    Not => return String::from("(call $push (i32.xor (call $pop) (i32.const -1)))"),
+    Add => "(i32.add (local.get $x) (local.get $y))",
+    Subtract => "(i32.sub (local.get $x) (local.get $y))",
+    EqualTo => "(i32.sub (i32.const 0) (i32.eq (local.get $x) (local.get $y)))",
+    GreaterThan => "(i32.sub (i32.const 0) (i32.gt_s (local.get $x) (local.get $y)))",
+    LessThan => "(i32.sub (i32.const 0) (i32.lt_s (local.get $x) (local.get $y)))",
+    And => "(i32.and (local.get $x) (local.get $y))",
+    Or => "(i32.or (local.get $x) (local.get $y))",
+    Xor => "(i32.xor (local.get $x) (local.get $y))",
+    Multiply => "(i32.mul (local.get $x) (local.get $y))",
+    // as the writer's routines do, dividing by 0 giving 1 or -1, against
+    // the sign of x, and leaving x as the remainder
+    Divide => {
+      "(if (result i32) (local.get $y) (then (i32.div_s (local.get $x) (local.get $y))) \
+       (else (select (i32.const 1) (i32.const -1) (i32.lt_s (local.get $x) (i32.const 0)))))"
+    }
+    Modulo => {
+      "(if (result i32) (local.get $y) (then (i32.rem_s (local.get $x) (local.get $y))) \
+       (else (local.get $x)))"
+    }
+    // wasm only counts the low 5 bits of a shift, so longer ones are done
+    // apart: to 0 to the left, and to the sign by 15 to the right
+    ShiftLeft => {
+      "(select (i32.const 0) (i32.shl (local.get $x) (local.get $y)) \
+       (i32.ge_u (i32.and (local.get $y) (i32.const 0xffff)) (i32.const 16)))"
+    }
+    ShiftRight => {
+      "(i32.shr_s (local.get $x) (select (i32.const 15) (local.get $y) \
+       (i32.gt_u (i32.and (local.get $y) (i32.const 0xffff)) (i32.const 15))))"
+    }
+    LessThanUnsigned => {
+      "(i32.sub (i32.const 0) (i32.lt_u (i32.and (local.get $x) (i32.const 0xffff)) \
+       (i32.and (local.get $y) (i32.const 0xffff))))"
+    }
+    GreaterThanUnsigned => {
+      "(i32.sub (i32.const 0) (i32.gt_u (i32.and (local.get $x) (i32.const 0xffff)) \
+       (i32.and (local.get $y) (i32.const 0xffff))))"
+    }
+  };
+
+  format!(
+    "(local.set $y (call $pop)) (local.set $x (call $pop)) (call $push {})",
+    result
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulate::test_programs;
+  use crate::targets::testing;
+  use crate::{parse_file, targets, Options};
+  use std::path::Path;
+  use wasmi::{Caller, Engine, Error, Linker, Module, Store};
+
+  /// The host's side of the machine
+  #[derive(Default)]
+  struct Host {
+    /// What the keyboard reads in turn, and then 0
+    keys: Vec<i32>,
+    screen: Vec<(i32, i32)>,
+  }
+
+  /// Runs the module from the given RAM on a wasm interpreter, returning
+  /// RAM as it halted
+  fn run(program: &Program, ram: Vec<i16>, host: Host) -> Result<(Vec<i16>, Host), String> {
+    let engine = Engine::default();
+    let binary = wat::parse_str(write(program, true)).map_err(|err| err.to_string())?;
+    let module = Module::new(&engine, &binary[..]).map_err(|err| err.to_string())?;
+
+    let mut store = Store::new(&engine, host);
+    let mut linker = Linker::<Host>::new(&engine);
+    linker
+      .func_wrap("env", "key", |mut caller: Caller<'_, Host>| {
+        let keys = &mut caller.data_mut().keys;
+        if keys.is_empty() {
+          0
+        } else {
+          keys.remove(0)
+        }
+      })
+      .unwrap()
+      .func_wrap(
+        "env",
+        "screen",
+        |mut caller: Caller<'_, Host>, address: i32, value: i32| {
+          caller.data_mut().screen.push((address, value))
+        },
+      )
+      .unwrap()
+      .func_wrap("env", "halt", || -> Result<(), Error> {
+        Err(Error::i32_exit(0))
+      })
+      .unwrap();
+    let instance = linker
+      .instantiate(&mut store, &module)
+      .and_then(|instance| instance.start(&mut store))
+      .map_err(|err| err.to_string())?;
+
+    let memory = instance.get_memory(&store, "memory").unwrap();
+    let image: Vec<u8> = ram.iter().flat_map(|word| word.to_le_bytes()).collect();
+    memory.data_mut(&mut store)[..image.len()].copy_from_slice(&image);
+
+    let run = instance
+      .get_typed_func::<(), ()>(&store, "run")
+      .map_err(|err| err.to_string())?;
+    match run.call(&mut store, ()) {
+      Err(err) if err.i32_exit_status() == Some(0) => (),
+      other => return Err(format!("ran to {:?} rather than halting", other)),
+    }
+
+    let ram = memory.data(&store)[..image.len()]
+      .chunks(2)
+      .map(|word| i16::from_le_bytes([word[0], word[1]]))
+      .collect();
+    Ok((ram, store.into_data()))
+  }
+
+  fn boot() -> Vec<i16> {
+    let mut ram = vec![0; 32768];
+    ram[0] = 256;
+    ram
+  }
+
+  #[test]
+  fn does_arithmetic_as_the_interpreter_does() {
+    let (file, expected) = testing::arithmetic();
+    let (ram, _) = run(&Program::build(&[file]).unwrap(), boot(), Host::default()).unwrap();
+    testing::check_arithmetic(&ram, &expected);
+  }
+
+  #[test]
+  fn passes_the_test_programs() {
+    for directory in
+      test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path()).unwrap()
+    {
+      let program =
+        targets::prepare(&[directory.display().to_string()], &Options::default()).unwrap();
+      testing::run_script(&directory, |ram| {
+        run(&program, ram, Host::default()).map(|(ram, _)| ram)
+      })
+      .unwrap();
+    }
+  }
+
+  #[test]
+  fn reads_keys_and_draws_through_the_host() {
+    let main = parse_file(
+      "function Sys.init 0\n\
+       label WAIT\npush constant 24576\npop pointer 1\npush that 0\npush constant 0\neq\n\
+       if-goto WAIT\n\
+       push that 0\npop temp 0\n\
+       push constant 16400\npop pointer 1\npush temp 0\npop that 2\n\
+       label END\ngoto END",
+      "Sys",
+    )
+    .unwrap();
+
+    let host = Host {
+      keys: vec![0, 0, 65, 66],
+      ..Host::default()
+    };
+    let (ram, host) = run(&Program::build(&[main]).unwrap(), boot(), host).unwrap();
+
+    assert_eq!(ram[5], 66);
+    assert_eq!(ram[16402], 66);
+    assert_eq!(host.keys, vec![]);
+    assert_eq!(host.screen, vec![(16402, 66)]);
+  }
+}
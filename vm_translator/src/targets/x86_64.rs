@@ -0,0 +1,592 @@
+//! Writes programs as GNU assembler source for x86-64 Linux, to build with
+//! `as -o program.o program.s && ld -o program program.o`, without libc.
+//!
+//! RAM is an array of 32K words, addressed by the low 15 bits of a pointer
+//! as in the C and WebAssembly targets, so the OS finds the heap and screen
+//! where it expects them. The stack lives in it with its pointer kept in
+//! `%r12`, wrapping within those 15 bits, stored back in RAM[0] once the
+//! program halts. Calls push the same frames as the book's translation, so
+//! `LCL`, `ARG`, `THIS` and `THAT` stay in RAM[1..4]; only the return
+//! address is left to the native stack. Static variables are arrays of
+//! their own, one per file, as in the C target.
+//!
+//! The program ends when it reaches the code of `Sys.halt`, inlined or not,
+//! jumps to the label it's at or returns from `Sys.init`. A small runtime
+//! comes with it, taking these arguments:
+//!
+//! ```text
+//! -r RAM          start from this RAM image, 32K little-endian words, with
+//!                 the stack pointer in RAM[0]
+//! -w RAM          write the RAM image once halted
+//! -o SCREEN.pbm   write the screen as a PBM image once halted
+//! ```
+
+use super::{mangle, Program, HALT};
+use crate::callgraph::ENTRY_POINT;
+use crate::parser::{MathCommand, MemorySegment, VmCommand};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+const RUNTIME: &str = r#"        .set SCREEN, 16384
+        .set RAM_BYTES, 65536
+        .set O_WRONLY_CREAT_TRUNC, 0x241
+
+        .bss
+        .balign 16
+        .skip 16                        # for frames at the bottom of RAM
+ram:    .skip RAM_BYTES
+pbm:    .skip 16384
+ram_in: .skip 8
+ram_out:
+        .skip 8
+screen_out:
+        .skip 8
+
+        .section .rodata
+pbm_header:
+        .ascii "P4\n512 256\n"
+        .set PBM_HEADER_SIZE, . - pbm_header
+usage:  .ascii "usage: PROGRAM [-r RAM] [-w RAM] [-o SCREEN.pbm]\n"
+        .set USAGE_SIZE, . - usage
+cant_open:
+        .ascii "can't open a file\n"
+        .set CANT_OPEN_SIZE, . - cant_open
+
+        .text
+        .globl _start
+_start:
+        leaq ram(%rip), %rbx
+        movq (%rsp), %rcx               # argc, then the arguments left
+        leaq 16(%rsp), %rsi
+        decq %rcx
+1:      testq %rcx, %rcx
+        jz 3f
+        cmpq $2, %rcx
+        jb vm_usage
+        movq (%rsi), %rdi
+        cmpb $0x2d, (%rdi)              # '-'
+        jne vm_usage
+        cmpb $0, 2(%rdi)
+        jne vm_usage
+        movb 1(%rdi), %al
+        leaq ram_in(%rip), %rdx
+        cmpb $0x72, %al                 # 'r'
+        je 2f
+        leaq ram_out(%rip), %rdx
+        cmpb $0x77, %al                 # 'w'
+        je 2f
+        leaq screen_out(%rip), %rdx
+        cmpb $0x6f, %al                 # 'o'
+        jne vm_usage
+2:      movq 8(%rsi), %rdi
+        movq %rdi, (%rdx)
+        addq $16, %rsi
+        subq $2, %rcx
+        jmp 1b
+3:      movw $256, (%rbx)
+        movq ram_in(%rip), %rdi
+        testq %rdi, %rdi
+        jz 4f
+        xorl %esi, %esi                 # O_RDONLY
+        call vm_open
+        movl %eax, %edi
+        movq %rbx, %rsi
+        movl $RAM_BYTES, %edx
+        xorl %eax, %eax                 # read
+        syscall
+        movl $3, %eax                   # close
+        syscall
+4:      movzwl (%rbx), %r12d
+        andl $0x7fff, %r12d
+        jmp vm_main
+
+vm_halt:
+        movw %r12w, (%rbx)
+        movq ram_out(%rip), %rdi
+        testq %rdi, %rdi
+        jz 1f
+        movl $O_WRONLY_CREAT_TRUNC, %esi
+        call vm_open
+        movl %eax, %edi
+        movq %rbx, %rsi
+        movl $RAM_BYTES, %edx
+        movl $1, %eax                   # write
+        syscall
+        movl $3, %eax
+        syscall
+1:      cmpq $0, screen_out(%rip)
+        je 4f
+        # rows of PBM have their leftmost pixel in the top bit of each byte,
+        # where those of the screen have it in the bottom one
+        leaq 2 * SCREEN(%rbx), %rsi
+        leaq pbm(%rip), %rdi
+        movl $16384, %r8d
+2:      movb (%rsi), %al
+        movl $8, %ecx
+3:      shrb %al
+        rclb %dl
+        loop 3b
+        movb %dl, (%rdi)
+        incq %rsi
+        incq %rdi
+        decl %r8d
+        jnz 2b
+        movq screen_out(%rip), %rdi
+        movl $O_WRONLY_CREAT_TRUNC, %esi
+        call vm_open
+        movl %eax, %r13d
+        movl %eax, %edi
+        leaq pbm_header(%rip), %rsi
+        movl $PBM_HEADER_SIZE, %edx
+        movl $1, %eax
+        syscall
+        movl %r13d, %edi
+        leaq pbm(%rip), %rsi
+        movl $16384, %edx
+        movl $1, %eax
+        syscall
+        movl %r13d, %edi
+        movl $3, %eax
+        syscall
+4:      xorl %edi, %edi
+        movl $60, %eax                  # exit
+        syscall
+
+# opens the file at %rdi with the flags in %esi, returning it in %eax
+vm_open:
+        movl $0644, %edx
+        movl $2, %eax
+        syscall
+        testq %rax, %rax
+        js 1f
+        ret
+1:      leaq cant_open(%rip), %rsi
+        movl $CANT_OPEN_SIZE, %edx
+        jmp vm_fail
+
+vm_usage:
+        leaq usage(%rip), %rsi
+        movl $USAGE_SIZE, %edx
+vm_fail:
+        movl $2, %edi                   # stderr
+        movl $1, %eax
+        syscall
+        movl $1, %edi
+        movl $60, %eax
+        syscall
+"#;
+
+/// The assembly of the whole program, with each command preceded by a
+/// comment naming it and where it came from if `annotate` is set
+pub fn write(program: &Program, annotate: bool) -> String {
+  let mut s = String::from("# Translated from VM code by vm_translator\n");
+  s.push_str(RUNTIME);
+
+  for (namespace, words) in &program.statics {
+    if *words > 0 {
+      writeln!(s, "        .lcomm s_{}, {}", mangle(namespace), 2 * words).unwrap();
+    }
+  }
+
+  s.push_str("\nvm_main:\n");
+  if program.bootstrap {
+    // as the bootstrap code does, with the segments' pointers set apart
+    s.push_str(
+      "        movl $256, %r12d\n        movw $-1, 2(%rbx)\n        movw $-2, 4(%rbx)\n        \
+       movw $-3, 6(%rbx)\n        movw $-4, 8(%rbx)\n",
+    );
+    call(&mut s, ENTRY_POINT, 0);
+  } else {
+    for n in program.start() {
+      writeln!(s, "        call {}", routine_name(program, n)).unwrap();
+    }
+  }
+  s.push_str("        jmp vm_halt\n");
+
+  for n in 0..program.routines.len() {
+    write_routine(&mut s, program, n, annotate);
+  }
+
+  s
+}
+
+fn routine_name(program: &Program, n: usize) -> String {
+  match &program.routines[n].name {
+    Some(name) => format!("f_{}", mangle(name)),
+    None => format!("top_{}", n),
+  }
+}
+
+fn write_routine(s: &mut String, program: &Program, n: usize, annotate: bool) {
+  let routine = &program.routines[n];
+  writeln!(s, "\n{}:", routine_name(program, n)).unwrap();
+  if routine.nlocals > 0 {
+    writeln!(
+      s,
+      "        leaq (%rbx,%r12,2), %rdi\n        movl ${}, %ecx\n        xorl %eax, %eax\n        \
+       rep stosw\n        addl ${}, %r12d\n        andl $0x7fff, %r12d",
+      routine.nlocals, routine.nlocals
+    )
+    .unwrap();
+  }
+
+  let statics = format!("s_{}", mangle(&routine.namespace));
+  let label = |label: &str| format!(".L{}_{}", n, mangle(label));
+  let targets: HashSet<&str> = routine
+    .commands
+    .iter()
+    .filter(|command| !program.halts(command))
+    .filter_map(|command| match &command.command {
+      VmCommand::Goto(label) | VmCommand::IfGoto(label) => Some(label.as_str()),
+      _ => None,
+    })
+    .collect();
+  let mut at_label = None;
+  let mut halted = false;
+
+  for command in &routine.commands {
+    if annotate {
+      writeln!(s, "        # {} {}", command.span, command.command).unwrap();
+    }
+
+    let halts = program.halts(command);
+    match &command.command {
+      // inlined code of Sys.halt, which halts once reached and can only be
+      // jumped into at its labels
+      VmCommand::Label(name) if halts && targets.contains(name.as_str()) => {
+        writeln!(s, "{}:\n        jmp vm_halt", label(name)).unwrap()
+      }
+      _ if halts && halted => (),
+      _ if halts => s.push_str("        jmp vm_halt\n"),
+      VmCommand::Arithmetic(math) => arithmetic(s, *math),
+      VmCommand::Push {
+        segment: MemorySegment::Constant,
+        index,
+      } => writeln!(
+        s,
+        "        movw ${}, (%rbx,%r12,2)\n        incl %r12d\n        andl $0x7fff, %r12d",
+        index
+      )
+      .unwrap(),
+      VmCommand::Push { segment, index } => {
+        let operand = operand(s, *segment, *index, &statics);
+        writeln!(
+          s,
+          "        movw {}, %ax\n        movw %ax, (%rbx,%r12,2)\n        \
+           incl %r12d\n        andl $0x7fff, %r12d",
+          operand
+        )
+        .unwrap();
+      }
+      VmCommand::Pop { segment, index } => {
+        s.push_str(
+          "        decl %r12d\n        andl $0x7fff, %r12d\n        movw (%rbx,%r12,2), %ax\n",
+        );
+        let operand = operand(s, *segment, *index, &statics);
+        writeln!(s, "        movw %ax, {}", operand).unwrap();
+      }
+      VmCommand::Label(name) if targets.contains(name.as_str()) => {
+        writeln!(s, "{}:", label(name)).unwrap()
+      }
+      VmCommand::Label(_) => (),
+      VmCommand::Goto(name) if at_label == Some(name) => s.push_str("        jmp vm_halt\n"),
+      VmCommand::Goto(name) => writeln!(s, "        jmp {}", label(name)).unwrap(),
+      VmCommand::IfGoto(name) => writeln!(
+        s,
+        "        decl %r12d\n        andl $0x7fff, %r12d\n        \
+         cmpw $0, (%rbx,%r12,2)\n        jne {}",
+        label(name)
+      )
+      .unwrap(),
+      VmCommand::Call { name, .. } if name == HALT => s.push_str("        jmp vm_halt\n"),
+      VmCommand::Call { name, nargs } => call(s, name, *nargs),
+      VmCommand::Return if routine.name.as_deref() == Some(ENTRY_POINT) => {
+        s.push_str("        jmp vm_halt\n")
+      }
+      VmCommand::Return => s.push_str(
+        "        movzwl 2(%rbx), %ecx            # the frame\n        \
+         andl $0x7fff, %ecx\n        \
+         decl %r12d\n        andl $0x7fff, %r12d\n        movw (%rbx,%r12,2), %ax\n        \
+         movzwl 4(%rbx), %edx\n        andl $0x7fff, %edx\n        \
+         movw %ax, (%rbx,%rdx,2)\n        \
+         leal 1(%edx), %r12d\n        andl $0x7fff, %r12d\n        \
+         movl -8(%rbx,%rcx,2), %eax      # LCL and ARG\n        movl %eax, 2(%rbx)\n        \
+         movl -4(%rbx,%rcx,2), %eax      # THIS and THAT\n        movl %eax, 6(%rbx)\n        \
+         ret\n",
+      ),
+      VmCommand::Function { .. } => unreachable!("routines start after their function command"),
+    }
+
+    halted = halts;
+    at_label = match &command.command {
+      VmCommand::Label(name) => Some(name),
+      _ => None,
+    };
+  }
+
+  // for code that runs off the end of its function
+  s.push_str("        ret\n");
+}
+
+/// Pushes a frame as the book's translation does, saving the segments'
+/// pointers two at a time, and calls the function
+fn call(s: &mut String, name: &str, nargs: u16) {
+  writeln!(
+    s,
+    "        movw $0, (%rbx,%r12,2)\n        \
+     movl 2(%rbx), %eax\n        movl %eax, 2(%rbx,%r12,2)\n        \
+     movl 6(%rbx), %eax\n        movl %eax, 6(%rbx,%r12,2)\n        \
+     leal -{}(%r12), %eax\n        movw %ax, 4(%rbx)\n        \
+     addl $5, %r12d\n        andl $0x7fff, %r12d\n        \
+     movw %r12w, 2(%rbx)\n        call f_{}",
+    nargs,
+    mangle(name)
+  )
+  .unwrap();
+}
+
+/// The operand for a word of a segment, first writing what finds its
+/// address into `%rdx`, wrapped to 15 bits, for segments kept behind a
+/// pointer
+fn operand(s: &mut String, segment: MemorySegment, index: u16, statics: &str) -> String {
+  let pointer = match segment {
+    MemorySegment::Local => 1,
+    MemorySegment::Argument => 2,
+    MemorySegment::This => 3,
+    MemorySegment::That => 4,
+    MemorySegment::Pointer => return format!("{}(%rbx)", 2 * (3 + index)),
+    MemorySegment::Temp => return format!("{}(%rbx)", 2 * (5 + index)),
+    MemorySegment::Static => return format!("{}+{}(%rip)", statics, 2 * u32::from(index)),
+    MemorySegment::Constant => unreachable!("constants aren't locations"),
+  };
+
+  writeln!(s, "        movzwl {}(%rbx), %edx", 2 * pointer).unwrap();
+  if index > 0 {
+    writeln!(s, "        addw ${}, %dx", index).unwrap();
+  }
+  s.push_str("        andl $0x7fff, %edx\n");
+  String::from("(%rbx,%rdx,2)")
+}
+
+/// Writes an arithmetic command, as the interpreter evaluates it, with y in
+/// `%cx` and x left in place below it
+fn arithmetic(s: &mut String, math: MathCommand) {
+  use MathCommand::*;
+
+  const X: &str = "-2(%rbx,%r12,2)";
+
+  match math {
+    Negate => return writeln!(s, "        negw {}", X).unwrap(),
+    Not => return writeln!(s, "        notw {}", X).unwrap(),
+    _ => s.push_str(
+      "        decl %r12d\n        andl $0x7fff, %r12d\n        movw (%rbx,%r12,2), %cx\n",
+    ),
+  }
+
+  let compare = |condition: &str| {
+    format!(
+      "        movw {x}, %ax\n        cmpw %cx, %ax\n        set{} %al\n        \
+       movzbl %al, %eax\n        negl %eax\n        movw %ax, {x}",
+      condition,
+      x = X
+    )
+  };
+  let code = match math {
+    Add => format!("        addw %cx, {}", X),
+    Subtract => format!("        subw %cx, {}", X),
+    And => format!("        andw %cx, {}", X),
+    Or => format!("        orw %cx, {}", X),
+    Xor => format!("        xorw %cx, {}", X),
+    EqualTo => compare("e"),
+    GreaterThan => compare("g"),
+    LessThan => compare("l"),
+    LessThanUnsigned => compare("b"),
+    GreaterThanUnsigned => compare("a"),
+    Multiply => format!(
+      "        movw {x}, %ax\n        imulw %cx, %ax\n        movw %ax, {x}",
+      x = X
+    ),
+    // as the writer's routines do, dividing by 0 giving 1 or -1, against
+    // the sign of x, and leaving x as the remainder
+    Divide | Modulo => format!(
+      "        movswl {x}, %eax\n        movswl %cx, %ecx\n        testl %ecx, %ecx\n        \
+       jz 1f\n        cltd\n        idivl %ecx\n        movw {result}, {x}\n        jmp 2f\n\
+       1:{zero}\
+       2:",
+      x = X,
+      result = if math == Divide { "%ax" } else { "%dx" },
+      zero = if math == Divide {
+        format!(
+          "      sarl $31, %eax\n        leal 1(%eax,%eax), %eax\n        negl %eax\n        \
+           movw %ax, {}\n",
+          X
+        )
+      } else {
+        String::from("\n")
+      },
+    ),
+    // x86 only counts the low 5 bits of a shift, so longer ones are done
+    // apart: to 0 to the left, and to the sign by 15 to the right
+    ShiftLeft => format!(
+      "        movzwl %cx, %ecx\n        movzwl {x}, %eax\n        shll %cl, %eax\n        \
+       xorl %edx, %edx\n        cmpl $16, %ecx\n        cmovael %edx, %eax\n        movw %ax, {x}",
+      x = X
+    ),
+    ShiftRight => format!(
+      "        movzwl %cx, %ecx\n        movl $15, %edx\n        cmpl %edx, %ecx\n        \
+       cmoval %edx, %ecx\n        movswl {x}, %eax\n        sarl %cl, %eax\n        movw %ax, {x}",
+      x = X
+    ),
+    Negate | Not => unreachable!(),
+  };
+  writeln!(s, "{}", code).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emulate::test_programs;
+  use crate::targets::testing;
+  use crate::{parse_file, targets, Options};
+  use std::fs;
+  use std::path::Path;
+  use std::process;
+
+  fn succeed(command: &mut process::Command) -> Result<(), String> {
+    match command.status() {
+      Ok(status) if status.success() => Ok(()),
+      status => Err(format!("{:?} failed with {:?}", command, status)),
+    }
+  }
+
+  /// Assembles and links the program in `build`
+  fn assemble(program: &Program, build: &Path) -> Result<(), String> {
+    fs::write(build.join("program.s"), write(program, false)).unwrap();
+    let tool = |name: &str| {
+      let mut command = process::Command::new(name);
+      command.current_dir(build);
+      command
+    };
+    succeed(tool("as").args(["-o", "program.o", "program.s"]))?;
+    succeed(tool("ld").args(["-o", "program", "program.o"]))
+  }
+
+  /// Assembles and links the program in `build`, then runs it from the
+  /// given RAM, returning RAM as it halted
+  fn run(program: &Program, build: &Path, ram: Vec<i16>) -> Result<Vec<i16>, String> {
+    assemble(program, build)?;
+
+    let image: Vec<u8> = ram.iter().flat_map(|word| word.to_le_bytes()).collect();
+    fs::write(build.join("in.ram"), image).unwrap();
+    succeed(
+      process::Command::new(build.join("program"))
+        .current_dir(build)
+        .args(["-r", "in.ram", "-w", "out.ram"]),
+    )?;
+    Ok(read_ram(&build.join("out.ram")))
+  }
+
+  fn read_ram(path: &Path) -> Vec<i16> {
+    fs::read(path)
+      .unwrap()
+      .chunks(2)
+      .map(|word| i16::from_le_bytes([word[0], word[1]]))
+      .collect()
+  }
+
+  /// A directory to build in, or none without GNU tools to build with
+  fn build_directory(name: &str) -> Option<std::path::PathBuf> {
+    for tool in ["as", "ld"] {
+      if process::Command::new(tool)
+        .arg("--version")
+        .output()
+        .is_err()
+      {
+        eprintln!("skipping: no {}", tool);
+        return None;
+      }
+    }
+
+    let build = std::env::temp_dir().join(format!("vm_translator_{}_{}", name, process::id()));
+    fs::create_dir_all(&build).unwrap();
+    Some(build)
+  }
+
+  #[test]
+  fn does_arithmetic_as_the_interpreter_does() {
+    let build = match build_directory("x86_64_math") {
+      Some(build) => build,
+      None => return,
+    };
+
+    let (file, expected) = testing::arithmetic();
+    let program = Program::build(&[file]).unwrap();
+    let ram = run(&program, &build, vec![0; 32768]).unwrap();
+    testing::check_arithmetic(&ram, &expected);
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+
+  #[test]
+  fn writes_the_screen_and_wraps_pointers() {
+    let build = match build_directory("x86_64_screen") {
+      Some(build) => build,
+      None => return,
+    };
+
+    // the top left and bottom right pixels, then RAM[3000] through a THAT
+    // of 35768
+    let main = parse_file(
+      "function Sys.init 0\n\
+       push constant 16384\npop pointer 1\npush constant 1\npop that 0\n\
+       push constant 24575\npop pointer 1\npush constant 32767\nnot\npop that 0\n\
+       push constant 32767\npush constant 3001\nadd\npop pointer 1\n\
+       push constant 9\npop that 0\n\
+       call Sys.halt 0\n\
+       function Sys.halt 0\nlabel LOOP\ngoto LOOP",
+      "Main",
+    )
+    .unwrap();
+    let program = Program::build(&[main]).unwrap();
+    assemble(&program, &build).unwrap();
+    succeed(
+      process::Command::new(build.join("program"))
+        .current_dir(&build)
+        .args(["-w", "out.ram", "-o", "screen.pbm"]),
+    )
+    .unwrap();
+
+    let ram = read_ram(&build.join("out.ram"));
+    assert_eq!(ram.len(), 32768);
+    assert_eq!(ram[3000], 9);
+    let pbm = fs::read(build.join("screen.pbm")).unwrap();
+    let header = b"P4\n512 256\n";
+    assert_eq!(pbm.len(), header.len() + 512 / 8 * 256);
+    assert_eq!(&pbm[..header.len()], header);
+    let pixels = &pbm[header.len()..];
+    assert_eq!(pixels[0], 0x80);
+    assert_eq!(pixels[pixels.len() / 2], 0);
+    assert_eq!(pixels[pixels.len() - 1], 0x01);
+    assert_eq!(pixels.iter().filter(|&&byte| byte != 0).count(), 2);
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+
+  #[test]
+  fn passes_the_test_programs_natively() {
+    let build = match build_directory("x86_64") {
+      Some(build) => build,
+      None => return,
+    };
+
+    for directory in
+      test_programs(Path::new(env!("CARGO_MANIFEST_DIR")).join("..").as_path()).unwrap()
+    {
+      let program =
+        targets::prepare(&[directory.display().to_string()], &Options::default()).unwrap();
+      testing::run_script(&directory, |ram| run(&program, &build, ram)).unwrap();
+    }
+
+    fs::remove_dir_all(&build).unwrap();
+  }
+}
@@ -1,10 +1,44 @@
-use crate::parser::{MathCommand, MemoryCommand, MemorySegment};
+use crate::parser::{MathCommand, MemoryCommand, MemorySegment, VmCommand};
 use std::io::Write;
 
+mod cached;
+mod checked;
+mod extended;
+mod routines;
+
+pub use checked::{Panic, PANIC_ADDRESS};
+
+/// How generated code keeps the stack
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Codegen {
+  /// Every value lives in RAM, as the VM specification describes
+  Stack,
+  /// The top of the stack is kept in D within a basic block, and only
+  /// spilled to RAM at labels, jumps, calls and returns
+  CachedTop,
+}
+
 pub struct Writer {
   pub output: Vec<u8>,
   pub namespace: String,
   pub current_function: String,
+  /// ROM address of the next instruction written
+  pub address: usize,
+  pub codegen: Codegen,
+  /// Call the routines `write_routines` writes for `eq`, `gt` and `lt`,
+  /// rather than writing each comparison out in full
+  pub shared_comparisons: bool,
+  /// Guard each command against overflowing the stack and other mistakes,
+  /// halting with a `Panic` code. Checked code keeps the whole stack in RAM
+  /// whatever the `codegen`.
+  pub checked: bool,
+  /// The number of locals of the function being written
+  locals: u16,
+  /// How many values checked code knows are on the stack in the current
+  /// basic block, above the function's locals
+  known_depth: usize,
+  /// Whether D holds the top of the stack, which isn't in RAM
+  cached: bool,
   jump_index: usize,
   return_index: usize
 }
@@ -16,10 +50,60 @@ impl Writer {
       namespace: String::from(namespace),
       jump_index: 0,
       current_function: String::new(),
+      address: 0,
+      codegen: Codegen::Stack,
+      shared_comparisons: false,
+      checked: false,
+      locals: 0,
+      known_depth: 0,
+      cached: false,
       return_index: 0
     }
   }
 
+  /// Writes any parsed command, scoping labels to the function being written
+  pub fn write_command(&mut self, command: &VmCommand) {
+    use VmCommand::*;
+
+    if self.codegen == Codegen::CachedTop && !self.checked {
+      return self.write_cached(command);
+    }
+
+    if self.checked {
+      self.write_checks(command);
+    }
+
+    match command {
+      Arithmetic(command) => self.write_math(*command),
+      Push { segment, index } => self.write_push_pop(MemoryCommand::Push, *segment, *index),
+      Pop { segment, index } => self.write_push_pop(MemoryCommand::Pop, *segment, *index),
+      Label(label) => {
+        let label = self.scoped_label(label);
+        self.write_label(&label)
+      }
+      Goto(label) => {
+        let label = self.scoped_label(label);
+        self.write_goto(&label)
+      }
+      IfGoto(label) => {
+        let label = self.scoped_label(label);
+        self.write_if(&label)
+      }
+      Function { name, nlocals } => self.write_function(name, *nlocals),
+      Call { name, nargs } => self.write_call(name, *nargs),
+      Return => self.write_return(),
+    }
+  }
+
+  /// Labels are local to their function, so `LOOP` in `Main.run` is `Main.run$LOOP`
+  fn scoped_label(&self, label: &str) -> String {
+    if self.current_function.is_empty() {
+      String::from(label)
+    } else {
+      format!("{}${}", self.current_function, label)
+    }
+  }
+
   /// Writes the bootstrap ASM to the output
   /// Initializes SP to 256 and calls Sys.init
   pub fn write_init(&mut self) {
@@ -92,6 +176,10 @@ impl Writer {
   pub fn write_math(&mut self, command: MathCommand) {
     use MathCommand::*;
 
+    if self.uses_routine(command) {
+      return self.write_routine_call(command);
+    }
+
     match command {
       Add => {
         self.writeln("// add");
@@ -404,10 +492,11 @@ impl Writer {
         A=M-1
         M=D",
       ),
+      _ => unreachable!("{} is written by a routine", command),
     }
   }
 
-  pub fn write_push_pop(&mut self, command: MemoryCommand, segment: MemorySegment, index: usize) {
+  pub fn write_push_pop(&mut self, command: MemoryCommand, segment: MemorySegment, index: u16) {
     use MemoryCommand::*;
     use MemorySegment::*;
     match command {
@@ -551,7 +640,7 @@ impl Writer {
 
           self.write_dec_sp();
         }
-        Constant => panic!("cannot pop to the constant segment"),
+        Constant => unreachable!("the parser rejects popping to the constant segment"),
         This => {
           self.writeln(&format!("// pop this {}", index));
           // set R13 to *THIS+index
@@ -641,12 +730,16 @@ impl Writer {
     self.writeln(&format!("({})", label));
   }
 
-  pub fn write_function(&mut self, name: &str, num_locals: usize) {
+  pub fn write_function(&mut self, name: &str, num_locals: u16) {
     self.current_function = String::from(name);
 
     self.writeln(&format!("// function {} {}", name, num_locals));
     self.writeln(&format!("({})", name));
 
+    if self.checked {
+      self.write_overflow_check(num_locals);
+    }
+
     for _arg in 0..num_locals {
       self.writeln("// init local");
       self.writeln("@0");
@@ -659,9 +752,8 @@ impl Writer {
     }
   }
 
-  pub fn write_call(&mut self, name: &str, num_args: usize) {
+  pub fn write_call(&mut self, name: &str, num_args: u16) {
     self.return_index += 1;
-    println!("write_call, @AFTER_{}_{}.{}", name,  &self.namespace,&self.return_index);
     
     // Push the return address to the stack
     self.writeln(&format!("@AFTER_{}_{}.{}", name,  &self.namespace,&self.return_index));
@@ -832,12 +924,24 @@ impl Writer {
     self.writeln("@SP");
     self.writeln("A=M-1");
     self.writeln("D=M");
-    self.writeln(&format!("{}", register));
+    self.writeln(register);
     self.writeln("A=M");
     self.writeln("M=D");
   }
 
+  /// Writes a comment line, which takes up no ROM
+  pub fn write_comment(&mut self, comment: &str) {
+    self.writeln(&format!("// {}", comment));
+  }
+
   fn writeln(&mut self, content: &str) {
+    // labels and comments take up no ROM
+    self.address += content
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('('))
+      .count();
+
     writeln!(self.output, "{}", content).expect("problem writing to buffer");
   }
 }
@@ -854,4 +958,18 @@ mod tests {
 
     assert_eq!(std::str::from_utf8(&writer.output).unwrap(), "@SP\nM=D\n");
   }
+
+  #[test]
+  fn counts_rom_addresses() {
+    let mut writer = Writer::new("test_namespace");
+    writer.write_comment("push constant 7");
+    writer.write_label("LOOP");
+    assert_eq!(writer.address, 0);
+
+    writer.write_goto("LOOP");
+    assert_eq!(writer.address, 2);
+
+    writer.write_math(MathCommand::Negate);
+    assert_eq!(writer.address, 8);
+  }
 }
@@ -0,0 +1,254 @@
+use super::Writer;
+use crate::parser::{MathCommand, MemorySegment, VmCommand};
+
+/// Pops to `local`, `argument`, `this` and `that` up to this index step the
+/// address up from the base, which beats computing it from there on
+const STEPPED_INDEX_LIMIT: u16 = 9;
+
+/// Code generation for `Codegen::CachedTop`. Within a basic block the top
+/// of the stack stays in D, so `push constant 7, push local 0, add` comes to
+/// a handful of instructions rather than dozens. Every label starts a block
+/// with the whole stack in RAM, as do the return addresses of calls.
+impl Writer {
+  pub(super) fn write_cached(&mut self, command: &VmCommand) {
+    use VmCommand::*;
+
+    match command {
+      Arithmetic(math) => {
+        self.writeln(&format!("// {}", math));
+        self.cached_math(*math);
+      }
+      Push { segment, index } => {
+        self.writeln(&format!("// push {} {}", segment, index));
+        self.cached_push(*segment, *index);
+      }
+      Pop { segment, index } => {
+        self.writeln(&format!("// pop {} {}", segment, index));
+        self.cached_pop(*segment, *index);
+      }
+      Label(label) => {
+        self.flush();
+        let label = self.scoped_label(label);
+        self.write_label(&label);
+      }
+      Goto(label) => {
+        self.flush();
+        let label = self.scoped_label(label);
+        self.write_goto(&label);
+      }
+      IfGoto(label) => {
+        let label = self.scoped_label(label);
+        self.writeln(&format!("// if-goto {}", label));
+        self.load_top();
+        self.writeln(&format!("@{}", label));
+        self.writeln("D;JNE");
+        self.cached = false;
+      }
+      Function { name, nlocals } => {
+        self.flush();
+        self.current_function = name.clone();
+        self.writeln(&format!("// function {} {}", name, nlocals));
+        self.writeln(&format!("({})", name));
+        self.zero_locals(*nlocals);
+      }
+      Call { name, nargs } => {
+        self.flush();
+        self.write_call(name, *nargs);
+      }
+      Return => {
+        self.flush();
+        self.write_return();
+      }
+    }
+  }
+
+  /// Moves a cached top of the stack out to RAM, so that the whole stack is there
+  pub fn flush(&mut self) {
+    if self.cached {
+      self.writeln("@SP");
+      self.writeln("AM=M+1");
+      self.writeln("A=A-1");
+      self.writeln("M=D");
+      self.cached = false;
+    }
+  }
+
+  /// Pops the top of the stack into D, unless it's there already
+  fn load_top(&mut self) {
+    if !self.cached {
+      self.writeln("@SP");
+      self.writeln("AM=M-1");
+      self.writeln("D=M");
+      self.cached = true;
+    }
+  }
+
+  fn cached_math(&mut self, math: MathCommand) {
+    use MathCommand::*;
+
+    // y is in D, and x on top of the stack in RAM
+    self.load_top();
+
+    let unary = match math {
+      Negate => Some("D=-D"),
+      Not => Some("D=!D"),
+      _ => None,
+    };
+    if let Some(instruction) = unary {
+      self.writeln(instruction);
+      return;
+    }
+
+    if self.uses_routine(math) {
+      return self.call_routine(math);
+    }
+
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+
+    let (jump, index) = match math {
+      Add => return self.writeln("D=D+M"),
+      Subtract => return self.writeln("D=M-D"),
+      And => return self.writeln("D=D&M"),
+      Or => return self.writeln("D=D|M"),
+      EqualTo => ("JEQ", self.jump_index),
+      GreaterThan => ("JGT", self.jump_index),
+      LessThan => ("JLT", self.jump_index),
+      _ => unreachable!("{} is written by a routine", math),
+    };
+    self.jump_index += 1;
+
+    self.writeln("D=M-D");
+    self.writeln(&format!("@TRUE.{}", index));
+    self.writeln(&format!("D;{}", jump));
+    self.writeln("D=0");
+    self.writeln(&format!("@END.{}", index));
+    self.writeln("0;JMP");
+    self.writeln(&format!("(TRUE.{})", index));
+    self.writeln("D=-1");
+    self.writeln(&format!("(END.{})", index));
+  }
+
+  fn cached_push(&mut self, segment: MemorySegment, index: u16) {
+    use MemorySegment::*;
+
+    self.flush();
+
+    match segment {
+      Constant => match index {
+        0 | 1 => self.writeln(&format!("D={}", index)),
+        _ => {
+          self.writeln(&format!("@{}", index));
+          self.writeln("D=A");
+        }
+      },
+      Local | Argument | This | That => {
+        let base = base_register(segment);
+
+        match index {
+          0 | 1 => {
+            self.writeln(&format!("@{}", base));
+            self.writeln(if index == 0 { "A=M" } else { "A=M+1" });
+          }
+          _ => {
+            self.writeln(&format!("@{}", index));
+            self.writeln("D=A");
+            self.writeln(&format!("@{}", base));
+            self.writeln("A=D+M");
+          }
+        }
+        self.writeln("D=M");
+      }
+      Static | Pointer | Temp => {
+        let address = self.fixed_address(segment, index);
+        self.writeln(&format!("@{}", address));
+        self.writeln("D=M");
+      }
+    }
+
+    self.cached = true;
+  }
+
+  fn cached_pop(&mut self, segment: MemorySegment, index: u16) {
+    use MemorySegment::*;
+
+    self.load_top();
+
+    match segment {
+      Constant => unreachable!("the parser rejects popping to the constant segment"),
+      Local | Argument | This | That if index <= STEPPED_INDEX_LIMIT => {
+        self.writeln(&format!("@{}", base_register(segment)));
+
+        if index == 0 {
+          self.writeln("A=M");
+        } else {
+          self.writeln("A=M+1");
+          for _ in 1..index {
+            self.writeln("A=A+1");
+          }
+        }
+        self.writeln("M=D");
+      }
+      Local | Argument | This | That => {
+        // with the value v in R13 and the address a in D, storing a + v in
+        // R13 lets each be recovered from the other without another register
+        self.writeln("@R13");
+        self.writeln("M=D");
+        self.writeln(&format!("@{}", index));
+        self.writeln("D=A");
+        self.writeln(&format!("@{}", base_register(segment)));
+        self.writeln("D=D+M");
+        self.writeln("@R13");
+        self.writeln("M=D+M");
+        self.writeln("D=M-D");
+        self.writeln("A=M-D");
+        self.writeln("M=D");
+      }
+      Static | Pointer | Temp => {
+        let address = self.fixed_address(segment, index);
+        self.writeln(&format!("@{}", address));
+        self.writeln("M=D");
+      }
+    }
+
+    self.cached = false;
+  }
+
+  /// The address of a segment entry known when translating
+  fn fixed_address(&self, segment: MemorySegment, index: u16) -> String {
+    match segment {
+      MemorySegment::Static => format!("{}.{}", self.namespace, index),
+      MemorySegment::Pointer => (3 + index).to_string(),
+      MemorySegment::Temp => (5 + index).to_string(),
+      _ => unreachable!("{} isn't at a fixed address", segment),
+    }
+  }
+
+  /// Pushes `count` zeros, for a function's locals
+  fn zero_locals(&mut self, count: u16) {
+    if count == 0 {
+      return;
+    }
+
+    self.writeln("@SP");
+    self.writeln("A=M");
+    self.writeln("M=0");
+    for _ in 1..count {
+      self.writeln("A=A+1");
+      self.writeln("M=0");
+    }
+    self.writeln("D=A+1");
+    self.writeln("@SP");
+    self.writeln("M=D");
+  }
+}
+
+fn base_register(segment: MemorySegment) -> &'static str {
+  match segment {
+    MemorySegment::Local => "LCL",
+    MemorySegment::Argument => "ARG",
+    MemorySegment::This => "THIS",
+    MemorySegment::That => "THAT",
+    _ => unreachable!("{} has no base register", segment),
+  }
+}
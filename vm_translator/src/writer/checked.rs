@@ -0,0 +1,293 @@
+use super::Writer;
+use crate::inliner::stack_effect;
+use crate::parser::{MemorySegment, VmCommand};
+use crate::statics::VARIABLE_LIMIT;
+use std::fmt;
+
+/// Where `$$panic` leaves the code of what went wrong: the last word static
+/// variables could take, which checked programs leave free, since the
+/// generated code uses R13 to R15 as scratch
+pub const PANIC_ADDRESS: u16 = VARIABLE_LIMIT - 1;
+
+/// The first address past the stack, which starts at 256
+const STACK_END: u16 = 2048;
+/// The first address past the heap, where the screen starts
+const HEAP_END: u16 = 16384;
+/// The first address past RAM, the keyboard being the last word
+const RAM_END: u16 = 24577;
+
+/// What a checked program found wrong, with the code it leaves in
+/// `PANIC_ADDRESS` before halting
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Panic {
+  /// SP would reach 2048, where the heap starts
+  StackOverflow = 1,
+  /// A command would take more values than the function has pushed
+  StackUnderflow = 2,
+  /// `pop pointer 0` with a value outside the heap, where objects live
+  BadPointer = 3,
+  /// `this` or `that` outside RAM
+  BadAddress = 4,
+  /// `div` or `mod` by zero
+  DivisionByZero = 5,
+}
+
+impl Panic {
+  pub const ALL: [Panic; 5] = [
+    Panic::StackOverflow,
+    Panic::StackUnderflow,
+    Panic::BadPointer,
+    Panic::BadAddress,
+    Panic::DivisionByZero,
+  ];
+
+  pub fn from_code(code: i16) -> Option<Panic> {
+    Panic::ALL
+      .iter()
+      .copied()
+      .find(|panic| *panic as i16 == code)
+  }
+
+  /// Where checks jump to, which sets the code and goes on to `$$panic`
+  fn label(self) -> &'static str {
+    match self {
+      Panic::StackOverflow => "$$panic.stack_overflow",
+      Panic::StackUnderflow => "$$panic.stack_underflow",
+      Panic::BadPointer => "$$panic.bad_pointer",
+      Panic::BadAddress => "$$panic.bad_address",
+      Panic::DivisionByZero => "$$panic.division_by_zero",
+    }
+  }
+}
+
+impl fmt::Display for Panic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let description = match self {
+      Panic::StackOverflow => "stack overflow",
+      Panic::StackUnderflow => "stack underflow",
+      Panic::BadPointer => "pointer outside the heap",
+      Panic::BadAddress => "this or that outside RAM",
+      Panic::DivisionByZero => "division by zero",
+    };
+
+    write!(f, "{}", description)
+  }
+}
+
+/// The guards `Writer::checked` adds ahead of each command. Each costs a
+/// handful of instructions, and none of them changes the stack.
+impl Writer {
+  pub(super) fn write_checks(&mut self, command: &VmCommand) {
+    use VmCommand::*;
+
+    // the stack at a label depends on how it was reached
+    if let Label(_) | Function { .. } = command {
+      self.known_depth = 0;
+    }
+
+    let (taken, pushed) = stack_effect(command);
+    self.write_underflow_check(taken);
+
+    match command {
+      Push { segment, index } => {
+        self.write_overflow_check(1);
+        self.write_address_check(*segment, *index);
+      }
+      Pop { segment, index } => {
+        self.write_address_check(*segment, *index);
+
+        if *segment == MemorySegment::Pointer && *index == 0 {
+          self.write_pointer_check();
+        }
+      }
+      // the frame the call saves
+      Call { .. } => self.write_overflow_check(5),
+      Function { nlocals, .. } => self.locals = *nlocals,
+      _ => (),
+    }
+
+    self.known_depth = self.known_depth - taken + pushed;
+  }
+
+  /// Panics if pushing `words` more would take SP to 2048
+  pub(super) fn write_overflow_check(&mut self, words: u16) {
+    self.writeln("@SP");
+    self.writeln("D=M");
+    self.writeln(&format!("@{}", STACK_END - words));
+    self.writeln("D=D-A");
+    self.writeln(&format!("@{}", Panic::StackOverflow.label()));
+    self.writeln("D;JGE");
+  }
+
+  /// Panics unless the current function has pushed `words` values of its
+  /// own, or code outside functions has pushed them above 256. Values pushed
+  /// earlier in the same basic block needn't be checked for.
+  fn write_underflow_check(&mut self, words: usize) {
+    if words <= self.known_depth {
+      return;
+    }
+    self.known_depth = words;
+
+    if self.current_function.is_empty() {
+      self.writeln("@SP");
+      self.writeln("D=M");
+      self.writeln(&format!("@{}", 256 + words));
+      self.writeln("D=D-A");
+    } else {
+      self.writeln("@LCL");
+      self.writeln("D=M");
+      self.writeln(&format!("@{}", self.locals as usize + words));
+      self.writeln("D=D+A");
+      self.writeln("@SP");
+      self.writeln("D=M-D");
+    }
+
+    self.writeln(&format!("@{}", Panic::StackUnderflow.label()));
+    self.writeln("D;JLT");
+  }
+
+  /// Panics if `this` or `that` at `index` is outside RAM
+  fn write_address_check(&mut self, segment: MemorySegment, index: u16) {
+    let base = match segment {
+      MemorySegment::This => "THIS",
+      MemorySegment::That => "THAT",
+      _ => return,
+    };
+
+    self.writeln(&format!("@{}", base));
+    self.writeln("D=M");
+    self.writeln(&format!("@{}", index));
+    self.writeln("D=D+A");
+    self.write_range_check(0, RAM_END, Panic::BadAddress);
+  }
+
+  /// Panics if the value on top of the stack isn't in the heap
+  fn write_pointer_check(&mut self) {
+    self.writeln("@SP");
+    self.writeln("A=M-1");
+    self.writeln("D=M");
+    self.write_range_check(STACK_END, HEAP_END, Panic::BadPointer);
+  }
+
+  /// Panics unless `start <= D < end`
+  fn write_range_check(&mut self, start: u16, end: u16, panic: Panic) {
+    if start > 0 {
+      self.writeln(&format!("@{}", start));
+      self.writeln("D=D-A");
+    }
+    self.writeln(&format!("@{}", panic.label()));
+    self.writeln("D;JLT");
+    self.writeln(&format!("@{}", end - start));
+    self.writeln("D=D-A");
+    self.writeln(&format!("@{}", panic.label()));
+    self.writeln("D;JGE");
+  }
+
+  /// Panics if y, in D, is zero
+  pub(super) fn write_division_check(&mut self) {
+    self.writeln(&format!("@{}", Panic::DivisionByZero.label()));
+    self.writeln("D;JEQ");
+  }
+
+  /// `$$panic` and the entry points that set each code before going to it
+  pub(super) fn write_panic_routine(&mut self) {
+    for panic in Panic::ALL.iter() {
+      self.writeln(&format!("({})", panic.label()));
+      self.writeln(&format!("@{}", *panic as u16));
+      self.writeln("D=A");
+      self.writeln("@$$panic");
+      self.writeln("0;JMP");
+    }
+
+    self.writeln("($$panic)");
+    self.writeln(&format!("@{}", PANIC_ADDRESS));
+    self.writeln("M=D");
+    self.writeln("($$panic.halt)");
+    self.writeln("@$$panic.halt");
+    self.writeln("0;JMP");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{parse_file, translate, Options};
+  use hack_assembler::emulator::Cpu;
+  use hack_assembler::parser::Parser;
+
+  /// Runs a checked program until it halts
+  fn execute(vm: &str) -> Cpu {
+    let options = Options {
+      checked: true,
+      ..Options::default()
+    };
+    let asm = translate(&[parse_file(vm, "Sys").unwrap()], &options).asm;
+    let mut cpu = Cpu::new(
+      Parser::new()
+        .assemble(&String::from_utf8(asm).unwrap())
+        .unwrap(),
+    );
+
+    assert!(cpu.run(1_000_000));
+    cpu
+  }
+
+  /// The panic a checked program halts with, if any
+  fn run(vm: &str) -> Option<Panic> {
+    Panic::from_code(execute(vm).ram[PANIC_ADDRESS as usize])
+  }
+
+  #[test]
+  fn panics_on_mistakes() {
+    let program = |body: &str| {
+      format!(
+        "function Sys.init 1\n{}\nlabel END\ngoto END\n\
+         function Sys.recurse 0\ncall Sys.recurse 0\nreturn\n\
+         function Sys.leak 0\nlabel LOOP\npush constant 1\ngoto LOOP",
+        body
+      )
+    };
+    let cases = [
+      ("push constant 1\npop local 0", None),
+      ("push constant 3000\npop pointer 0\npush this 5", None),
+      ("push constant 16384\npop pointer 1\npush that 0", None),
+      ("call Sys.recurse 0", Some(Panic::StackOverflow)),
+      ("call Sys.leak 0", Some(Panic::StackOverflow)),
+      ("pop local 0", Some(Panic::StackUnderflow)),
+      ("push constant 1\nadd", Some(Panic::StackUnderflow)),
+      ("push constant 0\npop pointer 0", Some(Panic::BadPointer)),
+      (
+        "push constant 16384\npop pointer 0",
+        Some(Panic::BadPointer),
+      ),
+      (
+        "push constant 24570\npop pointer 1\npush that 7",
+        Some(Panic::BadAddress),
+      ),
+      (
+        "push constant 5\nneg\npop pointer 1\npush constant 1\npop that 0",
+        Some(Panic::BadAddress),
+      ),
+      (
+        "push constant 7\npush constant 0\ndiv",
+        Some(Panic::DivisionByZero),
+      ),
+    ];
+
+    for (body, expected) in cases.iter() {
+      assert_eq!(run(&program(body)), *expected, "{}", body);
+    }
+  }
+
+  #[test]
+  fn keeps_the_code_out_of_the_scratch_registers() {
+    // `mul` is a routine, which is passed where to return in R15
+    let cpu = execute(
+      "function Sys.init 1\npush constant 6\npush constant 7\nmul\npop local 0\n\
+       label END\ngoto END",
+    );
+
+    assert_ne!(cpu.ram[15], 0);
+    assert_eq!(cpu.ram[PANIC_ADDRESS as usize], 0);
+  }
+}
@@ -0,0 +1,357 @@
+use super::Writer;
+use crate::parser::MathCommand;
+
+/// The routines for the extended operations, following the convention of
+/// `write_routines`. Their working variables, named `$$op.name`, get RAM
+/// from the assembler like static variables do.
+impl Writer {
+  pub(super) fn write_extended_routine(&mut self, math: MathCommand) {
+    use MathCommand::*;
+
+    match math {
+      Multiply => self.write_multiply(),
+      Divide | Modulo => self.write_divide(),
+      ShiftLeft => self.write_shift_left(),
+      ShiftRight => self.write_shift_right(),
+      Xor => self.write_xor(),
+      LessThanUnsigned => self.write_unsigned("lt_u", "lt"),
+      GreaterThanUnsigned => self.write_unsigned("gt_u", "gt"),
+      _ => unreachable!("{} has no extended routine", math),
+    }
+  }
+
+  /// Shift and add, clearing each bit of y as it's added in so that small
+  /// multipliers finish early
+  fn write_multiply(&mut self) {
+    self.writeln("($$mul)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@$$mul.x");
+    self.writeln("M=D");
+    self.writeln("@$$mul.product");
+    self.writeln("M=0");
+    self.writeln("@$$mul.bit");
+    self.writeln("M=1");
+
+    self.writeln("($$mul.loop)");
+    self.writeln("@R13");
+    self.writeln("D=M");
+    self.writeln("@$$mul.done");
+    self.writeln("D;JEQ");
+    self.writeln("@$$mul.bit");
+    self.writeln("D=D&M");
+    self.writeln("@$$mul.next");
+    self.writeln("D;JEQ");
+    self.writeln("@R13");
+    self.writeln("M=M-D");
+    self.writeln("@$$mul.x");
+    self.writeln("D=M");
+    self.writeln("@$$mul.product");
+    self.writeln("M=D+M");
+
+    self.writeln("($$mul.next)");
+    self.writeln("@$$mul.x");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$mul.bit");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$mul.loop");
+    self.writeln("0;JMP");
+
+    self.writeln("($$mul.done)");
+    self.writeln("@$$mul.product");
+    self.writeln("D=M");
+    self.write_routine_return();
+  }
+
+  /// `div` and `mod` share long division of |x| by |y|, with the signs
+  /// put back afterwards. Dividing by zero gives -1, or 1 for negative x,
+  /// and leaves x as the remainder.
+  fn write_divide(&mut self) {
+    self.writeln("($$div)");
+    self.writeln("@$$div.remainder");
+    self.writeln("M=0");
+    self.writeln("@$$div.start");
+    self.writeln("0;JMP");
+    self.writeln("($$mod)");
+    self.writeln("@$$div.remainder");
+    self.writeln("M=-1");
+
+    // n = |x|
+    self.writeln("($$div.start)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@$$div.n");
+    self.writeln("M=D");
+    self.writeln("@$$div.x_negative");
+    self.writeln("M=0");
+    self.writeln("@$$div.x_positive");
+    self.writeln("D;JGE");
+    self.writeln("@$$div.x_negative");
+    self.writeln("M=-1");
+    self.writeln("@$$div.n");
+    self.writeln("M=-M");
+
+    // d = |y|, and the quotient is negative when just one of x and y is
+    self.writeln("($$div.x_positive)");
+    self.writeln("@$$div.x_negative");
+    self.writeln("D=M");
+    self.writeln("@$$div.q_negative");
+    self.writeln("M=D");
+    self.writeln("@R13");
+    self.writeln("D=M");
+    self.writeln("@$$div.d");
+    self.writeln("M=D");
+    self.writeln("@$$div.y_positive");
+    self.writeln("D;JGE");
+    self.writeln("@$$div.d");
+    self.writeln("M=-M");
+    self.writeln("@$$div.q_negative");
+    self.writeln("M=!M");
+
+    self.writeln("($$div.y_positive)");
+    self.writeln("@$$div.q");
+    self.writeln("M=0");
+    self.writeln("@$$div.r");
+    self.writeln("M=0");
+    self.writeln("@16");
+    self.writeln("D=A");
+    self.writeln("@$$div.count");
+    self.writeln("M=D");
+
+    // shift the top bit of n into r, and q along with it
+    self.writeln("($$div.loop)");
+    self.writeln("@$$div.r");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$div.n");
+    self.writeln("D=M");
+    self.writeln("@$$div.shifted");
+    self.writeln("D;JGE");
+    self.writeln("@$$div.r");
+    self.writeln("M=M+1");
+    self.writeln("($$div.shifted)");
+    self.writeln("@$$div.n");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$div.q");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+
+    // r >= d as unsigned numbers: r can only have its top bit set when it's
+    // past d, which is at most 0x8000, and otherwise r - d can't overflow
+    // unless d is 0x8000, when it comes out negative as it should
+    self.writeln("@$$div.r");
+    self.writeln("D=M");
+    self.writeln("@$$div.subtract");
+    self.writeln("D;JLT");
+    self.writeln("@$$div.d");
+    self.writeln("D=D-M");
+    self.writeln("@$$div.next");
+    self.writeln("D;JLT");
+    self.writeln("($$div.subtract)");
+    self.writeln("@$$div.d");
+    self.writeln("D=M");
+    self.writeln("@$$div.r");
+    self.writeln("M=M-D");
+    self.writeln("@$$div.q");
+    self.writeln("M=M+1");
+
+    self.writeln("($$div.next)");
+    self.writeln("@$$div.count");
+    self.writeln("MD=M-1");
+    self.writeln("@$$div.loop");
+    self.writeln("D;JGT");
+
+    // the quotient or the remainder, with D saying whether to negate it
+    self.writeln("@$$div.remainder");
+    self.writeln("D=M");
+    self.writeln("@$$div.of_remainder");
+    self.writeln("D;JNE");
+    self.writeln("@$$div.q");
+    self.writeln("D=M");
+    self.writeln("@$$div.result");
+    self.writeln("M=D");
+    self.writeln("@$$div.q_negative");
+    self.writeln("D=M");
+    self.writeln("@$$div.sign");
+    self.writeln("0;JMP");
+    self.writeln("($$div.of_remainder)");
+    self.writeln("@$$div.r");
+    self.writeln("D=M");
+    self.writeln("@$$div.result");
+    self.writeln("M=D");
+    self.writeln("@$$div.x_negative");
+    self.writeln("D=M");
+
+    self.writeln("($$div.sign)");
+    self.writeln("@$$div.positive");
+    self.writeln("D;JEQ");
+    self.writeln("@$$div.result");
+    self.writeln("M=-M");
+    self.writeln("($$div.positive)");
+    self.writeln("@$$div.result");
+    self.writeln("D=M");
+    self.write_routine_return();
+  }
+
+  /// Doubles x y times, using R13 as the count
+  fn write_shift_left(&mut self) {
+    self.writeln("($$shl)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@$$shl.x");
+    self.writeln("M=D");
+    self.writeln("@R13");
+    self.writeln("D=M");
+    self.writeln("@$$shl.out");
+    self.writeln("D;JLT");
+    self.writeln("@16");
+    self.writeln("D=D-A");
+    self.writeln("@$$shl.out");
+    self.writeln("D;JGE");
+
+    self.writeln("($$shl.loop)");
+    self.writeln("@R13");
+    self.writeln("MD=M-1");
+    self.writeln("@$$shl.done");
+    self.writeln("D;JLT");
+    self.writeln("@$$shl.x");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$shl.loop");
+    self.writeln("0;JMP");
+
+    self.writeln("($$shl.done)");
+    self.writeln("@$$shl.x");
+    self.writeln("D=M");
+    self.write_routine_return();
+
+    // every bit shifted out
+    self.writeln("($$shl.out)");
+    self.writeln("D=0");
+    self.write_routine_return();
+  }
+
+  /// Copies bit y + i of x to bit i of the result, for as long as there
+  /// are bits left to copy, then fills the top bits with the sign of x
+  fn write_shift_right(&mut self) {
+    self.writeln("($$shr)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@$$shr.x");
+    self.writeln("M=D");
+    self.writeln("@$$shr.result");
+    self.writeln("M=0");
+    self.writeln("@$$shr.to");
+    self.writeln("M=1");
+    self.writeln("@$$shr.from");
+    self.writeln("M=1");
+    self.writeln("@R13");
+    self.writeln("D=M");
+    self.writeln("@$$shr.fill");
+    self.writeln("D;JLT");
+    self.writeln("@16");
+    self.writeln("D=D-A");
+    self.writeln("@$$shr.fill");
+    self.writeln("D;JGE");
+
+    // from = 1 << y
+    self.writeln("($$shr.from_loop)");
+    self.writeln("@R13");
+    self.writeln("MD=M-1");
+    self.writeln("@$$shr.copy");
+    self.writeln("D;JLT");
+    self.writeln("@$$shr.from");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$shr.from_loop");
+    self.writeln("0;JMP");
+
+    self.writeln("($$shr.copy)");
+    self.writeln("@$$shr.from");
+    self.writeln("D=M");
+    self.writeln("@$$shr.fill");
+    self.writeln("D;JEQ");
+    self.writeln("@$$shr.x");
+    self.writeln("D=D&M");
+    self.writeln("@$$shr.copied");
+    self.writeln("D;JEQ");
+    self.writeln("@$$shr.to");
+    self.writeln("D=M");
+    self.writeln("@$$shr.result");
+    self.writeln("M=D+M");
+    self.writeln("($$shr.copied)");
+    self.writeln("@$$shr.from");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$shr.to");
+    self.writeln("D=M");
+    self.writeln("M=D+M");
+    self.writeln("@$$shr.copy");
+    self.writeln("0;JMP");
+
+    self.writeln("($$shr.fill)");
+    self.writeln("@$$shr.x");
+    self.writeln("D=M");
+    self.writeln("@$$shr.done");
+    self.writeln("D;JGE");
+    self.writeln("($$shr.fill_loop)");
+    self.writeln("@$$shr.to");
+    self.writeln("D=M");
+    self.writeln("@$$shr.done");
+    self.writeln("D;JEQ");
+    self.writeln("@$$shr.result");
+    self.writeln("M=D+M");
+    self.writeln("@$$shr.to");
+    self.writeln("M=D+M");
+    self.writeln("@$$shr.fill_loop");
+    self.writeln("0;JMP");
+
+    self.writeln("($$shr.done)");
+    self.writeln("@$$shr.result");
+    self.writeln("D=M");
+    self.write_routine_return();
+  }
+
+  /// x ^ y = (x | y) & !(x & y), reading x back from just past the top of
+  /// the stack
+  fn write_xor(&mut self) {
+    self.writeln("($$xor)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@R13");
+    self.writeln("D=D&M");
+    self.writeln("@R14");
+    self.writeln("M=!D");
+    self.writeln("@SP");
+    self.writeln("A=M");
+    self.writeln("D=M");
+    self.writeln("@R13");
+    self.writeln("D=D|M");
+    self.writeln("@R14");
+    self.writeln("D=D&M");
+    self.write_routine_return();
+  }
+
+  /// Flipping the top bits of both operands orders them as unsigned
+  /// numbers, and then the signed comparison finishes the job
+  fn write_unsigned(&mut self, name: &str, signed: &str) {
+    self.writeln(&format!("($${})", name));
+    self.writeln("@32767");
+    self.writeln("D=!A");
+    self.writeln("@R13");
+    self.writeln("M=D+M");
+    self.writeln("@SP");
+    self.writeln("A=M-1");
+    self.writeln("M=D+M");
+    self.writeln(&format!("@$${}", signed));
+    self.writeln("0;JMP");
+  }
+}
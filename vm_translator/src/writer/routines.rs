@@ -0,0 +1,316 @@
+use super::Writer;
+use crate::parser::MathCommand;
+
+/// Routines shared by the whole program, for the operations too long to
+/// write out at every use. The call site leaves y in R13 and x on top of the
+/// stack, and passes the address to return to in R15. The routine pops x and
+/// returns the result in D.
+///
+/// `gt` and `lt` only subtract operands of the same sign, so comparisons
+/// like `32767 gt -2` can't overflow.
+impl Writer {
+  /// Whether the operation is a call to a shared routine
+  pub(super) fn uses_routine(&self, math: MathCommand) -> bool {
+    use MathCommand::*;
+
+    match math {
+      EqualTo | GreaterThan | LessThan => self.shared_comparisons,
+      Add | Subtract | Negate | And | Or | Not => false,
+      _ => true,
+    }
+  }
+
+  /// Writes the routines for the operations in `used` that need them, first
+  /// jumping over them if `jump_over`, as code that doesn't follow the
+  /// bootstrap would otherwise run into them
+  pub fn write_routines(&mut self, used: &[MathCommand], jump_over: bool) {
+    use MathCommand::*;
+
+    let mut routines = Vec::new();
+    let mut add = |math| {
+      if !routines.contains(&math) {
+        routines.push(math);
+      }
+    };
+
+    for &math in used.iter().filter(|&&math| self.uses_routine(math)) {
+      match math {
+        // one routine does both
+        Modulo => add(Divide),
+        // which finish in the signed ones
+        LessThanUnsigned => {
+          add(math);
+          add(LessThan);
+        }
+        GreaterThanUnsigned => {
+          add(math);
+          add(GreaterThan);
+        }
+        _ => add(math),
+      }
+    }
+
+    if routines.is_empty() && !self.checked {
+      return;
+    }
+
+    self.writeln("// shared routines");
+
+    if jump_over {
+      self.writeln("@$$start");
+      self.writeln("0;JMP");
+    }
+
+    if routines
+      .iter()
+      .any(|math| matches!(math, EqualTo | GreaterThan | LessThan))
+    {
+      self.writeln("($$false)");
+      self.writeln("D=0");
+      self.write_routine_return();
+
+      self.writeln("($$true)");
+      self.writeln("D=-1");
+      self.write_routine_return();
+    }
+
+    for math in routines {
+      match math {
+        EqualTo => self.write_equal_to(),
+        // x > y when x >= 0 > y, and never when y >= 0 > x
+        GreaterThan => self.write_ordering("gt", "JGT", "$$true", "$$false"),
+        // and the other way around for x < y
+        LessThan => self.write_ordering("lt", "JLT", "$$false", "$$true"),
+        _ => self.write_extended_routine(math),
+      }
+    }
+
+    if self.checked {
+      self.write_panic_routine();
+    }
+
+    if jump_over {
+      self.writeln("($$start)");
+    }
+  }
+
+  pub(super) fn write_routine_return(&mut self) {
+    self.writeln("@R15");
+    self.writeln("A=M");
+    self.writeln("0;JMP");
+  }
+
+  fn write_equal_to(&mut self) {
+    self.writeln("($$eq)");
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln("@R13");
+    self.writeln("D=D-M");
+    self.writeln("@$$true");
+    self.writeln("D;JEQ");
+    self.writeln("@$$false");
+    self.writeln("0;JMP");
+  }
+
+  /// The routine for `gt` or `lt`, jumping straight to `y_negative` when
+  /// only y is negative and to `x_negative` when only x is
+  fn write_ordering(&mut self, name: &str, jump: &str, y_negative: &str, x_negative: &str) {
+    self.writeln(&format!("($${})", name));
+    self.writeln("@R13");
+    self.writeln("D=M");
+    self.writeln(&format!("@$${}.y_negative", name));
+    self.writeln("D;JLT");
+
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln(&format!("@{}", x_negative));
+    self.writeln("D;JLT");
+    self.writeln(&format!("@$${}.same_sign", name));
+    self.writeln("0;JMP");
+
+    self.writeln(&format!("($${}.y_negative)", name));
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.writeln(&format!("@{}", y_negative));
+    self.writeln("D;JGE");
+
+    // x is in D, and x - y can't overflow
+    self.writeln(&format!("($${}.same_sign)", name));
+    self.writeln("@R13");
+    self.writeln("D=D-M");
+    self.writeln("@$$true");
+    self.writeln(&format!("D;{}", jump));
+    self.writeln("@$$false");
+    self.writeln("0;JMP");
+  }
+
+  /// Calls the routine for an operation with y already in D, leaving the
+  /// result in D
+  pub(super) fn call_routine(&mut self, math: MathCommand) {
+    let return_label = format!("$$returned.{}", self.jump_index);
+    self.jump_index += 1;
+
+    if self.checked && matches!(math, MathCommand::Divide | MathCommand::Modulo) {
+      self.write_division_check();
+    }
+
+    self.writeln("@R13");
+    self.writeln("M=D");
+    self.writeln(&format!("@{}", return_label));
+    self.writeln("D=A");
+    self.writeln("@R15");
+    self.writeln("M=D");
+    self.writeln(&format!("@$${}", math));
+    self.writeln("0;JMP");
+    self.writeln(&format!("({})", return_label));
+  }
+
+  /// An operation for the stack code generation, which keeps y and the
+  /// result in RAM
+  pub(super) fn write_routine_call(&mut self, math: MathCommand) {
+    self.writeln(&format!("// {}", math));
+    self.writeln("@SP");
+    self.writeln("AM=M-1");
+    self.writeln("D=M");
+    self.call_routine(math);
+    self.writeln("@SP");
+    self.writeln("AM=M+1");
+    self.writeln("A=A-1");
+    self.writeln("M=D");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::optimizer::evaluate_binary;
+  use crate::parser::MathCommand;
+  use crate::writer::Codegen;
+  use crate::{parse_file, translate, Bootstrap, Options};
+  use hack_assembler::emulator::Cpu;
+  use hack_assembler::parser::Parser;
+
+  /// The stack after running `vm` from an empty stack at 256
+  fn run(vm: &str, codegen: Codegen) -> Vec<i16> {
+    let options = Options {
+      bootstrap: Bootstrap::Never,
+      shared_comparisons: true,
+      codegen,
+      ..Options::default()
+    };
+    let asm = translate(&[parse_file(vm, "Main").unwrap()], &options).asm;
+    let mut cpu = Cpu::new(
+      Parser::new()
+        .assemble(&String::from_utf8(asm).unwrap())
+        .unwrap(),
+    );
+    cpu.ram[0] = 256;
+
+    assert!(cpu.run(100_000));
+    cpu.ram[256..cpu.ram[0] as usize].to_vec()
+  }
+
+  fn push(value: i32) -> String {
+    match value {
+      -32768 => String::from("push constant 32767\nneg\npush constant 1\nsub"),
+      value if value < 0 => format!("push constant {}\nneg", -value),
+      value => format!("push constant {}", value),
+    }
+  }
+
+  #[test]
+  fn compares_without_overflowing() {
+    let cases = [
+      (32767, -2, [0, -1, 0]),
+      (-2, 32767, [0, 0, -1]),
+      (-32768, 1, [0, 0, -1]),
+      (1, -32768, [0, -1, 0]),
+      (-32768, -32768, [-1, 0, 0]),
+      (5, 7, [0, 0, -1]),
+      (-7, -5, [0, 0, -1]),
+      (0, 0, [-1, 0, 0]),
+    ];
+    for (x, y, expected) in cases.iter() {
+      let vm: String = ["eq", "gt", "lt"]
+        .iter()
+        .map(|op| format!("{}\n{}\n{}\n", push(*x), push(*y), op))
+        .collect();
+
+      for codegen in [Codegen::Stack, Codegen::CachedTop].iter() {
+        assert_eq!(
+          run(&vm, *codegen),
+          expected,
+          "{} ? {} with {:?}",
+          x,
+          y,
+          codegen
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn computes_extended_operations() {
+    use MathCommand::*;
+
+    let operations = [
+      Multiply,
+      Divide,
+      Modulo,
+      ShiftLeft,
+      ShiftRight,
+      Xor,
+      LessThanUnsigned,
+      GreaterThanUnsigned,
+    ];
+    let cases = [
+      (6, 7),
+      (-6, 7),
+      (100, -7),
+      (-100, -7),
+      (32767, 2),
+      (-32768, -1),
+      (-32768, 3),
+      (12345, 1),
+      (-12345, 15),
+      (-5, 16),
+      (3, -1),
+      (0, 0),
+      (7, 0),
+      (-7, 0),
+    ];
+
+    for (x, y) in cases.iter() {
+      let vm: String = operations
+        .iter()
+        .map(|op| format!("{}\n{}\n{}\n", push(*x), push(*y), op))
+        .collect();
+      let expected: Vec<i16> = operations
+        .iter()
+        .map(|&op| {
+          let (x, y) = (*x as i16, *y as i16);
+          match (op, y) {
+            // which the optimizer leaves alone
+            (Divide, 0) if x < 0 => 1,
+            (Divide, 0) => -1,
+            (Modulo, 0) => x,
+            _ => evaluate_binary(op, x, y).unwrap(),
+          }
+        })
+        .collect();
+
+      for codegen in [Codegen::Stack, Codegen::CachedTop].iter() {
+        assert_eq!(
+          run(&vm, *codegen),
+          expected,
+          "{} and {} with {:?}",
+          x,
+          y,
+          codegen
+        );
+      }
+    }
+  }
+}